@@ -0,0 +1,22 @@
+//! Arbitrary bytes as `changes.log`: opening must fail cleanly or leave a
+//! readable change log. The bytes follow a valid header, so they reach the
+//! record parser.
+#![no_main]
+
+use std::path::Path;
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use rust_embedded_kv_store::{ChangeCursor, Db, MemFs, Vfs, FORMAT_VERSION};
+
+fuzz_target!(|changes: &[u8]| {
+    let fs = MemFs::new();
+    let mut file = fs.create(Path::new("db/changes.log")).unwrap();
+    file.write(&[b"KVCL".as_slice(), &(FORMAT_VERSION as u16).to_le_bytes(), &[0, 0]].concat()).unwrap();
+    file.write(changes).unwrap();
+    let Ok(mut db) = Db::open_with_vfs(Arc::new(fs), "db") else {
+        return;
+    };
+    let first = db.oldest_change_seq().unwrap_or(1);
+    db.read_changes(&mut ChangeCursor::from_seq(first), usize::MAX).unwrap();
+});
@@ -0,0 +1,23 @@
+//! Arbitrary bytes as `data.log`: opening must fail cleanly or yield a Db
+//! whose every indexed value can be read back. The bytes follow a valid
+//! header, so they reach the record parser.
+#![no_main]
+
+use std::path::Path;
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use rust_embedded_kv_store::{Db, MemFs, Vfs, FORMAT_VERSION};
+
+fuzz_target!(|data: &[u8]| {
+    let fs = MemFs::new();
+    let mut file = fs.create(Path::new("db/data.log")).unwrap();
+    file.write(&[b"KVDL".as_slice(), &(FORMAT_VERSION as u16).to_le_bytes(), &[0, 0]].concat()).unwrap();
+    file.write(data).unwrap();
+    let Ok(mut db) = Db::open_with_vfs(Arc::new(fs), "db") else {
+        return;
+    };
+    for (key, value) in db.scan(..).unwrap() {
+        assert_eq!(db.get(&key).unwrap(), Some(value));
+    }
+});
@@ -0,0 +1,21 @@
+//! Arbitrary bytes as a KvStore data file: format detection and opening
+//! must fail cleanly or succeed, never panic. Detection sees the bytes as
+//! they are; opening sees them behind a valid header, so they reach the
+//! record parser.
+#![no_main]
+
+use std::path::PathBuf;
+
+use libfuzzer_sys::fuzz_target;
+use rust_embedded_kv_store::{detect_format, KvStore, FORMAT_VERSION};
+
+fuzz_target!(|data: &[u8]| {
+    let path: PathBuf = std::env::temp_dir().join(format!("kvstore-fuzz-{}.log", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let _ = detect_format(&path);
+    std::fs::write(&path, [[b"KVSL".as_slice(), &(FORMAT_VERSION as u16).to_le_bytes(), &[0, 0]].concat().as_slice(), data].concat()).unwrap();
+    if let Ok(mut store) = KvStore::open(&path) {
+        // Values are only decoded here, so this may report corruption too.
+        let _ = store.scan(..);
+    }
+});
@@ -0,0 +1,26 @@
+//! Arbitrary bytes as `wal.log`: replay must fail cleanly or apply whole
+//! transactions, each of which then shows up in the change log. The bytes
+//! follow a valid header, so they reach the record parser.
+#![no_main]
+
+use std::path::Path;
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use rust_embedded_kv_store::{ChangeCursor, Db, MemFs, Vfs, FORMAT_VERSION};
+
+fuzz_target!(|wal: &[u8]| {
+    let fs = MemFs::new();
+    let header = [b"KVWL".as_slice(), &(FORMAT_VERSION as u16).to_le_bytes(), &[0, 0]].concat();
+    let mut file = fs.create(Path::new("db/wal.log")).unwrap();
+    file.write(&header).unwrap();
+    file.write(wal).unwrap();
+    let Ok(mut db) = Db::open_with_vfs(Arc::new(fs.clone()), "db") else {
+        return;
+    };
+    db.scan(..).unwrap();
+    if let Some(first) = db.oldest_change_seq() {
+        db.read_changes(&mut ChangeCursor::from_seq(first), usize::MAX).unwrap();
+    }
+    assert_eq!(fs.open(Path::new("db/wal.log")).unwrap().len().unwrap(), header.len() as u64);
+});
@@ -0,0 +1,310 @@
+//! WAL archiving for point-in-time recovery.
+//!
+//! With archiving enabled every committed transaction is also appended, with
+//! its commit time, to segment files in an archive directory:
+//!
+//! ```text
+//! [timestamp ms since the epoch: u64][BEGIN seq ... COMMIT as in the WAL]
+//! ```
+//!
+//! after the file header.
+//! Segments are named after the first sequence number they hold (zero-padded
+//! so they sort) and roll over at [`ARCHIVE_SEGMENT_BYTES`]. Segments older
+//! than the newest base backup are no longer needed and may be deleted.
+//!
+//! Recovery restores a base backup and replays archived transactions after
+//! the backup's sequence number, up to a [`RecoveryTarget`].
+
+use std::io::{self, BufReader, Error, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backup::read_backup_info;
+use crate::changes::{encode_batch, read_batch, ChangeLog};
+use crate::clock::unix_millis;
+use crate::format::{check_header, header, FileKind, HEADER_LEN};
+use crate::vfs::{read_exact_at, read_prefix, OsFs, Vfs, VfsFile, VfsReader, VfsWriter};
+use crate::wal_kv::{Db, Op};
+
+/// Size after which the archive starts a new segment.
+pub const ARCHIVE_SEGMENT_BYTES: u64 = 16 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "archive";
+
+/// How far [`Db::recover_to`] replays the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Every archived transaction.
+    Latest,
+    /// Transactions up to and including this sequence number.
+    Seq(u64),
+    /// Transactions committed at or before this time.
+    Time(SystemTime),
+}
+
+pub(crate) struct Archive {
+    vfs: Arc<dyn Vfs>,
+    dir: PathBuf,
+    /// Segment being appended to; the first is created when archiving is
+    /// enabled on an empty archive.
+    segment: Option<VfsWriter>,
+    segment_len: u64,
+    /// Seq the next record must have; `None` until the first segment exists.
+    next_seq: Option<u64>,
+}
+
+impl Archive {
+    /// Opens the archive in `dir` on `vfs`, dropping a record left
+    /// half-written by a crash. Records are appended once the change log
+    /// holds their batch, so such a record is the start of the next batch in
+    /// `changes`, at the end of the newest segment; any other bytes after the
+    /// last whole record are corruption.
+    fn open(vfs: Arc<dyn Vfs>, dir: &Path, changes: &mut ChangeLog) -> Result<Self> {
+        vfs.create_dir_all(dir)?;
+        let paths = segments(vfs.as_ref(), dir)?;
+        let mut next_seq = None;
+        let mut newest = None;
+        for (i, path) in paths.iter().enumerate() {
+            let Some(mut file) = open_segment(vfs.as_ref(), path)? else {
+                newest = None;
+                continue;
+            };
+            next_seq = first_seq(path);
+            let mut valid_len = HEADER_LEN;
+            let mut reader = BufReader::new(VfsReader::new(file.as_ref(), HEADER_LEN));
+            loop {
+                match read_record(&mut reader) {
+                    Ok(Some((_, seq, ops))) => {
+                        valid_len += record_len(&ops);
+                        next_seq = seq.checked_add(1);
+                    }
+                    Ok(None) => break,
+                    // A record cut short, or a corrupt length pointing past
+                    // the end; the check below tells the two apart.
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            drop(reader);
+
+            let torn = file.len()? - valid_len;
+            if torn > 0 {
+                let is_last = i + 1 == paths.len();
+                if !is_last || !is_torn_append(file.as_ref(), valid_len, next_seq, changes)? {
+                    return Err(Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("archive segment {}: corrupt record at offset {valid_len}", path.display()),
+                    ));
+                }
+                file.truncate(valid_len)?;
+                file.sync()?;
+            }
+            newest = Some((file, valid_len));
+        }
+
+        let (segment, segment_len) = match newest {
+            Some((file, len)) => (Some(VfsWriter(file)), len),
+            None => (None, 0),
+        };
+        Ok(Self { vfs, dir: dir.to_path_buf(), segment, segment_len, next_seq })
+    }
+
+    /// Durably appends one transaction committed at `at`.
+    pub(crate) fn append(&mut self, seq: u64, ops: &[Op], at: SystemTime) -> Result<()> {
+        let segment = match &mut self.segment {
+            Some(segment) if self.segment_len < ARCHIVE_SEGMENT_BYTES => segment,
+            _ => {
+                self.segment_len = HEADER_LEN;
+                self.segment.insert(create_segment(self.vfs.as_ref(), &self.dir, seq)?)
+            }
+        };
+        let mut record = unix_millis(at).to_le_bytes().to_vec();
+        record.extend(encode_batch(seq, ops));
+        segment.write_all(&record)?;
+        segment.file_mut().sync()?;
+        self.segment_len += record.len() as u64;
+        self.next_seq = seq.checked_add(1);
+        Ok(())
+    }
+
+    /// Creates the first segment of an empty archive, which records that the
+    /// archive starts at `seq` even before anything is appended.
+    fn start(&mut self, seq: u64) -> Result<()> {
+        self.segment = Some(create_segment(self.vfs.as_ref(), &self.dir, seq)?);
+        self.segment_len = HEADER_LEN;
+        self.next_seq = Some(seq);
+        Ok(())
+    }
+}
+
+impl Db {
+    /// Starts archiving every commit to `dir`, on the Db's filesystem.
+    /// Archiving lasts until the Db is closed, so enable it again after each
+    /// open.
+    ///
+    /// An empty archive starts with the next commit. Commits made while
+    /// archiving was off are archived now from the change log, stamped with
+    /// the current time. Fails with
+    /// [`io::ErrorKind::NotFound`] if some of them are no longer retained,
+    /// since the archive would have a gap; take a new base backup and archive
+    /// into a fresh directory instead.
+    pub fn enable_archiving<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        let mut archive = Archive::open(self.vfs.clone(), dir.as_ref(), &mut self.changes)?;
+        let Some(mut next) = archive.next_seq else {
+            archive.start(self.last_seq() + 1)?;
+            self.archive = Some(archive);
+            return Ok(());
+        };
+        if next <= self.last_seq() {
+            loop {
+                let batches = self.read_raw_changes(next, 256)?;
+                if batches.is_empty() {
+                    break;
+                }
+                for (seq, ops) in batches {
+                    if seq != next {
+                        return Err(Error::new(io::ErrorKind::NotFound, format!("change log no longer has seq {next}")));
+                    }
+                    archive.append(seq, &ops, self.clock.now())?;
+                    next = seq + 1;
+                }
+            }
+        }
+        self.archive = Some(archive);
+        Ok(())
+    }
+
+    pub fn disable_archiving(&mut self) {
+        self.archive = None;
+    }
+
+    /// Restores the base backup in `backup_dir` into `dir`, then replays the
+    /// archived transactions that follow it, up to `target`.
+    ///
+    /// A [`RecoveryTarget::Seq`] before the backup's own sequence number
+    /// can't be reached from it and fails with `InvalidInput`.
+    pub fn recover_to<P, Q, R>(backup_dir: P, archive_dir: Q, dir: R, target: RecoveryTarget) -> Result<Db>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+        R: AsRef<Path>,
+    {
+        if let RecoveryTarget::Seq(until) = target {
+            let base_seq = read_backup_info(backup_dir.as_ref())?.seq;
+            if until < base_seq {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("target seq {until} is before the base backup (seq {base_seq})"),
+                ));
+            }
+        }
+        let mut db = Db::restore(backup_dir, dir)?;
+
+        for path in segments(&OsFs, archive_dir.as_ref())? {
+            let Some(file) = open_segment(&OsFs, &path)? else {
+                continue;
+            };
+            let mut reader = BufReader::new(VfsReader::new(file.as_ref(), HEADER_LEN));
+            loop {
+                let (millis, seq, ops) = match read_record(&mut reader) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    // The archive's own torn tail: nothing after it was committed.
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                };
+                if seq <= db.last_seq() {
+                    continue;
+                }
+                let past_target = match target {
+                    RecoveryTarget::Latest => false,
+                    RecoveryTarget::Seq(until) => seq > until,
+                    RecoveryTarget::Time(until) => UNIX_EPOCH + Duration::from_millis(millis) > until,
+                };
+                if past_target {
+                    return Ok(db);
+                }
+                if seq != db.last_seq() + 1 {
+                    return Err(Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("archive is missing seq {} (next found: {seq})", db.last_seq() + 1),
+                    ));
+                }
+                db.write_batch_at(seq, ops)?;
+            }
+        }
+        Ok(db)
+    }
+}
+
+/// Segment files in `dir`, oldest first.
+pub(crate) fn segments(vfs: &dyn Vfs, dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut names: Vec<String> =
+        vfs.list_dir(dir)?.into_iter().filter(|name| name.ends_with(&format!(".{SEGMENT_EXTENSION}"))).collect();
+    names.sort();
+    Ok(names.into_iter().map(|name| dir.join(name)).collect())
+}
+
+/// Opens a segment after checking its header, or returns `None` if a crash
+/// interrupted its creation before the header was complete; the next append
+/// recreates it.
+fn open_segment(vfs: &dyn Vfs, path: &Path) -> Result<Option<Box<dyn VfsFile>>> {
+    let file = vfs.open(path)?;
+    let start = read_prefix(file.as_ref(), file.len()?.min(HEADER_LEN))?;
+    if start.len() < HEADER_LEN as usize && header(FileKind::Archive).starts_with(&start) {
+        return Ok(None);
+    }
+    check_header(&start, FileKind::Archive, path)?;
+    Ok(Some(file))
+}
+
+/// The sequence number a segment is named after, which its first record has.
+fn first_seq(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn create_segment(vfs: &dyn Vfs, dir: &Path, first_seq: u64) -> Result<VfsWriter> {
+    let path = dir.join(format!("{first_seq:020}.{SEGMENT_EXTENSION}"));
+    let mut file = VfsWriter(vfs.create(&path)?);
+    file.write_all(&header(FileKind::Archive))?;
+    file.file_mut().sync()?;
+    vfs.sync_dir(&path)?;
+    Ok(file)
+}
+
+/// Whether the bytes from `offset` to the end of `file` are the start of the
+/// record for batch `next_seq`, as a crash while appending it leaves them.
+fn is_torn_append(file: &dyn VfsFile, offset: u64, next_seq: Option<u64>, changes: &mut ChangeLog) -> Result<bool> {
+    let Some(next_seq) = next_seq else {
+        return Ok(false);
+    };
+    let batch = match changes.read(next_seq, 1)?.pop() {
+        Some((seq, ops)) if seq == next_seq => encode_batch(seq, &ops),
+        _ => return Ok(false),
+    };
+    let mut tail = vec![0u8; (file.len()? - offset) as usize];
+    if tail.len() >= 8 + batch.len() {
+        return Ok(false);
+    }
+    read_exact_at(file, &mut tail, offset)?;
+    // The first 8 bytes are the commit time, which only the record had.
+    Ok(tail.get(8..).is_none_or(|rest| batch.starts_with(rest)))
+}
+
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<(u64, u64, Vec<Op>)>> {
+    let mut millis = [0u8; 8];
+    match reader.read_exact(&mut millis) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    match read_batch(reader)? {
+        Some((seq, ops)) => Ok(Some((u64::from_le_bytes(millis), seq, ops))),
+        None => Err(Error::new(io::ErrorKind::UnexpectedEof, "archive record cut short")),
+    }
+}
+
+fn record_len(ops: &[Op]) -> u64 {
+    8 + encode_batch(0, ops).len() as u64
+}
@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Error, Result};
+use std::ops::{Bound, RangeBounds};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use futures_core::Stream;
+
+use crate::wal_kv::{Db, Op};
+
+type Bytes = Vec<u8>;
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Async handle to a [`Db`].
+///
+/// All disk work (reads, WAL writes and fsyncs) runs on a dedicated IO
+/// thread, so awaiting a `get` or a `commit` never blocks the executor.
+/// The handle is cheap to clone and works with any executor.
+///
+/// A `Db` does one thing at a time, so there is a single IO thread and jobs
+/// run in the order they were submitted: a `get` issued after a `commit`
+/// waits for that commit's fsync.
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Arc<Mutex<Db>>,
+    io: Arc<IoThread>,
+}
+
+impl AsyncDb {
+    const SCAN_BATCH: usize = 256;
+
+    pub fn new(db: Db) -> Self {
+        Self { db: Arc::new(Mutex::new(db)), io: Arc::new(IoThread::new()) }
+    }
+
+    /// Opens `./data.log` and `./wal.log` on the IO thread.
+    pub async fn open() -> Result<Self> {
+        let io = IoThread::new();
+        let db = io.spawn(Db::new).await?;
+        Ok(Self { db: Arc::new(Mutex::new(db)), io: Arc::new(io) })
+    }
+
+    pub fn get<K>(&self, key: K) -> IoFuture<Option<Bytes>>
+    where K: AsRef<[u8]>,
+    {
+        let key = key.as_ref().to_vec();
+        self.with_db(move |db| db.get(key))
+    }
+
+    /// Streams every live key/value pair in `range`, in key order.
+    ///
+    /// Entries are fetched from the IO thread in batches; the Db is not locked
+    /// between batches, so concurrent commits may be observed part way through.
+    pub fn scan<R>(&self, range: R) -> ScanStream
+    where R: RangeBounds<Bytes>,
+    {
+        ScanStream {
+            db: self.clone(),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            buffered: VecDeque::new(),
+            pending: None,
+            done: false,
+        }
+    }
+
+    pub fn scan_prefix<P>(&self, prefix: P) -> ScanStream
+    where P: AsRef<[u8]>,
+    {
+        self.scan(crate::wal_kv::prefix_range(prefix.as_ref()))
+    }
+
+    pub fn begin_transaction(&self) -> AsyncTransaction {
+        AsyncTransaction {
+            db: self.clone(),
+            operations: Vec::new(),
+        }
+    }
+
+    fn with_db<T, F>(&self, f: F) -> IoFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Db) -> Result<T> + Send + 'static,
+    {
+        let db = Arc::clone(&self.db);
+        self.io.spawn(move || {
+            let mut db = db.lock().map_err(|_| Error::other("db mutex poisoned"))?;
+            f(&mut db)
+        })
+    }
+}
+
+/// Buffered operations for an [`AsyncDb`]; nothing touches disk until `commit`.
+pub struct AsyncTransaction {
+    db: AsyncDb,
+    operations: Vec<Op>,
+}
+
+impl AsyncTransaction {
+    pub fn set<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let k = key.as_ref().to_vec();
+        let v = value.as_ref().to_vec();
+        self.operations.push(Op::Set(k, v));
+    }
+
+    pub fn delete<K>(&mut self, key: K)
+    where
+        K: AsRef<[u8]>,
+    {
+        let k = key.as_ref().to_vec();
+        self.operations.push(Op::Delete(k));
+    }
+
+    /// Resolves once the transaction is durable in the data log.
+    pub fn commit(self) -> IoFuture<()> {
+        let operations = self.operations;
+        self.db.with_db(move |db| db.commit(operations))
+    }
+}
+
+/// Stream of key/value pairs returned by [`AsyncDb::scan`].
+pub struct ScanStream {
+    db: AsyncDb,
+    start: Bound<Bytes>,
+    end: Bound<Bytes>,
+    buffered: VecDeque<(Bytes, Bytes)>,
+    pending: Option<IoFuture<Vec<(Bytes, Bytes)>>>,
+    done: bool,
+}
+
+impl ScanStream {
+    /// Convenience for callers that do not use `futures::StreamExt`.
+    pub async fn next(&mut self) -> Option<Result<(Bytes, Bytes)>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for ScanStream {
+    type Item = Result<(Bytes, Bytes)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(entry) = this.buffered.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let pending = this.pending.get_or_insert_with(|| {
+                let range = (this.start.clone(), this.end.clone());
+                this.db.with_db(move |db| db.scan_limit(range, AsyncDb::SCAN_BATCH))
+            });
+
+            let batch = match Pin::new(pending).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(batch) => batch,
+            };
+            this.pending = None;
+
+            match batch {
+                Ok(batch) => {
+                    if batch.len() < AsyncDb::SCAN_BATCH {
+                        this.done = true;
+                    }
+                    if let Some((last, _)) = batch.last() {
+                        this.start = Bound::Excluded(last.clone());
+                    }
+                    this.buffered.extend(batch);
+                }
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+/// Future resolved by a job running on the IO thread.
+pub struct IoFuture<T> {
+    shared: Arc<Mutex<Slot<T>>>,
+}
+
+struct Slot<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for IoFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Completes an [`IoFuture`]; if dropped without a result (the job panicked)
+/// the future resolves to an error instead of hanging.
+struct Completer<T> {
+    shared: Arc<Mutex<Slot<T>>>,
+    sent: bool,
+}
+
+impl<T> Completer<T> {
+    fn complete(mut self, result: Result<T>) {
+        self.fill(result);
+        self.sent = true;
+    }
+
+    fn fill(&self, result: Result<T>) {
+        let mut slot = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+        slot.result = Some(result);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if !self.sent {
+            self.fill(Err(Error::other("io thread terminated")));
+        }
+    }
+}
+
+/// Thread that runs blocking disk work off the executor, one job at a time.
+struct IoThread {
+    sender: Option<Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl IoThread {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let worker = thread::Builder::new()
+            .name("kv-io".to_string())
+            .spawn(move || Self::work(&receiver))
+            .expect("failed to spawn io thread");
+        Self { sender: Some(sender), worker: Some(worker) }
+    }
+
+    fn work(receiver: &Receiver<Job>) {
+        while let Ok(job) = receiver.recv() {
+            job();
+        }
+    }
+
+    fn spawn<T, F>(&self, f: F) -> IoFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        let completer = Completer { shared: Arc::clone(&shared), sent: false };
+        let job: Job = Box::new(move || completer.complete(f()));
+        if let Some(sender) = &self.sender {
+            // A send error drops the job, and with it the completer, which
+            // resolves the future to an error.
+            let _ = sender.send(job);
+        }
+        IoFuture { shared }
+    }
+}
+
+impl Drop for IoThread {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
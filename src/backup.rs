@@ -0,0 +1,327 @@
+//! Online backups.
+//!
+//! The data log segments and change log are only ever appended to between
+//! commits, and compaction or retention replace them with new files rather
+//! than rewriting them in place. So a backup only needs to note how long each
+//! file is at a commit point and keep a handle open on it; copying that prefix
+//! later yields exactly the state as of that commit, however many writes
+//! happen meanwhile.
+//!
+//! A backup directory is laid out like a Db without a WAL: the segments, a
+//! manifest listing them, `changes.log` and `backup.info`. Backing up into a
+//! directory that holds an earlier backup of the same Db is incremental.
+//! `backup.info` records the backup's seq, the id of the Db it came from and
+//! the length of every segment it holds. Sealed segments never change, so
+//! one the target already has in full is left alone, and the active segment
+//! only gets what was appended since. Only segments the info file lists, at
+//! the length it lists, are trusted that way, and only if it names this Db;
+//! anything else in the target is copied afresh. The change log and manifest
+//! are rewritten, and segments the Db no longer has are removed. The info
+//! file is removed first and written last, so a directory without it is an
+//! interrupted backup and is refused by [`Db::restore`].
+//!
+//! Backups from before segments hold a single `data.log` and no manifest;
+//! they restore as before.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Error, Read, Result, Write};
+use std::path::Path;
+
+use crate::format::{header, FileKind, HEADER_LEN};
+use crate::manifest::{manifest_file_name, read_current, write_current, Manifest, CURRENT_FILE, FORMAT_VERSION};
+use crate::segments::segment_file_name;
+use crate::vfs::{read_exact_at, sync_parent_dir, OsFs, Vfs, VfsFile, VfsReader, VfsWriter};
+use crate::wal_kv::Db;
+
+pub(crate) const BACKUP_INFO_FILE: &str = "backup.info";
+
+/// A consistent view of a Db's files as of one commit, ready to be copied.
+pub struct Backup {
+    seq: u64,
+    /// Id of the Db the backup is of.
+    db_id: u64,
+    segment_size: u64,
+    /// Every data log segment, oldest first, with its id and length.
+    segments: Vec<(u64, Box<dyn VfsFile>, u64)>,
+    changes: Box<dyn VfsFile>,
+    changes_len: u64,
+}
+
+impl Db {
+    /// Captures the current commit point for a backup. This is cheap, so with
+    /// a shared `Mutex<Db>` call it under the lock and run
+    /// [`Backup::write_to`] after releasing it; writers are not blocked while
+    /// the files are copied.
+    pub fn start_backup(&mut self) -> Result<Backup> {
+        if self.id == 0 {
+            self.id = new_db_id();
+            self.write_manifest(&self.segment_ids())?;
+        }
+        let mut segments = Vec::with_capacity(self.segment_count());
+        for segment in &self.sealed {
+            segments.push((segment.id, self.vfs.open(&self.segment_path(segment.id))?, segment.len));
+        }
+        let active = self.active_segment;
+        segments.push((active, self.vfs.open(&self.segment_path(active))?, self.data_writer_pos));
+        Ok(Backup {
+            seq: self.last_seq(),
+            db_id: self.id,
+            segment_size: self.segment_size,
+            segments,
+            changes: self.vfs.open(&self.dir().join(Db::CHANGES_FILE))?,
+            changes_len: self.changes.file_len(),
+        })
+    }
+
+    /// Writes a self-contained copy of the Db as of the latest commit to
+    /// `dir`, which must be empty or hold an earlier backup of this Db to
+    /// bring up to date. Returns the sequence number the backup reflects.
+    pub fn backup_to<P: AsRef<Path>>(&mut self, dir: P) -> Result<u64> {
+        let backup = self.start_backup()?;
+        let seq = backup.seq();
+        backup.write_to(dir)?;
+        Ok(seq)
+    }
+
+    /// Writes the Db as of the latest commit to `dir` as an ordinary on-disk
+    /// Db, e.g. to keep an in-memory Db. `dir` must not already hold a Db;
+    /// open it with [`Db::open`].
+    pub fn persist_to<P: AsRef<Path>>(&mut self, dir: P) -> Result<()> {
+        self.start_backup()?.write_db_files(dir.as_ref())
+    }
+
+    /// Copies the backup in `backup_dir` into `dir`, which must not already
+    /// hold a Db, and opens it at the backup's sequence number.
+    pub fn restore<P, Q>(backup_dir: P, dir: Q) -> Result<Db>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let backup_dir = backup_dir.as_ref();
+        let dir = dir.as_ref();
+        let seq = read_backup_info(backup_dir)?.seq;
+        ensure_no_db(dir)?;
+
+        let mut names = vec![Db::CHANGES_FILE.to_string()];
+        match read_current(&OsFs, backup_dir)? {
+            Some((number, manifest)) => {
+                names.extend(manifest.segments.iter().map(|&id| segment_file_name(id)));
+                names.extend([manifest_file_name(number), CURRENT_FILE.to_string()]);
+            }
+            None => names.push(Db::DATA_FILE.to_string()),
+        }
+        fs::create_dir_all(dir)?;
+        for name in &names {
+            let target = dir.join(name);
+            fs::copy(backup_dir.join(name), &target)?;
+            File::open(&target)?.sync_all()?;
+        }
+        sync_parent_dir(&dir.join(Db::CHANGES_FILE))?;
+
+        let mut db = Db::open(dir)?;
+        // The change log may have been emptied before the backup, e.g. by a
+        // bulk load, and older backups have no manifest to say otherwise.
+        if db.last_seq() < seq {
+            db.set_last_seq(seq);
+            db.write_manifest(&db.segment_ids())?;
+        }
+        Ok(db)
+    }
+}
+
+impl Backup {
+    /// Sequence number of the last commit included in the backup.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The captured data log: on its own, a complete image of the Db.
+    pub(crate) fn read_data(&mut self) -> Result<Vec<u8>> {
+        let mut data = header(FileKind::DataLog).to_vec();
+        for (_, file, len) in &self.segments {
+            let start = data.len();
+            data.resize(start + (len - HEADER_LEN) as usize, 0);
+            read_exact_at(file.as_ref(), &mut data[start..], HEADER_LEN)?;
+        }
+        Ok(data)
+    }
+
+    /// Copies the captured files into `dir`, which must be empty or hold an
+    /// earlier backup of the same Db; only what that backup lacks is copied.
+    pub fn write_to<P: AsRef<Path>>(self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        if dir.join(Db::WAL_FILE).exists() {
+            return Err(Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} holds a Db, not a backup", dir.display()),
+            ));
+        }
+        let info_path = dir.join(BACKUP_INFO_FILE);
+        let mut copied = BTreeMap::new();
+        if info_path.exists() {
+            // An info file that can't be read only costs a full copy.
+            match read_backup_info(dir) {
+                Ok(info) if info.db_id == self.db_id => copied = info.segments,
+                _ => {}
+            }
+            fs::remove_file(&info_path)?;
+            sync_parent_dir(&info_path)?;
+        }
+        self.write_files(dir, &copied)?;
+
+        let mut info = File::create(&info_path)?;
+        writeln!(info, "seq {}", self.seq)?;
+        writeln!(info, "db {:016x}", self.db_id)?;
+        for (id, _, len) in &self.segments {
+            writeln!(info, "segment {id} {len}")?;
+        }
+        info.sync_all()?;
+        sync_parent_dir(&info_path)
+    }
+
+    /// Copies the captured files into `dir`, which must not already hold a
+    /// Db or backup: enough for [`Db::open`] on their own.
+    pub(crate) fn write_db_files(&self, dir: &Path) -> Result<()> {
+        ensure_no_db(dir)?;
+        self.write_files(dir, &BTreeMap::new())
+    }
+
+    /// Brings the segments, change log and manifest in `dir` up to date with
+    /// the captured ones and removes files the capture doesn't list. `copied`
+    /// has the length of each segment an earlier backup of this Db left in
+    /// `dir`.
+    fn write_files(&self, dir: &Path, copied: &BTreeMap<u64, u64>) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        for (id, file, len) in &self.segments {
+            let have = copied.get(id).copied().unwrap_or(0);
+            copy_segment(file.as_ref(), *len, have, &dir.join(segment_file_name(*id)))?;
+        }
+        copy_records(FileKind::ChangeLog, self.changes.as_ref(), self.changes_len, &dir.join(Db::CHANGES_FILE))?;
+
+        let mut manifests = BTreeSet::new();
+        let mut stale = Vec::new();
+        let live: BTreeSet<String> = self.segments.iter().map(|(id, _, _)| segment_file_name(*id)).collect();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(number) = name.strip_prefix("MANIFEST-").and_then(|n| n.parse::<u64>().ok()) {
+                manifests.insert(number);
+            } else if (name.starts_with("data") && name.ends_with(".log") && !live.contains(&name))
+                || name.ends_with(".hint")
+            {
+                stale.push(name);
+            }
+        }
+        let number = manifests.last().map_or(1, |last| last + 1);
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            segment_size: self.segment_size,
+            segments: self.segments.iter().map(|(id, _, _)| *id).collect(),
+            last_seq: self.seq,
+            history_start: 0,
+            // A restored copy is a Db of its own, with its own id.
+            id: 0,
+        };
+        write_current(&OsFs, dir, number, &manifest)?;
+        stale.extend(manifests.range(..number.saturating_sub(1)).map(|&n| manifest_file_name(n)));
+        for name in stale {
+            fs::remove_file(dir.join(name))?;
+        }
+        sync_parent_dir(&dir.join(CURRENT_FILE))
+    }
+}
+
+/// What `backup.info` records about a complete backup.
+pub(crate) struct BackupInfo {
+    pub(crate) seq: u64,
+    /// Id of the Db backed up; 0 for backups that predate ids.
+    db_id: u64,
+    /// Length of each segment, by id.
+    segments: BTreeMap<u64, u64>,
+}
+
+/// Reads `backup.info` in a complete backup directory. Older backups record
+/// only the seq.
+pub(crate) fn read_backup_info(backup_dir: &Path) -> Result<BackupInfo> {
+    let info = match fs::read_to_string(backup_dir.join(BACKUP_INFO_FILE)) {
+        Ok(info) => info,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a complete backup", backup_dir.display()),
+            ));
+        }
+        Err(e) => return Err(e),
+    };
+    let malformed = || Error::new(io::ErrorKind::InvalidData, "malformed backup.info");
+    let mut lines = info.lines();
+    let seq = lines.next().and_then(|line| line.strip_prefix("seq ")).and_then(|seq| seq.parse().ok());
+    let mut info = BackupInfo { seq: seq.ok_or_else(malformed)?, db_id: 0, segments: BTreeMap::new() };
+    for line in lines {
+        match line.split_once(' ') {
+            Some(("db", id)) => info.db_id = u64::from_str_radix(id, 16).map_err(|_| malformed())?,
+            Some(("segment", entry)) => {
+                let (id, len) = entry.split_once(' ').ok_or_else(malformed)?;
+                let id = id.parse().map_err(|_| malformed())?;
+                info.segments.insert(id, len.parse().map_err(|_| malformed())?);
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(info)
+}
+
+/// A fresh random Db id, never 0.
+fn new_db_id() -> u64 {
+    loop {
+        let id = RandomState::new().build_hasher().finish();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+fn ensure_no_db(dir: &Path) -> Result<()> {
+    for name in [Db::DATA_FILE, CURRENT_FILE, BACKUP_INFO_FILE] {
+        if dir.join(name).exists() {
+            return Err(Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already contains {name}", dir.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Brings `target` up to the first `len` bytes of the segment `source`. An
+/// earlier backup of the same Db that recorded copying `have` bytes of it
+/// left a prefix, all of it for a sealed segment, so only the rest is copied;
+/// a target of any other length is copied afresh.
+fn copy_segment(source: &dyn VfsFile, len: u64, have: u64, target: &Path) -> Result<()> {
+    let mut out = VfsWriter(OsFs.open(target)?);
+    let mut have = have;
+    if have > len || out.file().len()? != have {
+        out.file_mut().truncate(0)?;
+        have = 0;
+    }
+    let copied = io::copy(&mut VfsReader::new(source, have).take(len - have), &mut out)?;
+    if copied != len - have {
+        return Err(Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than expected", target.display())));
+    }
+    out.file_mut().sync()
+}
+
+/// Writes a `kind` header to `target`, followed by the records in the first
+/// `len` bytes of `source`.
+fn copy_records(kind: FileKind, source: &dyn VfsFile, len: u64, target: &Path) -> Result<()> {
+    let mut out = File::create(target)?;
+    out.write_all(&header(kind))?;
+    let len = len - HEADER_LEN;
+    let copied = io::copy(&mut VfsReader::new(source, HEADER_LEN).take(len), &mut out)?;
+    if copied != len {
+        return Err(Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than expected", target.display())));
+    }
+    out.sync_all()
+}
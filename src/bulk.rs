@@ -0,0 +1,241 @@
+//! Bulk loading that bypasses the WAL.
+//!
+//! Records are buffered in memory and, when the buffer is full, spilled to
+//! sorted run files. `finish` merges the runs with the Db's current live
+//! records into a new data log, syncs it once and renames it over the data
+//! log, so the load becomes visible all at once and a failure at any earlier
+//! point leaves the Db's contents as they were.
+
+use std::collections::{btree_map, BTreeMap};
+use std::io::{self, BufReader, BufWriter, Error, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use crate::format::{header, FileKind};
+use crate::secondary::is_index_definition;
+use crate::segments::RecordPos;
+use crate::vfs::{VfsReader, VfsWriter};
+use crate::wal_kv::{is_system_key, read_field, write_record, Db, OP_PUT};
+
+type Bytes = Vec<u8>;
+
+/// Default size of buffered keys and values before they are spilled to a run.
+pub const DEFAULT_BULK_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Loads many records into a [`Db`] without going through the WAL. Created by
+/// [`Db::bulk_loader`]; nothing is visible until [`BulkLoader::finish`].
+pub struct BulkLoader<'a> {
+    db: &'a mut Db,
+    buffer: BTreeMap<Bytes, Bytes>,
+    buffered_bytes: usize,
+    memory_limit: usize,
+    runs: Vec<PathBuf>,
+    inserted: u64,
+}
+
+impl Db {
+    /// Starts a bulk load. Loaded records overwrite existing ones with the
+    /// same key; within a load, the last insert of a key wins.
+    ///
+    /// A bulk load is not recorded in the change log: it is replaced by a
+    /// single empty commit, so change readers and followers that were behind
+    /// it start over from a snapshot. Watchers are not notified.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if secondary indexes are
+    /// registered; register them after the load, which backfills them. Also
+    /// fails while archiving is enabled, since the archive could not replay
+    /// the load; enable it afterwards along with a new base backup.
+    pub fn bulk_loader(&mut self) -> Result<BulkLoader<'_>> {
+        if self.has_secondary_indexes() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "bulk loading does not maintain secondary indexes; register them after the load",
+            ));
+        }
+        if self.archive.is_some() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                "bulk loads are not archived; enable archiving after the load",
+            ));
+        }
+        Ok(BulkLoader {
+            db: self,
+            buffer: BTreeMap::new(),
+            buffered_bytes: 0,
+            memory_limit: DEFAULT_BULK_MEMORY,
+            runs: Vec::new(),
+            inserted: 0,
+        })
+    }
+}
+
+impl BulkLoader<'_> {
+    /// Sets how many bytes of keys and values are buffered before spilling
+    /// a sorted run to disk.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes.max(1);
+        self
+    }
+
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        if is_system_key(key) {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "keys starting with 0xFF are reserved"));
+        }
+        self.buffered_bytes += key.len() + value.as_ref().len();
+        if let Some(old) = self.buffer.insert(key.to_vec(), value.as_ref().to_vec()) {
+            self.buffered_bytes -= key.len() + old.len();
+        }
+        self.inserted += 1;
+        if self.buffered_bytes >= self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Merges everything inserted into the Db and makes it visible. Returns
+    /// the number of records inserted.
+    pub fn finish(mut self) -> Result<u64> {
+        if self.inserted == 0 {
+            return Ok(0);
+        }
+
+        // Lowest priority first: the Db's current records, then runs in the
+        // order they were written, then the in-memory buffer.
+        let run_files = self.runs.iter().map(|path| self.db.vfs.open(path)).collect::<Result<Vec<_>>>()?;
+        let mut sources: Vec<Source> = Vec::with_capacity(self.runs.len() + 2);
+        // The load doesn't maintain stored indexes; dropping their versions
+        // makes the next registration rebuild them.
+        let mut live = self.db.live_records();
+        live.retain(|(key, _)| !is_index_definition(key));
+        sources.push(Source::Db(live.into_iter()));
+        for file in &run_files {
+            sources.push(Source::Run(BufReader::new(VfsReader::new(file.as_ref(), 0))));
+        }
+        sources.push(Source::Memory(std::mem::take(&mut self.buffer).into_iter()));
+
+        let tmp_path = self.db.dir().join("data.log.bulk");
+        let seq = self.db.last_seq() + 1;
+        let result = merge_into(self.db, &mut sources, &tmp_path).and_then(|()| self.swap(seq, &tmp_path));
+        if result.is_err() {
+            let _ = self.db.vfs.remove_file(&tmp_path);
+        }
+        result?;
+
+        // The old history doesn't lead to the new contents, so it is cut back
+        // to an empty commit at the load's seq. The manifest already records
+        // that seq, so a crash before this finishes is repaired on open.
+        if let Err(e) = self.db.changes.restart(seq) {
+            self.db.poisoned = true;
+            return Err(e);
+        }
+        Ok(self.inserted)
+    }
+
+    /// Swaps the merged data log in as one commit at `seq`. The manifest
+    /// naming it also records the seq and the change log's restart, so the
+    /// Db keeps its old seq unless that manifest was written.
+    fn swap(&mut self, seq: u64, merged: &Path) -> Result<()> {
+        let (last_seq, history_start, manifest_number) =
+            (self.db.last_seq(), self.db.history_start, self.db.manifest_number);
+        self.db.set_last_seq(seq);
+        self.db.history_start = seq;
+        let result = self.db.replace_data_file(merged);
+        if result.is_err() {
+            if self.db.manifest_number == manifest_number {
+                self.db.set_last_seq(last_seq);
+                self.db.history_start = history_start;
+            } else {
+                // The swap is committed but the in-memory index may not
+                // match it; reopening finishes it.
+                self.db.poisoned = true;
+            }
+        }
+        result
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let path = self.db.dir().join(format!("bulk-{}.run", self.runs.len()));
+        // Register first so the file is cleaned up even if writing fails.
+        self.runs.push(path.clone());
+        let mut writer = BufWriter::new(VfsWriter(self.db.vfs.create(&path)?));
+        for (key, value) in std::mem::take(&mut self.buffer) {
+            write_record(&mut writer, OP_PUT, &key, &value)?;
+        }
+        writer.flush()?;
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+}
+
+impl Drop for BulkLoader<'_> {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = self.db.vfs.remove_file(path);
+        }
+    }
+}
+
+enum Source<'a> {
+    /// Live records already in the Db, as keys and data log positions.
+    Db(std::vec::IntoIter<(Bytes, RecordPos)>),
+    Memory(btree_map::IntoIter<Bytes, Bytes>),
+    Run(BufReader<VfsReader<'a>>),
+}
+
+impl Source<'_> {
+    fn next(&mut self, db: &mut Db) -> Result<Option<(Bytes, Bytes)>> {
+        match self {
+            Source::Db(iter) => match iter.next() {
+                Some((key, pos)) => Ok(Some((key, db.read_value(pos)?))),
+                None => Ok(None),
+            },
+            Source::Memory(iter) => Ok(iter.next()),
+            Source::Run(reader) => read_run_record(reader),
+        }
+    }
+}
+
+/// Merges sorted, duplicate-free sources into a new data log at `path`. When
+/// several sources hold a key, the one latest in `sources` wins.
+fn merge_into(db: &mut Db, sources: &mut [Source<'_>], path: &Path) -> Result<()> {
+    let mut heads = Vec::with_capacity(sources.len());
+    for source in sources.iter_mut() {
+        heads.push(source.next(db)?);
+    }
+
+    let mut writer = BufWriter::new(VfsWriter(db.vfs.create(path)?));
+    writer.write_all(&header(FileKind::DataLog))?;
+    while let Some(min) = heads.iter().flatten().map(|(k, _)| k).min().cloned() {
+        let mut winner = None;
+        for (i, head) in heads.iter_mut().enumerate() {
+            if head.as_ref().is_some_and(|(k, _)| *k == min) {
+                winner = head.take().map(|(_, v)| v);
+                *head = sources[i].next(db)?;
+            }
+        }
+        if let Some(value) = winner {
+            write_record(&mut writer, OP_PUT, &min, &value)?;
+        }
+    }
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.file_mut().sync()
+}
+
+fn read_run_record<R: Read>(reader: &mut R) -> Result<Option<(Bytes, Bytes)>> {
+    let mut header = [0u8; 9];
+    match reader.read_exact(&mut header[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut header[1..])?;
+    let klen = u32::from_le_bytes(header[1..5].try_into().expect("4 bytes")) as usize;
+    let vlen = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes")) as usize;
+    let key = read_field(reader, klen)?;
+    let value = read_field(reader, vlen)?;
+    Ok(Some((key, value)))
+}
@@ -0,0 +1,386 @@
+//! Change-data-capture log.
+//!
+//! Every committed batch is appended to `changes.log` with the same framing
+//! the WAL uses, plus the batch's sequence number:
+//!
+//! ```text
+//! [OP_BEGIN][seq: u64][OP_PUT klen vlen key value | OP_DELETE klen 0 key]...[OP_COMMIT]
+//! ```
+//!
+//! after the file header. Unlike the WAL, the change log is not cleared after each commit; it keeps
+//! the most recent transactions up to a retention limit so consumers can read
+//! every commit in order and resume from a saved sequence number.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, BufWriter, Error, Read, Result, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::format::{header, init_header, FileKind, HEADER_LEN};
+use crate::vfs::{Vfs, VfsReader, VfsWriter};
+use crate::wal_kv::{is_system_key, read_field, Db, Op, OP_BEGIN, OP_COMMIT, OP_DELETE, OP_PUT};
+
+type Bytes = Vec<u8>;
+
+/// One committed transaction read from the change log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeBatch {
+    pub seq: u64,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Put { key: Bytes, value: Bytes },
+    Delete { key: Bytes },
+}
+
+/// Position in the change log. Persist [`ChangeCursor::next_seq`] to resume
+/// after a restart with [`ChangeCursor::from_seq`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeCursor {
+    next_seq: u64,
+}
+
+impl ChangeCursor {
+    /// Cursor whose first batch will be the one with sequence number `seq`.
+    pub fn from_seq(seq: u64) -> Self {
+        Self { next_seq: seq }
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+pub(crate) struct ChangeLog {
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+    file: VfsWriter,
+    /// Sequence number to file offset for every retained batch.
+    offsets: BTreeMap<u64, u64>,
+    len: u64,
+    retention: usize,
+}
+
+impl ChangeLog {
+    pub(crate) const DEFAULT_RETENTION: usize = 10_000;
+
+    /// Opens the log, dropping a batch left half-written by a crash. Batches
+    /// are appended once the WAL holding them is synced, so such a batch is
+    /// shorter than the `pending` bytes the WAL's batches take here; a longer
+    /// tail that doesn't parse is corruption.
+    pub(crate) fn open(vfs: Arc<dyn Vfs>, path: PathBuf, pending: u64) -> Result<Self> {
+        let mut file = vfs.open(&path)?;
+        init_header(file.as_mut(), FileKind::ChangeLog, &path)?;
+
+        let mut offsets = BTreeMap::new();
+        let mut len = HEADER_LEN;
+        {
+            let reader = BufReader::new(VfsReader::new(file.as_ref(), HEADER_LEN));
+            let mut reader = CountingReader { inner: reader, pos: HEADER_LEN };
+            loop {
+                match read_batch(&mut reader) {
+                    Ok(Some((seq, _))) => {
+                        if offsets.keys().next_back().is_some_and(|&last| seq <= last) {
+                            return Err(Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("changes.log: seq {seq} at offset {len} is out of order"),
+                            ));
+                        }
+                        offsets.insert(seq, len);
+                        len = reader.pos;
+                    }
+                    Ok(None) => break,
+                    // A batch cut short, or a corrupt length pointing past
+                    // the end; the check below tells the two apart.
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let torn = file.len()? - len;
+        if torn > 0 && torn >= pending {
+            return Err(Error::new(io::ErrorKind::InvalidData, format!("changes.log: corrupt batch at offset {len}")));
+        }
+        if torn > 0 {
+            file.truncate(len)?;
+            file.sync()?;
+        }
+
+        Ok(Self { vfs, path, file: VfsWriter(file), offsets, len, retention: Self::DEFAULT_RETENTION })
+    }
+
+    pub(crate) fn last_seq(&self) -> Option<u64> {
+        self.offsets.keys().next_back().copied()
+    }
+
+    pub(crate) fn first_seq(&self) -> Option<u64> {
+        self.offsets.keys().next().copied()
+    }
+
+    pub(crate) fn file_len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn set_retention(&mut self, transactions: usize) {
+        self.retention = transactions.max(1);
+    }
+
+    /// Durably appends one batch, then trims old batches if the log has
+    /// grown to twice the retention limit.
+    pub(crate) fn append(&mut self, seq: u64, ops: &[Op]) -> Result<()> {
+        let record = encode_batch(seq, ops);
+        self.file.write_all(&record)?;
+        self.file.file_mut().sync()?;
+        self.offsets.insert(seq, self.len);
+        self.len += record.len() as u64;
+
+        if self.offsets.len() >= self.retention * 2 {
+            self.trim()?;
+        }
+        Ok(())
+    }
+
+    /// Discards every batch, e.g. after the Db's contents were replaced by a
+    /// snapshot and the old history no longer leads to them.
+    pub(crate) fn reset(&mut self) -> Result<()> {
+        self.file.file_mut().truncate(HEADER_LEN)?;
+        self.file.file_mut().sync()?;
+        self.offsets.clear();
+        self.len = HEADER_LEN;
+        Ok(())
+    }
+
+    /// Atomically replaces every batch with a single empty one at `seq`, so
+    /// that readers behind it learn the history was cut.
+    pub(crate) fn restart(&mut self, seq: u64) -> Result<()> {
+        let tmp_path = self.path.with_extension("log.tmp");
+        let record = encode_batch(seq, &[]);
+        {
+            let mut tmp = VfsWriter(self.vfs.create(&tmp_path)?);
+            tmp.write_all(&header(FileKind::ChangeLog))?;
+            tmp.write_all(&record)?;
+            tmp.file_mut().sync()?;
+        }
+        self.vfs.rename(&tmp_path, &self.path)?;
+        self.vfs.sync_dir(&self.path)?;
+
+        self.file = VfsWriter(self.vfs.open(&self.path)?);
+        self.offsets = BTreeMap::from([(seq, HEADER_LEN)]);
+        self.len = HEADER_LEN + record.len() as u64;
+        Ok(())
+    }
+
+    /// Up to `max` batches starting at `from_seq`.
+    pub(crate) fn read(&mut self, from_seq: u64, max: usize) -> Result<Vec<(u64, Vec<Op>)>> {
+        let Some((_, &start)) = self.offsets.range(from_seq..).next() else {
+            return Ok(Vec::new());
+        };
+        let mut reader = BufReader::new(VfsReader::new(self.file.file(), start));
+        let mut out = Vec::new();
+        while out.len() < max {
+            match read_batch(&mut reader)? {
+                Some(batch) => out.push(batch),
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+
+    /// Rewrites the log keeping only the newest `retention` batches.
+    fn trim(&mut self) -> Result<()> {
+        let drop_count = self.offsets.len().saturating_sub(self.retention);
+        match self.offsets.keys().nth(drop_count) {
+            Some(&first_kept) => self.discard_before(first_kept),
+            None => Ok(()),
+        }
+    }
+
+    /// Rewrites the log without the batches before `seq`, so that readers
+    /// behind it learn they were discarded.
+    pub(crate) fn discard_before(&mut self, seq: u64) -> Result<()> {
+        let Some((&first_kept, &cut)) = self.offsets.range(seq..).next() else {
+            return Ok(());
+        };
+        if cut == HEADER_LEN {
+            return Ok(());
+        }
+
+        let tmp_path = self.path.with_extension("log.tmp");
+        {
+            let mut tmp = BufWriter::new(VfsWriter(self.vfs.create(&tmp_path)?));
+            tmp.write_all(&header(FileKind::ChangeLog))?;
+            io::copy(&mut VfsReader::new(self.file.file(), cut).take(self.len - cut), &mut tmp)?;
+            tmp.into_inner().map_err(|e| e.into_error())?.file_mut().sync()?;
+        }
+        self.vfs.rename(&tmp_path, &self.path)?;
+        self.vfs.sync_dir(&self.path)?;
+
+        self.file = VfsWriter(self.vfs.open(&self.path)?);
+        // Everything from `cut` on moves to just after the header.
+        let shift = cut - HEADER_LEN;
+        self.offsets = self.offsets.split_off(&first_kept).into_iter().map(|(seq, off)| (seq, off - shift)).collect();
+        self.len -= shift;
+        Ok(())
+    }
+}
+
+impl Db {
+    /// Keeps at least the last `transactions` commits in the change log; older
+    /// ones are discarded in bulk once twice that many have accumulated.
+    pub fn set_change_retention(&mut self, transactions: usize) {
+        self.changes.set_retention(transactions);
+    }
+
+    /// Sequence number of the oldest commit still in the change log.
+    pub fn oldest_change_seq(&self) -> Option<u64> {
+        self.changes.first_seq()
+    }
+
+    /// Reads up to `max` committed transactions starting at the cursor and
+    /// advances it past them. Writes to the reserved system keyspace (index
+    /// entries) are left out.
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if the cursor points at a
+    /// commit that has already been discarded by retention.
+    pub fn read_changes(&mut self, cursor: &mut ChangeCursor, max: usize) -> Result<Vec<ChangeBatch>> {
+        let batches = self.read_raw_changes(cursor.next_seq, max)?;
+        if let Some((seq, _)) = batches.last() {
+            cursor.next_seq = seq.saturating_add(1);
+        }
+        Ok(batches
+            .into_iter()
+            .map(|(seq, ops)| ChangeBatch {
+                seq,
+                changes: ops
+                    .into_iter()
+                    .filter(|op| !is_system_key(op.key()))
+                    .map(|op| match op {
+                        Op::Set(key, value) => Change::Put { key, value },
+                        Op::Delete(key) => Change::Delete { key },
+                    })
+                    .collect(),
+            })
+            .filter(|batch| !batch.changes.is_empty())
+            .collect())
+    }
+
+    pub(crate) fn read_raw_changes(&mut self, from_seq: u64, max: usize) -> Result<Vec<(u64, Vec<Op>)>> {
+        // Sequence numbers start at 1 and are contiguous, so a log starting
+        // later than that has discarded everything before `first`.
+        if let Some(first) = self.changes.first_seq()
+            && first > 1
+            && from_seq < first
+        {
+            return Err(Error::new(
+                io::ErrorKind::NotFound,
+                format!("changes before seq {first} are no longer retained"),
+            ));
+        }
+        self.changes.read(from_seq, max)
+    }
+}
+
+pub(crate) fn encode_batch(seq: u64, ops: &[Op]) -> Bytes {
+    let mut out = vec![OP_BEGIN];
+    out.extend_from_slice(&seq.to_le_bytes());
+    for op in ops {
+        match op {
+            Op::Set(k, v) => {
+                out.push(OP_PUT);
+                out.extend_from_slice(&(k.len() as u32).to_le_bytes());
+                out.extend_from_slice(&(v.len() as u32).to_le_bytes());
+                out.extend_from_slice(k);
+                out.extend_from_slice(v);
+            }
+            Op::Delete(k) => {
+                out.push(OP_DELETE);
+                out.extend_from_slice(&(k.len() as u32).to_le_bytes());
+                out.extend_from_slice(&0u32.to_le_bytes());
+                out.extend_from_slice(k);
+            }
+        }
+    }
+    out.push(OP_COMMIT);
+    out
+}
+
+/// Reads one framed batch. `Ok(None)` at a clean end of input; a batch cut
+/// short fails with [`io::ErrorKind::UnexpectedEof`].
+pub(crate) fn read_batch<R: Read>(reader: &mut R) -> Result<Option<(u64, Vec<Op>)>> {
+    if !read_begin(reader)? {
+        return Ok(None);
+    }
+    let mut seq = [0u8; 8];
+    reader.read_exact(&mut seq)?;
+    Ok(Some((u64::from_le_bytes(seq), read_ops(reader)?)))
+}
+
+/// Reads one batch framed without a sequence number, as WALs were written
+/// before the change log existed.
+pub(crate) fn read_unsequenced_batch<R: Read>(reader: &mut R) -> Result<Option<Vec<Op>>> {
+    if !read_begin(reader)? {
+        return Ok(None);
+    }
+    read_ops(reader).map(Some)
+}
+
+/// Reads a BEGIN opcode; `false` at a clean end of input.
+fn read_begin<R: Read>(reader: &mut R) -> Result<bool> {
+    let mut op = [0u8; 1];
+    match reader.read_exact(&mut op) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    if op[0] != OP_BEGIN {
+        return Err(Error::new(io::ErrorKind::InvalidData, format!("expected BEGIN, found opcode {}", op[0])));
+    }
+    Ok(true)
+}
+
+/// Reads a batch's operations up to and including its COMMIT.
+fn read_ops<R: Read>(reader: &mut R) -> Result<Vec<Op>> {
+    let mut op = [0u8; 1];
+    let mut ops = Vec::new();
+    loop {
+        reader.read_exact(&mut op)?;
+        match op[0] {
+            OP_PUT | OP_DELETE => {
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                let klen = u32::from_le_bytes(len) as usize;
+                reader.read_exact(&mut len)?;
+                let vlen = u32::from_le_bytes(len) as usize;
+
+                let key = read_field(reader, klen)?;
+                if op[0] == OP_PUT {
+                    let value = read_field(reader, vlen)?;
+                    ops.push(Op::Set(key, value));
+                } else if vlen != 0 {
+                    return Err(Error::new(io::ErrorKind::InvalidData, "DELETE vlen != 0"));
+                } else {
+                    ops.push(Op::Delete(key));
+                }
+            }
+            OP_COMMIT => return Ok(ops),
+            other => {
+                return Err(Error::new(io::ErrorKind::InvalidData, format!("unknown opcode: {other}")));
+            }
+        }
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,51 @@
+//! Where the Db gets the time from.
+//!
+//! Commit timestamps in the archive and the continuous backup's snapshot
+//! schedule read a [`Clock`] instead of the system time, so a simulation can
+//! drive time from its seed with a [`SimClock`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, shared by every clone.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock {
+    /// Milliseconds since the epoch.
+    millis: Arc<AtomicU64>,
+}
+
+impl SimClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self { millis: Arc::new(AtomicU64::new(unix_millis(start))) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.millis.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
+
+/// Milliseconds since the epoch at `time`.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
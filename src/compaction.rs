@@ -0,0 +1,49 @@
+//! Data log compaction.
+//!
+//! The data log only ever grows: every overwrite and delete appends a record.
+//! Compaction rewrites it with one PUT record per live key, in key order.
+//! Segments can also be compacted a few at a time with
+//! [`Db::compact_segments`].
+
+use std::io::{Result, Write};
+
+use crate::format::{header, FileKind, HEADER_LEN};
+use crate::vfs::VfsWriter;
+use crate::wal_kv::{write_record, Db, OP_PUT};
+
+type Bytes = Vec<u8>;
+
+impl Db {
+    /// Rewrites the data log so it holds only the live records. The active
+    /// segment is sealed first, so every segment is compacted.
+    pub fn compact(&mut self) -> Result<()> {
+        if self.data_writer_pos > HEADER_LEN {
+            self.roll_over()?;
+        }
+        self.compact_segments(self.sealed.len())
+    }
+
+    /// A data log holding one PUT record per live key, in key order: a
+    /// self-contained image of the current state. It is built in memory from
+    /// the live records and leaves the Db's own files alone.
+    pub(crate) fn live_image(&mut self) -> Result<Bytes> {
+        let mut image = header(FileKind::DataLog).to_vec();
+        for (key, value) in self.scan_raw(..)? {
+            write_record(&mut image, OP_PUT, &key, &value)?;
+        }
+        Ok(image)
+    }
+
+    /// Replaces the Db's whole state with an image from `live_image`.
+    /// The change history no longer leads to the new state, so it is dropped.
+    pub(crate) fn install_image(&mut self, image: &[u8]) -> Result<()> {
+        let tmp_path = self.dir().join("data.log.install");
+        {
+            let mut file = VfsWriter(self.vfs.create(&tmp_path)?);
+            file.write_all(image)?;
+            file.file_mut().sync()?;
+        }
+        self.replace_data_file(&tmp_path)?;
+        self.changes.reset()
+    }
+}
@@ -0,0 +1,331 @@
+//! Continuous backup to an [`ObjectStore`], in the style of Litestream.
+//!
+//! A background thread polls the Db's change log and uploads each round of
+//! new commits as one object, and periodically uploads a full snapshot (the
+//! data log as of a commit). Object layout:
+//!
+//! ```text
+//! snapshots/<seq>             data log as of commit <seq>
+//! log/<first seq>-<last seq>  commits, framed as in changes.log
+//! ```
+//!
+//! Sequence numbers are zero-padded to 20 digits so keys sort numerically.
+//! [`restore_from_store`] downloads the newest snapshot and replays the log
+//! objects after it.
+
+use std::io::{self, Error, Result, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::changes::{encode_batch, read_batch};
+use crate::format::{header, FileKind};
+use crate::object_store::ObjectStore;
+use crate::manifest::CURRENT_FILE;
+use crate::vfs::{OsFs, Vfs, VfsWriter};
+use crate::wal_kv::{Db, Op};
+
+const SNAPSHOT_PREFIX: &str = "snapshots/";
+const LOG_PREFIX: &str = "log/";
+const BATCHES_PER_OBJECT: usize = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackupOptions {
+    /// How often new commits are uploaded.
+    pub sync_interval: Duration,
+    /// How often a fresh snapshot is uploaded.
+    pub snapshot_interval: Duration,
+    /// Snapshots to keep; older snapshots, and log objects only they need,
+    /// are deleted after each new snapshot. At least one is always kept.
+    pub retain_snapshots: usize,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            sync_interval: Duration::from_secs(1),
+            snapshot_interval: Duration::from_secs(24 * 60 * 60),
+            retain_snapshots: 2,
+        }
+    }
+}
+
+/// Progress of a [`BackupReplicator`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupStatus {
+    /// Every commit up to this sequence number is in the store.
+    pub uploaded_seq: u64,
+    pub latest_snapshot_seq: Option<u64>,
+    /// The most recent upload failure; cleared by the next successful round.
+    pub last_error: Option<String>,
+}
+
+/// Background thread copying a Db to an object store.
+pub struct BackupReplicator {
+    status: Arc<Mutex<BackupStatus>>,
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackupReplicator {
+    /// Starts uploading commits made through `db`, resuming after whatever
+    /// the store already holds. Keep writing through the same `Arc`.
+    pub fn start(db: Arc<Mutex<Db>>, store: Arc<dyn ObjectStore>, options: BackupOptions) -> Result<Self> {
+        let mut shipper = Shipper::new(db, store, options)?;
+        let status = Arc::clone(&shipper.status);
+        let (stop, stopped) = mpsc::channel();
+
+        let handle = thread::Builder::new()
+            .name("kv-backup".to_string())
+            .spawn(move || loop {
+                let stopping = !matches!(stopped.recv_timeout(options.sync_interval), Err(RecvTimeoutError::Timeout));
+                shipper.round();
+                if stopping {
+                    break;
+                }
+            })?;
+
+        Ok(Self { status, stop: Some(stop), handle: Some(handle) })
+    }
+
+    pub fn status(&self) -> BackupStatus {
+        self.status.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    /// Uploads any remaining commits and stops the thread.
+    pub fn stop(mut self) -> BackupStatus {
+        self.shutdown();
+        self.status()
+    }
+
+    fn shutdown(&mut self) {
+        // Dropping the sender wakes the thread for one last round.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for BackupReplicator {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The replicator's work, one round at a time. The simulation drives it
+/// directly instead of from a thread.
+pub(crate) struct Shipper {
+    db: Arc<Mutex<Db>>,
+    store: Arc<dyn ObjectStore>,
+    options: BackupOptions,
+    status: Arc<Mutex<BackupStatus>>,
+    next_seq: u64,
+    last_snapshot: Option<SystemTime>,
+}
+
+impl Shipper {
+    pub(crate) fn new(db: Arc<Mutex<Db>>, store: Arc<dyn ObjectStore>, options: BackupOptions) -> Result<Self> {
+        let snapshots = snapshot_seqs(store.as_ref())?;
+        let uploaded = log_objects(store.as_ref())?
+            .last()
+            .map(|&(_, last, _)| last)
+            .into_iter()
+            .chain(snapshots.last().copied())
+            .max()
+            .unwrap_or(0);
+        let status = BackupStatus { uploaded_seq: uploaded, latest_snapshot_seq: snapshots.last().copied(), last_error: None };
+        Ok(Self {
+            db,
+            store,
+            options,
+            status: Arc::new(Mutex::new(status)),
+            next_seq: uploaded + 1,
+            // An existing store has a snapshot of unknown age: take a new one.
+            last_snapshot: None,
+        })
+    }
+
+    pub(crate) fn status(&self) -> BackupStatus {
+        self.status.lock().map(|s| s.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn round(&mut self) {
+        let result = self.ship_log().and_then(|()| {
+            let due = match self.last_snapshot {
+                Some(at) => {
+                    let now = lock(&self.db)?.clock.now();
+                    now.duration_since(at).unwrap_or_default() >= self.options.snapshot_interval
+                }
+                None => true,
+            };
+            if due { self.ship_snapshot().map(drop) } else { Ok(()) }
+        });
+        if let Ok(mut status) = self.status.lock() {
+            status.uploaded_seq = self.next_seq - 1;
+            status.last_error = result.err().map(|e| e.to_string());
+        }
+    }
+
+    fn ship_log(&mut self) -> Result<()> {
+        loop {
+            let read = lock(&self.db)?.read_raw_changes(self.next_seq, BATCHES_PER_OBJECT);
+            let batches = match read {
+                Ok(batches) => batches,
+                // Commits were discarded before they were uploaded; a
+                // snapshot covers them.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let seq = self.ship_snapshot()?;
+                    self.next_seq = seq + 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let (Some(&(first, _)), Some(&(last, _))) = (batches.first(), batches.last()) else {
+                return Ok(());
+            };
+            let mut object = Vec::new();
+            for (seq, ops) in &batches {
+                object.extend(encode_batch(*seq, ops));
+            }
+            self.store.put(&log_key(first, last), &object)?;
+            self.next_seq = last + 1;
+        }
+    }
+
+    /// Uploads a snapshot and returns the sequence number it reflects.
+    fn ship_snapshot(&mut self) -> Result<u64> {
+        let (mut backup, now) = {
+            let mut db = lock(&self.db)?;
+            (db.start_backup()?, db.clock.now())
+        };
+        let seq = backup.seq();
+        self.store.put(&snapshot_key(seq), &backup.read_data()?)?;
+        self.last_snapshot = Some(now);
+        if let Ok(mut status) = self.status.lock() {
+            status.latest_snapshot_seq = Some(seq);
+        }
+        self.apply_retention()?;
+        Ok(seq)
+    }
+
+    fn apply_retention(&self) -> Result<()> {
+        let snapshots = snapshot_seqs(self.store.as_ref())?;
+        let keep = self.options.retain_snapshots.max(1);
+        if snapshots.len() <= keep {
+            return Ok(());
+        }
+        let oldest_kept = snapshots[snapshots.len() - keep];
+        for &seq in &snapshots[..snapshots.len() - keep] {
+            self.store.delete(&snapshot_key(seq))?;
+        }
+        for (_, last, key) in log_objects(self.store.as_ref())? {
+            if last <= oldest_kept {
+                self.store.delete(&key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds a Db in `dir`, which must not already hold one, from the newest
+/// snapshot in `store` and the log objects after it.
+pub fn restore_from_store<P: AsRef<Path>>(store: &dyn ObjectStore, dir: P) -> Result<Db> {
+    let dir = dir.as_ref();
+    if dir.join(Db::DATA_FILE).exists() || dir.join(CURRENT_FILE).exists() {
+        return Err(Error::new(io::ErrorKind::AlreadyExists, format!("{} already contains a Db", dir.display())));
+    }
+    restore_with_vfs(store, Arc::new(OsFs), dir)
+}
+
+/// [`restore_from_store`] into `dir` on `vfs`, without checking whether a
+/// Db is already there.
+pub(crate) fn restore_with_vfs(store: &dyn ObjectStore, vfs: Arc<dyn Vfs>, dir: &Path) -> Result<Db> {
+    let Some(&snapshot_seq) = snapshot_seqs(store)?.last() else {
+        return Err(Error::new(io::ErrorKind::NotFound, "no snapshot in the object store"));
+    };
+    let mut data = store
+        .get(&snapshot_key(snapshot_seq))?
+        .ok_or_else(|| Error::new(io::ErrorKind::NotFound, "snapshot disappeared during restore"))?;
+    // Snapshots uploaded by format version 1 have no header but the same
+    // records.
+    if !FileKind::DataLog.matches(&data) {
+        data.splice(..0, header(FileKind::DataLog));
+    }
+
+    vfs.create_dir_all(dir)?;
+    write_synced(vfs.as_ref(), &dir.join(Db::DATA_FILE), &data)?;
+    // An empty batch carries the snapshot's sequence number across reopens.
+    let mut changes = header(FileKind::ChangeLog).to_vec();
+    changes.extend(encode_batch(snapshot_seq, &[]));
+    write_synced(vfs.as_ref(), &dir.join(Db::CHANGES_FILE), &changes)?;
+    vfs.sync_dir(&dir.join(Db::DATA_FILE))?;
+
+    let mut db = Db::open_with_vfs(vfs, dir)?;
+    for (_, last, key) in log_objects(store)? {
+        if last <= db.last_seq() {
+            continue;
+        }
+        let object = store
+            .get(&key)?
+            .ok_or_else(|| Error::new(io::ErrorKind::NotFound, format!("{key} disappeared during restore")))?;
+        let mut reader = object.as_slice();
+        while let Some((seq, ops)) = read_batch(&mut reader)? {
+            apply(&mut db, seq, ops)?;
+        }
+    }
+    Ok(db)
+}
+
+fn apply(db: &mut Db, seq: u64, ops: Vec<Op>) -> Result<()> {
+    if seq <= db.last_seq() {
+        return Ok(());
+    }
+    if seq != db.last_seq() + 1 {
+        return Err(Error::new(
+            io::ErrorKind::NotFound,
+            format!("object store is missing seq {} (next found: {seq})", db.last_seq() + 1),
+        ));
+    }
+    db.write_batch_at(seq, ops)
+}
+
+fn snapshot_key(seq: u64) -> String {
+    format!("{SNAPSHOT_PREFIX}{seq:020}")
+}
+
+fn log_key(first: u64, last: u64) -> String {
+    format!("{LOG_PREFIX}{first:020}-{last:020}")
+}
+
+fn snapshot_seqs(store: &dyn ObjectStore) -> Result<Vec<u64>> {
+    Ok(store
+        .list(SNAPSHOT_PREFIX)?
+        .iter()
+        .filter_map(|key| key[SNAPSHOT_PREFIX.len()..].parse().ok())
+        .collect())
+}
+
+/// `(first seq, last seq, key)` of every log object, in order.
+fn log_objects(store: &dyn ObjectStore) -> Result<Vec<(u64, u64, String)>> {
+    Ok(store
+        .list(LOG_PREFIX)?
+        .into_iter()
+        .filter_map(|key| {
+            let (first, last) = key[LOG_PREFIX.len()..].split_once('-')?;
+            Some((first.parse().ok()?, last.parse().ok()?, key))
+        })
+        .collect())
+}
+
+fn write_synced(vfs: &dyn Vfs, path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = VfsWriter(vfs.create(path)?);
+    file.write_all(data)?;
+    file.file_mut().sync()
+}
+
+fn lock(db: &Mutex<Db>) -> Result<std::sync::MutexGuard<'_, Db>> {
+    db.lock().map_err(|_| Error::other("db mutex poisoned"))
+}
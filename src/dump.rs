@@ -0,0 +1,432 @@
+//! Export to and import from JSON Lines and CSV.
+//!
+//! Each record is a key and a value, encoded as text according to a
+//! [`BinaryEncoding`]:
+//!
+//! ```text
+//! {"key":"user:1","value":"alice"}      JSON Lines, one object per line
+//! key,value                             CSV, RFC 4180 quoting, with a header row
+//! user:1,alice
+//! ```
+//!
+//! In JSON Lines a `"value": null` deletes the key on import. Keys in the
+//! reserved system keyspace are never exported and are rejected on import.
+
+use std::io::{self, BufRead, Error, Result, Write};
+use std::ops::Bound;
+
+use crate::wal_kv::{is_system_key, prefix_range, Db};
+
+type Bytes = Vec<u8>;
+
+const EXPORT_PAGE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+/// How keys and values are turned into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryEncoding {
+    /// As-is; exporting fails on data that is not valid UTF-8.
+    Utf8,
+    Base64,
+    Hex,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    pub format: Format,
+    pub encoding: BinaryEncoding,
+    /// Records committed per transaction.
+    pub batch_size: usize,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self { format: Format::JsonLines, encoding: BinaryEncoding::Utf8, batch_size: 1000 }
+    }
+}
+
+impl Db {
+    /// Writes every record whose key starts with `prefix` (pass `b""` for the
+    /// whole Db) to `out`, in key order. Returns the number of records.
+    pub fn export<W: Write>(&mut self, mut out: W, prefix: &[u8], format: Format, encoding: BinaryEncoding) -> Result<u64> {
+        if format == Format::Csv {
+            out.write_all(b"key,value\n")?;
+        }
+        let (mut start, end) = prefix_range(prefix);
+        let mut count = 0;
+        loop {
+            let page = self.scan_limit((start, end.clone()), EXPORT_PAGE)?;
+            let Some((last, _)) = page.last() else { break };
+            start = Bound::Excluded(last.clone());
+            for (key, value) in &page {
+                let key = encode_field(key, encoding)?;
+                let value = encode_field(value, encoding)?;
+                match format {
+                    Format::JsonLines => writeln!(out, "{{\"key\":{},\"value\":{}}}", json_string(&key), json_string(&value))?,
+                    Format::Csv => writeln!(out, "{},{}", csv_field(&key), csv_field(&value))?,
+                }
+                count += 1;
+            }
+            if page.len() < EXPORT_PAGE {
+                break;
+            }
+        }
+        out.flush()?;
+        Ok(count)
+    }
+
+    /// Reads records from `input` and commits them in transactions of
+    /// `options.batch_size`. `progress` is called with the running total
+    /// after each commit. Returns the number of records imported.
+    ///
+    /// A malformed record fails with [`io::ErrorKind::InvalidData`] naming its
+    /// line, and a key in the reserved system keyspace fails with
+    /// [`io::ErrorKind::InvalidInput`]; batches committed before it stay
+    /// committed.
+    pub fn import<R, F>(&mut self, mut input: R, options: &ImportOptions, mut progress: F) -> Result<u64>
+    where
+        R: BufRead,
+        F: FnMut(u64),
+    {
+        let batch_size = options.batch_size.max(1) as u64;
+        let mut records = RecordReader { input: &mut input, format: options.format, line: 0, header_checked: false };
+        let mut total = 0;
+        let mut tx = self.begin_transaction();
+        let mut pending = 0;
+        while let Some((line, key, value)) = records.next_record()? {
+            let at_line = |e: Error| Error::new(io::ErrorKind::InvalidData, format!("line {line}: {e}"));
+            let key = decode_field(&key, options.encoding).map_err(at_line)?;
+            if is_system_key(&key) {
+                let msg = format!("line {line}: keys starting with 0xFF are reserved");
+                return Err(Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+            match value {
+                Some(value) => tx.set(key, decode_field(&value, options.encoding).map_err(at_line)?),
+                None => tx.delete(key),
+            }
+            pending += 1;
+            if pending == batch_size {
+                tx.commit()?;
+                total += pending;
+                pending = 0;
+                progress(total);
+                tx = self.begin_transaction();
+            }
+        }
+        if pending > 0 {
+            tx.commit()?;
+            total += pending;
+            progress(total);
+        }
+        Ok(total)
+    }
+}
+
+struct RecordReader<'a, R> {
+    input: &'a mut R,
+    format: Format,
+    line: u64,
+    header_checked: bool,
+}
+
+impl<R: BufRead> RecordReader<'_, R> {
+    /// Next `(line, key, value)`; a `None` value is a delete.
+    fn next_record(&mut self) -> Result<Option<(u64, String, Option<String>)>> {
+        loop {
+            let mut text = String::new();
+            if self.input.read_line(&mut text)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            let line = self.line;
+            if text.trim().is_empty() {
+                continue;
+            }
+            let invalid = |msg: String| Error::new(io::ErrorKind::InvalidData, format!("line {line}: {msg}"));
+
+            match self.format {
+                Format::JsonLines => {
+                    let (key, value) = parse_json_record(text.trim()).map_err(invalid)?;
+                    return Ok(Some((line, key, value)));
+                }
+                Format::Csv => {
+                    // A quoted field may span lines.
+                    while !quotes_balanced(&text) {
+                        if self.input.read_line(&mut text)? == 0 {
+                            return Err(invalid("unterminated quoted field".to_string()));
+                        }
+                        self.line += 1;
+                    }
+                    let fields = parse_csv_record(text.trim_end_matches(['\r', '\n'])).map_err(invalid)?;
+                    let first = !self.header_checked;
+                    self.header_checked = true;
+                    if first && fields == ["key", "value"] {
+                        continue;
+                    }
+                    return match <[String; 2]>::try_from(fields) {
+                        Ok([key, value]) => Ok(Some((line, key, Some(value)))),
+                        Err(fields) => Err(invalid(format!("expected 2 fields, found {}", fields.len()))),
+                    };
+                }
+            }
+        }
+    }
+}
+
+fn encode_field(bytes: &[u8], encoding: BinaryEncoding) -> Result<String> {
+    match encoding {
+        BinaryEncoding::Utf8 => String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::new(io::ErrorKind::InvalidData, "data is not valid UTF-8; export with base64 or hex")),
+        BinaryEncoding::Base64 => Ok(base64_encode(bytes)),
+        BinaryEncoding::Hex => Ok(bytes.iter().map(|b| format!("{b:02x}")).collect()),
+    }
+}
+
+fn decode_field(text: &str, encoding: BinaryEncoding) -> Result<Bytes> {
+    match encoding {
+        BinaryEncoding::Utf8 => Ok(text.as_bytes().to_vec()),
+        BinaryEncoding::Base64 => base64_decode(text),
+        BinaryEncoding::Hex => hex_decode(text),
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Result<Bytes> {
+    let invalid = || Error::new(io::ErrorKind::InvalidData, "invalid base64");
+    let bytes = text.as_bytes();
+    if !bytes.len().is_multiple_of(4) {
+        return Err(invalid());
+    }
+    let mut out = Vec::with_capacity(bytes.len() / 4 * 3);
+    for (index, chunk) in bytes.chunks(4).enumerate() {
+        let last = index == bytes.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(invalid());
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let v = BASE64_ALPHABET.iter().position(|&a| a == c).ok_or_else(invalid)?;
+            n = n << 6 | v as u32;
+        }
+        n <<= 6 * padding as u32;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+fn hex_decode(text: &str) -> Result<Bytes> {
+    let invalid = || Error::new(io::ErrorKind::InvalidData, "invalid hex");
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()).ok_or_else(invalid))
+        .collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Parses `{"key": "...", "value": "..." | null}`. Other members are ignored.
+fn parse_json_record(text: &str) -> std::result::Result<(String, Option<String>), String> {
+    let mut p = JsonParser { chars: text.chars().collect(), pos: 0 };
+    let mut key = None;
+    let mut value = None;
+    p.expect('{')?;
+    if !p.eat('}') {
+        loop {
+            let name = p.string()?;
+            p.expect(':')?;
+            let member = p.value()?;
+            match name.as_str() {
+                "key" => key = Some(member.ok_or("key must be a string")?),
+                "value" => value = Some(member),
+                _ => {}
+            }
+            if p.eat('}') {
+                break;
+            }
+            p.expect(',')?;
+        }
+    }
+    p.skip_whitespace();
+    if p.pos != p.chars.len() {
+        return Err("trailing characters after the object".to_string());
+    }
+    Ok((key.ok_or("missing \"key\"")?, value.ok_or("missing \"value\"")?))
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl JsonParser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> std::result::Result<(), String> {
+        if self.eat(c) { Ok(()) } else { Err(format!("expected '{c}' at column {}", self.pos + 1)) }
+    }
+
+    /// A string or `null`; other JSON values are not valid here.
+    fn value(&mut self) -> std::result::Result<Option<String>, String> {
+        self.skip_whitespace();
+        if self.chars[self.pos..].starts_with(&['n', 'u', 'l', 'l']) {
+            self.pos += 4;
+            return Ok(None);
+        }
+        self.string().map(Some)
+    }
+
+    fn string(&mut self) -> std::result::Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let c = *self.chars.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let e = *self.chars.get(self.pos).ok_or("unterminated escape")?;
+                    self.pos += 1;
+                    match e {
+                        '"' | '\\' | '/' => out.push(e),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let high = self.hex4()?;
+                            let code = if (0xD800..0xDC00).contains(&high) {
+                                if !(self.eat('\\') && self.eat('u')) {
+                                    return Err("unpaired surrogate".to_string());
+                                }
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err("unpaired surrogate".to_string());
+                                }
+                                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+                            } else {
+                                high
+                            };
+                            out.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+                        }
+                        other => return Err(format!("invalid escape \\{other}")),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> std::result::Result<u32, String> {
+        let digits: String = self.chars.get(self.pos..self.pos + 4).ok_or("truncated \\u escape")?.iter().collect();
+        self.pos += 4;
+        u32::from_str_radix(&digits, 16).map_err(|_| "invalid \\u escape".to_string())
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) || s.starts_with(' ') || s.ends_with(' ') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn quotes_balanced(text: &str) -> bool {
+    text.chars().filter(|&c| c == '"').count() % 2 == 0
+}
+
+fn parse_csv_record(text: &str) -> std::result::Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quoted field".to_string()),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                return Err("unexpected character after closing quote".to_string());
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                if c == '"' {
+                    return Err("quote inside an unquoted field".to_string());
+                }
+                field.push(c);
+                chars.next();
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
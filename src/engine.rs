@@ -0,0 +1,170 @@
+//! A storage-engine interface shared by [`KvStore`] and [`Db`].
+//!
+//! Code written against [`KvEngine`] can switch engines, and the same
+//! conformance tests run against every backend (`tests/conformance.rs`).
+//! Keys and values are bytes; [`KvStore`] only stores UTF-8 and rejects
+//! anything else with [`io::ErrorKind::InvalidInput`].
+
+use std::io::{self, Error, Result};
+use std::ops::Bound;
+
+use crate::simple_kv::KvStore;
+use crate::wal_kv::{prefix_range, Db, Op};
+
+type Bytes = Vec<u8>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Put(Bytes, Bytes),
+    Delete(Bytes),
+}
+
+/// Writes applied together by [`KvEngine::write`], in the order they were added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.ops.push(BatchOp::Put(key.as_ref().to_vec(), value.as_ref().to_vec()));
+        self
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key.as_ref().to_vec()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+pub trait KvEngine {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Bytes>>;
+
+    /// Applies `batch`; a later op on a key overrides an earlier one.
+    /// [`Db`] applies a batch atomically. [`KvStore`] has no WAL, so a crash
+    /// part-way through can leave a prefix of the batch applied.
+    fn write(&mut self, batch: WriteBatch) -> Result<()>;
+
+    /// Live key/value pairs with keys in `range`, in key order.
+    fn scan(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>>;
+
+    fn scan_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Bytes, Bytes)>> {
+        self.scan(prefix_range(prefix))
+    }
+
+    /// Makes every write so far durable.
+    fn flush(&mut self) -> Result<()>;
+}
+
+impl<E: KvEngine + ?Sized> KvEngine for Box<E> {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        (**self).get(key)
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        (**self).write(batch)
+    }
+
+    fn scan(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>> {
+        (**self).scan(range)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+impl KvEngine for Db {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        Db::get(self, key)
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let ops = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Put(key, value) => Op::Set(key, value),
+                BatchOp::Delete(key) => Op::Delete(key),
+            })
+            .collect();
+        self.commit(ops)
+    }
+
+    fn scan(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>> {
+        Db::scan(self, range)
+    }
+
+    /// Commits are synced before they return, so there is nothing to do.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl KvEngine for KvStore {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        // A key that is not UTF-8 can never have been stored.
+        let Ok(key) = std::str::from_utf8(key) else {
+            return Ok(None);
+        };
+        Ok(KvStore::get(self, key)?.map(String::into_bytes))
+    }
+
+    fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        // Validate everything first so a bad op does not leave half a batch.
+        let mut ops = Vec::with_capacity(batch.len());
+        for op in batch.ops {
+            ops.push(match op {
+                BatchOp::Put(key, value) => (utf8(key)?, Some(utf8(value)?)),
+                BatchOp::Delete(key) => (utf8(key)?, None),
+            });
+        }
+        for (key, value) in ops {
+            match value {
+                Some(value) => self.put(key, value)?,
+                None => self.delete(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>> {
+        let keys = self.keys_in(&range);
+        Ok(self
+            .read_keys(keys)?
+            .into_iter()
+            .map(|(k, v)| (k.into_bytes(), v.into_bytes()))
+            .collect())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        KvStore::flush(self)
+    }
+}
+
+fn utf8(bytes: Bytes) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| Error::new(io::ErrorKind::InvalidInput, "KvStore keys and values must be UTF-8"))
+}
@@ -0,0 +1,170 @@
+//! File headers.
+//!
+//! Every log the stores write starts with an 8-byte header:
+//!
+//! ```text
+//! [magic: 4 bytes][format version: u16][flags: u16]
+//! ```
+//!
+//! The magic says which kind of file it is, so a WAL can't be opened as a
+//! data log or a `KvStore` log as a `Db` one. The version is the
+//! [`FORMAT_VERSION`] that wrote the file; a newer one is refused with an
+//! error saying so rather than misparsed. Flags announce optional features
+//! a reader must understand; none are defined yet, so any set flag is
+//! refused too.
+//!
+//! Format version 1 files have no header. Opening one fails with a pointer
+//! to the routine in [`upgrade`](crate::upgrade) that adds it in place.
+
+use std::io::{self, Error, Result};
+use std::path::Path;
+
+use crate::manifest::FORMAT_VERSION;
+use crate::vfs::{read_exact_at, VfsFile};
+
+pub(crate) const HEADER_LEN: u64 = 8;
+
+/// Flags this build understands.
+const KNOWN_FLAGS: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileKind {
+    DataLog,
+    Wal,
+    ChangeLog,
+    Archive,
+    KvStore,
+    Hint,
+}
+
+impl FileKind {
+    const ALL: [FileKind; 6] =
+        [FileKind::DataLog, FileKind::Wal, FileKind::ChangeLog, FileKind::Archive, FileKind::KvStore, FileKind::Hint];
+
+    fn magic(self) -> &'static [u8; 4] {
+        match self {
+            FileKind::DataLog => b"KVDL",
+            FileKind::Wal => b"KVWL",
+            FileKind::ChangeLog => b"KVCL",
+            FileKind::Archive => b"KVAR",
+            FileKind::KvStore => b"KVSL",
+            FileKind::Hint => b"KVHT",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            FileKind::DataLog => "a Db data log",
+            FileKind::Wal => "a Db WAL",
+            FileKind::ChangeLog => "a Db change log",
+            FileKind::Archive => "an archive segment",
+            FileKind::KvStore => "a KvStore log",
+            FileKind::Hint => "a data log hint file",
+        }
+    }
+
+    fn upgrade_with(self) -> &'static str {
+        match self {
+            FileKind::DataLog | FileKind::Wal | FileKind::ChangeLog | FileKind::Hint => {
+                "`upgrade::upgrade_db` on its directory"
+            }
+            FileKind::Archive => "`upgrade::upgrade_archive` on its directory",
+            FileKind::KvStore => "`upgrade::upgrade_kvstore`",
+        }
+    }
+
+    /// Whether `bytes` start with this kind's magic.
+    pub(crate) fn matches(self, bytes: &[u8]) -> bool {
+        bytes.starts_with(self.magic())
+    }
+}
+
+/// The header this build writes for `kind`.
+pub(crate) fn header(kind: FileKind) -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..4].copy_from_slice(kind.magic());
+    header[4..6].copy_from_slice(&(FORMAT_VERSION as u16).to_le_bytes());
+    header[6..8].copy_from_slice(&KNOWN_FLAGS.to_le_bytes());
+    header
+}
+
+/// Checks that `bytes`, the start of the file at `path`, hold a `kind`
+/// header this build can read.
+pub(crate) fn check_header(bytes: &[u8], kind: FileKind, path: &Path) -> Result<()> {
+    let path = path.display();
+    if !kind.matches(bytes) || bytes.len() < HEADER_LEN as usize {
+        if let Some(other) = FileKind::ALL.into_iter().find(|other| other.matches(bytes)) {
+            let hint = match (other, kind) {
+                (FileKind::KvStore, FileKind::DataLog) => "; convert it with `migrate::migrate_kvstore`",
+                _ => "",
+            };
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path} is {}, not {}{hint}", other.describe(), kind.describe()),
+            ));
+        }
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{path} has no format header, so it was written by format version 1; upgrade it with {}",
+                kind.upgrade_with()
+            ),
+        ));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if u32::from(version) > FORMAT_VERSION {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{path} has format version {version}, but this build supports up to {FORMAT_VERSION}"),
+        ));
+    }
+    if u32::from(version) < FORMAT_VERSION {
+        return Err(Error::new(io::ErrorKind::InvalidData, format!("{path} has unknown format version {version}")));
+    }
+    let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{path} uses format flags {flags:#06x} that this build does not understand"),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the header at the start of `file`.
+pub(crate) fn check_file_header(file: &dyn VfsFile, kind: FileKind, path: &Path) -> Result<()> {
+    let mut start = vec![0u8; file.len()?.min(HEADER_LEN) as usize];
+    read_exact_at(file, &mut start, 0)?;
+    check_header(&start, kind, path)
+}
+
+/// Checks the header of `file`, or writes and syncs one if the file is new.
+/// A file shorter than a header that matches it so far was cut short by a
+/// crash while being created, and counts as new.
+pub(crate) fn init_header(file: &mut dyn VfsFile, kind: FileKind, path: &Path) -> Result<()> {
+    let len = file.len()?;
+    let mut start = vec![0u8; len.min(HEADER_LEN) as usize];
+    read_exact_at(file, &mut start, 0)?;
+    if len < HEADER_LEN && header(kind).starts_with(&start) {
+        if len > 0 {
+            file.truncate(0)?;
+        }
+        write_header(file, kind)?;
+        return file.sync();
+    }
+    check_header(&start, kind, path)
+}
+
+/// Appends a `kind` header to the empty `file`.
+pub(crate) fn write_header(file: &mut dyn VfsFile, kind: FileKind) -> Result<()> {
+    let header = header(kind);
+    let mut written = 0;
+    while written < header.len() {
+        match file.write(&header[written..])? {
+            0 => return Err(Error::new(io::ErrorKind::WriteZero, "failed to write the file header")),
+            n => written += n,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,173 @@
+//! Hint files.
+//!
+//! Indexing a data log segment reads every value just to step over it. A
+//! sealed segment never changes, so when one is sealed by a rollover or
+//! written by compaction, a hint file next to it (`data-<id>.hint`) lists
+//! just what the index needs from each record:
+//!
+//! ```text
+//! [file header][segment length: u64]
+//! [op][klen: u32][vlen: u32][offset: u64][key]...
+//! [CRC-32 of everything before it: u32]
+//! ```
+//!
+//! Open indexes a sealed segment from its hint when the checksum matches,
+//! the records it lists tile the segment exactly and the segment is still the
+//! length the hint was written for; otherwise it scans the segment as before.
+//! A missing, torn or stale hint only costs startup time. The active segment
+//! has no hint, and a segment id is cleared of any old hint before it is
+//! reused.
+
+use std::collections::BTreeMap;
+use std::io::{self, Result, Write};
+use std::path::{Path, PathBuf};
+
+use crate::format::{check_header, header, FileKind, HEADER_LEN};
+use crate::segments::RecordPos;
+use crate::vfs::{read_prefix, Vfs, VfsFile, VfsWriter};
+use crate::wal_kv::{Db, OP_DELETE, OP_PUT};
+
+type Bytes = Vec<u8>;
+
+/// One data log record, as far as the index is concerned.
+pub(crate) struct Hint {
+    pub(crate) op: u8,
+    pub(crate) key: Bytes,
+    pub(crate) value_len: u64,
+    pub(crate) offset: u64,
+}
+
+pub(crate) fn hint_file_name(id: u64) -> String {
+    if id == 0 {
+        "data.hint".to_string()
+    } else {
+        format!("data-{id:06}.hint")
+    }
+}
+
+/// The hints in the file at `path` for a segment `segment_len` bytes long,
+/// or `None` if there is no usable hint file.
+pub(crate) fn read_hints(vfs: &dyn Vfs, path: &Path, segment_len: u64) -> Result<Option<Vec<Hint>>> {
+    if !vfs.exists(path)? {
+        return Ok(None);
+    }
+    let file = vfs.open(path)?;
+    let contents = read_prefix(file.as_ref(), file.len()?)?;
+    Ok(decode(&contents, path, segment_len))
+}
+
+fn decode(contents: &[u8], path: &Path, segment_len: u64) -> Option<Vec<Hint>> {
+    let (body, crc) = contents.split_last_chunk::<4>()?;
+    if crc32(body) != u32::from_le_bytes(*crc) || check_header(body, FileKind::Hint, path).is_err() {
+        return None;
+    }
+    let mut rest = &body[HEADER_LEN as usize..];
+    if u64::from_le_bytes(*take(&mut rest)?) != segment_len {
+        return None;
+    }
+
+    let mut hints = Vec::new();
+    let mut next = HEADER_LEN;
+    while !rest.is_empty() {
+        let [op] = *take(&mut rest)?;
+        let key_len = u32::from_le_bytes(*take(&mut rest)?) as usize;
+        let value_len = u64::from(u32::from_le_bytes(*take(&mut rest)?));
+        let offset = u64::from_le_bytes(*take(&mut rest)?);
+        let key = rest.get(..key_len)?.to_vec();
+        rest = &rest[key_len..];
+        let known = op == OP_PUT || (op == OP_DELETE && value_len == 0);
+        if !known || offset != next {
+            return None;
+        }
+        next = offset + 9 + key_len as u64 + value_len;
+        hints.push(Hint { op, key, value_len, offset });
+    }
+    (next == segment_len).then_some(hints)
+}
+
+fn take<'a, const N: usize>(rest: &mut &'a [u8]) -> Option<&'a [u8; N]> {
+    let (head, tail) = rest.split_first_chunk::<N>()?;
+    *rest = tail;
+    Some(head)
+}
+
+/// Applies `hints` for `segment` to `index`, as indexing the segment would.
+pub(crate) fn apply_hints(hints: Vec<Hint>, segment: u64, index: &mut BTreeMap<Bytes, RecordPos>) {
+    for hint in hints {
+        if hint.op == OP_PUT {
+            index.insert(hint.key, RecordPos { segment, offset: hint.offset });
+        } else {
+            index.remove(&hint.key);
+        }
+    }
+}
+
+impl Db {
+    pub(crate) fn hint_path(&self, id: u64) -> PathBuf {
+        self.dir().join(hint_file_name(id))
+    }
+
+    /// Writes and syncs the hint file for segment `id`, `segment_len` bytes
+    /// long and holding the records in `hints`.
+    pub(crate) fn write_hints(&self, id: u64, segment_len: u64, hints: &[Hint]) -> Result<()> {
+        let mut contents = header(FileKind::Hint).to_vec();
+        contents.extend_from_slice(&segment_len.to_le_bytes());
+        for hint in hints {
+            contents.push(hint.op);
+            contents.extend_from_slice(&(hint.key.len() as u32).to_le_bytes());
+            contents.extend_from_slice(&(hint.value_len as u32).to_le_bytes());
+            contents.extend_from_slice(&hint.offset.to_le_bytes());
+            contents.extend_from_slice(&hint.key);
+        }
+        contents.extend_from_slice(&crc32(&contents).to_le_bytes());
+
+        let mut file = VfsWriter(self.vfs.create(&self.hint_path(id))?);
+        file.write_all(&contents)?;
+        file.file_mut().sync()
+    }
+
+    /// Writes the hint file for the sealed segment `id` from its records.
+    pub(crate) fn write_hints_from(&self, id: u64, file: &dyn VfsFile, segment_len: u64) -> Result<()> {
+        let mut hints = Vec::new();
+        let end = Db::scan_records(file, HEADER_LEN, |op, key, offset, value_len| {
+            hints.push(Hint { op, key, value_len, offset });
+            Ok(())
+        })?;
+        if end != segment_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("data log segment {id} ends in a torn record")));
+        }
+        self.write_hints(id, segment_len, &hints)
+    }
+
+    /// Removes the hint file for segment `id`, if there is one.
+    pub(crate) fn remove_hints(&self, id: u64) -> Result<()> {
+        let path = self.hint_path(id);
+        if self.vfs.exists(&path)? {
+            self.vfs.remove_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE), as used by zlib and gzip.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| CRC_TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8))
+}
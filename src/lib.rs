@@ -1,5 +1,55 @@
+pub mod archive;
+pub mod backup;
+pub mod bulk;
+pub mod changes;
+pub mod clock;
+pub mod compaction;
+pub mod continuous_backup;
+pub mod dump;
+pub mod engine;
+pub mod format;
+pub mod hints;
+pub mod manifest;
+pub mod migrate;
+pub mod object_store;
+pub mod raft;
+pub mod replication;
+pub mod secondary;
+pub mod segments;
 pub mod simple_kv;
+pub mod simulation;
+pub mod tuple;
+pub mod upgrade;
+pub mod vfs;
 pub mod wal_kv;
+pub mod watch;
+#[cfg(feature = "async")]
+pub mod async_db;
+#[cfg(feature = "serde")]
+pub mod typed;
 
+pub use archive::RecoveryTarget;
+pub use backup::Backup;
+pub use bulk::BulkLoader;
+pub use changes::{Change, ChangeBatch, ChangeCursor};
+pub use clock::{Clock, SimClock, SystemClock};
+pub use continuous_backup::{restore_from_store, BackupOptions, BackupReplicator, BackupStatus};
+pub use dump::{BinaryEncoding, Format, ImportOptions};
+pub use engine::{BatchOp, KvEngine, WriteBatch};
+pub use manifest::FORMAT_VERSION;
+pub use migrate::{detect_format, migrate_kvstore, DataFormat, MigrationReport};
+pub use object_store::{LocalFsStore, MemoryStore, ObjectStore};
+pub use raft::{RaftConfig, RaftNode};
+pub use replication::{Follower, Primary, ReplicationLag};
+pub use secondary::IndexEntry;
+pub use segments::DEFAULT_SEGMENT_SIZE;
 pub use simple_kv::KvStore;
-pub use wal_kv::{Db, Transaction};
\ No newline at end of file
+pub use simulation::{simulate, SimConfig, SimReport};
+pub use upgrade::{upgrade_archive, upgrade_db, upgrade_kvstore, UpgradeReport};
+pub use vfs::{FaultFs, MemFs, OsFs, Vfs, VfsFile};
+pub use wal_kv::{Db, Transaction};
+pub use watch::ChangeEvent;
+#[cfg(feature = "async")]
+pub use async_db::{AsyncDb, AsyncTransaction};
+#[cfg(feature = "serde")]
+pub use typed::TypedTree;
@@ -1,10 +1,228 @@
-use rust_embedded_kv_store::Db;
-use std::io::{self};
+use rust_embedded_kv_store::{
+    detect_format, migrate_kvstore, restore_from_store, upgrade_archive, upgrade_db, upgrade_kvstore, BinaryEncoding,
+    Db, Follower, Format, ImportOptions, LocalFsStore, Primary, RecoveryTarget, UpgradeReport, FORMAT_VERSION,
+};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
 
 fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => demo(),
+        ["primary", dir, addr] => run_primary(dir, addr, None),
+        ["primary", dir, addr, "--archive", archive_dir] => run_primary(dir, addr, Some(archive_dir)),
+        ["follower", dir, addr] => run_follower(dir, addr),
+        ["backup", dir, backup_dir] => {
+            let seq = Db::open(dir)?.backup_to(backup_dir)?;
+            println!("backed up {dir} to {backup_dir} at seq {seq}");
+            Ok(())
+        }
+        ["recover", backup_dir, archive_dir, dir, target @ ..] => {
+            let target = match target {
+                [] => RecoveryTarget::Latest,
+                ["seq", seq] => RecoveryTarget::Seq(parse_number(seq)?),
+                ["time", millis] => RecoveryTarget::Time(UNIX_EPOCH + Duration::from_millis(parse_number(millis)?)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "target must be `seq <n>` or `time <unix ms>`")),
+            };
+            let db = Db::recover_to(backup_dir, archive_dir, dir, target)?;
+            println!("recovered {dir} to seq {}", db.last_seq());
+            Ok(())
+        }
+        ["export", dir, file, flags @ ..] => run_export(dir, file, flags),
+        ["import", dir, file, flags @ ..] => run_import(dir, file, flags),
+        ["detect", file] => {
+            println!("{:?}", detect_format(file)?);
+            Ok(())
+        }
+        ["migrate", source, dir] => {
+            let report = migrate_kvstore(source, dir)?;
+            println!("migrated {} records ({} live keys) from {source} into {dir}", report.records, report.keys);
+            Ok(())
+        }
+        ["upgrade", what, path] => {
+            let report = match *what {
+                "db" => upgrade_db(path)?,
+                "kvstore" => upgrade_kvstore(path)?,
+                "archive" => upgrade_archive(path)?,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "upgrade db, kvstore or archive")),
+            };
+            print_upgrade(path, &report);
+            Ok(())
+        }
+        ["restore-store", store_dir, dir] => {
+            let db = restore_from_store(&LocalFsStore::open(store_dir)?, dir)?;
+            println!("restored {dir} to seq {}", db.last_seq());
+            Ok(())
+        }
+        _ => {
+            eprintln!("usage:");
+            eprintln!("  rust-embedded-kv-store");
+            eprintln!("      run the demo");
+            eprintln!("  rust-embedded-kv-store primary <dir> <addr> [--archive <archive_dir>]");
+            eprintln!("      serve <dir> to followers on <addr>");
+            eprintln!("  rust-embedded-kv-store follower <dir> <addr>");
+            eprintln!("      replicate the primary at <addr> into <dir>");
+            eprintln!("  rust-embedded-kv-store backup <dir> <backup_dir>");
+            eprintln!("      copy <dir> to <backup_dir>");
+            eprintln!("  rust-embedded-kv-store recover <backup_dir> <archive_dir> <dir> [seq <n> | time <unix ms>]");
+            eprintln!("      restore a backup and replay the archive");
+            eprintln!("  rust-embedded-kv-store export <dir> <file|-> [--prefix <p>] [--format jsonl|csv] [--encoding utf8|base64|hex]");
+            eprintln!("      dump records to JSON Lines or CSV");
+            eprintln!("  rust-embedded-kv-store import <dir> <file|-> [--format jsonl|csv] [--encoding utf8|base64|hex] [--batch-size <n>]");
+            eprintln!("      load records from JSON Lines or CSV");
+            eprintln!("  rust-embedded-kv-store detect <data.log>");
+            eprintln!("      report whether a data log was written by KvStore or Db");
+            eprintln!("  rust-embedded-kv-store migrate <kvstore data.log> <dir>");
+            eprintln!("      convert a KvStore log into a Db in <dir> and verify it");
+            eprintln!("  rust-embedded-kv-store upgrade db|kvstore|archive <dir or file>");
+            eprintln!("      rewrite files from an older format version in the current one");
+            eprintln!("  rust-embedded-kv-store restore-store <store_dir> <dir>");
+            eprintln!("      rebuild a Db from a continuous backup in a local object store");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn print_upgrade(path: &str, report: &UpgradeReport) {
+    if report.from_version == FORMAT_VERSION {
+        println!("{path} is already at format version {}", report.from_version);
+        return;
+    }
+    println!("upgraded {path} from format version {}:", report.from_version);
+    for file in &report.upgraded {
+        println!("  {}", file.display());
+    }
+}
+
+fn run_export(dir: &str, file: &str, flags: &[&str]) -> io::Result<()> {
+    let mut prefix = "";
+    let mut format = Format::JsonLines;
+    let mut encoding = BinaryEncoding::Utf8;
+    for pair in flags.chunks(2) {
+        match pair {
+            ["--prefix", p] => prefix = p,
+            ["--format", f] => format = parse_format(f)?,
+            ["--encoding", e] => encoding = parse_encoding(e)?,
+            _ => return Err(invalid_input(format!("unknown option: {}", pair.join(" ")))),
+        }
+    }
+
+    let mut db = Db::open(dir)?;
+    let out: Box<dyn Write> = match file {
+        "-" => Box::new(io::stdout().lock()),
+        path => Box::new(File::create(path)?),
+    };
+    let count = db.export(BufWriter::new(out), prefix.as_bytes(), format, encoding)?;
+    eprintln!("exported {count} records");
+    Ok(())
+}
+
+fn run_import(dir: &str, file: &str, flags: &[&str]) -> io::Result<()> {
+    let mut options = ImportOptions::default();
+    for pair in flags.chunks(2) {
+        match pair {
+            ["--format", f] => options.format = parse_format(f)?,
+            ["--encoding", e] => options.encoding = parse_encoding(e)?,
+            ["--batch-size", n] => options.batch_size = parse_number(n)? as usize,
+            _ => return Err(invalid_input(format!("unknown option: {}", pair.join(" ")))),
+        }
+    }
+
+    let mut db = Db::open(dir)?;
+    let input: Box<dyn BufRead> = match file {
+        "-" => Box::new(io::stdin().lock()),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let count = db.import(input, &options, |total| eprintln!("imported {total} records"))?;
+    eprintln!("done: {count} records");
+    Ok(())
+}
+
+fn parse_format(s: &str) -> io::Result<Format> {
+    match s {
+        "jsonl" => Ok(Format::JsonLines),
+        "csv" => Ok(Format::Csv),
+        _ => Err(invalid_input(format!("unknown format: {s}"))),
+    }
+}
+
+fn parse_encoding(s: &str) -> io::Result<BinaryEncoding> {
+    match s {
+        "utf8" => Ok(BinaryEncoding::Utf8),
+        "base64" => Ok(BinaryEncoding::Base64),
+        "hex" => Ok(BinaryEncoding::Hex),
+        _ => Err(invalid_input(format!("unknown encoding: {s}"))),
+    }
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn parse_number(s: &str) -> io::Result<u64> {
+    s.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("not a number: {s}")))
+}
+
+/// Reads `set <key> <value>`, `del <key>` and `get <key>` from stdin.
+fn run_primary(dir: &str, addr: &str, archive_dir: Option<&str>) -> io::Result<()> {
+    let mut db = Db::open(dir)?;
+    if let Some(archive_dir) = archive_dir {
+        db.enable_archiving(archive_dir)?;
+    }
+    let db = Arc::new(Mutex::new(db));
+    let primary = Primary::start(Arc::clone(&db), addr)?;
+    eprintln!("primary listening on {}", primary.local_addr());
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let mut db = db.lock().map_err(|_| io::Error::other("db mutex poisoned"))?;
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["set", key, value] => {
+                let mut tx = db.begin_transaction();
+                tx.set(key, value);
+                tx.commit()?;
+                println!("ok seq={}", db.last_seq());
+            }
+            ["del", key] => {
+                let mut tx = db.begin_transaction();
+                tx.delete(key);
+                tx.commit()?;
+                println!("ok seq={}", db.last_seq());
+            }
+            ["get", key] => println!("{:?}", db.get(key)?.map(String::from_utf8)),
+            _ => eprintln!("commands: set <key> <value> | del <key> | get <key>"),
+        }
+    }
+    primary.stop();
+    Ok(())
+}
+
+/// Reads `get <key>` and `lag` from stdin.
+fn run_follower(dir: &str, addr: &str) -> io::Result<()> {
+    let follower = Follower::start(dir, addr)?;
+    eprintln!("following {addr}");
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["get", key] => println!("{:?}", follower.get(key)?.map(String::from_utf8)),
+            ["lag"] => println!("{:?}", follower.lag()?),
+            _ => eprintln!("commands: get <key> | lag"),
+        }
+    }
+    follower.stop()?;
+    Ok(())
+}
+
+fn demo() -> io::Result<()> {
     // Start from a clean slate while experimenting
     let _ = std::fs::remove_file("data.log");
     let _ = std::fs::remove_file("wal.log");
+    let _ = std::fs::remove_file("changes.log");
+    // Without CURRENT, the next open starts a fresh manifest.
+    let _ = std::fs::remove_file("CURRENT");
 
     let mut db = Db::new()?;
 
@@ -0,0 +1,226 @@
+//! The manifest: what a Db directory is supposed to contain.
+//!
+//! `MANIFEST-<n>` records the on-disk format version, the options that must
+//! survive a reopen (the segment size), the live data log segments and the
+//! last sequence number committed when it was written, plus, once the data
+//! has been replaced wholesale, the seq the change log restarted at, and
+//! once the Db has been backed up, the random id backups recognize it by.
+//! `CURRENT` names the manifest in effect. A change writes and syncs the
+//! next manifest, then points `CURRENT` at it by writing a temporary file,
+//! syncing it, renaming it over `CURRENT` and syncing the directory; only then is the previous
+//! manifest removed. A crash at any point leaves `CURRENT` naming a complete
+//! manifest, old or new.
+//!
+//! Open refuses a directory that disagrees with its manifest: an older or
+//! newer format version, a listed segment that is missing, or a change log that ends
+//! before the manifest's sequence number. A change log that ends before the
+//! seq it restarted at was cut off by a crash between the replacement and the
+//! restart, so open restarts it. A directory without `CURRENT` was
+//! written before manifests existed, or by a backup restore; it is read as
+//! the single segment `data.log` and gets a manifest when opened. If such a
+//! directory holds anything only a manifest could account for (a later
+//! segment, a hint file or a manifest other than the first one an
+//! interrupted open may leave behind), `CURRENT` was lost and open refuses
+//! it rather than drop those segments.
+
+use std::io::{self, Error, Result, Write};
+use std::path::Path;
+
+use crate::segments::DEFAULT_SEGMENT_SIZE;
+use crate::vfs::{read_prefix, Vfs, VfsWriter};
+use crate::wal_kv::Db;
+
+pub(crate) const CURRENT_FILE: &str = "CURRENT";
+
+/// Version of the on-disk format this build writes. Version 1 had no file
+/// headers; version 2 added them.
+pub const FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) format: u32,
+    pub(crate) segment_size: u64,
+    /// Live segment ids, oldest first; the last one is active.
+    pub(crate) segments: Vec<u64>,
+    pub(crate) last_seq: u64,
+    /// Seq of the empty batch the change log was restarted at when the data
+    /// was last replaced wholesale; 0 if it never was.
+    pub(crate) history_start: u64,
+    /// Random id of the Db, given at its first backup; 0 until then.
+    pub(crate) id: u64,
+}
+
+impl Default for Manifest {
+    /// What a directory without a manifest holds.
+    fn default() -> Self {
+        Self { format: FORMAT_VERSION, segment_size: DEFAULT_SEGMENT_SIZE, segments: vec![0], last_seq: 0, history_start: 0, id: 0 }
+    }
+}
+
+impl Manifest {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let segments: Vec<String> = self.segments.iter().map(u64::to_string).collect();
+        let mut text = format!(
+            "format {}\nsegment_size {}\nsegments {}\nlast_seq {}\n",
+            self.format,
+            self.segment_size,
+            segments.join(" "),
+            self.last_seq
+        );
+        if self.history_start > 0 {
+            text.push_str(&format!("history_start {}\n", self.history_start));
+        }
+        if self.id != 0 {
+            text.push_str(&format!("id {:016x}\n", self.id));
+        }
+        text.into_bytes()
+    }
+
+    pub(crate) fn decode(contents: &[u8]) -> Result<Self> {
+        let malformed = |what: &str| Error::new(io::ErrorKind::InvalidData, format!("malformed manifest: {what}"));
+        let text = std::str::from_utf8(contents).map_err(|_| malformed("not UTF-8"))?;
+        let (mut format, mut segment_size, mut segments, mut last_seq) = (None, None, None, None);
+        let (mut history_start, mut id) = (None, None);
+        for line in text.lines() {
+            let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+            let parsed = match name {
+                "format" => format.replace(value.parse().map_err(|_| malformed("bad format"))?).is_none(),
+                "segment_size" => {
+                    segment_size.replace(value.parse().map_err(|_| malformed("bad segment_size"))?).is_none()
+                }
+                "segments" => {
+                    let ids = value.split(' ').map(str::parse).collect::<std::result::Result<Vec<u64>, _>>();
+                    segments.replace(ids.map_err(|_| malformed("bad segments"))?).is_none()
+                }
+                "last_seq" => last_seq.replace(value.parse().map_err(|_| malformed("bad last_seq"))?).is_none(),
+                "history_start" => {
+                    history_start.replace(value.parse().map_err(|_| malformed("bad history_start"))?).is_none()
+                }
+                "id" => id.replace(u64::from_str_radix(value, 16).map_err(|_| malformed("bad id"))?).is_none(),
+                other => return Err(malformed(&format!("unknown entry {other:?}"))),
+            };
+            if !parsed {
+                return Err(malformed(&format!("{name} appears twice")));
+            }
+        }
+
+        let format: u32 = format.ok_or_else(|| malformed("no format"))?;
+        if format > FORMAT_VERSION {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the Db uses format version {format}; this build supports up to {FORMAT_VERSION}"),
+            ));
+        }
+        let segments: Vec<u64> = segments.ok_or_else(|| malformed("no segments"))?;
+        let segment_size: u64 = segment_size.ok_or_else(|| malformed("no segment_size"))?;
+        if segment_size == 0 {
+            return Err(malformed("segment_size is 0"));
+        }
+        Ok(Self {
+            format,
+            segment_size,
+            segments,
+            last_seq: last_seq.ok_or_else(|| malformed("no last_seq"))?,
+            history_start: history_start.unwrap_or(0),
+            id: id.unwrap_or(0),
+        })
+    }
+}
+
+pub(crate) fn manifest_file_name(number: u64) -> String {
+    format!("MANIFEST-{number:06}")
+}
+
+/// The manifest `CURRENT` names in `dir`, with its number, or `None` if
+/// there is no `CURRENT` and `dir` holds only what a Db without a manifest
+/// has.
+pub(crate) fn read_current(vfs: &dyn Vfs, dir: &Path) -> Result<Option<(u64, Manifest)>> {
+    let current_path = dir.join(CURRENT_FILE);
+    if !vfs.exists(&current_path)? {
+        check_unmanaged(vfs, dir)?;
+        return Ok(None);
+    }
+    let current = vfs.open(&current_path)?;
+    let name = String::from_utf8(read_prefix(current.as_ref(), current.len()?)?)
+        .map_err(|_| Error::new(io::ErrorKind::InvalidData, "malformed CURRENT"))?;
+    let name = name.trim_end();
+    let number = name
+        .strip_prefix("MANIFEST-")
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, format!("CURRENT names {name:?}, not a manifest")))?;
+
+    let path = dir.join(name);
+    if !vfs.exists(&path)? {
+        return Err(Error::new(io::ErrorKind::NotFound, format!("CURRENT names {name}, which is missing")));
+    }
+    let file = vfs.open(&path)?;
+    let manifest = Manifest::decode(&read_prefix(file.as_ref(), file.len()?)?)?;
+    Ok(Some((number, manifest)))
+}
+
+/// Fails if `dir`, which has no `CURRENT`, holds files that only a manifest
+/// can describe. The first manifest is allowed when it lists just `data.log`
+/// or is torn: a crash while a Db was first opened leaves it behind.
+fn check_unmanaged(vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+    let mut names = vfs.list_dir(dir)?;
+    names.sort();
+    for name in names {
+        let managed = if name == manifest_file_name(1) {
+            let file = vfs.open(&dir.join(&name))?;
+            Manifest::decode(&read_prefix(file.as_ref(), file.len()?)?).is_ok_and(|m| m.segments != [0])
+        } else {
+            name.starts_with("MANIFEST-")
+                || (name.starts_with("data-") && name.ends_with(".log"))
+                || name.ends_with(".hint")
+        };
+        if managed {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has no CURRENT but holds {name}; the manifest naming its files is lost", dir.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Installs `manifest` as number `number` and removes the previous one.
+pub(crate) fn write_current(vfs: &dyn Vfs, dir: &Path, number: u64, manifest: &Manifest) -> Result<()> {
+    let name = manifest_file_name(number);
+    write_synced(vfs, &dir.join(&name), &manifest.encode())?;
+
+    let current_path = dir.join(CURRENT_FILE);
+    let tmp_path = dir.join("CURRENT.tmp");
+    write_synced(vfs, &tmp_path, format!("{name}\n").as_bytes())?;
+    vfs.rename(&tmp_path, &current_path)?;
+    vfs.sync_dir(&current_path)?;
+
+    if number > 1 {
+        vfs.remove_file(&dir.join(manifest_file_name(number - 1)))?;
+    }
+    Ok(())
+}
+
+fn write_synced(vfs: &dyn Vfs, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = VfsWriter(vfs.create(path)?);
+    file.write_all(contents)?;
+    file.file_mut().sync()
+}
+
+impl Db {
+    /// Records `segments` as the live segments, along with the current
+    /// options and sequence number, in a new manifest.
+    pub(crate) fn write_manifest(&mut self, segments: &[u64]) -> Result<()> {
+        let manifest = Manifest {
+            format: FORMAT_VERSION,
+            segment_size: self.segment_size,
+            segments: segments.to_vec(),
+            last_seq: self.last_seq(),
+            history_start: self.history_start,
+            id: self.id,
+        };
+        let number = self.manifest_number + 1;
+        write_current(self.vfs.as_ref(), self.dir(), number, &manifest)?;
+        self.manifest_number = number;
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Telling `KvStore` and `Db` data logs apart, and converting between them.
+//!
+//! Both write `data.log` records as `[op][klen][vlen][key][value]`, but with
+//! different opcodes:
+//!
+//! | record  | `KvStore` | `Db` |
+//! |---------|-----------|------|
+//! | put     | 0         | 1    |
+//! | delete  | 1         | 2    |
+//!
+//! Deletes always have `vlen == 0`, so a record with opcode 1 and a value can
+//! only be a `Db` put, while one without a value could be either. Logs
+//! written since format version 2 say which store wrote them in their
+//! header; only older ones need the opcodes examined.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Error, Read, Result, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::format::{check_header, FileKind, HEADER_LEN};
+use crate::manifest::CURRENT_FILE;
+use crate::simple_kv::KvStore;
+use crate::wal_kv::{Db, OP_DELETE, OP_PUT};
+
+type Bytes = Vec<u8>;
+
+/// Where an in-place migration keeps the original `KvStore` log.
+const KEPT_KVSTORE_FILE: &str = "data.log.kvstore";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    /// Missing or empty file.
+    Empty,
+    KvStore,
+    Db,
+    /// Every record has opcode 1 and no value, which reads as a `KvStore`
+    /// delete or as a `Db` put of an empty value.
+    Ambiguous,
+    /// Records that neither format writes, or a mix of both.
+    Unknown,
+}
+
+/// What [`migrate_kvstore`] converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Records read from the `KvStore` log, including overwritten and deleted ones.
+    pub records: u64,
+    /// Live keys written to the Db.
+    pub keys: u64,
+}
+
+/// Inspects the data log at `path`: its header if it has one, otherwise its
+/// records. A torn final record is ignored, as both stores do when opening.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<DataFormat> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DataFormat::Empty),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let start = read_start(&mut reader)?;
+    if FileKind::KvStore.matches(&start) {
+        return Ok(DataFormat::KvStore);
+    }
+    if FileKind::DataLog.matches(&start) {
+        return Ok(DataFormat::Db);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+
+    let (mut records, mut kvstore, mut db) = (0u64, false, false);
+    while let Some((op, key_len, value_len)) = read_header(&mut reader)? {
+        if !skip(&mut reader, key_len + value_len)? {
+            break;
+        }
+        records += 1;
+        match (op, value_len) {
+            (KvStore::OP_PUT, _) => kvstore = true,
+            (OP_PUT, 0) => {}
+            (OP_PUT, _) | (OP_DELETE, 0) => db = true,
+            _ => return Ok(DataFormat::Unknown),
+        }
+    }
+    Ok(match (records, kvstore, db) {
+        (0, _, _) => DataFormat::Empty,
+        (_, true, true) => DataFormat::Unknown,
+        (_, true, false) => DataFormat::KvStore,
+        (_, false, true) => DataFormat::Db,
+        (_, false, false) => DataFormat::Ambiguous,
+    })
+}
+
+/// Converts the `KvStore` log at `source` into a Db in `dir` and verifies
+/// that the Db holds exactly the store's live keys and values.
+///
+/// `dir` must not already contain a data log, except when `source` is that
+/// data log itself: then the original is first renamed to
+/// `data.log.kvstore` and kept there. If the conversion fails, the files it
+/// created are removed and the original is renamed back.
+pub fn migrate_kvstore<P, Q>(source: P, dir: Q) -> Result<MigrationReport>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let dir = dir.as_ref();
+    let mut source = source.as_ref().to_path_buf();
+    match detect_format(&source)? {
+        DataFormat::KvStore | DataFormat::Empty => {}
+        other => {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a KvStore log (detected {other:?})", source.display()),
+            ));
+        }
+    }
+
+    let target = dir.join(Db::DATA_FILE);
+    let existing = file_names(dir)?;
+    if dir.join(CURRENT_FILE).exists() {
+        return Err(Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already contains a data log", dir.display()),
+        ));
+    }
+    let mut renamed = false;
+    if target.exists() {
+        let in_place = fs::canonicalize(&target)? == fs::canonicalize(&source)?;
+        if !in_place {
+            return Err(Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already contains a data log", dir.display()),
+            ));
+        }
+        let kept = dir.join(KEPT_KVSTORE_FILE);
+        fs::rename(&source, &kept)?;
+        source = kept;
+        renamed = true;
+    }
+
+    match convert(&source, dir) {
+        Ok(report) => Ok(report),
+        Err(e) => {
+            for name in file_names(dir)?.difference(&existing) {
+                if name != KEPT_KVSTORE_FILE {
+                    fs::remove_file(dir.join(name))?;
+                }
+            }
+            if renamed {
+                fs::rename(&source, &target)?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Bulk loads the `KvStore` log at `source` into a Db in `dir` and checks the result.
+fn convert(source: &Path, dir: &Path) -> Result<MigrationReport> {
+    let (records, expected) = read_kvstore(source)?;
+    {
+        let mut db = Db::open(dir)?;
+        let mut loader = db.bulk_loader()?;
+        for (key, value) in &expected {
+            loader.insert(key, value)?;
+        }
+        loader.finish()?;
+    }
+
+    let mut db = Db::open(dir)?;
+    let actual = db.scan(..)?;
+    if actual.len() != expected.len() || actual.iter().zip(&expected).any(|((k, v), (ek, ev))| k != ek || v != ev) {
+        return Err(Error::new(io::ErrorKind::InvalidData, "migrated Db does not match the KvStore log"));
+    }
+    Ok(MigrationReport { records, keys: expected.len() as u64 })
+}
+
+/// Names of the files in `dir`; empty if it doesn't exist yet.
+fn file_names(dir: &Path) -> Result<BTreeSet<OsString>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = BTreeSet::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.insert(entry.file_name());
+        }
+    }
+    Ok(names)
+}
+
+/// Replays a `KvStore` log into its final key/value map.
+fn read_kvstore(path: &Path) -> Result<(u64, BTreeMap<Bytes, Bytes>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let start = read_start(&mut reader)?;
+    if FileKind::KvStore.matches(&start) {
+        check_header(&start, FileKind::KvStore, path)?;
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+    let mut records = 0;
+    let mut live = BTreeMap::new();
+    while let Some((op, key_len, value_len)) = read_header(&mut reader)? {
+        let mut key = Vec::new();
+        let mut value = Vec::new();
+        if (&mut reader).take(key_len).read_to_end(&mut key)? as u64 != key_len
+            || (&mut reader).take(value_len).read_to_end(&mut value)? as u64 != value_len
+        {
+            break;
+        }
+        records += 1;
+        if op == KvStore::OP_PUT {
+            live.insert(key, value);
+        } else {
+            live.remove(&key);
+        }
+    }
+    Ok((records, live))
+}
+
+/// Up to a header's worth of bytes from the start of the file.
+fn read_start(reader: &mut BufReader<File>) -> Result<Vec<u8>> {
+    let mut start = Vec::new();
+    reader.take(HEADER_LEN).read_to_end(&mut start)?;
+    Ok(start)
+}
+
+/// `(op, klen, vlen)` of the next record, or `None` at the end or at a torn header.
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<(u8, u64, u64)>> {
+    let mut header = [0u8; 9];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 => return Ok(None),
+            n => filled += n,
+        }
+    }
+    let key_len = u32::from_le_bytes(header[1..5].try_into().expect("4 bytes")) as u64;
+    let value_len = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes")) as u64;
+    Ok(Some((header[0], key_len, value_len)))
+}
+
+/// Skips `n` bytes; false if the input ends first.
+fn skip<R: Read>(reader: &mut R, n: u64) -> Result<bool> {
+    Ok(io::copy(&mut reader.take(n), &mut io::sink())? == n)
+}
@@ -0,0 +1,156 @@
+//! Minimal object store interface for continuous backups.
+//!
+//! Keys are `/`-separated strings. Objects are written whole and never
+//! modified, which is all the continuous backup needs and what S3-style
+//! stores offer.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Error, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::vfs::sync_parent_dir;
+
+type Bytes = Vec<u8>;
+
+pub trait ObjectStore: Send + Sync {
+    /// Stores `data` under `key`, replacing any existing object.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    fn get(&self, key: &str) -> Result<Option<Bytes>>;
+
+    /// Keys starting with `prefix`, in lexicographic order.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Removes `key`; removing a missing key is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Object store backed by a local directory, one file per object.
+pub struct LocalFsStore {
+    root: PathBuf,
+}
+
+impl LocalFsStore {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let valid = !key.is_empty()
+            && key.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+            && !key.contains('\\');
+        if !valid {
+            return Err(Error::new(io::ErrorKind::InvalidInput, format!("invalid object key: {key:?}")));
+        }
+        Ok(self.root.join(key))
+    }
+
+    fn collect(&self, dir: &Path, prefix: &str, out: &mut Vec<String>) -> Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let path = entry.path();
+            let relative = path.strip_prefix(&self.root).map_err(Error::other)?;
+            let key = relative.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+            if entry.file_type()?.is_dir() {
+                if prefix.starts_with(&key) || key.starts_with(prefix) {
+                    self.collect(&path, prefix, out)?;
+                }
+            } else if key.starts_with(prefix) && !name.ends_with(".tmp") {
+                out.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ObjectStore for LocalFsStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        sync_parent_dir(&path)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.collect(&self.root, prefix, &mut keys)?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Object store held in memory, shared by every clone. Useful in tests and
+/// simulations, where the store must outlive crashes of the Db.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    objects: Arc<Mutex<BTreeMap<String, Bytes>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn objects(&self) -> MutexGuard<'_, BTreeMap<String, Bytes>> {
+        self.objects.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.objects().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        Ok(self.objects().get(key).cloned())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let objects = self.objects();
+        Ok(objects.range(prefix.to_string()..).map(|(key, _)| key).take_while(|key| key.starts_with(prefix)).cloned().collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.objects().remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,1042 @@
+//! Raft-replicated Db.
+//!
+//! Each [`RaftNode`] keeps two Dbs in its directory:
+//!
+//! - `state/`: the replicated state machine. Committed entries are applied to
+//!   it together with a system key recording the applied index, in one batch,
+//!   so a restart never applies an entry twice.
+//! - `log/`: the Raft log and hard state (term, vote, snapshot metadata).
+//!
+//! Nodes are driven from outside: call [`RaftNode::tick`] on a timer, hand
+//! incoming messages to [`RaftNode::step`], and deliver whatever
+//! [`RaftNode::take_messages`] returns through a [`Transport`]. A write only
+//! counts as committed once a majority of the members have it in their logs.
+//!
+//! Membership changes add or remove one node at a time and take effect as
+//! soon as they are appended, per the Raft dissertation's single-server
+//! changes. Followers that need entries the leader has already compacted are
+//! sent a snapshot: the leader's compacted state Db.
+
+pub mod sim;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Error, Result};
+use std::ops::RangeBounds;
+use std::path::Path;
+
+use crate::changes::{encode_batch, read_batch};
+use crate::tuple;
+use crate::wal_kv::{is_system_key, Db, Op, SYSTEM_PREFIX};
+
+type Bytes = Vec<u8>;
+
+pub type NodeId = u64;
+
+/// Delivers messages between nodes.
+pub trait Transport {
+    fn send(&mut self, from: NodeId, to: NodeId, msg: Message);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RaftConfig {
+    /// Minimum ticks without hearing from a leader before campaigning; the
+    /// actual timeout is randomized in `[election_ticks, 2 * election_ticks)`.
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    /// Applied entries kept in the log before it is compacted.
+    pub snapshot_threshold: usize,
+    pub max_entries_per_message: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            snapshot_threshold: 1000,
+            max_entries_per_message: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembershipChange {
+    AddNode(NodeId),
+    RemoveNode(NodeId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// Appended by every new leader so it can commit entries from earlier terms.
+    Noop,
+    /// Membership of a brand-new cluster; always entry 1, with term 0.
+    Bootstrap(BTreeSet<NodeId>),
+    /// A transaction, framed as in the WAL.
+    Batch(Bytes),
+    Membership(MembershipChange),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub payload: Payload,
+}
+
+/// Compacted state as of `last_index`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub members: BTreeSet<NodeId>,
+    /// Compacted data log of the leader's state Db.
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    RequestVote { term: u64, last_log_index: u64, last_log_term: u64 },
+    RequestVoteResponse { term: u64, granted: bool },
+    AppendEntries { term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64 },
+    /// On success `match_index` is the last index the follower now shares with
+    /// the leader; on failure it is a hint for where to retry.
+    AppendEntriesResponse { term: u64, success: bool, match_index: u64 },
+    InstallSnapshot { term: u64, snapshot: Snapshot },
+    InstallSnapshotResponse { term: u64, last_index: u64 },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::RequestVoteResponse { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendEntriesResponse { term, .. }
+            | Message::InstallSnapshot { term, .. }
+            | Message::InstallSnapshotResponse { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// An entry appended to the leader's log that is not yet known to be
+/// committed. Only [`RaftNode::poll_commit`] reporting it committed means a
+/// majority has stored it.
+#[must_use = "the write is not committed until poll_commit says so"]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingCommit {
+    index: u64,
+    term: u64,
+}
+
+impl PendingCommit {
+    /// Log index of the entry.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+pub struct RaftNode {
+    id: NodeId,
+    config: RaftConfig,
+    state: Db,
+    log_db: Db,
+
+    term: u64,
+    voted_for: Option<NodeId>,
+    /// Entries after `snapshot_index`; `log[i].index == snapshot_index + 1 + i`.
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: BTreeSet<NodeId>,
+    /// Terms of the entries compacted since this node opened, for
+    /// `poll_commit`: the last index of each run of entries of one term,
+    /// mapped to that term, or to `None` where a snapshot replaced entries
+    /// this node never had. The runs cover `compacted_from..=snapshot_index`.
+    compacted_terms: BTreeMap<u64, Option<u64>>,
+    compacted_from: u64,
+    commit_index: u64,
+    last_applied: u64,
+
+    role: Role,
+    leader: Option<NodeId>,
+    votes: BTreeSet<NodeId>,
+    next_index: BTreeMap<NodeId, u64>,
+    match_index: BTreeMap<NodeId, u64>,
+
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    rng: u64,
+    outbox: Vec<(NodeId, Message)>,
+}
+
+const HARD_STATE_KEY: &[u8] = b"hard_state";
+const SNAPSHOT_META_KEY: &[u8] = b"snapshot_meta";
+const LOG_PREFIX: &[u8] = b"log/";
+
+const PAYLOAD_NOOP: u8 = 0;
+const PAYLOAD_BATCH: u8 = 1;
+const PAYLOAD_ADD_NODE: u8 = 2;
+const PAYLOAD_REMOVE_NODE: u8 = 3;
+const PAYLOAD_BOOTSTRAP: u8 = 4;
+
+impl RaftNode {
+    /// Opens node `id` stored in `dir`. `initial_members` seeds the
+    /// membership of a brand-new cluster and must be the same on every
+    /// initial member; pass an empty slice for a node that will be added to an
+    /// existing cluster. It is ignored once the node has persisted state.
+    /// `seed` drives election timeout randomization.
+    pub fn open<P: AsRef<Path>>(
+        id: NodeId,
+        dir: P,
+        initial_members: &[NodeId],
+        config: RaftConfig,
+        seed: u64,
+    ) -> Result<Self> {
+        if id == 0 {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "node id 0 is reserved"));
+        }
+        let dir = dir.as_ref();
+        let mut state = Db::open(dir.join("state"))?;
+        let mut log_db = Db::open(dir.join("log"))?;
+
+        let (term, voted_for) = match log_db.get(HARD_STATE_KEY)? {
+            Some(bytes) => {
+                let term = read_u64(&bytes, 0)?;
+                let vote = read_u64(&bytes, 8)?;
+                (term, (vote != 0).then_some(vote))
+            }
+            None => (0, None),
+        };
+
+        // The log Db keeps its own change history, which nothing reads.
+        log_db.set_change_retention(1);
+
+        let (snapshot_index, snapshot_term, snapshot_members) = match log_db.get(SNAPSHOT_META_KEY)? {
+            Some(bytes) => decode_snapshot_meta(&bytes)?,
+            None => {
+                let mut ops = vec![Op::Set(SNAPSHOT_META_KEY.to_vec(), encode_snapshot_meta(0, 0, &BTreeSet::new()))];
+                if !initial_members.is_empty() {
+                    let bootstrap = Entry {
+                        term: 0,
+                        index: 1,
+                        payload: Payload::Bootstrap(initial_members.iter().copied().collect()),
+                    };
+                    ops.push(Op::Set(log_key(1), encode_entry(&bootstrap)));
+                }
+                log_db.write_batch(ops)?;
+                (0, 0, BTreeSet::new())
+            }
+        };
+
+        let mut log = Vec::new();
+        for (key, value) in log_db.scan_prefix(LOG_PREFIX)? {
+            let index = read_u64_be(&key[LOG_PREFIX.len()..])?;
+            let entry = decode_entry(index, &value)?;
+            if entry.index > snapshot_index {
+                log.push(entry);
+            }
+        }
+
+        let applied = match state.get(applied_key())? {
+            Some(bytes) => read_u64(&bytes, 0)?,
+            None => 0,
+        };
+        let last_applied = applied.max(snapshot_index);
+
+        let mut node = Self {
+            id,
+            config,
+            state,
+            log_db,
+            term,
+            voted_for,
+            log,
+            snapshot_index,
+            snapshot_term,
+            snapshot_members,
+            compacted_terms: BTreeMap::new(),
+            compacted_from: snapshot_index + 1,
+            commit_index: last_applied,
+            last_applied,
+            role: Role::Follower,
+            leader: None,
+            votes: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            rng: seed ^ id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            outbox: Vec::new(),
+        };
+        node.reset_election_timer();
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    /// The leader this node currently follows, if known.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn applied_index(&self) -> u64 {
+        self.last_applied
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_index, |e| e.index)
+    }
+
+    /// Current voting members, including changes not yet committed.
+    pub fn members(&self) -> BTreeSet<NodeId> {
+        self.members_at(self.last_index())
+    }
+
+    /// Reads from this node's applied state. On a follower, or on a leader
+    /// that has just been deposed, the result may be stale.
+    pub fn get<K>(&mut self, key: K) -> Result<Option<Bytes>>
+    where K: AsRef<[u8]>,
+    {
+        self.state.get(key)
+    }
+
+    pub fn scan<R>(&mut self, range: R) -> Result<Vec<(Bytes, Bytes)>>
+    where R: RangeBounds<Bytes>,
+    {
+        self.state.scan(range)
+    }
+
+    pub fn begin_transaction(&mut self) -> RaftTransaction<'_> {
+        RaftTransaction { node: self, operations: Vec::new() }
+    }
+
+    /// Proposes adding or removing one voting member. Only one membership
+    /// change may be in flight at a time.
+    pub fn propose_membership(&mut self, change: MembershipChange) -> Result<PendingCommit> {
+        self.ensure_leader()?;
+        let pending = self.log.iter().any(|e| {
+            e.index > self.commit_index && matches!(e.payload, Payload::Membership(_))
+        });
+        if pending {
+            return Err(Error::other("a membership change is already in progress"));
+        }
+        let proposal = self.append_local(Payload::Membership(change))?;
+        if let MembershipChange::AddNode(id) = change
+            && id != self.id
+        {
+            self.next_index.insert(id, self.last_index());
+            self.match_index.insert(id, 0);
+        }
+        self.broadcast_append()?;
+        self.advance_commit()?;
+        Ok(proposal)
+    }
+
+    /// `Ok(true)` once a majority has stored `pending`, `Ok(false)` while
+    /// this node is still the leader trying to get it there. Fails once the
+    /// write can no longer be confirmed here: a different entry took its
+    /// index, its index was compacted away by a node that can't tell which
+    /// entry it held, or this node stopped leading the term it was proposed
+    /// in. In the last case a later leader may still commit it.
+    pub fn poll_commit(&self, pending: PendingCommit) -> Result<bool> {
+        let lost = |why: &str| Error::new(io::ErrorKind::Interrupted, format!("entry {}: {why}", pending.index));
+        let term = match self.term_at(pending.index) {
+            None if pending.index <= self.snapshot_index => match self.compacted_term(pending.index) {
+                Some(term) => Some(term),
+                // Entries of one term all come from that term's leader, so
+                // if it made the last compacted entry, its log held
+                // `pending` before it; a later term can't be in the snapshot.
+                None if pending.term >= self.snapshot_term => Some(self.snapshot_term),
+                None => return Err(lost("compacted before its outcome was known")),
+            },
+            term => term,
+        };
+        match term {
+            Some(term) if term != pending.term => Err(lost("a different entry was committed at its index")),
+            Some(_) if pending.index <= self.commit_index => Ok(true),
+            _ if self.role != Role::Leader || self.term != pending.term => {
+                Err(lost("leadership was lost before it was committed"))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Messages produced since the last call, as `(recipient, message)`.
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Sends everything in the outbox through `transport`.
+    pub fn flush<T: Transport + ?Sized>(&mut self, transport: &mut T) {
+        for (to, msg) in self.take_messages() {
+            transport.send(self.id, to, msg);
+        }
+    }
+
+    /// Advances logical time by one tick.
+    pub fn tick(&mut self) -> Result<()> {
+        match self.role {
+            Role::Leader => {
+                self.heartbeat_elapsed += 1;
+                if self.heartbeat_elapsed >= self.config.heartbeat_ticks {
+                    self.heartbeat_elapsed = 0;
+                    self.broadcast_append()?;
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                self.election_elapsed += 1;
+                if self.election_elapsed >= self.election_timeout && self.members().contains(&self.id) {
+                    self.campaign()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles one message from `from`.
+    pub fn step(&mut self, from: NodeId, msg: Message) -> Result<()> {
+        if msg.term() > self.term {
+            let leader = match msg {
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(msg.term(), leader)?;
+        }
+
+        match msg {
+            Message::RequestVote { term, last_log_index, last_log_term } => {
+                let up_to_date = last_log_term > self.last_term()
+                    || (last_log_term == self.last_term() && last_log_index >= self.last_index());
+                let granted = term == self.term
+                    && self.voted_for.is_none_or(|v| v == from)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(from);
+                    self.persist_hard_state()?;
+                    self.reset_election_timer();
+                }
+                self.send(from, Message::RequestVoteResponse { term: self.term, granted });
+            }
+            Message::RequestVoteResponse { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.has_quorum(&self.votes) {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            Message::AppendEntries { term, prev_log_index, prev_log_term, entries, leader_commit } => {
+                if term < self.term {
+                    self.send(from, Message::AppendEntriesResponse { term: self.term, success: false, match_index: 0 });
+                    return Ok(());
+                }
+                self.become_follower(term, Some(from))?;
+                self.handle_append(from, prev_log_index, prev_log_term, entries, leader_commit)?;
+            }
+            Message::AppendEntriesResponse { term, success, match_index } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+                if success {
+                    let m = self.match_index.entry(from).or_insert(0);
+                    *m = (*m).max(match_index);
+                    let next = *m + 1;
+                    self.next_index.insert(from, next);
+                    self.advance_commit()?;
+                    if next <= self.last_index() {
+                        self.send_append(from)?;
+                    }
+                } else {
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    let retry = next.saturating_sub(1).min(match_index + 1).max(1);
+                    self.next_index.insert(from, retry);
+                    self.send_append(from)?;
+                }
+            }
+            Message::InstallSnapshot { term, snapshot } => {
+                if term < self.term {
+                    self.send(from, Message::InstallSnapshotResponse { term: self.term, last_index: 0 });
+                    return Ok(());
+                }
+                self.become_follower(term, Some(from))?;
+                let last_index = snapshot.last_index;
+                if last_index > self.commit_index {
+                    self.install_snapshot(snapshot)?;
+                }
+                self.send(from, Message::InstallSnapshotResponse {
+                    term: self.term,
+                    last_index: self.commit_index.max(last_index),
+                });
+            }
+            Message::InstallSnapshotResponse { term, last_index } => {
+                if self.role == Role::Leader && term == self.term {
+                    let m = self.match_index.entry(from).or_insert(0);
+                    *m = (*m).max(last_index);
+                    let next = *m + 1;
+                    self.next_index.insert(from, next);
+                    self.advance_commit()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<()> {
+        if prev_log_index > self.last_index() {
+            let hint = self.last_index();
+            self.send(from, Message::AppendEntriesResponse { term: self.term, success: false, match_index: hint });
+            return Ok(());
+        }
+        // Entries at or below the snapshot are committed, so they match.
+        if prev_log_index > self.snapshot_index && self.term_at(prev_log_index) != Some(prev_log_term) {
+            let hint = prev_log_index.saturating_sub(1).max(self.snapshot_index);
+            self.send(from, Message::AppendEntriesResponse { term: self.term, success: false, match_index: hint });
+            return Ok(());
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut truncate_from = None;
+        let mut to_append = Vec::new();
+        for entry in entries {
+            if entry.index <= self.snapshot_index {
+                continue;
+            }
+            if truncate_from.is_none() && to_append.is_empty() {
+                match self.term_at(entry.index) {
+                    Some(t) if t == entry.term => continue,
+                    Some(_) => truncate_from = Some(entry.index),
+                    None => {}
+                }
+            }
+            to_append.push(entry);
+        }
+        self.write_log(truncate_from, to_append)?;
+
+        if leader_commit > self.commit_index {
+            self.commit_index = leader_commit.min(last_new);
+            self.apply_committed()?;
+        }
+        self.send(from, Message::AppendEntriesResponse { term: self.term, success: true, match_index: last_new });
+        Ok(())
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.id);
+        self.votes = BTreeSet::from([self.id]);
+        self.persist_hard_state()?;
+        self.reset_election_timer();
+
+        if self.has_quorum(&self.votes) {
+            return self.become_leader();
+        }
+        let msg = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers() {
+            self.send(peer, msg.clone());
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist_hard_state()?;
+        }
+        if self.role != Role::Follower || leader.is_some() {
+            self.reset_election_timer();
+        }
+        self.role = Role::Follower;
+        if leader.is_some() {
+            self.leader = leader;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        self.next_index.clear();
+        self.match_index.clear();
+        for peer in self.peers() {
+            self.next_index.insert(peer, self.last_index() + 1);
+            self.match_index.insert(peer, 0);
+        }
+        // Committing an entry of its own term commits everything before it.
+        let _ = self.append_local(Payload::Noop)?;
+        self.broadcast_append()?;
+        self.advance_commit()
+    }
+
+    fn ensure_leader(&self) -> Result<()> {
+        if self.role == Role::Leader {
+            Ok(())
+        } else {
+            Err(Error::other(format!("node {} is not the leader (leader: {:?})", self.id, self.leader)))
+        }
+    }
+
+    fn append_local(&mut self, payload: Payload) -> Result<PendingCommit> {
+        let entry = Entry { term: self.term, index: self.last_index() + 1, payload };
+        let pending = PendingCommit { index: entry.index, term: entry.term };
+        self.write_log(None, vec![entry])?;
+        Ok(pending)
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        for peer in self.peers() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, to: NodeId) -> Result<()> {
+        let last_index = self.last_index();
+        let next = *self.next_index.entry(to).or_insert(last_index + 1);
+        if next <= self.snapshot_index {
+            return self.send_snapshot(to);
+        }
+
+        let prev_log_index = next - 1;
+        let prev_log_term = self.term_at(prev_log_index).unwrap_or(self.snapshot_term);
+        let start = (next - self.snapshot_index - 1) as usize;
+        let entries: Vec<Entry> = self.log
+            .iter()
+            .skip(start)
+            .take(self.config.max_entries_per_message)
+            .cloned()
+            .collect();
+        self.send(to, Message::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+        });
+        Ok(())
+    }
+
+    /// Sends a snapshot of the applied state, copied from the state Db's
+    /// live records.
+    fn send_snapshot(&mut self, to: NodeId) -> Result<()> {
+        let last_index = self.last_applied;
+        let snapshot = Snapshot {
+            last_index,
+            last_term: self.term_at(last_index).unwrap_or(self.snapshot_term),
+            members: self.members_at(last_index),
+            data: self.state.live_image()?,
+        };
+        // Optimistically assume it lands; a rejection moves next_index back.
+        self.next_index.insert(to, last_index + 1);
+        self.send(to, Message::InstallSnapshot { term: self.term, snapshot });
+        Ok(())
+    }
+
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        self.state.install_image(&snapshot.data)?;
+
+        // Keep log entries past the snapshot if they agree with it.
+        let keep = self.term_at(snapshot.last_index) == Some(snapshot.last_term);
+        let kept: Vec<Entry> = if keep {
+            self.log.iter().filter(|e| e.index > snapshot.last_index).cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let mut ops: Vec<Op> = self.log
+            .iter()
+            .filter(|e| !kept.iter().any(|k| k.index == e.index))
+            .map(|e| Op::Delete(log_key(e.index)))
+            .collect();
+        ops.push(Op::Set(
+            SNAPSHOT_META_KEY.to_vec(),
+            encode_snapshot_meta(snapshot.last_index, snapshot.last_term, &snapshot.members),
+        ));
+        self.log_db.write_batch(ops)?;
+
+        // Committed entries, and any others that agree with the snapshot,
+        // are the snapshot's own.
+        let known = if keep { snapshot.last_index } else { self.commit_index };
+        for e in self.log.iter().take_while(|e| e.index <= known) {
+            record_compacted(&mut self.compacted_terms, e.index, Some(e.term));
+        }
+        if known < snapshot.last_index {
+            record_compacted(&mut self.compacted_terms, snapshot.last_index, None);
+        }
+        self.log = kept;
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        self.snapshot_members = snapshot.members;
+        self.commit_index = self.commit_index.max(snapshot.last_index);
+        self.last_applied = snapshot.last_index;
+        Ok(())
+    }
+
+    fn advance_commit(&mut self) -> Result<()> {
+        if self.role != Role::Leader {
+            return Ok(());
+        }
+        let members = self.members();
+        let mut n = self.last_index();
+        while n > self.commit_index {
+            if self.term_at(n) == Some(self.term) {
+                let acks: BTreeSet<NodeId> = members
+                    .iter()
+                    .copied()
+                    .filter(|&m| {
+                        if m == self.id {
+                            self.last_index() >= n
+                        } else {
+                            self.match_index.get(&m).copied().unwrap_or(0) >= n
+                        }
+                    })
+                    .collect();
+                if self.has_quorum(&acks) {
+                    self.commit_index = n;
+                    self.apply_committed()?;
+                    // Let followers learn the new commit index promptly.
+                    self.broadcast_append()?;
+                    break;
+                }
+            }
+            n -= 1;
+        }
+        Ok(())
+    }
+
+    fn apply_committed(&mut self) -> Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let payload = self.entry(index)
+                .map(|e| e.payload.clone())
+                .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, format!("missing log entry {index}")))?;
+            match payload {
+                Payload::Batch(batch) => {
+                    let (_, mut ops) = read_batch(&mut batch.as_slice())?
+                        .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, "empty batch entry"))?;
+                    ops.push(Op::Set(applied_key(), index.to_le_bytes().to_vec()));
+                    self.state.commit_unchecked(ops)?;
+                }
+                Payload::Membership(MembershipChange::RemoveNode(id)) if id == self.id => {
+                    if self.role == Role::Leader {
+                        self.role = Role::Follower;
+                        self.leader = None;
+                    }
+                }
+                Payload::Membership(_) | Payload::Bootstrap(_) | Payload::Noop => {}
+            }
+            self.last_applied = index;
+        }
+
+        if self.log.len() > self.config.snapshot_threshold {
+            self.compact_log()?;
+        }
+        Ok(())
+    }
+
+    /// Drops applied entries from the log; the state Db stands in for them.
+    fn compact_log(&mut self) -> Result<()> {
+        let upto = self.last_applied;
+        let term = self.term_at(upto).unwrap_or(self.snapshot_term);
+        let members = self.members_at(upto);
+
+        let mut ops: Vec<Op> = self.log
+            .iter()
+            .take_while(|e| e.index <= upto)
+            .map(|e| Op::Delete(log_key(e.index)))
+            .collect();
+        ops.push(Op::Set(SNAPSHOT_META_KEY.to_vec(), encode_snapshot_meta(upto, term, &members)));
+        self.log_db.write_batch(ops)?;
+
+        for e in self.log.iter().take_while(|e| e.index <= upto) {
+            record_compacted(&mut self.compacted_terms, e.index, Some(e.term));
+        }
+        self.log.retain(|e| e.index > upto);
+        self.snapshot_index = upto;
+        self.snapshot_term = term;
+        self.snapshot_members = members;
+        Ok(())
+    }
+
+    /// Durably truncates the log from `truncate_from` and appends `entries`, in one batch.
+    fn write_log(&mut self, truncate_from: Option<u64>, entries: Vec<Entry>) -> Result<()> {
+        if truncate_from.is_none() && entries.is_empty() {
+            return Ok(());
+        }
+        let mut ops = Vec::new();
+        if let Some(from) = truncate_from {
+            for e in self.log.iter().filter(|e| e.index >= from) {
+                ops.push(Op::Delete(log_key(e.index)));
+            }
+        }
+        for e in &entries {
+            ops.push(Op::Set(log_key(e.index), encode_entry(e)));
+        }
+        self.log_db.write_batch(ops)?;
+
+        if let Some(from) = truncate_from {
+            self.log.retain(|e| e.index < from);
+        }
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    fn persist_hard_state(&mut self) -> Result<()> {
+        let mut value = self.term.to_le_bytes().to_vec();
+        value.extend_from_slice(&self.voted_for.unwrap_or(0).to_le_bytes());
+        self.log_db.write_batch(vec![Op::Set(HARD_STATE_KEY.to_vec(), value)])
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    /// Term of compacted entry `index`, if this node held it.
+    fn compacted_term(&self, index: u64) -> Option<u64> {
+        if index < self.compacted_from {
+            return None;
+        }
+        self.compacted_terms.range(index..).next().and_then(|(_, &term)| term)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    fn members_at(&self, index: u64) -> BTreeSet<NodeId> {
+        let mut members = self.snapshot_members.clone();
+        for e in self.log.iter().take_while(|e| e.index <= index) {
+            match &e.payload {
+                Payload::Bootstrap(initial) => members = initial.clone(),
+                Payload::Membership(MembershipChange::AddNode(id)) => {
+                    members.insert(*id);
+                }
+                Payload::Membership(MembershipChange::RemoveNode(id)) => {
+                    members.remove(id);
+                }
+                _ => {}
+            }
+        }
+        members
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.members().into_iter().filter(|&m| m != self.id).collect()
+    }
+
+    fn has_quorum(&self, acks: &BTreeSet<NodeId>) -> bool {
+        let members = self.members();
+        let count = members.iter().filter(|m| acks.contains(m)).count();
+        count * 2 > members.len()
+    }
+
+    fn send(&mut self, to: NodeId, msg: Message) {
+        self.outbox.push((to, msg));
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        let spread = self.config.election_ticks.max(1) as u64;
+        self.election_timeout = self.config.election_ticks + (self.next_random() % spread) as u32;
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        x
+    }
+}
+
+/// Buffered writes proposed to the cluster as one log entry.
+pub struct RaftTransaction<'a> {
+    node: &'a mut RaftNode,
+    operations: Vec<Op>,
+}
+
+impl RaftTransaction<'_> {
+    pub fn set<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.operations.push(Op::Set(key.as_ref().to_vec(), value.as_ref().to_vec()));
+    }
+
+    pub fn delete<K>(&mut self, key: K)
+    where
+        K: AsRef<[u8]>,
+    {
+        self.operations.push(Op::Delete(key.as_ref().to_vec()));
+    }
+
+    /// Appends the transaction to the leader's log and starts replicating it.
+    /// It is committed once [`RaftNode::poll_commit`] returns `Ok(true)` for
+    /// the returned handle; until then it may still be lost.
+    pub fn commit(self) -> Result<PendingCommit> {
+        if self.operations.iter().any(|op| is_system_key(op.key())) {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "keys starting with 0xFF are reserved"));
+        }
+        self.node.ensure_leader()?;
+        let proposal = self.node.append_local(Payload::Batch(encode_batch(0, &self.operations)))?;
+        self.node.broadcast_append()?;
+        self.node.advance_commit()?;
+        Ok(proposal)
+    }
+}
+
+/// Extends `runs` to `index`, whose entries since the last recorded one
+/// have `term`.
+fn record_compacted(runs: &mut BTreeMap<u64, Option<u64>>, index: u64, term: Option<u64>) {
+    if let Some((&end, &last)) = runs.last_key_value()
+        && last == term
+    {
+        runs.remove(&end);
+    }
+    runs.insert(index, term);
+}
+
+fn applied_key() -> Bytes {
+    let mut key = vec![SYSTEM_PREFIX];
+    key.extend(tuple::pack(("raft", "applied")));
+    key
+}
+
+fn log_key(index: u64) -> Bytes {
+    let mut key = LOG_PREFIX.to_vec();
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn encode_entry(entry: &Entry) -> Bytes {
+    let mut out = entry.term.to_le_bytes().to_vec();
+    match &entry.payload {
+        Payload::Noop => out.push(PAYLOAD_NOOP),
+        Payload::Batch(batch) => {
+            out.push(PAYLOAD_BATCH);
+            out.extend_from_slice(batch);
+        }
+        Payload::Membership(MembershipChange::AddNode(id)) => {
+            out.push(PAYLOAD_ADD_NODE);
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        Payload::Membership(MembershipChange::RemoveNode(id)) => {
+            out.push(PAYLOAD_REMOVE_NODE);
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        Payload::Bootstrap(members) => {
+            out.push(PAYLOAD_BOOTSTRAP);
+            for m in members {
+                out.extend_from_slice(&m.to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+fn decode_entry(index: u64, bytes: &[u8]) -> Result<Entry> {
+    let term = read_u64(bytes, 0)?;
+    let kind = *bytes.get(8).ok_or_else(|| invalid("truncated log entry"))?;
+    let payload = match kind {
+        PAYLOAD_NOOP => Payload::Noop,
+        PAYLOAD_BATCH => Payload::Batch(bytes[9..].to_vec()),
+        PAYLOAD_ADD_NODE => Payload::Membership(MembershipChange::AddNode(read_u64(bytes, 9)?)),
+        PAYLOAD_REMOVE_NODE => Payload::Membership(MembershipChange::RemoveNode(read_u64(bytes, 9)?)),
+        PAYLOAD_BOOTSTRAP => Payload::Bootstrap(read_ids(bytes, 9)?),
+        other => return Err(invalid(&format!("unknown log entry kind: {other}"))),
+    };
+    Ok(Entry { term, index, payload })
+}
+
+fn encode_snapshot_meta(index: u64, term: u64, members: &BTreeSet<NodeId>) -> Bytes {
+    let mut out = index.to_le_bytes().to_vec();
+    out.extend_from_slice(&term.to_le_bytes());
+    for m in members {
+        out.extend_from_slice(&m.to_le_bytes());
+    }
+    out
+}
+
+fn decode_snapshot_meta(bytes: &[u8]) -> Result<(u64, u64, BTreeSet<NodeId>)> {
+    let index = read_u64(bytes, 0)?;
+    let term = read_u64(bytes, 8)?;
+    Ok((index, term, read_ids(bytes, 16)?))
+}
+
+fn read_ids(bytes: &[u8], mut at: usize) -> Result<BTreeSet<NodeId>> {
+    let mut ids = BTreeSet::new();
+    while at < bytes.len() {
+        ids.insert(read_u64(bytes, at)?);
+        at += 8;
+    }
+    Ok(ids)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Result<u64> {
+    bytes
+        .get(at..at + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().expect("slice of 8 bytes")))
+        .ok_or_else(|| invalid("truncated u64"))
+}
+
+fn read_u64_be(bytes: &[u8]) -> Result<u64> {
+    let b: [u8; 8] = bytes.try_into().map_err(|_| invalid("malformed log key"))?;
+    Ok(u64::from_be_bytes(b))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
@@ -0,0 +1,211 @@
+//! Deterministic in-process network for driving a Raft cluster.
+//!
+//! All nodes share one logical clock. Each [`SimNetwork::tick`] ticks every
+//! running node and then delivers messages, including replies to replies,
+//! until the network is quiet. Partitions, message loss, crashes and restarts
+//! are under the caller's control, and message loss is drawn from a seeded
+//! generator, so a given seed always replays the same run.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, Error, Result};
+use std::path::{Path, PathBuf};
+
+use super::{Message, NodeId, PendingCommit, RaftConfig, RaftNode, Transport};
+
+/// Upper bound on delivery rounds per tick, in case nodes keep answering each other.
+const MAX_ROUNDS_PER_TICK: usize = 1000;
+
+pub struct SimNetwork {
+    dir: PathBuf,
+    config: RaftConfig,
+    seed: u64,
+    initial_members: Vec<NodeId>,
+    /// `None` while a node is crashed.
+    nodes: BTreeMap<NodeId, Option<RaftNode>>,
+    mailbox: Mailbox,
+}
+
+struct Mailbox {
+    queue: VecDeque<(NodeId, NodeId, Message)>,
+    isolated: BTreeSet<NodeId>,
+    /// Probability in `[0, 1]` that any one message is lost.
+    drop_rate: f64,
+    rng: u64,
+}
+
+impl Transport for Mailbox {
+    fn send(&mut self, from: NodeId, to: NodeId, msg: Message) {
+        if self.isolated.contains(&from) || self.isolated.contains(&to) {
+            return;
+        }
+        if self.drop_rate > 0.0 && self.next_unit() < self.drop_rate {
+            return;
+        }
+        self.queue.push_back((from, to, msg));
+    }
+}
+
+impl Mailbox {
+    fn next_unit(&mut self) -> f64 {
+        // xorshift64
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl SimNetwork {
+    /// Creates a cluster of `ids`, storing node `n` under `dir/node-n`.
+    pub fn new<P: AsRef<Path>>(dir: P, ids: &[NodeId], config: RaftConfig, seed: u64) -> Result<Self> {
+        let mut net = Self {
+            dir: dir.as_ref().to_path_buf(),
+            config,
+            seed,
+            initial_members: ids.to_vec(),
+            nodes: BTreeMap::new(),
+            mailbox: Mailbox {
+                queue: VecDeque::new(),
+                isolated: BTreeSet::new(),
+                drop_rate: 0.0,
+                rng: seed | 1,
+            },
+        };
+        for &id in ids {
+            let node = RaftNode::open(id, net.node_dir(id), ids, config, seed)?;
+            net.nodes.insert(id, Some(node));
+        }
+        Ok(net)
+    }
+
+    pub fn node_dir(&self, id: NodeId) -> PathBuf {
+        self.dir.join(format!("node-{id}"))
+    }
+
+    /// The node, unless it is unknown or crashed.
+    pub fn node(&self, id: NodeId) -> Option<&RaftNode> {
+        self.nodes.get(&id).and_then(Option::as_ref)
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> Option<&mut RaftNode> {
+        self.nodes.get_mut(&id).and_then(Option::as_mut)
+    }
+
+    /// Ids of every node that is currently running.
+    pub fn running(&self) -> Vec<NodeId> {
+        self.nodes.iter().filter(|(_, n)| n.is_some()).map(|(&id, _)| id).collect()
+    }
+
+    /// The running leader with the highest term, if any.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .flatten()
+            .filter(|n| n.is_leader())
+            .max_by_key(|n| n.term())
+            .map(RaftNode::id)
+    }
+
+    /// Advances every running node by one tick and delivers messages until
+    /// the network is quiet.
+    pub fn tick(&mut self) -> Result<()> {
+        for node in self.nodes.values_mut().flatten() {
+            node.tick()?;
+            node.flush(&mut self.mailbox);
+        }
+        self.deliver()
+    }
+
+    pub fn run(&mut self, ticks: usize) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Delivers pending messages, and any they provoke, without advancing time.
+    pub fn deliver(&mut self) -> Result<()> {
+        for _ in 0..MAX_ROUNDS_PER_TICK {
+            if self.mailbox.queue.is_empty() {
+                break;
+            }
+            let round = std::mem::take(&mut self.mailbox.queue);
+            for (from, to, msg) in round {
+                if let Some(Some(node)) = self.nodes.get_mut(&to) {
+                    node.step(from, msg)?;
+                    node.flush(&mut self.mailbox);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Ticks until some node is leader, giving up after `max_ticks`.
+    pub fn wait_for_leader(&mut self, max_ticks: usize) -> Result<NodeId> {
+        for _ in 0..max_ticks {
+            if let Some(id) = self.leader() {
+                return Ok(id);
+            }
+            self.tick()?;
+        }
+        self.leader().ok_or_else(|| Error::new(io::ErrorKind::TimedOut, "no leader elected"))
+    }
+
+    /// Ticks until `pending`, made on node `id`, is committed there, giving
+    /// up after `max_ticks`. Fails as [`RaftNode::poll_commit`] does if the
+    /// write is lost.
+    pub fn wait_committed(&mut self, id: NodeId, pending: PendingCommit, max_ticks: usize) -> Result<()> {
+        for _ in 0..=max_ticks {
+            let node = self
+                .node(id)
+                .ok_or_else(|| Error::new(io::ErrorKind::NotFound, format!("node {id} is not running")))?;
+            if node.poll_commit(pending)? {
+                return Ok(());
+            }
+            self.tick()?;
+        }
+        Err(Error::new(io::ErrorKind::TimedOut, "write still not committed"))
+    }
+
+    /// Cuts node `id` off from every other node.
+    pub fn isolate(&mut self, id: NodeId) {
+        self.mailbox.isolated.insert(id);
+    }
+
+    /// Reconnects every isolated node.
+    pub fn heal(&mut self) {
+        self.mailbox.isolated.clear();
+    }
+
+    pub fn set_drop_rate(&mut self, rate: f64) {
+        self.mailbox.drop_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// Stops node `id`, discarding everything it has not made durable.
+    pub fn crash(&mut self, id: NodeId) {
+        if let Some(slot) = self.nodes.get_mut(&id) {
+            *slot = None;
+        }
+        self.mailbox.queue.retain(|(_, to, _)| *to != id);
+    }
+
+    /// Reopens a crashed node from its directory.
+    pub fn restart(&mut self, id: NodeId) -> Result<()> {
+        let node = RaftNode::open(id, self.node_dir(id), &self.initial_members, self.config, self.seed ^ self.mailbox.rng)?;
+        self.nodes.insert(id, Some(node));
+        Ok(())
+    }
+
+    /// Starts a new, empty node. It only takes part once the leader has
+    /// committed `MembershipChange::AddNode(id)`.
+    pub fn add_node(&mut self, id: NodeId) -> Result<()> {
+        if self.nodes.contains_key(&id) {
+            return Err(Error::new(io::ErrorKind::AlreadyExists, format!("node {id} already exists")));
+        }
+        let node = RaftNode::open(id, self.node_dir(id), &[], self.config, self.seed)?;
+        self.nodes.insert(id, Some(node));
+        Ok(())
+    }
+}
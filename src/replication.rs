@@ -0,0 +1,462 @@
+//! Primary/follower replication by shipping the change log over TCP.
+//!
+//! A follower connects and sends the sequence number it has applied. The
+//! primary streams every later batch from its change log; if the follower is
+//! too far behind (the batches it needs were discarded by retention) or ahead
+//! of the primary, the primary first sends a full snapshot of its keyspace.
+//! A follower ahead of the primary refuses it: it holds commits the primary
+//! never had. Snapshots are built and applied in memory as a whole.
+//!
+//! Every message is framed as `[kind: u8][len: u32][payload]`:
+//!
+//! - `HELLO` (follower to primary): applied seq `u64`
+//! - `SNAPSHOT_BEGIN`: seq `u64` the snapshot reflects
+//! - `SNAPSHOT_ENTRY`: `[klen: u32][key][value]`
+//! - `SNAPSHOT_END`: empty
+//! - `BATCH`: one change log batch, framed as in `changes.log`
+//! - `HEARTBEAT`: primary's latest seq `u64`
+
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, BufWriter, Error, Read, Result, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::changes::{encode_batch, read_batch};
+use crate::wal_kv::{read_field, Db, Op};
+
+type Bytes = Vec<u8>;
+
+const HELLO: u8 = 1;
+const SNAPSHOT_BEGIN: u8 = 2;
+const SNAPSHOT_ENTRY: u8 = 3;
+const SNAPSHOT_END: u8 = 4;
+const BATCH: u8 = 5;
+const HEARTBEAT: u8 = 6;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const BATCHES_PER_ROUND: usize = 256;
+
+/// Serves the change log of a [`Db`] to followers.
+pub struct Primary {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Primary {
+    /// Listens on `addr` and streams commits made through `db` to every
+    /// follower that connects. Keep writing through the same `Arc`.
+    pub fn start<A: ToSocketAddrs>(db: Arc<Mutex<Db>>, addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let accept_stop = Arc::clone(&stop);
+        let handle = thread::Builder::new()
+            .name("kv-primary".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accept_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let Ok(stream) = stream else { continue };
+                    let db = Arc::clone(&db);
+                    let stop = Arc::clone(&accept_stop);
+                    let _ = thread::Builder::new()
+                        .name("kv-primary-conn".to_string())
+                        .spawn(move || {
+                            // A failed connection is the follower's to retry.
+                            let _ = serve_follower(&db, stream, &stop);
+                        });
+                }
+            })?;
+
+        Ok(Self { addr, stop, handle: Some(handle) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting followers and ends every open stream.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the blocking accept so the listener thread sees the flag.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Primary {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn serve_follower(db: &Mutex<Db>, stream: TcpStream, stop: &AtomicBool) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let (kind, payload) = read_frame(&mut reader)?;
+    if kind != HELLO {
+        return Err(Error::new(io::ErrorKind::InvalidData, "expected HELLO from follower"));
+    }
+    let applied = read_u64(&payload)?;
+    let mut next = seq_after(applied)?;
+
+    let snapshot = {
+        let mut guard = lock(db)?;
+        let head = guard.last_seq();
+        let streamable = applied <= head
+            && (next > head || guard.oldest_change_seq().is_some_and(|first| next >= first));
+        if streamable { None } else { Some(take_snapshot(&mut guard)?) }
+    };
+    if let Some((seq, entries)) = snapshot {
+        send_snapshot(&mut writer, seq, entries)?;
+        next = seq_after(seq)?;
+    }
+
+    while !stop.load(Ordering::SeqCst) {
+        let (batches, head) = {
+            let mut guard = lock(db)?;
+            let batches = match guard.read_raw_changes(next, BATCHES_PER_ROUND) {
+                Ok(batches) => batches,
+                // Fell behind retention while streaming: start over from a snapshot.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let (seq, entries) = take_snapshot(&mut guard)?;
+                    drop(guard);
+                    send_snapshot(&mut writer, seq, entries)?;
+                    next = seq_after(seq)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            (batches, guard.last_seq())
+        };
+
+        for (seq, ops) in &batches {
+            write_frame(&mut writer, BATCH, &encode_batch(*seq, ops))?;
+            next = seq_after(*seq)?;
+        }
+        write_frame(&mut writer, HEARTBEAT, &head.to_le_bytes())?;
+        writer.flush()?;
+
+        if batches.is_empty() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// The whole keyspace and the seq it reflects. Taken under the Db lock;
+/// sending it is not, so writers aren't held up by a slow follower. The copy
+/// is held in memory until it is sent.
+fn take_snapshot(db: &mut Db) -> Result<(u64, Vec<(Bytes, Bytes)>)> {
+    Ok((db.last_seq(), db.scan_raw(..)?))
+}
+
+fn send_snapshot<W: Write>(writer: &mut W, seq: u64, entries: Vec<(Bytes, Bytes)>) -> Result<()> {
+    write_frame(writer, SNAPSHOT_BEGIN, &seq.to_le_bytes())?;
+    for (key, value) in entries {
+        let mut payload = Vec::with_capacity(4 + key.len() + value.len());
+        payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&key);
+        payload.extend_from_slice(&value);
+        write_frame(writer, SNAPSHOT_ENTRY, &payload)?;
+    }
+    write_frame(writer, SNAPSHOT_END, &[])?;
+    writer.flush()
+}
+
+/// The seq to stream after `seq`; a follower claiming the last possible one
+/// is rejected.
+fn seq_after(seq: u64) -> Result<u64> {
+    seq.checked_add(1)
+        .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, format!("no seq follows {seq}")))
+}
+
+/// Replication progress as seen by a follower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationLag {
+    /// Last seq applied locally.
+    pub applied_seq: u64,
+    /// Latest seq the primary reported.
+    pub primary_seq: u64,
+    /// How many commits the follower is behind the primary.
+    pub behind: u64,
+    /// Time since the last message from the primary, `None` if it has never been reached.
+    pub since_contact: Option<Duration>,
+    pub connected: bool,
+}
+
+#[derive(Default)]
+struct FollowerState {
+    primary_seq: u64,
+    last_contact: Option<Instant>,
+    connected: bool,
+    stream: Option<TcpStream>,
+}
+
+/// Read-only replica of a [`Primary`].
+pub struct Follower {
+    db: Arc<Mutex<Db>>,
+    state: Arc<Mutex<FollowerState>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Follower {
+    /// Opens (or creates) the replica in `dir` and starts following the
+    /// primary at `primary`, reconnecting until stopped.
+    pub fn start<P, A>(dir: P, primary: A) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        A: ToSocketAddrs,
+    {
+        let primary: Vec<SocketAddr> = primary.to_socket_addrs()?.collect();
+        let db = Arc::new(Mutex::new(Db::open(dir)?));
+        let state = Arc::new(Mutex::new(FollowerState::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let db = Arc::clone(&db);
+            let state = Arc::clone(&state);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("kv-follower".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        let _ = follow(&db, &state, &primary, &stop);
+                        if let Ok(mut s) = state.lock() {
+                            s.connected = false;
+                            s.stream = None;
+                        }
+                        if !stop.load(Ordering::SeqCst) {
+                            thread::sleep(RECONNECT_DELAY);
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self { db, state, stop, handle: Some(handle) })
+    }
+
+    pub fn get<K>(&self, key: K) -> Result<Option<Bytes>>
+    where K: AsRef<[u8]>,
+    {
+        lock(&self.db)?.get(key)
+    }
+
+    pub fn scan<R>(&self, range: R) -> Result<Vec<(Bytes, Bytes)>>
+    where R: RangeBounds<Bytes>,
+    {
+        lock(&self.db)?.scan(range)
+    }
+
+    pub fn scan_prefix<P>(&self, prefix: P) -> Result<Vec<(Bytes, Bytes)>>
+    where P: AsRef<[u8]>,
+    {
+        lock(&self.db)?.scan_prefix(prefix)
+    }
+
+    pub fn applied_seq(&self) -> Result<u64> {
+        Ok(lock(&self.db)?.last_seq())
+    }
+
+    pub fn lag(&self) -> Result<ReplicationLag> {
+        let applied_seq = self.applied_seq()?;
+        let state = lock(&self.state)?;
+        Ok(ReplicationLag {
+            applied_seq,
+            primary_seq: state.primary_seq,
+            behind: state.primary_seq.saturating_sub(applied_seq),
+            since_contact: state.last_contact.map(|t| t.elapsed()),
+            connected: state.connected,
+        })
+    }
+
+    /// Disconnects from the primary and returns the local replica.
+    pub fn stop(mut self) -> Result<Db> {
+        self.shutdown();
+        let db = Arc::clone(&self.db);
+        drop(self);
+        Arc::try_unwrap(db)
+            .map_err(|_| Error::other("replica still in use"))?
+            .into_inner()
+            .map_err(|_| Error::other("db mutex poisoned"))
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Ok(state) = self.state.lock()
+            && let Some(stream) = &state.stream
+        {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn follow(db: &Mutex<Db>, state: &Mutex<FollowerState>, primary: &[SocketAddr], stop: &AtomicBool) -> Result<()> {
+    let stream = TcpStream::connect(primary)?;
+    stream.set_nodelay(true)?;
+    {
+        let mut s = lock(state)?;
+        s.stream = Some(stream.try_clone()?);
+        s.connected = true;
+        s.last_contact = Some(Instant::now());
+    }
+    if stop.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let applied = lock(db)?.last_seq();
+    write_frame(&mut writer, HELLO, &applied.to_le_bytes())?;
+    writer.flush()?;
+
+    let mut snapshot: Option<(u64, BTreeMap<Bytes, Bytes>)> = None;
+    while !stop.load(Ordering::SeqCst) {
+        let (kind, payload) = read_frame(&mut reader)?;
+        lock(state)?.last_contact = Some(Instant::now());
+        match kind {
+            BATCH => {
+                let (seq, ops) = read_batch(&mut payload.as_slice())?
+                    .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, "empty BATCH frame"))?;
+                lock(db)?.apply_replicated(seq, ops)?;
+            }
+            HEARTBEAT => {
+                lock(state)?.primary_seq = read_u64(&payload)?;
+            }
+            SNAPSHOT_BEGIN => {
+                snapshot = Some((read_u64(&payload)?, BTreeMap::new()));
+            }
+            SNAPSHOT_ENTRY => {
+                let (_, entries) = snapshot
+                    .as_mut()
+                    .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, "snapshot entry outside snapshot"))?;
+                let (key, value) = split_entry(payload)?;
+                entries.insert(key, value);
+            }
+            SNAPSHOT_END => {
+                let (seq, entries) = snapshot
+                    .take()
+                    .ok_or_else(|| Error::new(io::ErrorKind::InvalidData, "snapshot end outside snapshot"))?;
+                lock(db)?.install_snapshot(seq, entries)?;
+                let mut s = lock(state)?;
+                s.primary_seq = s.primary_seq.max(seq);
+            }
+            other => {
+                return Err(Error::new(io::ErrorKind::InvalidData, format!("unknown frame kind: {other}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Db {
+    /// Applies a batch committed on the primary under the primary's seq.
+    pub(crate) fn apply_replicated(&mut self, seq: u64, ops: Vec<Op>) -> Result<()> {
+        if seq != self.last_seq() + 1 {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("replicated seq {seq} does not follow {}", self.last_seq()),
+            ));
+        }
+        let changes = if self.watchers.is_empty() { Vec::new() } else { self.net_changes(&ops)? };
+        self.write_batch_at(seq, ops)?;
+        self.watchers.notify(seq, &changes);
+        Ok(())
+    }
+
+    /// Replaces the whole keyspace with `entries` as of `seq`, as one batch
+    /// that deletes every other key. A replica ahead of `seq` holds commits
+    /// the primary doesn't, so it is refused rather than moved back.
+    ///
+    /// The snapshot arrives whole and goes through the WAL as a single batch,
+    /// so installing it needs memory and WAL space for a few copies of the
+    /// keyspace.
+    pub(crate) fn install_snapshot(&mut self, seq: u64, entries: BTreeMap<Bytes, Bytes>) -> Result<()> {
+        if seq < self.last_seq() {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("snapshot at seq {seq} is behind the replica's seq {}", self.last_seq()),
+            ));
+        }
+        let mut ops = Vec::new();
+        for (key, _) in self.scan_raw(..)? {
+            if !entries.contains_key(&key) {
+                ops.push(Op::Delete(key));
+            }
+        }
+        ops.extend(entries.into_iter().map(|(k, v)| Op::Set(k, v)));
+
+        let changes = if self.watchers.is_empty() { Vec::new() } else { self.net_changes(&ops)? };
+        self.write_batch_at(seq, ops)?;
+        self.watchers.notify(seq, &changes);
+        // The batch is a full replacement, so the history before it stays
+        // consistent if this is cut short; dropping it only tells readers
+        // from before the snapshot that they have to resynchronize.
+        self.changes.discard_before(seq)
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, kind: u8, payload: &[u8]) -> Result<()> {
+    writer.write_all(&[kind])?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<(u8, Bytes)> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let payload = read_field(reader, len)?;
+    Ok((header[0], payload))
+}
+
+fn read_u64(payload: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] = payload
+        .try_into()
+        .map_err(|_| Error::new(io::ErrorKind::InvalidData, "expected 8-byte seq"))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn split_entry(mut payload: Bytes) -> Result<(Bytes, Bytes)> {
+    if payload.len() < 4 {
+        return Err(Error::new(io::ErrorKind::InvalidData, "short snapshot entry"));
+    }
+    let klen = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    if payload.len() < 4 + klen {
+        return Err(Error::new(io::ErrorKind::InvalidData, "short snapshot entry"));
+    }
+    let value = payload.split_off(4 + klen);
+    payload.drain(..4);
+    Ok((payload, value))
+}
+
+fn lock<T>(m: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    m.lock().map_err(|_| Error::other("mutex poisoned"))
+}
@@ -0,0 +1,242 @@
+//! Secondary indexes maintained inside `Transaction::commit`.
+//!
+//! An index is a function from a record's `(key, value)` to zero or more index
+//! keys. Each index entry is stored as an empty-valued record in the reserved
+//! system keyspace:
+//!
+//! ```text
+//! 0xFF ++ tuple::pack(("index", name, index_key, primary_key))
+//! ```
+//!
+//! so entries for one index key are contiguous and ordered by primary key.
+//! Index updates are appended to the same WAL batch as the writes that caused
+//! them, so they become durable (or are lost) together.
+//!
+//! Each index also stores the version it was built with under
+//! `0xFF ++ tuple::pack(("index-def", name))`. Registering the same version
+//! again reuses the entries instead of rebuilding them. The first commit made
+//! while a stored index isn't registered deletes its version, since that
+//! commit's entries are missing, so the next registration rebuilds it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Error, Result};
+use std::ops::{Bound, RangeBounds};
+
+use crate::tuple::{self, Element};
+use crate::wal_kv::{Db, KeyChange, Op, SYSTEM_PREFIX};
+
+type Bytes = Vec<u8>;
+
+/// Computes the index keys for one record.
+pub type IndexFn = Box<dyn Fn(&[u8], &[u8]) -> Vec<Bytes> + Send>;
+
+/// A record found through a secondary index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub index_key: Bytes,
+    pub key: Bytes,
+    pub value: Bytes,
+}
+
+/// Index definitions registered on a [`Db`]. Definitions are code, so only
+/// their versions are persisted; they must be registered again after every
+/// open.
+#[derive(Default)]
+pub(crate) struct SecondaryIndexes {
+    defs: BTreeMap<String, IndexFn>,
+    /// Stored indexes not registered since the Db was opened.
+    unmaintained: BTreeSet<String>,
+}
+
+impl SecondaryIndexes {
+    fn entries(&self, name: &str, key: &[u8], value: Option<&Bytes>) -> Vec<Bytes> {
+        match (self.defs.get(name), value) {
+            (Some(f), Some(value)) => f(key, value)
+                .into_iter()
+                .map(|index_key| entry_key(name, &index_key, key))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Db {
+    /// Registers a secondary index. If the Db holds entries built with the
+    /// same `version` and kept up to date since, they are used as they are;
+    /// otherwise the index is rebuilt from the records already in the Db.
+    /// Change `version` whenever `index_fn` changes.
+    pub fn register_index<F>(&mut self, name: &str, version: u64, index_fn: F) -> Result<()>
+    where
+        F: Fn(&[u8], &[u8]) -> Vec<Bytes> + Send + 'static,
+    {
+        if self.secondary.defs.contains_key(name) {
+            return Err(Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("index already registered: {name}"),
+            ));
+        }
+
+        let stored = self.get(definition_key(name))?;
+        if stored.as_deref() != Some(version.to_be_bytes().as_slice()) {
+            let mut ops = Vec::new();
+            for (entry, _) in self.scan_raw(index_range(name))? {
+                ops.push(Op::Delete(entry));
+            }
+            for (key, value) in self.scan(..)? {
+                for index_key in index_fn(&key, &value) {
+                    ops.push(Op::Set(entry_key(name, &index_key, &key), Vec::new()));
+                }
+            }
+            ops.push(Op::Set(definition_key(name), version.to_be_bytes().to_vec()));
+            self.write_batch(ops)?;
+        }
+
+        self.secondary.unmaintained.remove(name);
+        self.secondary.defs.insert(name.to_string(), Box::new(index_fn));
+        Ok(())
+    }
+
+    /// Records whose index `name` produced exactly `index_key`, in primary key order.
+    pub fn index_get<K>(&mut self, name: &str, index_key: K) -> Result<Vec<IndexEntry>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let index_key = index_key.as_ref().to_vec();
+        self.index_scan(name, index_key.clone()..=index_key)
+    }
+
+    /// Records whose index keys fall in `range`, ordered by index key then primary key.
+    pub fn index_scan<R>(&mut self, name: &str, range: R) -> Result<Vec<IndexEntry>>
+    where
+        R: RangeBounds<Bytes>,
+    {
+        if !self.secondary.defs.contains_key(name) {
+            return Err(Error::new(io::ErrorKind::NotFound, format!("no such index: {name}")));
+        }
+
+        let (index_start, index_end) = index_range(name);
+        // Every entry for index key `k` lies strictly between
+        // `prefix(k) ++ 0x00` and `prefix(k) ++ 0xFF`.
+        let start = match range.start_bound() {
+            Bound::Included(k) => Bound::Included(index_key_prefix(name, k)),
+            Bound::Excluded(k) => Bound::Excluded(with_suffix(index_key_prefix(name, k), 0xFF)),
+            Bound::Unbounded => index_start,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => Bound::Excluded(with_suffix(index_key_prefix(name, k), 0xFF)),
+            Bound::Excluded(k) => Bound::Excluded(index_key_prefix(name, k)),
+            Bound::Unbounded => index_end,
+        };
+
+        let mut out = Vec::new();
+        for (entry, _) in self.scan_raw((start, end))? {
+            let (index_key, key) = parse_entry_key(&entry)?;
+            // Entries are written in the same batch as their records, so a
+            // missing record means the entry is stale; skip it.
+            if let Some(value) = self.get(&key)? {
+                out.push(IndexEntry { index_key, key, value });
+            }
+        }
+        Ok(out)
+    }
+
+    /// Notes which indexes the Db holds, so that commits made before they are
+    /// registered invalidate them.
+    pub(crate) fn load_index_definitions(&mut self) -> Result<()> {
+        let (start, end) = definition_range();
+        let mut names = BTreeSet::new();
+        for (key, _) in self.scan_raw((start, end))? {
+            match tuple::unpack(&key[1..])?.as_slice() {
+                [_, Element::String(name)] => names.insert(name.clone()),
+                _ => return Err(Error::new(io::ErrorKind::InvalidData, "malformed index definition")),
+            };
+        }
+        self.secondary.unmaintained = names;
+        Ok(())
+    }
+
+    /// Adds the index entry deletes and inserts implied by `changes` to the
+    /// batch, and the deletes of stored versions for indexes that aren't
+    /// registered.
+    pub(crate) fn with_index_ops(&self, mut ops: Vec<Op>, changes: &[KeyChange]) -> Vec<Op> {
+        for name in &self.secondary.unmaintained {
+            ops.push(Op::Delete(definition_key(name)));
+        }
+        for change in changes {
+            for name in self.secondary.defs.keys() {
+                let old_entries = self.secondary.entries(name, &change.key, change.old.as_ref());
+                let new_entries = self.secondary.entries(name, &change.key, change.new.as_ref());
+                for entry in &old_entries {
+                    if !new_entries.contains(entry) {
+                        ops.push(Op::Delete(entry.clone()));
+                    }
+                }
+                for entry in new_entries {
+                    if !old_entries.contains(&entry) {
+                        ops.push(Op::Set(entry, Vec::new()));
+                    }
+                }
+            }
+        }
+        ops
+    }
+
+    /// Called once a batch from `with_index_ops` is committed.
+    pub(crate) fn index_definitions_committed(&mut self) {
+        self.secondary.unmaintained.clear();
+    }
+
+    pub(crate) fn has_secondary_indexes(&self) -> bool {
+        !self.secondary.defs.is_empty()
+    }
+}
+
+pub(crate) fn is_index_definition(key: &[u8]) -> bool {
+    definition_range().contains(&key.to_vec())
+}
+
+fn definition_key(name: &str) -> Bytes {
+    system_key(("index-def", name))
+}
+
+fn definition_range() -> (Bound<Bytes>, Bound<Bytes>) {
+    let prefix = system_key(("index-def",));
+    (Bound::Included(with_suffix(prefix.clone(), 0x00)), Bound::Excluded(with_suffix(prefix, 0xFF)))
+}
+
+fn entry_key(name: &str, index_key: &[u8], key: &[u8]) -> Bytes {
+    system_key(("index", name, index_key, key))
+}
+
+fn index_key_prefix(name: &str, index_key: &[u8]) -> Bytes {
+    system_key(("index", name, index_key))
+}
+
+fn index_range(name: &str) -> (Bound<Bytes>, Bound<Bytes>) {
+    let prefix = system_key(("index", name));
+    (
+        Bound::Included(with_suffix(prefix.clone(), 0x00)),
+        Bound::Excluded(with_suffix(prefix, 0xFF)),
+    )
+}
+
+fn system_key<T>(t: T) -> Bytes
+where
+    T: tuple::IntoTuple,
+{
+    let mut key = vec![SYSTEM_PREFIX];
+    key.extend(tuple::pack(t));
+    key
+}
+
+fn with_suffix(mut key: Bytes, byte: u8) -> Bytes {
+    key.push(byte);
+    key
+}
+
+fn parse_entry_key(entry: &[u8]) -> Result<(Bytes, Bytes)> {
+    match tuple::unpack(&entry[1..])?.as_slice() {
+        [_, _, Element::Bytes(index_key), Element::Bytes(key)] => Ok((index_key.clone(), key.clone())),
+        _ => Err(Error::new(io::ErrorKind::InvalidData, "malformed index entry")),
+    }
+}
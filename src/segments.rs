@@ -0,0 +1,207 @@
+//! Segmented data log.
+//!
+//! The data log is split into segment files. Commits append to the active
+//! segment, the last one; once it has grown to the segment size, the next
+//! commit first starts a new one, and the full segment is sealed and never
+//! written again. Index entries point at a segment and an offset in it.
+//!
+//! The manifest lists the live segments, oldest first, and is replaced
+//! atomically whenever the list changes, so a crash leaves either the old
+//! list or the new one. Segment 0 is `data.log`: what Dbs written before
+//! segmentation hold, and what backup restores write. Later segments are
+//! `data-<id>.log`.
+//!
+//! Because records in later segments override earlier ones, sealed segments
+//! are compacted oldest first: tombstones in the oldest segments shadow
+//! nothing older, so they can be dropped along with overwritten records.
+
+use std::collections::BTreeMap;
+use std::io::{BufWriter, Result, Write};
+use std::path::{Path, PathBuf};
+
+use crate::format::{check_file_header, write_header, FileKind, HEADER_LEN};
+use crate::hints::Hint;
+use crate::vfs::{VfsFile, VfsWriter};
+use crate::wal_kv::{write_record, Db, OP_PUT};
+
+type Bytes = Vec<u8>;
+
+/// Size at which the active segment is sealed, unless changed with
+/// [`Db::set_segment_size`].
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where a record starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RecordPos {
+    pub(crate) segment: u64,
+    pub(crate) offset: u64,
+}
+
+/// A sealed segment and its length.
+pub(crate) struct Segment {
+    pub(crate) id: u64,
+    pub(crate) file: Box<dyn VfsFile>,
+    pub(crate) len: u64,
+}
+
+pub(crate) fn segment_file_name(id: u64) -> String {
+    if id == 0 {
+        Db::DATA_FILE.to_string()
+    } else {
+        format!("data-{id:06}.log")
+    }
+}
+
+impl Db {
+    /// Seals the active data log segment and starts a new one once it
+    /// reaches `bytes`. Defaults to [`DEFAULT_SEGMENT_SIZE`]; the setting is
+    /// kept in the manifest, so it survives a reopen.
+    pub fn set_segment_size(&mut self, bytes: u64) -> Result<()> {
+        self.segment_size = bytes.max(1);
+        self.write_manifest(&self.segment_ids())
+    }
+
+    /// Number of data log segments, the active one included.
+    pub fn segment_count(&self) -> usize {
+        self.sealed.len() + 1
+    }
+
+    pub(crate) fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir().join(segment_file_name(id))
+    }
+
+    /// Every live segment id, oldest first, ending with the active one.
+    pub(crate) fn segment_ids(&self) -> Vec<u64> {
+        self.sealed.iter().map(|s| s.id).chain([self.active_segment]).collect()
+    }
+
+    fn next_segment_id(&self) -> u64 {
+        self.segment_ids().into_iter().max().unwrap_or(0) + 1
+    }
+
+    /// Seals the active segment, writing its hint file, and makes a new,
+    /// empty one active.
+    pub(crate) fn roll_over(&mut self) -> Result<()> {
+        self.data_writer.flush()?;
+        let id = self.next_segment_id();
+        let mut file = self.vfs.create(&self.segment_path(id))?;
+        write_header(file.as_mut(), FileKind::DataLog)?;
+        file.sync()?;
+        self.remove_hints(id)?;
+        let mut ids = self.segment_ids();
+        ids.push(id);
+        self.write_manifest(&ids)?;
+
+        let sealed = self.replace_active(id, file)?;
+        self.sealed.push(sealed);
+        let sealed = self.sealed.last().expect("just sealed");
+        self.write_hints_from(sealed.id, sealed.file.as_ref(), sealed.len)
+    }
+
+    /// Compacts the oldest `count` sealed segments: their live records are
+    /// rewritten, in key order, into new segments, with hint files, that
+    /// take their place.
+    pub fn compact_segments(&mut self, count: usize) -> Result<()> {
+        let count = count.min(self.sealed.len());
+        if count == 0 {
+            return Ok(());
+        }
+        let old: Vec<u64> = self.sealed[..count].iter().map(|s| s.id).collect();
+        let live: Vec<(Bytes, RecordPos)> =
+            self.live_records().into_iter().filter(|(_, pos)| old.contains(&pos.segment)).collect();
+
+        let mut next_id = self.next_segment_id();
+        let mut outputs = Vec::new();
+        let mut moved = Vec::with_capacity(live.len());
+        let mut current: Option<(u64, BufWriter<VfsWriter>, u64, Vec<Hint>)> = None;
+        for (key, pos) in live {
+            let value = self.read_value(pos)?;
+            if current.as_ref().is_none_or(|&(_, _, len, _)| len >= self.segment_size) {
+                if let Some((id, writer, _, hints)) = current.take() {
+                    outputs.push(self.finish_segment(id, writer, &hints)?);
+                }
+                let mut file = self.vfs.create(&self.segment_path(next_id))?;
+                write_header(file.as_mut(), FileKind::DataLog)?;
+                current = Some((next_id, BufWriter::new(VfsWriter(file)), HEADER_LEN, Vec::new()));
+                next_id += 1;
+            }
+            let (id, writer, len, hints) = current.as_mut().expect("a segment is open");
+            write_record(writer, OP_PUT, &key, &value)?;
+            let record_len = 9 + key.len() as u64 + value.len() as u64;
+            moved.push((key.clone(), RecordPos { segment: *id, offset: *len }));
+            hints.push(Hint { op: OP_PUT, key, value_len: value.len() as u64, offset: *len });
+            *len += record_len;
+        }
+        if let Some((id, writer, _, hints)) = current.take() {
+            outputs.push(self.finish_segment(id, writer, &hints)?);
+        }
+
+        let ids: Vec<u64> =
+            outputs.iter().map(|&(id, _)| id).chain(self.segment_ids().into_iter().skip(count)).collect();
+        self.write_manifest(&ids)?;
+
+        let mut replacements = Vec::with_capacity(outputs.len());
+        for (id, len) in outputs {
+            replacements.push(Segment { id, file: self.vfs.open(&self.segment_path(id))?, len });
+        }
+        self.sealed.splice(..count, replacements);
+        for (key, pos) in moved {
+            self.index.insert(key, pos);
+        }
+        for id in old {
+            self.vfs.remove_file(&self.segment_path(id))?;
+            self.remove_hints(id)?;
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces every segment with the data log at `replacement`
+    /// and rebuilds the index from it. Only call between commits, when the
+    /// WAL is empty.
+    pub(crate) fn replace_data_file(&mut self, replacement: &Path) -> Result<()> {
+        self.data_writer.flush()?;
+        let id = self.next_segment_id();
+        let path = self.segment_path(id);
+        self.vfs.rename(replacement, &path)?;
+        self.remove_hints(id)?;
+        self.write_manifest(&[id])?;
+
+        let old = self.segment_ids();
+        let file = self.vfs.open(&path)?;
+        check_file_header(file.as_ref(), FileKind::DataLog, &path)?;
+        let mut index = BTreeMap::new();
+        Db::index_records(file.as_ref(), id, HEADER_LEN, &mut index)?;
+        self.index = index;
+        self.replace_active(id, file)?;
+        self.sealed.clear();
+        for id in old {
+            self.vfs.remove_file(&self.segment_path(id))?;
+            self.remove_hints(id)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `file`, segment `id`, the active segment and returns the one it
+    /// replaces.
+    fn replace_active(&mut self, id: u64, file: Box<dyn VfsFile>) -> Result<Segment> {
+        self.data_writer.flush()?;
+        let len = file.len()?;
+        let old = std::mem::replace(&mut self.data_writer, BufWriter::new(VfsWriter(file)));
+        let old = old.into_inner().map_err(|e| e.into_error())?;
+        Ok(Segment {
+            id: std::mem::replace(&mut self.active_segment, id),
+            file: old.0,
+            len: std::mem::replace(&mut self.data_writer_pos, len),
+        })
+    }
+
+    /// Syncs a finished compaction output and writes its hint file; returns
+    /// its id and length.
+    fn finish_segment(&self, id: u64, writer: BufWriter<VfsWriter>, hints: &[Hint]) -> Result<(u64, u64)> {
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.file_mut().sync()?;
+        let len = file.file().len()?;
+        self.write_hints(id, len, hints)?;
+        Ok((id, len))
+    }
+}
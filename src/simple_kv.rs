@@ -1,6 +1,11 @@
 use std::fs::{File, OpenOptions};
 use std::io::{self, BufWriter, BufReader, Write, Seek, Read, SeekFrom};
 use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+use crate::format::{check_header, header, FileKind, HEADER_LEN};
+use crate::wal_kv::{is_empty_range, read_field};
 
 pub struct KvStore {
     reader: BufReader<File>,
@@ -12,25 +17,43 @@ pub struct KvStore {
 impl KvStore {
     const PATH: &'static str = "./data.log";
 
-    const OP_PUT: u8 = 0;
-    const OP_DELETE: u8 = 1;
+    pub(crate) const OP_PUT: u8 = 0;
+    pub(crate) const OP_DELETE: u8 = 1;
 
     pub fn new() -> io::Result<Self> {
+        Self::open(Self::PATH)
+    }
+
+    /// Opens the store whose log is the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
         let mut rfile = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(Self::PATH)?;
+            .truncate(false)
+            .open(path)?;
+
+        let mut start = Vec::new();
+        (&mut rfile).take(HEADER_LEN).read_to_end(&mut start)?;
+        if start.len() < HEADER_LEN as usize && header(FileKind::KvStore).starts_with(&start) {
+            // A new log, or one whose header a crash cut short.
+            rfile.set_len(0)?;
+            rfile.seek(SeekFrom::Start(0))?;
+            rfile.write_all(&header(FileKind::KvStore))?;
+            rfile.sync_all()?;
+        } else {
+            check_header(&start, FileKind::KvStore, path)?;
+        }
 
         let (index, writer_pos) = Self::build_index(&mut rfile)?;
 
         rfile.seek(SeekFrom::Start(0))?;
         
         let wfile = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
-            .open(Self::PATH)?;
+            .open(path)?;
         
         let reader = BufReader::new(rfile);
         let writer = BufWriter::new(wfile);
@@ -38,9 +61,11 @@ impl KvStore {
         Ok(Self { reader, writer, index, writer_pos })
     }
 
+    /// Indexes the records after the header; `file` must be positioned
+    /// just past it.
     fn build_index(file: &mut File) -> io::Result<(BTreeMap<String, u64>, u64)> {
         let mut index = BTreeMap::new();
-        let mut offset: u64 = 0;
+        let mut offset: u64 = HEADER_LEN;
 
         loop {
             let entry_start = offset;
@@ -62,26 +87,35 @@ impl KvStore {
             let val_len = u32::from_le_bytes(len_buf) as u64;
             offset += 4;
 
-            let mut key_buf = vec![0u8; key_len as usize];
-            file.read_exact(&mut key_buf)?;
+            let key_buf = read_field(file, key_len as usize)?;
             offset += key_len;
 
-            let mut val_buf = vec![0u8; val_len as usize];
-            if val_len > 0 {
-                file.read_exact(&mut val_buf)?;
+            if io::copy(&mut file.take(val_len), &mut io::sink())? != val_len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record extends past the end of data.log"));
             }
             offset += val_len;
 
-            let key = String::from_utf8(key_buf).unwrap();
+            // Db's data log uses opcodes 1 (put, with a value) and 2; refuse
+            // it rather than misreading its puts as deletes.
+            let known = op == Self::OP_PUT || (op == Self::OP_DELETE && val_len == 0);
+            if !known {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected record (opcode {op}) at offset {entry_start}; data.log may have been written by Db"),
+                ));
+            }
+
+            let key = String::from_utf8(key_buf).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("key at offset {entry_start} is not UTF-8"))
+            })?;
 
             match op {
                 Self::OP_PUT => {
                     index.insert(key, entry_start);
                 },
-                Self::OP_DELETE => {
+                _ => {
                     index.remove(&key);
                 },
-                _ => {}
             }
         }
 
@@ -152,14 +186,66 @@ impl KvStore {
         self.reader.read_exact(&mut len_buf)?;
         let val_len = u32::from_le_bytes(len_buf) as usize;
 
-        let mut key_buf = vec![0u8; key_len];
-        let mut val_buf = vec![0u8; val_len];
-
-        self.reader.read_exact(&mut key_buf)?;
-        self.reader.read_exact(&mut val_buf)?;
+        self.reader.seek_relative(key_len as i64)?;
+        let val_buf = read_field(&mut self.reader, val_len)?;
 
-        let val = String::from_utf8(val_buf).unwrap();
+        let val = String::from_utf8(val_buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "value is not UTF-8"))?;
 
         Ok(Some(val))
     }
-}
\ No newline at end of file
+
+    /// Live records with keys in `range`, in key order.
+    pub fn scan<R>(&mut self, range: R) -> io::Result<Vec<(String, String)>>
+    where R: RangeBounds<String>,
+    {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let keys: Vec<String> = self.index.range(range).map(|(k, _)| k.clone()).collect();
+        self.read_keys(keys)
+    }
+
+    /// Live keys whose bytes fall in `range`, which need not be valid UTF-8.
+    pub(crate) fn keys_in(&self, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Vec<String> {
+        // The longest valid prefix of the start bound sorts at or before it.
+        let start = match &range.0 {
+            Bound::Included(b) | Bound::Excluded(b) => match std::str::from_utf8(b) {
+                Ok(s) => s,
+                Err(e) => std::str::from_utf8(&b[..e.valid_up_to()]).expect("valid prefix"),
+            },
+            Bound::Unbounded => "",
+        };
+        self.index
+            .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+            .map(|(k, _)| k)
+            .skip_while(|k| match &range.0 {
+                Bound::Included(b) => k.as_bytes() < b.as_slice(),
+                Bound::Excluded(b) => k.as_bytes() <= b.as_slice(),
+                Bound::Unbounded => false,
+            })
+            .take_while(|k| match &range.1 {
+                Bound::Included(b) => k.as_bytes() <= b.as_slice(),
+                Bound::Excluded(b) => k.as_bytes() < b.as_slice(),
+                Bound::Unbounded => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn read_keys(&mut self, keys: Vec<String>) -> io::Result<Vec<(String, String)>> {
+        let mut out = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(&key)? {
+                out.push((key, value));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Syncs every write so far to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
@@ -0,0 +1,416 @@
+//! Deterministic simulation of a whole Db deployment.
+//!
+//! [`simulate`] runs several clients with interleaved transactions, plus
+//! compaction, continuous backup, clock jumps, power failures and restarts,
+//! against one Db. Everything that could vary between runs comes from the
+//! seed: the schedule of steps is drawn from one random generator, the disk
+//! is a [`FaultFs`] whose crashes tear unsynced writes with seeded
+//! randomness, time is a [`SimClock`], and the backup replicator's
+//! background rounds run inline at scheduled steps instead of on a thread.
+//! A seed therefore replays bit for bit, and a failure seen in CI can be
+//! rerun locally from the seed in its message.
+//!
+//! The Db is checked against a model throughout: every read, every recovery
+//! (which must land on the acknowledged commits, plus the one in flight
+//! either entirely or not at all), and every restore from the backup store
+//! (which must reproduce some acknowledged commit).
+
+use std::collections::BTreeMap;
+use std::io::{self, Error, Result};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::clock::SimClock;
+use crate::continuous_backup::{restore_with_vfs, BackupOptions, Shipper};
+use crate::object_store::{MemoryStore, ObjectStore};
+use crate::vfs::{next_random, FaultFs, MemFs};
+use crate::wal_kv::Db;
+
+type Bytes = Vec<u8>;
+type Model = BTreeMap<Bytes, Bytes>;
+type Ops = Vec<(Bytes, Option<Bytes>)>;
+
+const DIR: &str = "db";
+const START_TIME: Duration = Duration::from_secs(1_700_000_000);
+
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// Clients with a transaction open at the same time.
+    pub clients: usize,
+    pub steps: usize,
+    /// Size of the keyspace; smaller means more overwrites.
+    pub keys: u64,
+    /// Data log segment size; small sizes roll over every few commits.
+    pub segment_size: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self { clients: 4, steps: 400, keys: 16, segment_size: 256 }
+    }
+}
+
+/// What a run did. Two runs of the same seed and config produce equal
+/// reports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimReport {
+    pub seed: u64,
+    pub commits: u64,
+    pub crashes: u64,
+    pub restores: u64,
+    /// One line per step.
+    pub trace: Vec<String>,
+}
+
+impl SimReport {
+    /// FNV-1a hash of the trace, for comparing runs at a glance.
+    pub fn digest(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for line in &self.trace {
+            for &byte in line.as_bytes().iter().chain(b"\n") {
+                hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+}
+
+/// Runs the simulation for `seed`. Fails with a message naming the seed and
+/// step if the Db ever disagrees with the model or fails without a fault
+/// having been injected.
+pub fn simulate(seed: u64, config: &SimConfig) -> Result<SimReport> {
+    let mut sim = Sim::new(seed, *config);
+    sim.restart()?;
+    // Kept in the manifest across the restarts that follow.
+    sim.db().set_segment_size(config.segment_size)?;
+    for step in 0..config.steps {
+        sim.step = step;
+        sim.step()?;
+    }
+    sim.step = config.steps;
+    sim.crash()?;
+    sim.check_restore()?;
+    Ok(sim.report)
+}
+
+/// Why a step stopped: the Db returned an error, or disagreed with the model.
+enum Failure {
+    Db(Error),
+    Mismatch(String),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        Failure::Db(e)
+    }
+}
+
+/// A running Db and its backup replicator.
+struct Node {
+    db: Arc<Mutex<Db>>,
+    shipper: Shipper,
+}
+
+struct Sim {
+    config: SimConfig,
+    step: usize,
+    rng: u64,
+    fs: FaultFs,
+    clock: SimClock,
+    store: Arc<dyn ObjectStore>,
+    node: Option<Node>,
+    /// State after each commit; `history[seq]`.
+    history: Vec<Model>,
+    /// State if the commit that failed takes effect on recovery.
+    in_flight: Option<Model>,
+    /// Writes buffered by each client's open transaction.
+    clients: Vec<Option<Ops>>,
+    /// Whether the power is set to fail, making Db errors expected.
+    power_cut: bool,
+    report: SimReport,
+}
+
+impl Sim {
+    fn new(seed: u64, config: SimConfig) -> Self {
+        Self {
+            config,
+            step: 0,
+            rng: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            fs: FaultFs::new(),
+            clock: SimClock::new(UNIX_EPOCH + START_TIME),
+            store: Arc::new(MemoryStore::new()),
+            node: None,
+            history: vec![Model::new()],
+            in_flight: None,
+            clients: vec![None; config.clients.max(1)],
+            power_cut: false,
+            report: SimReport { seed, ..SimReport::default() },
+        }
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        next_random(&mut self.rng) % n
+    }
+
+    fn trace(&mut self, line: String) {
+        self.report.trace.push(format!("{} {line}", self.step));
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::other(format!("simulation seed {} step {}: {message}", self.report.seed, self.step))
+    }
+
+    fn acked(&self) -> &Model {
+        &self.history[self.history.len() - 1]
+    }
+
+    fn acked_seq(&self) -> u64 {
+        self.history.len() as u64 - 1
+    }
+
+    fn db(&self) -> MutexGuard<'_, Db> {
+        let node = self.node.as_ref().expect("the Db is open between steps");
+        node.db.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn step(&mut self) -> Result<()> {
+        let result = match self.below(100) {
+            0..60 => {
+                let client = self.below(self.clients.len() as u64) as usize;
+                self.client_step(client)
+            }
+            60..68 => self.compact(),
+            68..80 => self.backup_round(),
+            80..88 => {
+                let by = Duration::from_secs(self.below(3600));
+                self.clock.advance(by);
+                self.trace(format!("clock +{}s", by.as_secs()));
+                Ok(())
+            }
+            88..92 => return self.check_restore(),
+            92..97 => {
+                if !self.power_cut {
+                    let after = self.below(40);
+                    self.fs.lose_power_after(Some(after));
+                    self.power_cut = true;
+                    self.trace(format!("power fails after {after} operations"));
+                }
+                Ok(())
+            }
+            _ => return self.crash(),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(Failure::Db(e)) if self.power_cut => {
+                self.trace(format!("db error: {e}"));
+                self.crash()
+            }
+            Err(Failure::Db(e)) => Err(self.error(&format!("unexpected error: {e}"))),
+            Err(Failure::Mismatch(message)) => Err(self.error(&message)),
+        }
+    }
+
+    fn client_step(&mut self, client: usize) -> std::result::Result<(), Failure> {
+        let Some(mut ops) = self.clients[client].take() else {
+            self.clients[client] = Some(Ops::new());
+            self.trace(format!("c{client} begin"));
+            return Ok(());
+        };
+        let key = format!("k{}", self.below(self.config.keys)).into_bytes();
+        match self.below(10) {
+            0..4 => {
+                let value = format!("v{}", self.step).into_bytes();
+                self.trace(format!("c{client} set {}", String::from_utf8_lossy(&key)));
+                ops.push((key, Some(value)));
+            }
+            4 => {
+                self.trace(format!("c{client} delete {}", String::from_utf8_lossy(&key)));
+                ops.push((key, None));
+            }
+            5 => {
+                // Transactions apply to whatever is committed by then, so
+                // the expected view is the latest state plus own writes.
+                let expected = apply(self.acked(), &ops).get(&key).cloned();
+                let found = {
+                    let mut db = self.db();
+                    let mut tx = db.begin_transaction();
+                    stage(&mut tx, &ops);
+                    tx.get(&key)?
+                };
+                self.trace(format!("c{client} get {} = {:?}", String::from_utf8_lossy(&key), found.is_some()));
+                if found != expected {
+                    return Err(Failure::Mismatch(format!(
+                        "c{client} read {key:?} as {found:?}, expected {expected:?}"
+                    )));
+                }
+            }
+            6 => {
+                let found: Model = self.db().scan(..)?.into_iter().collect();
+                self.trace(format!("c{client} scan {} keys", found.len()));
+                if &found != self.acked() {
+                    return Err(Failure::Mismatch(format!("scan returned {found:?}, expected {:?}", self.acked())));
+                }
+            }
+            7 | 8 => {
+                let after = apply(self.acked(), &ops);
+                self.in_flight = Some(after);
+                let committed = {
+                    let mut db = self.db();
+                    let mut tx = db.begin_transaction();
+                    stage(&mut tx, &ops);
+                    tx.commit()
+                };
+                committed?;
+                let after = self.in_flight.take().unwrap_or_default();
+                self.history.push(after);
+                self.report.commits += 1;
+                self.trace(format!("c{client} commit {} ops, seq {}", ops.len(), self.acked_seq()));
+                return Ok(());
+            }
+            _ => {
+                self.trace(format!("c{client} abort"));
+                return Ok(());
+            }
+        }
+        self.clients[client] = Some(ops);
+        Ok(())
+    }
+
+    fn compact(&mut self) -> std::result::Result<(), Failure> {
+        if self.below(2) == 0 {
+            self.trace("compact".to_string());
+            self.db().compact()?;
+        } else {
+            let count = 1 + self.below(3) as usize;
+            self.trace(format!("compact {count} segments"));
+            self.db().compact_segments(count)?;
+        }
+        Ok(())
+    }
+
+    /// One round of the replicator's background work.
+    fn backup_round(&mut self) -> std::result::Result<(), Failure> {
+        let node = self.node.as_mut().expect("the Db is open between steps");
+        node.shipper.round();
+        let status = node.shipper.status();
+        self.trace(format!("backup uploaded {} snapshot {:?}", status.uploaded_seq, status.latest_snapshot_seq));
+        if let Some(e) = status.last_error {
+            return Err(Failure::Db(Error::other(e)));
+        }
+        if status.uploaded_seq != self.acked_seq() {
+            return Err(Failure::Mismatch(format!(
+                "backup round uploaded up to seq {}, but {} commits were acknowledged",
+                status.uploaded_seq,
+                self.acked_seq()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Cuts the power, optionally again during recovery, then restarts.
+    fn crash(&mut self) -> Result<()> {
+        self.node = None;
+        self.clients.iter_mut().for_each(|client| *client = None);
+        self.power_cut = false;
+        self.report.crashes += 1;
+
+        let torn = self.below(2) == 0;
+        self.crash_fs(torn);
+        if self.below(4) == 0 {
+            let after = self.below(8);
+            self.trace(format!("crash torn={torn}, again after {after} operations of recovery"));
+            self.fs.lose_power_after(Some(after));
+            drop(Db::open_with_vfs(Arc::new(self.fs.clone()), DIR));
+            self.crash_fs(torn);
+        } else {
+            self.trace(format!("crash torn={torn}"));
+        }
+        self.restart()
+    }
+
+    fn crash_fs(&mut self, torn: bool) {
+        if torn {
+            let seed = next_random(&mut self.rng);
+            self.fs.crash_torn(seed);
+        } else {
+            self.fs.crash();
+        }
+    }
+
+    /// Opens the Db and checks it recovered a state the clients could have
+    /// seen acknowledged.
+    fn restart(&mut self) -> Result<()> {
+        let mut db = Db::open_with_vfs(Arc::new(self.fs.clone()), DIR)
+            .map_err(|e| self.error(&format!("recovery failed: {e}")))?;
+        db.set_clock(Arc::new(self.clock.clone()));
+
+        let state: Model = db.scan(..)?.into_iter().collect();
+        let seq = db.last_seq();
+        let in_flight = self.in_flight.take();
+        if seq == self.acked_seq() && &state == self.acked() {
+            self.trace(format!("recovered seq {seq}"));
+        } else if seq == self.acked_seq() + 1 && in_flight.as_ref() == Some(&state) {
+            self.history.push(state);
+            self.trace(format!("recovered seq {seq} with the commit in flight"));
+        } else {
+            return Err(self.error(&format!(
+                "recovered {state:?} at seq {seq}; acknowledged {:?} at seq {}, in flight {in_flight:?}",
+                self.acked(),
+                self.acked_seq()
+            )));
+        }
+
+        let seqs: Vec<u64> = db.read_raw_changes(1, usize::MAX)?.into_iter().map(|(seq, _)| seq).collect();
+        if !seqs.iter().copied().eq(1..=seq) {
+            return Err(self.error(&format!("change log holds seqs {seqs:?} after recovering seq {seq}")));
+        }
+
+        let db = Arc::new(Mutex::new(db));
+        let options = BackupOptions { snapshot_interval: Duration::from_secs(2 * 3600), ..BackupOptions::default() };
+        let shipper = Shipper::new(Arc::clone(&db), Arc::clone(&self.store), options)?;
+        self.node = Some(Node { db, shipper });
+        Ok(())
+    }
+
+    /// Restores the backup into a scratch filesystem and checks it matches
+    /// an acknowledged commit.
+    fn check_restore(&mut self) -> Result<()> {
+        let mut db = match restore_with_vfs(self.store.as_ref(), Arc::new(MemFs::new()), Path::new("restore")) {
+            Ok(db) => db,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.trace("restore: nothing uploaded yet".to_string());
+                return Ok(());
+            }
+            Err(e) => return Err(self.error(&format!("restore failed: {e}"))),
+        };
+        let seq = db.last_seq();
+        let state: Model = db.scan(..)?.into_iter().collect();
+        self.report.restores += 1;
+        self.trace(format!("restored seq {seq}"));
+        if self.history.get(seq as usize) != Some(&state) {
+            return Err(self.error(&format!("restore at seq {seq} produced {state:?}")));
+        }
+        Ok(())
+    }
+}
+
+fn apply(model: &Model, ops: &[(Bytes, Option<Bytes>)]) -> Model {
+    let mut model = model.clone();
+    for (key, value) in ops {
+        match value {
+            Some(value) => model.insert(key.clone(), value.clone()),
+            None => model.remove(key),
+        };
+    }
+    model
+}
+
+fn stage(tx: &mut crate::wal_kv::Transaction<'_>, ops: &[(Bytes, Option<Bytes>)]) {
+    for (key, value) in ops {
+        match value {
+            Some(value) => tx.set(key, value),
+            None => tx.delete(key),
+        }
+    }
+}
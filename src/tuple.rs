@@ -0,0 +1,325 @@
+//! Tuple layer: packs heterogeneous tuples into keys whose byte order matches
+//! tuple order, in the style of FoundationDB's tuple encoding.
+//!
+//! Each element starts with a type code, so elements of different types sort
+//! by type first (null, bytes, string, nested tuple, integer, bool), matching
+//! the derived `Ord` on [`Element`].
+//!
+//! - null: `0x00` (`0x00 0xFF` inside a nested tuple)
+//! - bytes / string: `0x01` / `0x02`, payload with `0x00` escaped as `0x00 0xFF`, then `0x00`
+//! - nested tuple: `0x05`, the packed elements, then `0x00`
+//! - integer: `0x14` for zero; `0x14 + n` followed by the `n`-byte big-endian
+//!   magnitude for positives, `0x14 - n` followed by its one's complement for negatives
+//! - bool: `0x26` for false, `0x27` for true
+
+use std::io::{self, Error, Result};
+use std::ops::Bound;
+
+type Bytes = Vec<u8>;
+
+const NULL: u8 = 0x00;
+const BYTES: u8 = 0x01;
+const STRING: u8 = 0x02;
+const NESTED: u8 = 0x05;
+const INT_ZERO: u8 = 0x14;
+const FALSE: u8 = 0x26;
+const TRUE: u8 = 0x27;
+const ESCAPE: u8 = 0xFF;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Element {
+    Null,
+    Bytes(Bytes),
+    String(String),
+    Tuple(Vec<Element>),
+    Int(i64),
+    Bool(bool),
+}
+
+/// Packs a tuple into a key.
+pub fn pack<T>(tuple: T) -> Bytes
+where
+    T: IntoTuple,
+{
+    let mut out = Vec::new();
+    for element in &tuple.into_tuple() {
+        encode(element, &mut out, false);
+    }
+    out
+}
+
+/// Unpacks a key written by [`pack`].
+pub fn unpack(bytes: &[u8]) -> Result<Vec<Element>> {
+    let mut input = bytes;
+    let mut elements = Vec::new();
+    while !input.is_empty() {
+        elements.push(decode(&mut input, false)?);
+    }
+    Ok(elements)
+}
+
+/// Range covering every key that extends `prefix` by one or more elements
+/// (the packed prefix itself is excluded). Pass it to `Db::scan`.
+pub fn range<T>(prefix: T) -> (Bound<Bytes>, Bound<Bytes>)
+where
+    T: IntoTuple,
+{
+    let packed = pack(prefix);
+    let mut start = packed.clone();
+    start.push(0x00);
+    let mut end = packed;
+    end.push(0xFF);
+    (Bound::Included(start), Bound::Excluded(end))
+}
+
+fn encode(element: &Element, out: &mut Bytes, nested: bool) {
+    match element {
+        Element::Null if nested => out.extend_from_slice(&[NULL, ESCAPE]),
+        Element::Null => out.push(NULL),
+        Element::Bytes(b) => {
+            out.push(BYTES);
+            encode_escaped(b, out);
+        }
+        Element::String(s) => {
+            out.push(STRING);
+            encode_escaped(s.as_bytes(), out);
+        }
+        Element::Tuple(elements) => {
+            out.push(NESTED);
+            for e in elements {
+                encode(e, out, true);
+            }
+            out.push(0x00);
+        }
+        Element::Int(i) => encode_int(*i, out),
+        Element::Bool(false) => out.push(FALSE),
+        Element::Bool(true) => out.push(TRUE),
+    }
+}
+
+fn encode_escaped(bytes: &[u8], out: &mut Bytes) {
+    for &b in bytes {
+        out.push(b);
+        if b == 0x00 {
+            out.push(ESCAPE);
+        }
+    }
+    out.push(0x00);
+}
+
+fn encode_int(i: i64, out: &mut Bytes) {
+    if i == 0 {
+        out.push(INT_ZERO);
+        return;
+    }
+    let magnitude = i.unsigned_abs();
+    let len = 8 - (magnitude.leading_zeros() / 8) as usize;
+    let be = magnitude.to_be_bytes();
+    if i > 0 {
+        out.push(INT_ZERO + len as u8);
+        out.extend_from_slice(&be[8 - len..]);
+    } else {
+        out.push(INT_ZERO - len as u8);
+        out.extend(be[8 - len..].iter().map(|b| !b));
+    }
+}
+
+fn decode(input: &mut &[u8], nested: bool) -> Result<Element> {
+    let code = take(input, 1)?[0];
+    match code {
+        NULL => {
+            if nested {
+                let escape = take(input, 1)?[0];
+                if escape != ESCAPE {
+                    return Err(invalid("nested null missing escape"));
+                }
+            }
+            Ok(Element::Null)
+        }
+        BYTES => Ok(Element::Bytes(decode_escaped(input)?)),
+        STRING => {
+            let bytes = decode_escaped(input)?;
+            String::from_utf8(bytes)
+                .map(Element::String)
+                .map_err(|_| invalid("string element is not valid utf-8"))
+        }
+        NESTED => {
+            let mut elements = Vec::new();
+            loop {
+                match input.first() {
+                    None => return Err(invalid("unterminated nested tuple")),
+                    Some(&0x00) if input.get(1) != Some(&ESCAPE) => {
+                        *input = &input[1..];
+                        return Ok(Element::Tuple(elements));
+                    }
+                    Some(_) => elements.push(decode(input, true)?),
+                }
+            }
+        }
+        c if (INT_ZERO - 8..=INT_ZERO + 8).contains(&c) => decode_int(input, c),
+        FALSE => Ok(Element::Bool(false)),
+        TRUE => Ok(Element::Bool(true)),
+        other => Err(invalid(&format!("unknown tuple type code: {other:#04x}"))),
+    }
+}
+
+fn decode_escaped(input: &mut &[u8]) -> Result<Bytes> {
+    let mut out = Vec::new();
+    loop {
+        let b = take(input, 1)?[0];
+        if b != 0x00 {
+            out.push(b);
+        } else if input.first() == Some(&ESCAPE) {
+            *input = &input[1..];
+            out.push(0x00);
+        } else {
+            return Ok(out);
+        }
+    }
+}
+
+fn decode_int(input: &mut &[u8], code: u8) -> Result<Element> {
+    if code == INT_ZERO {
+        return Ok(Element::Int(0));
+    }
+    let positive = code > INT_ZERO;
+    let len = code.abs_diff(INT_ZERO) as usize;
+    let mut be = [0u8; 8];
+    be[8 - len..].copy_from_slice(take(input, len)?);
+    if !positive {
+        for b in &mut be[8 - len..] {
+            *b = !*b;
+        }
+    }
+    let magnitude = u64::from_be_bytes(be);
+    let value = if positive {
+        i64::try_from(magnitude).map_err(|_| invalid("integer out of range"))?
+    } else if magnitude == i64::MIN.unsigned_abs() {
+        i64::MIN
+    } else {
+        -i64::try_from(magnitude).map_err(|_| invalid("integer out of range"))?
+    };
+    Ok(Element::Int(value))
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if input.len() < n {
+        return Err(invalid("truncated tuple"));
+    }
+    let (head, rest) = input.split_at(n);
+    *input = rest;
+    Ok(head)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Values that can be packed as a tuple: `Vec<Element>` and Rust tuples of
+/// up to six elements convertible into [`Element`].
+pub trait IntoTuple {
+    fn into_tuple(self) -> Vec<Element>;
+}
+
+impl IntoTuple for Vec<Element> {
+    fn into_tuple(self) -> Vec<Element> {
+        self
+    }
+}
+
+impl IntoTuple for &[Element] {
+    fn into_tuple(self) -> Vec<Element> {
+        self.to_vec()
+    }
+}
+
+impl IntoTuple for () {
+    fn into_tuple(self) -> Vec<Element> {
+        Vec::new()
+    }
+}
+
+macro_rules! impl_into_tuple {
+    ($($name:ident),+) => {
+        impl<$($name),+> IntoTuple for ($($name,)+)
+        where
+            $($name: Into<Element>),+
+        {
+            #[allow(non_snake_case)]
+            fn into_tuple(self) -> Vec<Element> {
+                let ($($name,)+) = self;
+                vec![$($name.into()),+]
+            }
+        }
+    };
+}
+
+impl_into_tuple!(A);
+impl_into_tuple!(A, B);
+impl_into_tuple!(A, B, C);
+impl_into_tuple!(A, B, C, D);
+impl_into_tuple!(A, B, C, D, E);
+impl_into_tuple!(A, B, C, D, E, F);
+
+impl From<()> for Element {
+    fn from(_: ()) -> Self {
+        Element::Null
+    }
+}
+
+impl From<bool> for Element {
+    fn from(b: bool) -> Self {
+        Element::Bool(b)
+    }
+}
+
+macro_rules! impl_from_int {
+    ($($t:ty),+) => {
+        $(impl From<$t> for Element {
+            fn from(i: $t) -> Self {
+                Element::Int(i64::from(i))
+            }
+        })+
+    };
+}
+
+impl_from_int!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<&str> for Element {
+    fn from(s: &str) -> Self {
+        Element::String(s.to_string())
+    }
+}
+
+impl From<String> for Element {
+    fn from(s: String) -> Self {
+        Element::String(s)
+    }
+}
+
+impl From<&[u8]> for Element {
+    fn from(b: &[u8]) -> Self {
+        Element::Bytes(b.to_vec())
+    }
+}
+
+impl From<Bytes> for Element {
+    fn from(b: Bytes) -> Self {
+        Element::Bytes(b)
+    }
+}
+
+impl From<Vec<Element>> for Element {
+    fn from(elements: Vec<Element>) -> Self {
+        Element::Tuple(elements)
+    }
+}
+
+impl<T> From<Option<T>> for Element
+where
+    T: Into<Element>,
+{
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Element::Null, Into::into)
+    }
+}
@@ -0,0 +1,838 @@
+//! Typed view over the byte-oriented [`Db`].
+//!
+//! Keys are flattened into [`tuple`](crate::tuple) elements and packed with
+//! [`tuple::pack`], so for any two keys `a` and `b`, `a < b` exactly when
+//! `encode(a) < encode(b)` byte-wise, and range scans over integers, strings
+//! and tuples come back in their natural order.
+//!
+//! How values map to elements:
+//! - bools, strings and byte strings: the matching element
+//! - integers up to 64 bits and chars: an integer element; `u64` is offset
+//!   by 2^63 so that its whole range fits
+//! - 128-bit integers: two integer elements, the high and low halves
+//! - floats: an integer element holding the IEEE bits, with every bit but
+//!   the sign flipped for negatives, so `-0.0` sorts just before `0.0` and
+//!   NaN after infinity
+//! - options: null for `None`, the value as a nested tuple for `Some`
+//! - sequences and maps: a nested tuple of the elements
+//! - tuples and structs: fields concatenated in order
+//! - enums: variant index as an integer, then the variant's fields
+//!
+//! The elements don't record the Rust types, so `deserialize_any` is
+//! unsupported.
+
+use std::fmt;
+use std::io::{self, Error, Result};
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::{ser, Serialize};
+
+use crate::tuple::{self, Element};
+use crate::wal_kv::{Db, Transaction};
+
+type Bytes = Vec<u8>;
+type Marker<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+/// Encodes `value` with the order-preserving key encoding.
+pub fn to_key<T>(value: &T) -> Result<Bytes>
+where
+    T: Serialize + ?Sized,
+{
+    let mut ser = KeySerializer { out: Vec::new() };
+    value.serialize(&mut ser)?;
+    Ok(tuple::pack(ser.out))
+}
+
+/// Decodes a value written by [`to_key`]; trailing bytes are an error.
+pub fn from_key<T>(bytes: &[u8]) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    let elements = tuple::unpack(bytes)?;
+    let mut de = KeyDeserializer { input: &elements };
+    let value = T::deserialize(&mut de)?;
+    de.finish()?;
+    Ok(value)
+}
+
+/// Turns values into bytes and back for a [`TypedTree`].
+pub trait Codec<T> {
+    fn encode(value: &T) -> Result<Bytes>;
+    fn decode(bytes: &[u8]) -> Result<T>;
+}
+
+/// Default value codec: the same compact encoding used for keys.
+pub struct OrderedCodec;
+
+impl<T> Codec<T> for OrderedCodec
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn encode(value: &T) -> Result<Bytes> {
+        to_key(value)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T> {
+        from_key(bytes)
+    }
+}
+
+/// Read access shared by [`Db`] and [`Transaction`], so typed reads work on
+/// either. Reads through a `Transaction` see its pending writes.
+pub trait Readable {
+    fn read(&mut self, key: &[u8]) -> Result<Option<Bytes>>;
+    fn read_range(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>>;
+}
+
+impl Readable for Db {
+    fn read(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get(key)
+    }
+
+    fn read_range(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>> {
+        self.scan(range)
+    }
+}
+
+impl Readable for Transaction<'_> {
+    fn read(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.get(key)
+    }
+
+    fn read_range(&mut self, range: (Bound<Bytes>, Bound<Bytes>)) -> Result<Vec<(Bytes, Bytes)>> {
+        self.scan(range)
+    }
+}
+
+/// A named, typed keyspace inside a [`Db`].
+///
+/// Every key is stored as the packed tree name followed by the packed key,
+/// so trees never overlap and each tree's entries stay contiguous.
+pub struct TypedTree<K, V, C = OrderedCodec> {
+    prefix: Bytes,
+    _marker: Marker<K, V, C>,
+}
+
+impl<K, V, C> TypedTree<K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    C: Codec<V>,
+{
+    pub fn new<N>(name: N) -> Self
+    where
+        N: AsRef<[u8]>,
+    {
+        Self { prefix: tuple::pack((name.as_ref(),)), _marker: PhantomData }
+    }
+
+    pub fn get<R>(&self, reader: &mut R, key: &K) -> Result<Option<V>>
+    where
+        R: Readable + ?Sized,
+    {
+        match reader.read(&self.raw_key(key)?)? {
+            Some(bytes) => C::decode(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, tx: &mut Transaction<'_>, key: &K, value: &V) -> Result<()> {
+        tx.set(self.raw_key(key)?, C::encode(value)?);
+        Ok(())
+    }
+
+    pub fn remove(&self, tx: &mut Transaction<'_>, key: &K) -> Result<()> {
+        tx.delete(self.raw_key(key)?);
+        Ok(())
+    }
+
+    /// Entries whose keys fall in `range`, in key order.
+    pub fn range<R, B>(&self, reader: &mut R, range: B) -> Result<Vec<(K, V)>>
+    where
+        R: Readable + ?Sized,
+        B: RangeBounds<K>,
+    {
+        // No element starts with 0xFF, so this ends before any longer tree
+        // name that extends this one.
+        let mut tree_end = self.prefix.clone();
+        tree_end.push(0xFF);
+        let start = match range.start_bound() {
+            Bound::Included(k) => Bound::Included(self.raw_key(k)?),
+            Bound::Excluded(k) => Bound::Excluded(self.raw_key(k)?),
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => Bound::Included(self.raw_key(k)?),
+            Bound::Excluded(k) => Bound::Excluded(self.raw_key(k)?),
+            Bound::Unbounded => Bound::Excluded(tree_end),
+        };
+
+        reader
+            .read_range((start, end))?
+            .into_iter()
+            .map(|(k, v)| Ok((from_key(&k[self.prefix.len()..])?, C::decode(&v)?)))
+            .collect()
+    }
+
+    pub fn iter<R>(&self, reader: &mut R) -> Result<Vec<(K, V)>>
+    where
+        R: Readable + ?Sized,
+    {
+        self.range(reader, ..)
+    }
+
+    fn raw_key(&self, key: &K) -> Result<Bytes> {
+        let mut raw = self.prefix.clone();
+        raw.extend(to_key(key)?);
+        Ok(raw)
+    }
+}
+
+/// Error raised while encoding or decoding keys; surfaced as
+/// [`io::ErrorKind::InvalidData`].
+#[derive(Debug)]
+pub struct EncodingError(String);
+
+impl EncodingError {
+    fn new(msg: impl Into<String>) -> Self {
+        Self(msg.into())
+    }
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for EncodingError {}
+
+impl ser::Error for EncodingError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl de::Error for EncodingError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl From<EncodingError> for Error {
+    fn from(e: EncodingError) -> Self {
+        Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Flattens a value into tuple elements.
+struct KeySerializer {
+    out: Vec<Element>,
+}
+
+impl KeySerializer {
+    fn int(&mut self, i: i64) -> SerResult {
+        self.out.push(Element::Int(i));
+        Ok(())
+    }
+
+    /// `value`'s elements as one nested tuple.
+    fn push_nested<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult {
+        let mut inner = KeySerializer { out: Vec::new() };
+        value.serialize(&mut inner)?;
+        self.out.push(Element::Tuple(inner.out));
+        Ok(())
+    }
+}
+
+/// Maps `u64` onto `i64` in order, so the whole range fits an integer element.
+fn unsigned_to_int(v: u64) -> i64 {
+    (v ^ (1 << 63)) as i64
+}
+
+fn int_to_unsigned(i: i64) -> u64 {
+    (i as u64) ^ (1 << 63)
+}
+
+type SerResult = std::result::Result<(), EncodingError>;
+
+impl<'a> ser::Serializer for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = EncodingError;
+    type SerializeSeq = SeqSerializer<'a>;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = SeqSerializer<'a>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> SerResult {
+        self.out.push(Element::Bool(v));
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> SerResult {
+        self.int(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> SerResult {
+        self.int(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> SerResult {
+        self.int(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> SerResult {
+        self.int(v)
+    }
+
+    fn serialize_i128(self, v: i128) -> SerResult {
+        self.int((v >> 64) as i64)?;
+        self.int(unsigned_to_int(v as u64))
+    }
+
+    fn serialize_u8(self, v: u8) -> SerResult {
+        self.int(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> SerResult {
+        self.int(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> SerResult {
+        self.int(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> SerResult {
+        self.int(unsigned_to_int(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> SerResult {
+        self.int(unsigned_to_int((v >> 64) as u64))?;
+        self.int(unsigned_to_int(v as u64))
+    }
+
+    fn serialize_f32(self, v: f32) -> SerResult {
+        let bits = v.to_bits() as i32;
+        self.int(if bits < 0 { bits ^ i32::MAX } else { bits }.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> SerResult {
+        let bits = v.to_bits() as i64;
+        self.int(if bits < 0 { bits ^ i64::MAX } else { bits })
+    }
+
+    fn serialize_char(self, v: char) -> SerResult {
+        self.int(u32::from(v).into())
+    }
+
+    fn serialize_str(self, v: &str) -> SerResult {
+        self.out.push(Element::String(v.to_string()));
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> SerResult {
+        self.out.push(Element::Bytes(v.to_vec()));
+        Ok(())
+    }
+
+    fn serialize_none(self) -> SerResult {
+        self.out.push(Element::Null);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> SerResult {
+        self.push_nested(value)
+    }
+
+    fn serialize_unit(self) -> SerResult {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> SerResult {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, index: u32, _variant: &'static str) -> SerResult {
+        self.int(index.into())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> SerResult {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> SerResult {
+        self.int(index.into())?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> std::result::Result<Self::SerializeSeq, EncodingError> {
+        Ok(SeqSerializer { parent: self, items: KeySerializer { out: Vec::new() } })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> std::result::Result<Self, EncodingError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> std::result::Result<Self, EncodingError> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> std::result::Result<Self, EncodingError> {
+        self.int(index.into())?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> std::result::Result<Self::SerializeMap, EncodingError> {
+        Ok(SeqSerializer { parent: self, items: KeySerializer { out: Vec::new() } })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> std::result::Result<Self, EncodingError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> std::result::Result<Self, EncodingError> {
+        self.int(index.into())?;
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl ser::SerializeTuple for &mut KeySerializer {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut KeySerializer {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut KeySerializer {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut KeySerializer {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T) -> SerResult {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut KeySerializer {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T) -> SerResult {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> SerResult {
+        Ok(())
+    }
+}
+
+/// Sequences and maps: the elements are collected into one nested tuple,
+/// which sorts a shorter sequence before any longer one it prefixes.
+struct SeqSerializer<'a> {
+    parent: &'a mut KeySerializer,
+    items: KeySerializer,
+}
+
+impl SeqSerializer<'_> {
+    fn finish(self) -> SerResult {
+        self.parent.out.push(Element::Tuple(self.items.out));
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult {
+        value.serialize(&mut self.items)
+    }
+
+    fn end(self) -> SerResult {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = EncodingError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> SerResult {
+        key.serialize(&mut self.items)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> SerResult {
+        value.serialize(&mut self.items)
+    }
+
+    fn end(self) -> SerResult {
+        self.finish()
+    }
+}
+
+/// Reads a value back from the elements [`KeySerializer`] flattened it into.
+struct KeyDeserializer<'a> {
+    input: &'a [Element],
+}
+
+type DeResult<T> = std::result::Result<T, EncodingError>;
+
+fn expected(what: &str) -> EncodingError {
+    EncodingError::new(format!("expected {what} in key"))
+}
+
+impl<'a> KeyDeserializer<'a> {
+    fn next(&mut self) -> DeResult<&'a Element> {
+        let (first, rest) = self.input.split_first().ok_or_else(|| EncodingError::new("unexpected end of key"))?;
+        self.input = rest;
+        Ok(first)
+    }
+
+    fn int(&mut self) -> DeResult<i64> {
+        match self.next()? {
+            Element::Int(i) => Ok(*i),
+            _ => Err(expected("an integer")),
+        }
+    }
+
+    fn int_as<T: TryFrom<i64>>(&mut self) -> DeResult<T> {
+        T::try_from(self.int()?).map_err(|_| EncodingError::new("integer out of range"))
+    }
+
+    fn unsigned(&mut self) -> DeResult<u64> {
+        Ok(int_to_unsigned(self.int()?))
+    }
+
+    fn nested(&mut self) -> DeResult<KeyDeserializer<'a>> {
+        match self.next()? {
+            Element::Tuple(items) => Ok(KeyDeserializer { input: items }),
+            _ => Err(expected("a nested tuple")),
+        }
+    }
+
+    fn finish(&self) -> DeResult<()> {
+        if self.input.is_empty() {
+            Ok(())
+        } else {
+            Err(EncodingError::new("trailing elements in key"))
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut KeyDeserializer<'_> {
+    type Error = EncodingError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> DeResult<V::Value> {
+        Err(EncodingError::new("key encoding is not self-describing"))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        match self.next()? {
+            Element::Bool(b) => visitor.visit_bool(*b),
+            _ => Err(expected("a bool")),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_i8(self.int_as()?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_i16(self.int_as()?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_i32(self.int_as()?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_i64(self.int()?)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let high = self.int()?;
+        let low = self.unsigned()?;
+        visitor.visit_i128((i128::from(high) << 64) | i128::from(low))
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_u8(self.int_as()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_u16(self.int_as()?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_u32(self.int_as()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_u64(self.unsigned()?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let high = self.unsigned()?;
+        let low = self.unsigned()?;
+        visitor.visit_u128((u128::from(high) << 64) | u128::from(low))
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let bits: i32 = self.int_as()?;
+        let bits = if bits < 0 { bits ^ i32::MAX } else { bits };
+        visitor.visit_f32(f32::from_bits(bits as u32))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let bits = self.int()?;
+        let bits = if bits < 0 { bits ^ i64::MAX } else { bits };
+        visitor.visit_f64(f64::from_bits(bits as u64))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let c = char::from_u32(self.int_as()?).ok_or_else(|| EncodingError::new("invalid char"))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        match self.next()? {
+            Element::String(s) => visitor.visit_string(s.clone()),
+            _ => Err(expected("a string")),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        match self.next()? {
+            Element::Bytes(b) => visitor.visit_byte_buf(b.clone()),
+            _ => Err(expected("a byte string")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        match self.next()? {
+            Element::Null => visitor.visit_none(),
+            Element::Tuple(items) => {
+                let mut inner = KeyDeserializer { input: items };
+                let value = visitor.visit_some(&mut inner)?;
+                inner.finish()?;
+                Ok(value)
+            }
+            _ => Err(expected("an option")),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let mut inner = self.nested()?;
+        let value = visitor.visit_seq(Elements { de: &mut inner, remaining: None })?;
+        inner.finish()?;
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, remaining: Some(len) })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> DeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, remaining: Some(len) })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> DeResult<V::Value> {
+        let mut inner = self.nested()?;
+        let value = visitor.visit_map(Elements { de: &mut inner, remaining: None })?;
+        inner.finish()?;
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> DeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, remaining: Some(fields.len()) })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> DeResult<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> DeResult<V::Value> {
+        Err(EncodingError::new("key encoding does not store identifiers"))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> DeResult<V::Value> {
+        Err(EncodingError::new("key encoding is not self-describing"))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Walks fixed-arity elements (`remaining: Some`) or the whole of a nested
+/// tuple holding a sequence or map (`remaining: None`).
+struct Elements<'a, 'b> {
+    de: &'a mut KeyDeserializer<'b>,
+    remaining: Option<usize>,
+}
+
+impl Elements<'_, '_> {
+    fn has_next(&mut self) -> bool {
+        match &mut self.remaining {
+            Some(0) => false,
+            Some(n) => {
+                *n -= 1;
+                true
+            }
+            None => !self.de.input.is_empty(),
+        }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, '_> {
+    type Error = EncodingError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> DeResult<Option<T::Value>> {
+        if !self.has_next() {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, '_> {
+    type Error = EncodingError;
+
+    fn next_key_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> DeResult<Option<T::Value>> {
+        if !self.has_next() {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> DeResult<T::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut KeyDeserializer<'_> {
+    type Error = EncodingError;
+    type Variant = Self;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> DeResult<(T::Value, Self)> {
+        let index: u32 = self.int_as()?;
+        let value = seed.deserialize(index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut KeyDeserializer<'_> {
+    type Error = EncodingError;
+
+    fn unit_variant(self) -> DeResult<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> DeResult<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> DeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, remaining: Some(len) })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> DeResult<V::Value> {
+        visitor.visit_seq(Elements { de: self, remaining: Some(fields.len()) })
+    }
+}
@@ -0,0 +1,205 @@
+//! Upgrading files written by older format versions in place.
+//!
+//! Format version 1 files have no header but otherwise hold the same records
+//! as now, so upgrading one writes a header followed by its old contents to
+//! a temporary file, syncs it and renames it over the original: a crash
+//! leaves either the old file or the upgraded one, and running the upgrade
+//! again finishes the job. Files that already have a current header are only
+//! checked, so every routine here can be run any number of times.
+//!
+//! A Db's manifest is rewritten with the new format version last, once every
+//! file it covers has been upgraded; until then the Db refuses to open.
+//!
+//! One file predates version 1's framing: a WAL from before the change log
+//! existed has no sequence number after each BEGIN. A Db without a change log
+//! can only have such a WAL, so its transactions are renumbered from 1 as
+//! they are upgraded.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, Error, Result, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::segments as archive_segments;
+use crate::changes::{encode_batch, read_unsequenced_batch};
+use crate::format::{check_header, header, FileKind, HEADER_LEN};
+use crate::manifest::{read_current, write_current, Manifest, FORMAT_VERSION};
+use crate::migrate::{detect_format, DataFormat};
+use crate::segments::segment_file_name;
+use crate::vfs::{read_exact_at, OsFs, Vfs, VfsFile, VfsReader, VfsWriter};
+use crate::wal_kv::Db;
+
+/// What an upgrade changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradeReport {
+    /// Format version the files were in; [`FORMAT_VERSION`] if they were
+    /// already current.
+    pub from_version: u32,
+    /// Files that were rewritten.
+    pub upgraded: Vec<PathBuf>,
+}
+
+/// Upgrades the Db, or the backup, in `dir` to the current format version.
+/// The Db must not be open.
+pub fn upgrade_db<P: AsRef<Path>>(dir: P) -> Result<UpgradeReport> {
+    upgrade_db_with_vfs(&OsFs, dir.as_ref())
+}
+
+pub(crate) fn upgrade_db_with_vfs(vfs: &dyn Vfs, dir: &Path) -> Result<UpgradeReport> {
+    let current = read_current(vfs, dir)?;
+    let segments = current.as_ref().map_or_else(|| vec![0], |(_, manifest)| manifest.segments.clone());
+
+    let mut upgraded = Vec::new();
+    for id in segments {
+        let path = dir.join(segment_file_name(id));
+        if current.is_some() && !vfs.exists(&path)? {
+            return Err(Error::new(io::ErrorKind::NotFound, format!("{} is missing", path.display())));
+        }
+        // Refuse to put a Db header on anything but Db records.
+        let check = |file: &dyn VfsFile| Db::index_records(file, id, 0, &mut BTreeMap::new()).map(drop);
+        if upgrade_file(vfs, &path, FileKind::DataLog, check)? {
+            upgraded.push(path);
+        }
+    }
+    if let Some(path) = upgrade_unsequenced_wal(vfs, dir)? {
+        upgraded.push(path);
+    }
+    for (name, kind) in [(Db::WAL_FILE, FileKind::Wal), (Db::CHANGES_FILE, FileKind::ChangeLog)] {
+        let path = dir.join(name);
+        if upgrade_file(vfs, &path, kind, |_| Ok(()))? {
+            upgraded.push(path);
+        }
+    }
+
+    let from_version = match current {
+        Some((number, manifest)) => {
+            let from_version = manifest.format;
+            if from_version < FORMAT_VERSION {
+                write_current(vfs, dir, number + 1, &Manifest { format: FORMAT_VERSION, ..manifest })?;
+            }
+            from_version
+        }
+        None if upgraded.is_empty() => FORMAT_VERSION,
+        None => 1,
+    };
+    Ok(UpgradeReport { from_version, upgraded })
+}
+
+/// Upgrades the `KvStore` log at `path` to the current format version. The
+/// store must not be open.
+pub fn upgrade_kvstore<P: AsRef<Path>>(path: P) -> Result<UpgradeReport> {
+    let path = path.as_ref();
+    let check = |_: &dyn VfsFile| match detect_format(path)? {
+        DataFormat::KvStore | DataFormat::Ambiguous | DataFormat::Empty => Ok(()),
+        other => Err(Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a KvStore log (detected {other:?})", path.display()),
+        )),
+    };
+    let upgraded = upgrade_file(&OsFs, path, FileKind::KvStore, check)?;
+    Ok(report(upgraded.then(|| path.to_path_buf()).into_iter().collect()))
+}
+
+/// Upgrades every segment in the WAL archive in `dir` to the current format
+/// version. Archiving into it must not be enabled.
+pub fn upgrade_archive<P: AsRef<Path>>(dir: P) -> Result<UpgradeReport> {
+    let mut upgraded = Vec::new();
+    for path in archive_segments(&OsFs, dir.as_ref())? {
+        if upgrade_file(&OsFs, &path, FileKind::Archive, |_| Ok(()))? {
+            upgraded.push(path);
+        }
+    }
+    Ok(report(upgraded))
+}
+
+fn report(upgraded: Vec<PathBuf>) -> UpgradeReport {
+    let from_version = if upgraded.is_empty() { FORMAT_VERSION } else { 1 };
+    UpgradeReport { from_version, upgraded }
+}
+
+/// Gives the file at `path` a `kind` header if it has none, after `check`
+/// accepts its old contents. Returns whether it was rewritten; a missing
+/// file, or one already upgraded, is left alone.
+fn upgrade_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    kind: FileKind,
+    check: impl FnOnce(&dyn VfsFile) -> Result<()>,
+) -> Result<bool> {
+    if !vfs.exists(path)? {
+        return Ok(false);
+    }
+    let file = vfs.open(path)?;
+    let len = file.len()?;
+    let mut start = vec![0u8; len.min(HEADER_LEN) as usize];
+    read_exact_at(file.as_ref(), &mut start, 0)?;
+    // Version 1 records never start with a magic's first byte, so anything
+    // that does was written by a later version: a header, or the start of
+    // one that the store fixes itself when opening.
+    if start.first().is_none_or(|&first| first == header(kind)[0]) {
+        if len >= HEADER_LEN {
+            check_header(&start, kind, path)?;
+        }
+        return Ok(false);
+    }
+    check(file.as_ref())?;
+
+    rewrite(vfs, path, kind, |tmp| {
+        let copied = io::copy(&mut VfsReader::new(file.as_ref(), 0), tmp)?;
+        if copied != len {
+            return Err(Error::new(io::ErrorKind::UnexpectedEof, format!("{} changed while upgrading", path.display())));
+        }
+        Ok(())
+    })?;
+    Ok(true)
+}
+
+/// Rewrites the Db's WAL with sequence numbers and a header if it was written
+/// before the change log existed. Returns its path if it was rewritten.
+fn upgrade_unsequenced_wal(vfs: &dyn Vfs, dir: &Path) -> Result<Option<PathBuf>> {
+    let path = dir.join(Db::WAL_FILE);
+    if vfs.exists(&dir.join(Db::CHANGES_FILE))? || !vfs.exists(&path)? {
+        return Ok(None);
+    }
+    let file = vfs.open(&path)?;
+    let mut first = [0u8; 1];
+    if file.len()? == 0 {
+        return Ok(None);
+    }
+    read_exact_at(file.as_ref(), &mut first, 0)?;
+    if first[0] == header(FileKind::Wal)[0] {
+        return Ok(None);
+    }
+
+    let mut reader = BufReader::new(VfsReader::new(file.as_ref(), 0));
+    let mut batches = Vec::new();
+    loop {
+        match read_unsequenced_batch(&mut reader) {
+            Ok(Some(ops)) => batches.push(ops),
+            Ok(None) => break,
+            // Cut short by a crash, so never acknowledged.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    rewrite(vfs, &path, FileKind::Wal, |tmp| {
+        for (seq, ops) in (1..).zip(&batches) {
+            tmp.write_all(&encode_batch(seq, ops))?;
+        }
+        Ok(())
+    })?;
+    Ok(Some(path))
+}
+
+/// Replaces the file at `path` with a `kind` header followed by what `body`
+/// writes, through a synced temporary file.
+fn rewrite(vfs: &dyn Vfs, path: &Path, kind: FileKind, body: impl FnOnce(&mut VfsWriter) -> Result<()>) -> Result<()> {
+    let mut tmp_name = path.file_name().expect("a file path").to_os_string();
+    tmp_name.push(".upgrade");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut tmp = VfsWriter(vfs.create(&tmp_path)?);
+    tmp.write_all(&header(kind))?;
+    body(&mut tmp)?;
+    tmp.file_mut().sync()?;
+    vfs.rename(&tmp_path, path)?;
+    vfs.sync_dir(path)
+}
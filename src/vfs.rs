@@ -0,0 +1,613 @@
+//! The file operations the Db needs, so it can run on disk, in memory, or
+//! on storage that fails on purpose.
+//!
+//! Every file the Db writes is an append-only log that is read back by
+//! offset, truncated when a crash left a torn tail, or replaced wholesale
+//! with a rename. [`VfsFile`] is exactly that: positional reads, appends,
+//! sync and truncate.
+//!
+//! [`OsFs`] is the real filesystem, [`MemFs`] keeps files in memory, and
+//! [`FaultFs`] is an in-memory filesystem that can fail syncs and writes,
+//! write short, and simulate a crash. Open a Db on one with
+//! [`Db::open_with_vfs`](crate::Db::open_with_vfs).
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub trait Vfs: Send + Sync {
+    /// Opens `path` for reading and appending, creating it if missing.
+    fn open(&self, path: &Path) -> Result<Box<dyn VfsFile>>;
+
+    /// Creates an empty file at `path`, replacing any existing one.
+    fn create(&self, path: &Path) -> Result<Box<dyn VfsFile>>;
+
+    /// Atomically moves `from` over `to`. Handles open on the old `to` keep
+    /// reading its old contents.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    fn exists(&self, path: &Path) -> Result<bool>;
+
+    /// Names of the files directly inside the directory `dir`.
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>>;
+
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Makes renames and creations of entries in the directory holding
+    /// `path` durable.
+    fn sync_dir(&self, path: &Path) -> Result<()>;
+}
+
+pub trait VfsFile: Send + Sync {
+    /// Reads from `offset`; returns 0 at the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
+
+    /// Appends `buf` to the end of the file and returns how much was written.
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    fn sync(&mut self) -> Result<()>;
+
+    fn truncate(&mut self, len: u64) -> Result<()>;
+
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// The real filesystem.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFs;
+
+struct OsFile(File);
+
+impl Vfs for OsFs {
+    fn open(&self, path: &Path) -> Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        file.set_len(0)?;
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        path.try_exists()
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        sync_parent_dir(path)
+    }
+}
+
+/// Makes renames and creations in the directory holding `path` durable on
+/// the real filesystem; a no-op where directories can't be synced.
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+impl VfsFile for OsFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::FileExt::read_at(&self.0, buf, offset)
+        }
+        #[cfg(windows)]
+        {
+            std::os::windows::fs::FileExt::seek_read(&self.0, buf, offset)
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.0.sync_all()
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+}
+
+type Contents = Arc<Mutex<Vec<u8>>>;
+
+/// Files kept in memory, shared by every clone.
+#[derive(Clone, Default)]
+pub struct MemFs {
+    files: Arc<Mutex<HashMap<PathBuf, Contents>>>,
+}
+
+struct MemFile(Contents);
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn files(&self) -> MutexGuard<'_, HashMap<PathBuf, Contents>> {
+        lock(&self.files)
+    }
+}
+
+impl Vfs for MemFs {
+    fn open(&self, path: &Path) -> Result<Box<dyn VfsFile>> {
+        let contents = self.files().entry(path.to_path_buf()).or_default().clone();
+        Ok(Box::new(MemFile(contents)))
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn VfsFile>> {
+        let contents = Arc::new(Mutex::new(Vec::new()));
+        self.files().insert(path.to_path_buf(), contents.clone());
+        Ok(Box::new(MemFile(contents)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut files = self.files();
+        let contents = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_path_buf(), contents);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        self.files().remove(path).map(drop).ok_or_else(|| not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.files().contains_key(path))
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        Ok(names_in(self.files().keys(), dir))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl MemFile {
+    fn contents(&self) -> MutexGuard<'_, Vec<u8>> {
+        lock(&self.0)
+    }
+}
+
+impl VfsFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let contents = self.contents();
+        let start = (offset as usize).min(contents.len());
+        let n = buf.len().min(contents.len() - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.contents().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        self.contents().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.contents().len() as u64)
+    }
+}
+
+/// In-memory filesystem with injectable faults, shared by every clone.
+///
+/// Each file remembers its contents as of its last successful sync, and
+/// [`FaultFs::crash`] rolls every file back to that, as a power loss would.
+/// Creating, renaming and removing files is treated as durable immediately.
+///
+/// Writes, syncs, truncates, creates, renames and removals are counted as
+/// operations; [`FaultFs::lose_power_after`] cuts the power at any one of
+/// them, so a test can crash the Db at every step of a workload.
+#[derive(Clone, Default)]
+pub struct FaultFs {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    files: HashMap<PathBuf, Arc<Mutex<FaultData>>>,
+    /// Bumped by each crash; handles from before it stop working.
+    generation: u64,
+    operations: u64,
+    /// Operations still allowed before the power goes out.
+    power_left: Option<u64>,
+    powered_off: bool,
+    /// Successful syncs still to go before the one that fails.
+    failing_sync: Option<u64>,
+    /// Bytes that may still be written before every later write fails.
+    bytes_left: Option<u64>,
+    max_write: Option<usize>,
+}
+
+#[derive(Default)]
+struct FaultData {
+    contents: Vec<u8>,
+    synced: Vec<u8>,
+}
+
+struct FaultFile {
+    fs: FaultFs,
+    data: Arc<Mutex<FaultData>>,
+    generation: u64,
+}
+
+impl FaultFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the sync that comes after `after` more successful ones, once;
+    /// `None` cancels it.
+    pub fn fail_sync(&self, after: Option<u64>) {
+        self.state().failing_sync = after;
+    }
+
+    /// Accepts `after` more bytes of writes, then fails every write, as a
+    /// full disk would; `None` stops failing them.
+    pub fn fail_writes(&self, after: Option<u64>) {
+        self.state().bytes_left = after;
+    }
+
+    /// Makes every write store at most `max` bytes and report the short
+    /// count; `None` restores full writes.
+    pub fn short_writes(&self, max: Option<usize>) {
+        self.state().max_write = max.map(|max| max.max(1));
+    }
+
+    /// Lets `after` more operations succeed, then fails that one and every
+    /// later operation and read, as if the machine lost power; `None`
+    /// cancels it. Restart with [`FaultFs::crash`] or [`FaultFs::crash_torn`].
+    pub fn lose_power_after(&self, after: Option<u64>) {
+        self.state().power_left = after;
+    }
+
+    /// Number of successful operations so far.
+    pub fn operations(&self) -> u64 {
+        self.state().operations
+    }
+
+    /// Simulates a power loss: every file loses what was written since its
+    /// last successful sync, handles opened before the crash fail from now
+    /// on, and injected faults are cleared.
+    pub fn crash(&self) {
+        self.restart(None);
+    }
+
+    /// Like [`FaultFs::crash`], but each file keeps a prefix, chosen from
+    /// `seed`, of what was appended since its last sync, as when the disk
+    /// wrote back some pages and not others. An unsynced truncate is kept or
+    /// undone as a whole.
+    pub fn crash_torn(&self, seed: u64) {
+        self.restart(Some(seed));
+    }
+
+    fn restart(&self, seed: Option<u64>) {
+        let mut state = self.state();
+        let mut rng = seed.map(|seed| seed | 1);
+        let mut paths: Vec<&PathBuf> = state.files.keys().collect();
+        paths.sort();
+        for path in paths {
+            let mut data = lock(&state.files[path]);
+            // How much of the current contents survives, if not just the synced part.
+            let kept = match rng.as_mut() {
+                None => None,
+                Some(rng) if data.contents.starts_with(&data.synced) => {
+                    let unsynced = (data.contents.len() - data.synced.len()) as u64;
+                    Some(data.synced.len() + (next_random(rng) % (unsynced + 1)) as usize)
+                }
+                Some(rng) => next_random(rng).is_multiple_of(2).then_some(data.contents.len()),
+            };
+            match kept {
+                Some(len) => data.contents.truncate(len),
+                None => data.contents = data.synced.clone(),
+            }
+            data.synced = data.contents.clone();
+        }
+        state.generation += 1;
+        state.failing_sync = None;
+        state.bytes_left = None;
+        state.max_write = None;
+        state.power_left = None;
+        state.powered_off = false;
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        lock(&self.state)
+    }
+
+    fn handle(&self, data: Arc<Mutex<FaultData>>, generation: u64) -> Box<dyn VfsFile> {
+        Box::new(FaultFile { fs: self.clone(), data, generation })
+    }
+}
+
+impl Vfs for FaultFs {
+    fn open(&self, path: &Path) -> Result<Box<dyn VfsFile>> {
+        let mut state = self.state();
+        state.check_power()?;
+        if !state.files.contains_key(path) {
+            state.operation()?;
+        }
+        let data = state.files.entry(path.to_path_buf()).or_default().clone();
+        Ok(self.handle(data, state.generation))
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn VfsFile>> {
+        let mut state = self.state();
+        state.operation()?;
+        let data = Arc::new(Mutex::new(FaultData::default()));
+        state.files.insert(path.to_path_buf(), data.clone());
+        Ok(self.handle(data, state.generation))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut state = self.state();
+        state.operation()?;
+        let data = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        let mut state = self.state();
+        state.operation()?;
+        state.files.remove(path).map(drop).ok_or_else(|| not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        let state = self.state();
+        state.check_power()?;
+        Ok(state.files.contains_key(path))
+    }
+
+    fn list_dir(&self, dir: &Path) -> Result<Vec<String>> {
+        let state = self.state();
+        state.check_power()?;
+        Ok(names_in(state.files.keys(), dir))
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl FaultState {
+    fn check_power(&self) -> Result<()> {
+        if self.powered_off {
+            return Err(Error::other("simulated power loss"));
+        }
+        Ok(())
+    }
+
+    /// Counts one operation, or cuts the power if it was due.
+    fn operation(&mut self) -> Result<()> {
+        self.check_power()?;
+        match &mut self.power_left {
+            Some(0) => {
+                self.powered_off = true;
+                return self.check_power();
+            }
+            Some(left) => *left -= 1,
+            None => {}
+        }
+        self.operations += 1;
+        Ok(())
+    }
+}
+
+impl FaultFile {
+    /// Fails if a crash happened since the file was opened, or the power is out.
+    fn check(&self, state: &FaultState) -> Result<()> {
+        if state.generation != self.generation {
+            return Err(Error::other("file handle was opened before a simulated crash"));
+        }
+        state.check_power()
+    }
+
+    fn data(&self) -> Result<MutexGuard<'_, FaultData>> {
+        self.check(&self.fs.state())?;
+        Ok(lock(&self.data))
+    }
+}
+
+impl VfsFile for FaultFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let data = self.data()?;
+        let start = (offset as usize).min(data.contents.len());
+        let n = buf.len().min(data.contents.len() - start);
+        buf[..n].copy_from_slice(&data.contents[start..start + n]);
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Lock order: filesystem state, then file data.
+        let mut state = self.fs.state();
+        self.check(&state)?;
+        state.operation()?;
+        let mut data = lock(&self.data);
+        let mut n = buf.len().min(state.max_write.unwrap_or(usize::MAX));
+        if let Some(left) = &mut state.bytes_left {
+            if *left == 0 && n > 0 {
+                return Err(Error::other("injected write failure"));
+            }
+            n = n.min(*left as usize);
+            *left -= n as u64;
+        }
+        data.contents.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let mut state = self.fs.state();
+        self.check(&state)?;
+        state.operation()?;
+        let mut data = lock(&self.data);
+        match &mut state.failing_sync {
+            Some(0) => {
+                state.failing_sync = None;
+                return Err(Error::other("injected sync failure"));
+            }
+            Some(left) => *left -= 1,
+            None => {}
+        }
+        data.synced = data.contents.clone();
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<()> {
+        let mut state = self.fs.state();
+        self.check(&state)?;
+        state.operation()?;
+        lock(&self.data).contents.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.data()?.contents.len() as u64)
+    }
+}
+
+pub(crate) fn next_random(state: &mut u64) -> u64 {
+    // xorshift64
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    x
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Names of the `paths` directly inside `dir`.
+fn names_in<'a>(paths: impl Iterator<Item = &'a PathBuf>, dir: &Path) -> Vec<String> {
+    paths
+        .filter(|path| path.parent() == Some(dir))
+        .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .collect()
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display()))
+}
+
+/// [`Write`] adapter that appends to a [`VfsFile`]; short writes are retried
+/// by `write_all`.
+pub(crate) struct VfsWriter(pub(crate) Box<dyn VfsFile>);
+
+impl VfsWriter {
+    pub(crate) fn file(&self) -> &dyn VfsFile {
+        self.0.as_ref()
+    }
+
+    pub(crate) fn file_mut(&mut self) -> &mut dyn VfsFile {
+        self.0.as_mut()
+    }
+}
+
+impl Write for VfsWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Sequential [`Read`] over a [`VfsFile`], starting at an offset.
+pub(crate) struct VfsReader<'a> {
+    file: &'a dyn VfsFile,
+    pos: u64,
+}
+
+impl<'a> VfsReader<'a> {
+    pub(crate) fn new(file: &'a dyn VfsFile, pos: u64) -> Self {
+        Self { file, pos }
+    }
+}
+
+impl Read for VfsReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Fills `buf` from `offset`, failing with `UnexpectedEof` if the file ends first.
+pub(crate) fn read_exact_at(file: &dyn VfsFile, buf: &mut [u8], offset: u64) -> Result<()> {
+    VfsReader::new(file, offset).read_exact(buf)
+}
+
+/// The first `len` bytes of `file`.
+pub(crate) fn read_prefix(file: &dyn VfsFile, len: u64) -> Result<Vec<u8>> {
+    let mut out = vec![0u8; len as usize];
+    read_exact_at(file, &mut out, 0)?;
+    Ok(out)
+}
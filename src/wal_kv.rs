@@ -1,81 +1,246 @@
 use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Result, Error, BufWriter, BufReader, Write, Seek, Read, SeekFrom};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::archive::Archive;
+use crate::simple_kv::KvStore;
+use crate::changes::{encode_batch, ChangeLog};
+use crate::clock::{Clock, SystemClock};
+use crate::format::{check_file_header, init_header, FileKind, HEADER_LEN};
+use crate::hints::{apply_hints, hint_file_name, read_hints};
+use crate::manifest::{read_current, FORMAT_VERSION};
+use crate::secondary::SecondaryIndexes;
+use crate::segments::{segment_file_name, RecordPos, Segment};
+use crate::vfs::{read_exact_at, MemFs, OsFs, Vfs, VfsFile, VfsReader, VfsWriter};
+use crate::watch::Watchers;
+use std::io::{self, Result, Error, BufWriter, BufReader, Write, Read};
 
 type Bytes = Vec<u8>;
+/// Transactions with their sequence numbers.
+type Batches = Vec<(u64, Vec<Op>)>;
 
-const OP_BEGIN: u8 = 0;
-const OP_PUT: u8 = 1;
-const OP_DELETE: u8 = 2;
-const OP_COMMIT: u8 = 3;
+pub(crate) const OP_BEGIN: u8 = 0;
+pub(crate) const OP_PUT: u8 = 1;
+pub(crate) const OP_DELETE: u8 = 2;
+pub(crate) const OP_COMMIT: u8 = 3;
+
+/// Keys starting with this byte are reserved for the Db's own bookkeeping
+/// (secondary index entries); they are hidden from scans and rejected in
+/// user transactions.
+pub(crate) const SYSTEM_PREFIX: u8 = 0xFF;
 
 pub struct Db {
-    wal_reader: BufReader<File>,
-    wal_writer: BufWriter<File>,
-    data_reader: BufReader<File>,
-    data_writer: BufWriter<File>,
-    index: BTreeMap<Bytes, u64>,
-    data_writer_pos: u64,
+    dir: PathBuf,
+    pub(crate) vfs: Arc<dyn Vfs>,
+    wal_writer: BufWriter<VfsWriter>,
+    /// Appends to the active data log segment.
+    pub(crate) data_writer: BufWriter<VfsWriter>,
+    pub(crate) index: BTreeMap<Bytes, RecordPos>,
+    pub(crate) data_writer_pos: u64,
+    pub(crate) active_segment: u64,
+    /// Every other live segment, oldest first.
+    pub(crate) sealed: Vec<Segment>,
+    pub(crate) segment_size: u64,
+    /// Number of the manifest in effect; 0 before the first is written.
+    pub(crate) manifest_number: u64,
+    /// Seq the change log restarted at when the data was last replaced
+    /// wholesale, recorded in the manifest; 0 if it never was.
+    pub(crate) history_start: u64,
+    /// Random id recorded in the manifest at the first backup; 0 until then.
+    pub(crate) id: u64,
+    pub(crate) secondary: SecondaryIndexes,
+    pub(crate) watchers: Watchers,
+    pub(crate) changes: ChangeLog,
+    pub(crate) archive: Option<Archive>,
+    pub(crate) clock: Arc<dyn Clock>,
+    seq: u64,
+    /// Set when a commit failed after reaching the WAL: the in-memory state
+    /// may no longer match the files until the Db is reopened.
+    pub(crate) poisoned: bool,
 }
 
 impl Db {
-    const DATA_PATH: &'static str = "./data.log";
-    const WAL_PATH: &'static str = "./wal.log";
+    pub(crate) const DATA_FILE: &'static str = "data.log";
+    pub(crate) const WAL_FILE: &'static str = "wal.log";
+    pub(crate) const CHANGES_FILE: &'static str = "changes.log";
+
+    /// Directory of every in-memory Db, inside its own filesystem.
+    const MEMORY_DIR: &'static str = ":memory:";
 
+    /// Opens the Db in the current directory.
     pub fn new() -> Result<Self> {
-        let mut data_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(Self::DATA_PATH)?;
-        
-        let mut wal_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(Self::WAL_PATH)?;
-        
-        Self::process_wal(&mut wal_file, &mut data_file)?;
-        data_file.seek(SeekFrom::Start(0))?;
-        let (index, data_writer_pos) = Self::build_index(&mut data_file)?;
-
-        let mut wal_read_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(Self::WAL_PATH)?;
-        
-        let mut wal_write_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(Self::WAL_PATH)?;
-        
-        let mut data_read_file = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(Self::DATA_PATH)?;
-                
-        let mut data_write_file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(Self::DATA_PATH)?;
-        
-        let wal_reader = BufReader::new(wal_read_file);
-        let wal_writer = BufWriter::new(wal_write_file);
-        let data_reader = BufReader::new(data_read_file);
-        let data_writer = BufWriter::new(data_write_file);
-        
-        Ok(Self { wal_reader, wal_writer, data_reader, data_writer, index, data_writer_pos })
-    }
-
-    fn process_wal(wal: &mut File, data: &mut File) -> Result<()> {
-        // read the entire wal file [OP][..][COMMIT]
-        wal.seek(SeekFrom::Start(0))?;
-        data.seek(SeekFrom::End(0))?;
+        Self::open(".")
+    }
+
+    /// Opens the Db stored in `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        Self::open_with_vfs(Arc::new(OsFs), dir)
+    }
+
+    /// Opens an empty Db that keeps everything in memory and writes no
+    /// files. It is dropped with the `Db`; use [`Db::persist_to`] to keep it.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::open_with_vfs(Arc::new(MemFs::new()), Self::MEMORY_DIR)
+    }
+
+    /// Opens the Db stored in `dir` on `vfs`, e.g. a [`FaultFs`] to test how
+    /// the Db handles failing or crashing storage.
+    ///
+    /// [`FaultFs`]: crate::vfs::FaultFs
+    pub fn open_with_vfs<P: AsRef<Path>>(vfs: Arc<dyn Vfs>, dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        vfs.create_dir_all(&dir)?;
+        let current = read_current(vfs.as_ref(), &dir)?;
+        let has_manifest = current.is_some();
+        let (manifest_number, manifest) = current.unwrap_or_default();
+        let Some((&active_segment, sealed_ids)) = manifest.segments.split_last() else {
+            return Err(Error::new(io::ErrorKind::InvalidData, "the manifest lists no data log segments"));
+        };
+        if has_manifest && manifest.format < FORMAT_VERSION {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the Db in {} uses format version {}; upgrade it with `upgrade::upgrade_db`",
+                    dir.display(),
+                    manifest.format
+                ),
+            ));
+        }
+        if has_manifest {
+            for &id in &manifest.segments {
+                let name = segment_file_name(id);
+                if !vfs.exists(&dir.join(&name))? {
+                    return Err(Error::new(io::ErrorKind::NotFound, format!("data log segment {name} is missing")));
+                }
+            }
+        }
+
+        // Sealed segments were synced in full before they were sealed. Their
+        // hint files, where usable, spare reading the values.
+        let mut index = BTreeMap::new();
+        let mut sealed = Vec::with_capacity(sealed_ids.len());
+        for &id in sealed_ids {
+            let name = segment_file_name(id);
+            let file = vfs.open(&dir.join(&name))?;
+            check_file_header(file.as_ref(), FileKind::DataLog, &dir.join(&name))?;
+            let len = file.len()?;
+            if let Some(hints) = read_hints(vfs.as_ref(), &dir.join(hint_file_name(id)), len)? {
+                apply_hints(hints, id, &mut index);
+            } else if Self::index_records(file.as_ref(), id, HEADER_LEN, &mut index)? != len {
+                return Err(Error::new(io::ErrorKind::InvalidData, format!("data log segment {name} ends in a torn record")));
+            }
+            sealed.push(Segment { id, file, len });
+        }
+
+        let data_path = dir.join(segment_file_name(active_segment));
+        let mut data_file = VfsWriter(vfs.open(&data_path)?);
+        init_header(data_file.file_mut(), FileKind::DataLog, &data_path)?;
+        let wal_path = dir.join(Self::WAL_FILE);
+        let mut wal_file = vfs.open(&wal_path)?;
+        init_header(wal_file.as_mut(), FileKind::Wal, &wal_path)?;
+
+        // A crash while appending can leave a torn record at the end of the
+        // active segment; cut it off so replayed records follow the last
+        // whole one. The data log is synced before the WAL is cleared, so
+        // that can only be part of a batch the WAL still holds; anything
+        // else after the last whole record is a corrupt record, not a tear.
+        let (replayed, torn_txn) = Self::read_wal(wal_file.as_ref())?;
+        let valid_len = Self::index_records(data_file.file(), active_segment, HEADER_LEN, &mut index)?;
+        let torn = data_file.file().len()? - valid_len;
+        if torn > 0 {
+            let pending: u64 = replayed.iter().flat_map(|(_, ops)| ops).map(Op::record_len).sum();
+            if torn >= pending {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "data log segment {} has a corrupt record at offset {valid_len}",
+                        segment_file_name(active_segment)
+                    ),
+                ));
+            }
+            data_file.file_mut().truncate(valid_len)?;
+            data_file.file_mut().sync()?;
+        }
+        Self::replay_wal(&replayed, &mut data_file)?;
+
+        // A crash after the WAL was synced may or may not have reached the
+        // change log; only append batches it hasn't seen. A transaction the
+        // change log has was synced in the WAL first, so it can't be torn.
+        let pending = replayed.iter().map(|(seq, ops)| encode_batch(*seq, ops).len() as u64).sum();
+        let mut changes = ChangeLog::open(vfs.clone(), dir.join(Self::CHANGES_FILE), pending)?;
+        if let Some(seq) = torn_txn.filter(|&seq| changes.last_seq().is_some_and(|last| seq <= last)) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("wal.log: transaction {seq} is cut short, but the change log already has it"),
+            ));
+        }
+        for (seq, ops) in replayed {
+            if changes.last_seq().is_none_or(|last| seq > last) {
+                changes.append(seq, &ops)?;
+            }
+        }
+        // Replacing the data log wholesale commits in the manifest before the
+        // change log restarts; finish a restart that a crash cut off.
+        if manifest.history_start > 0 && changes.last_seq().is_none_or(|last| last < manifest.history_start) {
+            changes.restart(manifest.history_start)?;
+        }
+        // Replacing the data log wholesale empties the change log, so the
+        // manifest's sequence number is a floor. A change log that ends
+        // before it belongs to some other state of the Db.
+        if let Some(last) = changes.last_seq().filter(|&last| last < manifest.last_seq) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the change log ends at seq {last}, before the manifest's seq {}", manifest.last_seq),
+            ));
+        }
+        let seq = changes.last_seq().unwrap_or(0).max(manifest.last_seq);
+
+        wal_file.truncate(HEADER_LEN)?;
+        wal_file.sync()?;
+
+        let data_writer_pos = Self::index_records(data_file.file(), active_segment, valid_len, &mut index)?;
+
+        let wal_writer = BufWriter::new(VfsWriter(wal_file));
+        let data_writer = BufWriter::new(data_file);
+
+        let mut db = Self {
+            dir,
+            vfs,
+            wal_writer,
+            data_writer,
+            index,
+            data_writer_pos,
+            active_segment,
+            sealed,
+            segment_size: manifest.segment_size,
+            manifest_number,
+            history_start: manifest.history_start,
+            id: manifest.id,
+            secondary: SecondaryIndexes::default(),
+            watchers: Watchers::default(),
+            changes,
+            archive: None,
+            clock: Arc::new(SystemClock),
+            seq,
+            poisoned: false,
+        };
+        if !has_manifest {
+            db.write_manifest(&manifest.segments)?;
+        }
+        db.load_index_definitions()?;
+        Ok(db)
+    }
+
+    /// Every committed WAL transaction, with its sequence number, and the
+    /// sequence number of a torn transaction after them, which is left out.
+    fn read_wal(wal: &dyn VfsFile) -> Result<(Batches, Option<u64>)> {
+        // read the entire wal file [BEGIN seq][..][COMMIT]
+        let wal = &mut BufReader::new(VfsReader::new(wal, HEADER_LEN));
         let mut in_txn = false;
+        let mut txn_seq: u64 = 0;
         let mut txn: Vec<Op> = Vec::new();
+        let mut replayed = Vec::new();
         loop {
             let mut op_buf = [0u8; 1];
             match wal.read_exact(&mut op_buf) {
@@ -85,6 +250,9 @@ impl Db {
             }
             match op_buf[0] {
                 OP_BEGIN => {
+                    let mut seq_buf = [0u8; 8];
+                    if !read_exact_or_break(wal, &mut seq_buf)? { break; }
+                    txn_seq = u64::from_le_bytes(seq_buf);
                     in_txn = true;
                     txn.clear();
                 }, 
@@ -100,10 +268,8 @@ impl Db {
                     if !read_exact_or_break(wal, &mut val_len)? { break; }
                     let vlen = u32::from_le_bytes(val_len) as usize;
 
-                    let mut key_buf = vec![0u8; klen];
-                    let mut val_buf = vec![0u8; vlen];
-                    if !read_exact_or_break(wal, &mut key_buf)? { break; }
-                    if !read_exact_or_break(wal, &mut val_buf)? { break; }
+                    let Some(key_buf) = read_field_or_break(wal, klen)? else { break; };
+                    let Some(val_buf) = read_field_or_break(wal, vlen)? else { break; };
 
                     txn.push(Op::Set(key_buf, val_buf));
 
@@ -123,8 +289,7 @@ impl Db {
                         return Err(Error::new(io::ErrorKind::InvalidData, "DELETE vlen != 0"));
                     }
 
-                    let mut key_buf = vec![0u8; klen];
-                    if !read_exact_or_break(wal, &mut key_buf)? { break; }
+                    let Some(key_buf) = read_field_or_break(wal, klen)? else { break; };
 
                     txn.push(Op::Delete(key_buf));
                     
@@ -133,45 +298,7 @@ impl Db {
                     if !in_txn {
                         return Err(Error::new(io::ErrorKind::InvalidData, "COMMIT outside txn"));
                     }
-                    for t in txn.drain(..) {
-                        match t {
-                            Op::Set(key, value) => {
-                                let op: u8 = OP_PUT;
-
-                                let key_bytes = key.as_slice();
-                                let val_bytes = value.as_slice();
-
-                                let key_len = key_bytes.len() as u32;
-                                let key_len_bytes = key_len.to_le_bytes();
-
-                                let val_len = val_bytes.len() as u32;
-                                let val_len_bytes = val_len.to_le_bytes();
-
-                                data.write_all(&[op])?;
-                                data.write_all(&key_len_bytes)?;
-                                data.write_all(&val_len_bytes)?;
-                                data.write_all(key_bytes)?;
-                                data.write_all(val_bytes)?;
-                            },
-                            Op::Delete(key) => {
-                                let op: u8 = OP_DELETE;
-                                let key_bytes = key.as_slice();
-
-                                let key_len = key_bytes.len() as u32;
-                                let key_len_bytes = key_len.to_le_bytes();
-
-                                let val_len_bytes = 0u32.to_le_bytes();
-
-                                data.write_all(&[op])?;
-                                data.write_all(&key_len_bytes)?;
-                                data.write_all(&val_len_bytes)?;
-                                data.write_all(key_bytes)?;
-                            }
-                        }
-                        
-                    }
-                    data.flush()?;
-                    data.sync_all()?;
+                    replayed.push((txn_seq, std::mem::take(&mut txn)));
                     in_txn = false;
                 },
                 other => {
@@ -182,65 +309,190 @@ impl Db {
                 }
             }
         }
-        wal.set_len(0)?;
-        wal.seek(SeekFrom::Start(0))?;
-        wal.sync_all()?;
+        Ok((replayed, in_txn.then_some(txn_seq)))
+    }
 
+    /// Re-applies the `replayed` WAL transactions to the data log. The caller
+    /// clears the WAL.
+    fn replay_wal(replayed: &[(u64, Vec<Op>)], data: &mut VfsWriter) -> Result<()> {
+        for (_, ops) in replayed {
+            for op in ops {
+                match op {
+                    Op::Set(key, value) => write_record(data, OP_PUT, key, value)?,
+                    Op::Delete(key) => write_record(data, OP_DELETE, key, &[])?,
+                }
+            }
+            data.file_mut().sync()?;
+        }
         Ok(())
     }
 
-    fn build_index(file: &mut File) -> io::Result<(BTreeMap<Bytes, u64>, u64)> {
-        let mut index = BTreeMap::new();
-        let mut offset: u64 = 0;
+    /// Applies the records of `segment` from `start` on to `index` and
+    /// returns the offset just past the last complete one; a torn record at
+    /// the end is not counted.
+    pub(crate) fn index_records(
+        file: &dyn VfsFile,
+        segment: u64,
+        start: u64,
+        index: &mut BTreeMap<Bytes, RecordPos>,
+    ) -> io::Result<u64> {
+        Self::scan_records(file, start, |op, key, offset, _| {
+            match op {
+                OP_PUT => {
+                    index.insert(key, RecordPos { segment, offset });
+                },
+                OP_DELETE => {
+                    index.remove(&key);
+                },
+                KvStore::OP_PUT => {
+                    return Err(Error::new(
+                        io::ErrorKind::InvalidData,
+                        "data.log was written by KvStore; convert it with `migrate::migrate_kvstore`",
+                    ));
+                }
+                other => {
+                    return Err(Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown opcode {other} in data.log at offset {offset}"),
+                    ));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Calls `visit(op, key, offset, value length)` for each whole record in
+    /// `file` from `start`, without reading values. Returns the offset after
+    /// the last one.
+    pub(crate) fn scan_records(
+        file: &dyn VfsFile,
+        start: u64,
+        mut visit: impl FnMut(u8, Bytes, u64, u64) -> io::Result<()>,
+    ) -> io::Result<u64> {
+        let reader = &mut BufReader::new(VfsReader::new(file, start));
+        let mut offset = start;
 
         loop {
             let entry_start = offset;
             let mut op_buf = [0u8; 1];
-            if !read_exact_or_break(file, &mut op_buf)? { break; }
+            if !read_exact_or_break(reader, &mut op_buf)? { break; }
             let op = op_buf[0];
-            offset += 1;
             
             let mut len_buf = [0u8; 4];
-            if !read_exact_or_break(file, &mut len_buf)? { break; }
+            if !read_exact_or_break(reader, &mut len_buf)? { break; }
             let key_len = u32::from_le_bytes(len_buf) as u64;
-            offset += 4;
 
-            if !read_exact_or_break(file, &mut len_buf)? { break; }
+            if !read_exact_or_break(reader, &mut len_buf)? { break; }
             let val_len = u32::from_le_bytes(len_buf) as u64;
-            offset += 4;
 
-            let mut key_buf = vec![0u8; key_len as usize];
-            if !read_exact_or_break(file, &mut key_buf)? { break; }
-            offset += key_len;
+            let Some(key_buf) = read_field_or_break(reader, key_len as usize)? else { break; };
+            // Only the key is kept; skip the value without buffering it.
+            if io::copy(&mut reader.take(val_len), &mut io::sink())? != val_len { break; }
+            offset = entry_start + 9 + key_len + val_len;
+            visit(op, key_buf, entry_start, val_len)?;
+        }
 
-            let mut val_buf = vec![0u8; val_len as usize];
-            if val_len > 0 {
-                if !read_exact_or_break(file, &mut val_buf)? { break; }
-            }
-            offset += val_len;
+        Ok(offset)
+    }
+
+    pub(crate) fn commit(&mut self, ops: Vec<Op>) -> Result<()> {
+        if ops.iter().any(|op| is_system_key(op.key())) {
+            return Err(Error::new(io::ErrorKind::InvalidInput, "keys starting with 0xFF are reserved"));
+        }
+        self.commit_unchecked(ops)
+    }
+
+    /// `commit` without the reserved keyspace check, for batches that carry
+    /// the Db's own bookkeeping alongside user writes.
+    pub(crate) fn commit_unchecked(&mut self, ops: Vec<Op>) -> Result<()> {
+        let changes = if self.has_secondary_indexes() || !self.watchers.is_empty() {
+            self.net_changes(&ops)?
+        } else {
+            Vec::new()
+        };
+        let ops = self.with_index_ops(ops, &changes);
+        self.write_batch(ops)?;
+        self.index_definitions_committed();
+        self.watchers.notify(self.seq, &changes);
+        Ok(())
+    }
+
+    /// Directory holding the Db's files; for an in-memory Db, a placeholder.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Replaces the clock used for commit timestamps and backup schedules,
+    /// e.g. with a [`SimClock`](crate::clock::SimClock) in a simulation.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sequence number of the most recent commit.
+    pub fn last_seq(&self) -> u64 {
+        self.seq
+    }
 
+    /// Overrides the sequence number when it is known better than the
+    /// change log, which may have been reset (e.g. a restored backup).
+    pub(crate) fn set_last_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
+    /// Net effect of `ops` on each user key they touch, against the committed
+    /// state. System keys are left out.
+    pub(crate) fn net_changes(&mut self, ops: &[Op]) -> Result<Vec<KeyChange>> {
+        let mut final_values: BTreeMap<&[u8], Option<&Bytes>> = BTreeMap::new();
+        for op in ops.iter().filter(|op| !is_system_key(op.key())) {
             match op {
-                OP_PUT => {
-                    index.insert(key_buf, entry_start);
-                },
-                OP_DELETE => {
-                    index.remove(&key_buf);
-                },
-                _ => {}
-            }
+                Op::Set(k, v) => final_values.insert(k, Some(v)),
+                Op::Delete(k) => final_values.insert(k, None),
+            };
         }
 
-        Ok((index, offset))
+        let mut changes = Vec::with_capacity(final_values.len());
+        for (key, new) in final_values {
+            let old = self.get(key)?;
+            changes.push(KeyChange { key: key.to_vec(), old, new: new.cloned() });
+        }
+        Ok(changes)
+    }
+
+    /// Makes `ops` durable as one WAL transaction and applies them to the data log.
+    pub(crate) fn write_batch(&mut self, ops: Vec<Op>) -> Result<()> {
+        let seq = self.seq.checked_add(1).ok_or_else(|| Error::new(io::ErrorKind::InvalidData, "sequence numbers exhausted"))?;
+        self.write_batch_at(seq, ops)
+    }
+
+    /// Like `write_batch` but with an explicit sequence number, for batches
+    /// that were sequenced elsewhere (replication).
+    pub(crate) fn write_batch_at(&mut self, seq: u64, ops: Vec<Op>) -> Result<()> {
+        if self.poisoned {
+            return Err(Error::other("an earlier commit failed part-way; reopen the Db to recover"));
+        }
+        if let Err(e) = self.write_wal(seq, &ops) {
+            // The batch may not be durable; drop it so that neither the next
+            // commit nor recovery applies it.
+            if self.discard_wal().is_err() {
+                self.poisoned = true;
+            }
+            return Err(e);
+        }
+        // From here on recovery will apply the batch from the WAL even if
+        // the rest fails.
+        let result = self.apply_batch(seq, ops);
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result
     }
 
-    fn commit(&mut self, ops: Vec<Op>) -> Result<()> {
+    fn write_wal(&mut self, seq: u64, ops: &[Op]) -> Result<()> {
         // write to WAL (begin, set/delete, commit)
-        // Write to DATA
-        // update index
-        self.append_begin()?;
+        self.append_begin(seq)?;
         println!("Wrote OP_BEGIN to WAL");
 
-        for op in &ops {
+        for op in ops {
             // 
             match op {
                 Op::Set(k, v) => {
@@ -258,9 +510,22 @@ impl Db {
         println!("Wrote OP_COMMIT to WAL buffer");
 
         self.wal_writer.flush()?;
-        self.wal_writer.get_ref().sync_all()?;
+        self.wal_writer.get_mut().file_mut().sync()?;
 
         println!("Synced buffer contents with disk");
+        Ok(())
+    }
+
+    fn apply_batch(&mut self, seq: u64, ops: Vec<Op>) -> Result<()> {
+        if self.data_writer_pos >= self.segment_size {
+            self.roll_over()?;
+        }
+        // Write to DATA
+        // update index
+        self.changes.append(seq, &ops)?;
+        if let Some(archive) = &mut self.archive {
+            archive.append(seq, &ops, self.clock.now())?;
+        }
 
         for op in ops {
             match op {
@@ -274,15 +539,17 @@ impl Db {
         }
 
         self.data_writer.flush()?;
-        self.data_writer.get_ref().sync_all()?;
+        self.data_writer.get_mut().file_mut().sync()?;
 
         self.clear_wal()?;
+        self.seq = seq;
 
         Ok(())
     }
 
-    fn append_begin(&mut self) -> Result<()> {
+    fn append_begin(&mut self, seq: u64) -> Result<()> {
         self.wal_writer.write_all(&[OP_BEGIN])?;
+        self.wal_writer.write_all(&seq.to_le_bytes())?;
         Ok(())
     }
 
@@ -332,7 +599,7 @@ impl Db {
 
         self.data_writer.flush()?;
 
-        self.index.insert(key, self.data_writer_pos);
+        self.index.insert(key, RecordPos { segment: self.active_segment, offset: self.data_writer_pos });
         self.data_writer_pos += 1 + 4 + 4 + key_len as u64 + val_len as u64;
 
         Ok(())
@@ -360,12 +627,26 @@ impl Db {
         Ok(())
     }
 
+    /// Keys and data log positions of every live record, system keys
+    /// included.
+    pub(crate) fn live_records(&self) -> Vec<(Bytes, RecordPos)> {
+        self.index.iter().map(|(k, &pos)| (k.clone(), pos)).collect()
+    }
+
+    /// Throws away everything written to the WAL since it was last cleared,
+    /// including anything still buffered.
+    fn discard_wal(&mut self) -> Result<()> {
+        let file = self.vfs.open(&self.dir.join(Self::WAL_FILE))?;
+        // Dropping the old writer flushes its buffer; truncating comes after.
+        self.wal_writer = BufWriter::new(VfsWriter(file));
+        self.clear_wal()
+    }
+
     fn clear_wal(&mut self) -> io::Result<()> {
         self.wal_writer.flush()?;
-        let f = self.wal_writer.get_mut();
-        f.set_len(0)?;
-        f.seek(SeekFrom::Start(0))?;
-        f.sync_all()?;
+        let f = self.wal_writer.get_mut().file_mut();
+        f.truncate(HEADER_LEN)?;
+        f.sync()?;
         Ok(())
     }
 
@@ -373,29 +654,87 @@ impl Db {
     where K: AsRef<[u8]>,
     {
         let key_bytes = key.as_ref();
-        let Some(&offset) = self.index.get(key_bytes) else {
+        let Some(&pos) = self.index.get(key_bytes) else {
             return Ok(None);
         };
 
-        self.data_reader.seek(SeekFrom::Start(offset))?;
+        self.read_value(pos).map(Some)
+    }
 
-        let mut op_buf = [0u8; 1];
-        self.data_reader.read_exact(&mut op_buf)?;
-        let _op = op_buf[0];
+    /// Returns every live key/value pair whose key falls in `range`, in key order.
+    pub fn scan<R>(&mut self, range: R) -> Result<Vec<(Bytes, Bytes)>>
+    where R: RangeBounds<Bytes>,
+    {
+        self.scan_limit(range, usize::MAX)
+    }
 
-        let mut len_buf = [0u8; 4];
-        self.data_reader.read_exact(&mut len_buf)?;
-        let key_len = u32::from_le_bytes(len_buf) as usize;
-        self.data_reader.read_exact(&mut len_buf)?;
-        let val_len = u32::from_le_bytes(len_buf) as usize;
+    /// Returns every live key/value pair whose key starts with `prefix`.
+    pub fn scan_prefix<P>(&mut self, prefix: P) -> Result<Vec<(Bytes, Bytes)>>
+    where P: AsRef<[u8]>,
+    {
+        self.scan(prefix_range(prefix.as_ref()))
+    }
 
-        let mut key_buf = vec![0u8; key_len];
-        let mut val_buf = vec![0u8; val_len];
+    pub(crate) fn scan_limit<R>(&mut self, range: R, limit: usize) -> Result<Vec<(Bytes, Bytes)>>
+    where R: RangeBounds<Bytes>,
+    {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let entries: Vec<(Bytes, RecordPos)> = self.index
+            .range(range)
+            .take_while(|(k, _)| !is_system_key(k))
+            .take(limit)
+            .map(|(k, &pos)| (k.clone(), pos))
+            .collect();
+
+        self.read_entries(entries)
+    }
+
+    /// Like `scan_limit` but includes the reserved system keyspace.
+    pub(crate) fn scan_raw<R>(&mut self, range: R) -> Result<Vec<(Bytes, Bytes)>>
+    where R: RangeBounds<Bytes>,
+    {
+        if is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        let entries: Vec<(Bytes, RecordPos)> = self.index
+            .range(range)
+            .map(|(k, &pos)| (k.clone(), pos))
+            .collect();
 
-        self.data_reader.read_exact(&mut key_buf)?;
-        self.data_reader.read_exact(&mut val_buf)?;
+        self.read_entries(entries)
+    }
 
-        Ok(Some(val_buf))
+    fn read_entries(&mut self, entries: Vec<(Bytes, RecordPos)>) -> Result<Vec<(Bytes, Bytes)>> {
+        let mut out = Vec::with_capacity(entries.len());
+        for (key, pos) in entries {
+            let value = self.read_value(pos)?;
+            out.push((key, value));
+        }
+        Ok(out)
+    }
+
+    pub(crate) fn read_value(&mut self, pos: RecordPos) -> Result<Bytes> {
+        let file = if pos.segment == self.active_segment {
+            self.data_writer.get_ref().file()
+        } else {
+            match self.sealed.iter().find(|s| s.id == pos.segment) {
+                Some(segment) => segment.file.as_ref(),
+                None => return Err(Error::other(format!("data log segment {} is not open", pos.segment))),
+            }
+        };
+        let offset = pos.offset;
+
+        let mut header = [0u8; 9];
+        read_exact_at(file, &mut header, offset)?;
+        let key_len = u32::from_le_bytes(header[1..5].try_into().expect("4 bytes")) as u64;
+        let val_len = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes")) as usize;
+
+        let mut val_buf = vec![0u8; val_len];
+        read_exact_at(file, &mut val_buf, offset + 9 + key_len)?;
+
+        Ok(val_buf)
     }
 
     pub fn begin_transaction(&mut self) -> Transaction<'_> {
@@ -407,11 +746,34 @@ impl Db {
 }
 
 
-enum Op {
+pub(crate) enum Op {
     Set(Bytes, Bytes), 
     Delete(Bytes)
 }
 
+/// Net effect of one transaction on one key; `None` means absent.
+pub(crate) struct KeyChange {
+    pub key: Bytes,
+    pub old: Option<Bytes>,
+    pub new: Option<Bytes>,
+}
+
+impl Op {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            Op::Set(k, _) | Op::Delete(k) => k,
+        }
+    }
+
+    /// Length of the data log record for this op.
+    pub(crate) fn record_len(&self) -> u64 {
+        match self {
+            Op::Set(k, v) => 9 + k.len() as u64 + v.len() as u64,
+            Op::Delete(k) => 9 + k.len() as u64,
+        }
+    }
+}
+
 pub struct Transaction<'db> {
     db: &'db mut Db,
     operations: Vec<Op>, 
@@ -438,15 +800,121 @@ impl<'db> Transaction<'db> {
         println!("Added DELETE to Transaction OPS");
     }
 
+    /// Reads `key` as this transaction sees it: its own pending writes first,
+    /// then the committed state of the Db.
+    pub fn get<K>(&mut self, key: K) -> Result<Option<Bytes>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        for op in self.operations.iter().rev() {
+            match op {
+                Op::Set(k, v) if k.as_slice() == key => return Ok(Some(v.clone())),
+                Op::Delete(k) if k.as_slice() == key => return Ok(None),
+                _ => {}
+            }
+        }
+        self.db.get(key)
+    }
+
+    /// Scans `range` with this transaction's pending writes applied on top of
+    /// the committed state.
+    pub fn scan<R>(&mut self, range: R) -> Result<Vec<(Bytes, Bytes)>>
+    where
+        R: RangeBounds<Bytes>,
+    {
+        let mut pending: BTreeMap<&Bytes, Option<&Bytes>> = BTreeMap::new();
+        for op in &self.operations {
+            match op {
+                Op::Set(k, v) if range.contains(k) => { pending.insert(k, Some(v)); },
+                Op::Delete(k) if range.contains(k) => { pending.insert(k, None); },
+                _ => {}
+            }
+        }
+
+        let mut merged: BTreeMap<Bytes, Bytes> = self.db.scan(range)?.into_iter().collect();
+        for (k, v) in pending {
+            match v {
+                Some(v) => { merged.insert(k.clone(), v.clone()); },
+                None => { merged.remove(k); },
+            }
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    pub fn scan_prefix<P>(&mut self, prefix: P) -> Result<Vec<(Bytes, Bytes)>>
+    where
+        P: AsRef<[u8]>,
+    {
+        self.scan(prefix_range(prefix.as_ref()))
+    }
+
     pub fn commit(self) -> Result<()> {
         self.db.commit(self.operations)
     }
 }
 
-fn read_exact_or_break(file: &mut File, buf: &mut [u8]) -> io::Result<bool> {
+fn read_exact_or_break<R: Read>(file: &mut R, buf: &mut [u8]) -> io::Result<bool> {
     match file.read_exact(buf) {
         Ok(()) => Ok(true),
         Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
         Err(e) => Err(e),
     }
-}
\ No newline at end of file
+}
+
+/// Reads a `len`-byte field whose length came from the input. Memory is
+/// allocated as bytes arrive, so a corrupt length fails with
+/// [`io::ErrorKind::UnexpectedEof`] instead of a huge allocation.
+pub(crate) fn read_field<R: Read>(reader: &mut R, len: usize) -> io::Result<Bytes> {
+    let mut buf = Vec::with_capacity(len.min(64 * 1024));
+    if reader.take(len as u64).read_to_end(&mut buf)? != len {
+        return Err(Error::new(io::ErrorKind::UnexpectedEof, "record extends past the end of the input"));
+    }
+    Ok(buf)
+}
+
+fn read_field_or_break<R: Read>(reader: &mut R, len: usize) -> io::Result<Option<Bytes>> {
+    match read_field(reader, len) {
+        Ok(buf) => Ok(Some(buf)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether no key can fall in `range`. `BTreeMap::range` panics on some
+/// such ranges (start after end), so scans check this first.
+pub(crate) fn is_empty_range<T: Ord, R: RangeBounds<T>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        _ => false,
+    }
+}
+
+pub(crate) fn is_system_key(key: &[u8]) -> bool {
+    key.first() == Some(&SYSTEM_PREFIX)
+}
+
+/// Writes one data log record: `[op][klen][vlen][key][value]`.
+pub(crate) fn write_record<W: Write>(w: &mut W, op: u8, key: &[u8], value: &[u8]) -> Result<()> {
+    w.write_all(&[op])?;
+    w.write_all(&(key.len() as u32).to_le_bytes())?;
+    w.write_all(&(value.len() as u32).to_le_bytes())?;
+    w.write_all(key)?;
+    w.write_all(value)?;
+    Ok(())
+}
+
+/// Range covering every key that starts with `prefix`.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Bytes>, Bound<Bytes>) {
+    let start = Bound::Included(prefix.to_vec());
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (start, Bound::Excluded(end));
+        }
+    }
+    (start, Bound::Unbounded)
+}
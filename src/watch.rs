@@ -0,0 +1,66 @@
+//! Change notifications for committed transactions.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::wal_kv::{Db, KeyChange};
+
+type Bytes = Vec<u8>;
+
+/// One key changed by a committed transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// Sequence number of the commit that made the change.
+    pub seq: u64,
+    pub key: Bytes,
+    /// Value before the commit, `None` if the key was absent.
+    pub old: Option<Bytes>,
+    /// Value after the commit, `None` if the key was deleted.
+    pub new: Option<Bytes>,
+}
+
+#[derive(Default)]
+pub(crate) struct Watchers {
+    subscribers: Vec<(Bytes, Sender<ChangeEvent>)>,
+}
+
+impl Watchers {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Sends each change to every subscriber whose prefix matches, dropping
+    /// subscribers whose receiver has gone away. Writes that left a key as it
+    /// was are not reported.
+    pub(crate) fn notify(&mut self, seq: u64, changes: &[KeyChange]) {
+        self.subscribers.retain(|(prefix, tx)| {
+            changes
+                .iter()
+                .filter(|c| c.old != c.new && c.key.starts_with(prefix))
+                .all(|c| {
+                    tx.send(ChangeEvent {
+                        seq,
+                        key: c.key.clone(),
+                        old: c.old.clone(),
+                        new: c.new.clone(),
+                    })
+                    .is_ok()
+                })
+        });
+    }
+}
+
+impl Db {
+    /// Subscribes to changes under `prefix` (empty for every key).
+    ///
+    /// Events are sent once the commit is durable, one per key the
+    /// transaction changed, in commit order. Dropping the receiver
+    /// unsubscribes.
+    pub fn watch<P>(&mut self, prefix: P) -> Receiver<ChangeEvent>
+    where
+        P: AsRef<[u8]>,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.watchers.subscribers.push((prefix.as_ref().to_vec(), tx));
+        rx
+    }
+}
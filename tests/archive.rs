@@ -0,0 +1,244 @@
+mod common;
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use rust_embedded_kv_store::{Db, FaultFs, RecoveryTarget, SimClock, Vfs};
+
+use common::{set, TempDir};
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An archived Db, a base backup taken after its third commit, and the
+/// Db's contents after each commit (index 0 being empty).
+struct Archived {
+    _dir: TempDir,
+    archive: TempDir,
+    base: TempDir,
+    states: Vec<Pairs>,
+}
+
+/// Ten commits, one second apart, each overwriting `counter` and adding a key.
+fn archived(name: &str) -> Archived {
+    let dir = TempDir::new(&format!("{name}-db"));
+    let archive = TempDir::new(&format!("{name}-archive"));
+    let base = TempDir::new(&format!("{name}-base"));
+    let clock = SimClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+
+    let mut db = Db::open(&dir).unwrap();
+    db.set_clock(Arc::new(clock.clone()));
+    db.enable_archiving(&archive).unwrap();
+    let mut states = vec![Vec::new()];
+    for i in 1..=10 {
+        clock.advance(Duration::from_secs(1));
+        let mut tx = db.begin_transaction();
+        tx.set("counter", i.to_string());
+        tx.set(format!("key{i:02}"), "x");
+        tx.commit().unwrap();
+        states.push(db.scan(..).unwrap());
+        if i == 3 {
+            assert_eq!(db.backup_to(&base).unwrap(), 3);
+        }
+    }
+    Archived { _dir: dir, archive, base, states }
+}
+
+#[test]
+fn recovery_replays_up_to_a_sequence_number() {
+    let a = archived("pitr-seq");
+    for seq in [3, 4, 7, 10] {
+        let target = TempDir::new("pitr-seq-target");
+        let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Seq(seq)).unwrap();
+        assert_eq!(db.last_seq(), seq);
+        assert_eq!(db.scan(..).unwrap(), a.states[seq as usize]);
+    }
+
+    // Past the archive's end is the same as the latest state.
+    let target = TempDir::new("pitr-seq-target");
+    let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Seq(99)).unwrap();
+    assert_eq!(db.scan(..).unwrap(), a.states[10]);
+    let target = TempDir::new("pitr-seq-target");
+    let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Latest).unwrap();
+    assert_eq!(db.last_seq(), 10);
+    assert_eq!(db.scan(..).unwrap(), a.states[10]);
+}
+
+#[test]
+fn a_sequence_number_before_the_base_backup_is_refused() {
+    let a = archived("pitr-early");
+    let target = TempDir::new("pitr-early-target");
+    let e = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Seq(2)).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    // Nothing was restored, so the directory can be used again.
+    assert_eq!(std::fs::read_dir(&*target).unwrap().count(), 0);
+    let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Seq(3)).unwrap();
+    assert_eq!(db.scan(..).unwrap(), a.states[3]);
+}
+
+#[test]
+fn recovery_replays_up_to_a_time() {
+    let a = archived("pitr-time");
+    let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    // Commit `i` was made `i` seconds after the start; the bound is inclusive.
+    for (at, seq) in [(Duration::from_secs(5), 5), (Duration::from_millis(5_999), 5), (Duration::from_secs(6), 6)] {
+        let target = TempDir::new("pitr-time-target");
+        let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Time(start + at)).unwrap();
+        assert_eq!(db.last_seq(), seq, "{at:?}");
+        assert_eq!(db.scan(..).unwrap(), a.states[seq as usize]);
+    }
+
+    // A time before the backup leaves the backup as it is.
+    let target = TempDir::new("pitr-time-target");
+    let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Time(start)).unwrap();
+    assert_eq!(db.last_seq(), 3);
+    assert_eq!(db.scan(..).unwrap(), a.states[3]);
+}
+
+#[test]
+fn a_recovered_db_continues_from_the_replayed_seq() {
+    let a = archived("pitr-continue");
+    let target = TempDir::new("pitr-continue-target");
+    let mut db = Db::recover_to(&a.base, &a.archive, &target, RecoveryTarget::Seq(6)).unwrap();
+    set(&mut db, "after", "recovery");
+    assert_eq!(db.last_seq(), 7);
+    drop(db);
+
+    let mut db = Db::open(&target).unwrap();
+    assert_eq!(db.last_seq(), 7);
+    let mut want = a.states[6].clone();
+    want.insert(0, (b"after".to_vec(), b"recovery".to_vec()));
+    assert_eq!(db.scan(..).unwrap(), want);
+}
+
+#[test]
+fn commits_made_while_archiving_was_off_are_caught_up() {
+    let dir = TempDir::new("pitr-catch-up");
+    let archive = TempDir::new("pitr-catch-up-archive");
+    let base = TempDir::new("pitr-catch-up-base");
+    let mut db = Db::open(&dir).unwrap();
+    db.enable_archiving(&archive).unwrap();
+    set(&mut db, "a", "1");
+    db.backup_to(&base).unwrap();
+    set(&mut db, "b", "2");
+    drop(db);
+
+    // Reopened without archiving, then enabled again later.
+    let mut db = Db::open(&dir).unwrap();
+    set(&mut db, "c", "3");
+    db.enable_archiving(&archive).unwrap();
+    set(&mut db, "d", "4");
+    let want = db.scan(..).unwrap();
+
+    let target = TempDir::new("pitr-catch-up-target");
+    let mut recovered = Db::recover_to(&base, &archive, &target, RecoveryTarget::Latest).unwrap();
+    assert_eq!(recovered.last_seq(), 4);
+    assert_eq!(recovered.scan(..).unwrap(), want);
+}
+
+/// Length of the archive record for a commit setting a two-byte key to a
+/// one-byte value: timestamp, BEGIN and seq, the PUT, COMMIT.
+const RECORD_LEN: u64 = 8 + 9 + 12 + 1;
+
+fn commit(db: &mut Db, i: u64) -> std::io::Result<()> {
+    let mut tx = db.begin_transaction();
+    tx.set(format!("k{}", i % 10), "v");
+    tx.commit()
+}
+
+fn only_segment(archive: &Path) -> PathBuf {
+    let mut paths: Vec<PathBuf> = fs::read_dir(archive).unwrap().map(|e| e.unwrap().path()).collect();
+    assert_eq!(paths.len(), 1, "{paths:?}");
+    paths.pop().unwrap()
+}
+
+#[test]
+fn a_torn_last_record_is_dropped_and_archived_again() {
+    let dir = TempDir::new("archive-torn");
+    let archive = TempDir::new("archive-torn-archive");
+    let mut db = Db::open(&dir).unwrap();
+    db.enable_archiving(&archive).unwrap();
+    for i in 1..=5 {
+        commit(&mut db, i).unwrap();
+    }
+    drop(db);
+
+    // Half of the last record, as a crash while appending it leaves.
+    let segment = only_segment(&archive);
+    let full = fs::read(&segment).unwrap();
+    assert_eq!(full.len() as u64, 8 + 5 * RECORD_LEN);
+    fs::write(&segment, &full[..full.len() - RECORD_LEN as usize / 2]).unwrap();
+
+    let mut db = Db::open(&dir).unwrap();
+    db.enable_archiving(&archive).unwrap();
+    let catch_up = fs::read(&segment).unwrap();
+    assert_eq!(catch_up.len(), full.len());
+    // Only the re-archived commit's timestamp may differ.
+    assert_eq!(catch_up[..full.len() - RECORD_LEN as usize], full[..full.len() - RECORD_LEN as usize]);
+    commit(&mut db, 6).unwrap();
+}
+
+#[test]
+fn a_corrupt_record_before_the_end_is_not_truncated_away() {
+    let dir = TempDir::new("archive-corrupt");
+    let archive = TempDir::new("archive-corrupt-archive");
+    let mut db = Db::open(&dir).unwrap();
+    db.enable_archiving(&archive).unwrap();
+    for i in 1..=5 {
+        commit(&mut db, i).unwrap();
+    }
+    drop(db);
+
+    // The key length of the second record now points past the end.
+    let segment = only_segment(&archive);
+    let mut bytes = fs::read(&segment).unwrap();
+    let klen = (8 + RECORD_LEN + 8 + 9 + 1) as usize;
+    bytes[klen..klen + 4].copy_from_slice(&0x00FF_FFFFu32.to_le_bytes());
+    fs::write(&segment, &bytes).unwrap();
+
+    let mut db = Db::open(&dir).unwrap();
+    let e = db.enable_archiving(&archive).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(fs::read(&segment).unwrap(), bytes);
+
+    // Nor is a tail that isn't the start of the next commit.
+    bytes.truncate((8 + 5 * RECORD_LEN) as usize);
+    bytes.extend_from_slice(&[7; 12]);
+    fs::write(&segment, &bytes).unwrap();
+    let e = db.enable_archiving(&archive).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    assert_eq!(fs::read(&segment).unwrap(), bytes);
+}
+
+/// Bytes of archived records in `dir` on `fs`.
+fn archived_len(fs: &FaultFs, dir: &Path) -> u64 {
+    let mut total = 0;
+    for name in fs.list_dir(dir).unwrap() {
+        let len = fs.open(&dir.join(name)).unwrap().len().unwrap();
+        total += len.saturating_sub(8);
+    }
+    total
+}
+
+#[test]
+fn a_crash_while_archiving_leaves_every_commit_archived_once() {
+    for after in 0.. {
+        let fs = FaultFs::new();
+        let mut db = Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap();
+        db.enable_archiving("archive").unwrap();
+        fs.lose_power_after(Some(after));
+        let finished = (1..=3).all(|i| commit(&mut db, i).is_ok());
+        drop(db);
+        fs.crash_torn(after);
+
+        let mut db = Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap();
+        db.enable_archiving("archive").unwrap();
+        assert_eq!(archived_len(&fs, Path::new("archive")), db.last_seq() * RECORD_LEN, "after {after} operations");
+        commit(&mut db, 4).unwrap();
+        if finished {
+            break;
+        }
+    }
+}
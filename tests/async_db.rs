@@ -0,0 +1,157 @@
+#![cfg(feature = "async")]
+
+use std::future::Future;
+use std::io::ErrorKind;
+use std::ops::{Bound, RangeBounds};
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake};
+use std::thread::{self, Thread};
+
+use rust_embedded_kv_store::{AsyncDb, Db};
+
+/// Wakes the thread blocked in `block_on`.
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Minimal executor: polls `future` on this thread, parking until woken.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{i:04}").into_bytes()
+}
+
+/// An AsyncDb holding `key0000..key{n}`, each set to its own index.
+fn filled(n: usize) -> AsyncDb {
+    let db = AsyncDb::new(Db::open_in_memory().unwrap());
+    let mut tx = db.begin_transaction();
+    for i in 0..n {
+        tx.set(key(i), i.to_string());
+    }
+    block_on(tx.commit()).unwrap();
+    db
+}
+
+fn collect(db: &AsyncDb, range: impl RangeBounds<Vec<u8>>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut stream = db.scan(range);
+    let mut out = Vec::new();
+    block_on(async {
+        while let Some(entry) = stream.next().await {
+            out.push(entry.unwrap());
+        }
+    });
+    out
+}
+
+#[test]
+fn commits_resolve_once_gets_can_see_them() {
+    let db = AsyncDb::new(Db::open_in_memory().unwrap());
+    assert_eq!(block_on(db.get("a")).unwrap(), None);
+
+    let mut tx = db.begin_transaction();
+    tx.set("a", "1");
+    tx.set("b", "2");
+    // Nothing is visible before the commit runs.
+    assert_eq!(block_on(db.get("a")).unwrap(), None);
+    block_on(tx.commit()).unwrap();
+    assert_eq!(block_on(db.get("a")).unwrap(), Some(b"1".to_vec()));
+
+    let mut tx = db.begin_transaction();
+    tx.delete("a");
+    block_on(tx.commit()).unwrap();
+    assert_eq!(block_on(db.get("a")).unwrap(), None);
+    assert_eq!(block_on(db.get("b")).unwrap(), Some(b"2".to_vec()));
+
+    // Errors from the Db come back through the future.
+    let mut tx = db.begin_transaction();
+    tx.set([0xFF], "reserved");
+    assert_eq!(block_on(tx.commit()).err().unwrap().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn clones_commit_concurrently() {
+    let db = AsyncDb::new(Db::open_in_memory().unwrap());
+    let writers: Vec<_> = (0..8)
+        .map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                for i in 0..25 {
+                    let mut tx = db.begin_transaction();
+                    tx.set(key(t * 25 + i), "v");
+                    block_on(tx.commit()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(collect(&db, ..).len(), 200);
+}
+
+#[test]
+fn scans_page_through_more_than_one_batch() {
+    // One batch is 256 entries; cover a partial, an exact and a longer page.
+    for n in [0, 255, 256, 512, 700] {
+        let db = filled(n);
+        let entries = collect(&db, ..);
+        assert_eq!(entries.len(), n);
+        for (i, (k, v)) in entries.iter().enumerate() {
+            assert_eq!((k, v), (&key(i), &i.to_string().into_bytes()));
+        }
+    }
+
+    let db = filled(700);
+    let entries = collect(&db, key(100)..key(650));
+    assert_eq!(entries.len(), 550);
+    assert_eq!((entries[0].0.clone(), entries[549].0.clone()), (key(100), key(649)));
+    let entries = collect(&db, (Bound::Excluded(key(255)), Bound::Included(key(256))));
+    assert_eq!(entries.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(), [key(256)]);
+
+    let mut stream = db.scan_prefix("key06");
+    let mut count = 0;
+    block_on(async {
+        while let Some(entry) = stream.next().await {
+            assert!(entry.unwrap().0.starts_with(b"key06"));
+            count += 1;
+        }
+    });
+    assert_eq!(count, 100);
+}
+
+#[test]
+fn a_scan_sees_commits_made_between_batches() {
+    let db = filled(300);
+    let mut stream = db.scan(..);
+    block_on(async {
+        assert_eq!(stream.next().await.unwrap().unwrap().0, key(0));
+        // The first batch is buffered; later ones are fetched after this commit.
+        let mut tx = db.begin_transaction();
+        tx.set(key(299), "changed");
+        tx.delete(key(1));
+        tx.commit().await.unwrap();
+
+        let mut rest = Vec::new();
+        while let Some(entry) = stream.next().await {
+            rest.push(entry.unwrap());
+        }
+        assert_eq!(rest.len(), 299);
+        assert_eq!(rest[0].0, key(1));
+        assert_eq!(rest.last().unwrap().1, b"changed");
+    });
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use rust_embedded_kv_store::{Db, FORMAT_VERSION};
+
+use common::{set, TempDir};
+
+fn write_all(db: &mut Db, range: std::ops::Range<u32>) {
+    for i in range {
+        set(db, &format!("key{:02}", i % 9), &format!("value{i}"));
+    }
+}
+
+#[test]
+fn restore_reproduces_the_backed_up_state() {
+    let dir = TempDir::new("backup-source");
+    let mut db = Db::open(&dir).unwrap();
+    write_all(&mut db, 0..30);
+    let backup = TempDir::new("backup");
+    assert_eq!(db.backup_to(&backup).unwrap(), 30);
+    let want = db.scan(..).unwrap();
+    write_all(&mut db, 30..40);
+
+    let target = TempDir::new("backup-restored");
+    let mut restored = Db::restore(&backup, &target).unwrap();
+    assert_eq!(restored.scan(..).unwrap(), want);
+    assert_eq!(restored.last_seq(), 30);
+    set(&mut restored, "next", "x");
+    assert_eq!(restored.last_seq(), 31);
+    drop(restored);
+    assert_eq!(Db::open(&target).unwrap().last_seq(), 31);
+}
+
+#[test]
+fn restore_keeps_the_backup_seq_without_a_change_history() {
+    // A backup from before segments whose change log was emptied.
+    let backup = TempDir::new("legacy-backup");
+    let header = |magic: &[u8]| [magic, &[FORMAT_VERSION as u8, 0, 0, 0]].concat();
+    let mut data = header(b"KVDL");
+    data.extend([1, 1, 0, 0, 0, 1, 0, 0, 0, b'k', b'v']);
+    fs::write(backup.join("data.log"), data).unwrap();
+    fs::write(backup.join("changes.log"), header(b"KVCL")).unwrap();
+    fs::write(backup.join("backup.info"), "seq 7\n").unwrap();
+
+    let target = TempDir::new("legacy-restored");
+    let db = Db::restore(&backup, &target).unwrap();
+    assert_eq!(db.last_seq(), 7);
+    drop(db);
+    let mut db = Db::open(&target).unwrap();
+    assert_eq!(db.last_seq(), 7);
+    assert_eq!(db.get("k").unwrap(), Some(b"v".to_vec()));
+}
+
+fn modified(path: &Path) -> std::time::SystemTime {
+    fs::metadata(path).unwrap().modified().unwrap()
+}
+
+#[test]
+fn backing_up_again_copies_only_what_changed() {
+    let dir = TempDir::new("incremental-source");
+    let mut db = Db::open(&dir).unwrap();
+    db.set_segment_size(128).unwrap();
+    write_all(&mut db, 0..20);
+    let backup = TempDir::new("incremental");
+    db.backup_to(&backup).unwrap();
+
+    let sealed: Vec<_> = (1..db.segment_count() - 1).map(|id| backup.join(format!("data-{id:06}.log"))).collect();
+    assert!(!sealed.is_empty());
+    let before: Vec<_> = sealed.iter().map(|path| modified(path)).collect();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+
+    write_all(&mut db, 20..40);
+    assert_eq!(db.backup_to(&backup).unwrap(), 40);
+    let after: Vec<_> = sealed.iter().map(|path| modified(path)).collect();
+    assert_eq!(before, after, "sealed segments were copied again");
+
+    let target = TempDir::new("incremental-restored");
+    let mut restored = Db::restore(&backup, &target).unwrap();
+    assert_eq!(restored.scan(..).unwrap(), db.scan(..).unwrap());
+    assert_eq!(restored.last_seq(), 40);
+    assert_eq!(restored.segment_count(), db.segment_count());
+}
+
+#[test]
+fn a_backup_of_another_db_is_copied_afresh() {
+    let dir = TempDir::new("foreign-source");
+    let mut db = Db::open(&dir).unwrap();
+    db.set_segment_size(128).unwrap();
+    write_all(&mut db, 0..20);
+    let backup = TempDir::new("foreign");
+    db.backup_to(&backup).unwrap();
+
+    // A copy restored from the backup starts out with the same segments, but
+    // is a Db of its own.
+    let other_dir = TempDir::new("foreign-other");
+    let mut other = Db::restore(&backup, &other_dir).unwrap();
+    write_all(&mut other, 40..45);
+    let sealed: Vec<_> = (1..db.segment_count() - 1).map(|id| backup.join(format!("data-{id:06}.log"))).collect();
+    let before: Vec<_> = sealed.iter().map(|path| modified(path)).collect();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+
+    other.backup_to(&backup).unwrap();
+    for (path, before) in sealed.iter().zip(before) {
+        assert_ne!(modified(path), before, "{} was trusted", path.display());
+    }
+    let target = TempDir::new("foreign-restored");
+    let mut restored = Db::restore(&backup, &target).unwrap();
+    assert_eq!(restored.scan(..).unwrap(), other.scan(..).unwrap());
+    assert_eq!(restored.last_seq(), 25);
+}
+
+#[test]
+fn backups_and_restores_refuse_the_wrong_directories() {
+    let dir = TempDir::new("refuse-source");
+    let mut db = Db::open(&dir).unwrap();
+    set(&mut db, "a", "1");
+    assert!(db.backup_to(&dir).is_err(), "backing up over a Db");
+
+    let backup = TempDir::new("refuse-backup");
+    db.backup_to(&backup).unwrap();
+    assert!(Db::restore(&backup, &dir).is_err(), "restoring over a Db");
+
+    fs::remove_file(backup.join("backup.info")).unwrap();
+    let target = TempDir::new("refuse-restored");
+    assert!(Db::restore(&backup, &target).is_err(), "restoring an interrupted backup");
+}
+
+fn segment_files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("data") && name.ends_with(".log"))
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn incremental_backups_follow_rollover_and_compaction() {
+    let dir = TempDir::new("segments-source");
+    let mut db = Db::open(&dir).unwrap();
+    db.set_segment_size(96).unwrap();
+    write_all(&mut db, 0..15);
+    let backup = TempDir::new("segments");
+    db.backup_to(&backup).unwrap();
+
+    // Compaction replaces the sealed segments with new ones.
+    db.compact().unwrap();
+    write_all(&mut db, 15..25);
+    db.backup_to(&backup).unwrap();
+    assert_eq!(segment_files(&backup), segment_files(&dir));
+
+    // A segment in the backup that isn't a prefix of the Db's is copied afresh.
+    let active = segment_files(&dir).pop().unwrap();
+    fs::write(backup.join(&active), b"not a segment").unwrap();
+    write_all(&mut db, 25..27);
+    db.backup_to(&backup).unwrap();
+    assert_eq!(fs::read(backup.join(&active)).unwrap(), fs::read(dir.join(&active)).unwrap());
+
+    let target = TempDir::new("segments-restored");
+    let mut restored = Db::restore(&backup, &target).unwrap();
+    assert_eq!(restored.scan(..).unwrap(), db.scan(..).unwrap());
+    assert_eq!(restored.last_seq(), 27);
+}
@@ -0,0 +1,173 @@
+mod common;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use rust_embedded_kv_store::{ChangeCursor, Db, FaultFs, Vfs};
+
+use common::{set, TempDir};
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn pairs(items: &[(&str, &str)]) -> Pairs {
+    items.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+}
+
+/// A Db holding `a`, `b` and `c` after three commits.
+fn seeded(db: &mut Db) {
+    set(db, "a", "old-a");
+    set(db, "b", "old-b");
+    set(db, "c", "old-c");
+}
+
+fn load(db: &mut Db) -> std::io::Result<u64> {
+    // Small enough that nearly every insert spills a run.
+    let mut loader = db.bulk_loader()?.with_memory_limit(8);
+    loader.insert("b", "run-b")?;
+    loader.insert("d", "run-d")?;
+    loader.insert("b", "later-b")?;
+    loader.insert("e", "e")?;
+    loader.insert("d", "buffered-d")?;
+    loader.finish()
+}
+
+fn loaded() -> Pairs {
+    pairs(&[("a", "old-a"), ("b", "later-b"), ("c", "old-c"), ("d", "buffered-d"), ("e", "e")])
+}
+
+#[test]
+fn spilled_runs_merge_with_the_db_and_the_latest_insert_wins() {
+    let dir = TempDir::new("bulk-merge");
+    let mut db = Db::open(&dir).unwrap();
+    seeded(&mut db);
+    assert_eq!(load(&mut db).unwrap(), 5);
+    assert_eq!(db.scan(..).unwrap(), loaded());
+    assert_eq!(db.last_seq(), 4);
+
+    let names: Vec<String> =
+        std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+    assert!(!names.iter().any(|name| name.contains("bulk")), "{names:?}");
+}
+
+#[test]
+fn a_finished_load_survives_reopening() {
+    let dir = TempDir::new("bulk-reopen");
+    let mut db = Db::open(&dir).unwrap();
+    seeded(&mut db);
+    load(&mut db).unwrap();
+    set(&mut db, "f", "after");
+    drop(db);
+
+    let mut db = Db::open(&dir).unwrap();
+    let mut want = loaded();
+    want.push((b"f".to_vec(), b"after".to_vec()));
+    assert_eq!(db.scan(..).unwrap(), want);
+    assert_eq!(db.last_seq(), 5);
+
+    // Readers from before the load can't follow the history across it.
+    let e = db.read_changes(&mut ChangeCursor::from_seq(2), 10).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    let batches = db.read_changes(&mut ChangeCursor::from_seq(4), 10).unwrap();
+    assert_eq!(batches.iter().map(|b| b.seq).collect::<Vec<_>>(), [5]);
+}
+
+fn open(fs: &FaultFs) -> Db {
+    Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap()
+}
+
+#[test]
+fn a_failed_load_leaves_the_db_unchanged() {
+    let fs = FaultFs::new();
+    let mut db = open(&fs);
+    seeded(&mut db);
+    let before = db.scan(..).unwrap();
+
+    // The merged data log's sync is the first one `finish` makes.
+    fs.fail_sync(Some(0));
+    assert!(load(&mut db).is_err());
+    assert_eq!(db.scan(..).unwrap(), before);
+    assert_eq!(db.last_seq(), 3);
+    assert_eq!(db.read_changes(&mut ChangeCursor::from_seq(1), 10).unwrap().len(), 3);
+    let files = fs.list_dir(Path::new("db")).unwrap();
+    assert!(!files.iter().any(|name| name.contains("bulk")), "{files:?}");
+
+    // Dropping a loader without finishing changes nothing either.
+    let mut loader = db.bulk_loader().unwrap().with_memory_limit(1);
+    loader.insert("z", "z").unwrap();
+    drop(loader);
+    assert_eq!(db.scan(..).unwrap(), before);
+
+    set(&mut db, "d", "next");
+    assert_eq!(db.last_seq(), 4);
+}
+
+#[test]
+fn a_failed_swap_keeps_the_history_and_seq() {
+    let fs = FaultFs::new();
+    let mut db = open(&fs);
+    seeded(&mut db);
+    let before = db.scan(..).unwrap();
+
+    // Sync 0 is the merged data log's; sync 1 is the manifest naming it.
+    fs.fail_sync(Some(1));
+    assert!(load(&mut db).is_err());
+    assert_eq!(db.scan(..).unwrap(), before);
+    assert_eq!(db.last_seq(), 3);
+    assert_eq!(db.read_changes(&mut ChangeCursor::from_seq(1), 10).unwrap().len(), 3);
+    set(&mut db, "d", "next");
+    assert_eq!(db.last_seq(), 4);
+    drop(db);
+
+    let mut db = open(&fs);
+    assert_eq!(db.last_seq(), 4);
+    assert_eq!(db.read_changes(&mut ChangeCursor::from_seq(1), 10).unwrap().len(), 4);
+    assert_eq!(load(&mut db).unwrap(), 5);
+    assert_eq!(db.last_seq(), 5);
+}
+
+#[test]
+fn a_crash_during_finish_leaves_old_or_new_contents_with_a_consistent_history() {
+    for after in 0.. {
+        let fs = FaultFs::new();
+        let mut db = open(&fs);
+        seeded(&mut db);
+        fs.lose_power_after(Some(after));
+        let finished = load(&mut db).is_ok();
+        drop(db);
+        fs.crash();
+
+        let mut db = open(&fs);
+        let contents = db.scan(..).unwrap();
+        if contents == loaded() {
+            assert_eq!(db.last_seq(), 4, "after {after} operations");
+        } else {
+            assert!(!finished, "after {after} operations: finished load was lost");
+            assert_eq!(contents, pairs(&[("a", "old-a"), ("b", "old-b"), ("c", "old-c")]));
+        }
+        // Whatever survived, the history never leads a reader from before the
+        // load to its contents.
+        let history = db.read_changes(&mut ChangeCursor::from_seq(1), 10);
+        if contents == loaded() {
+            assert!(history.is_err(), "after {after} operations");
+        }
+        if finished {
+            break;
+        }
+    }
+}
+
+#[test]
+fn bulk_loading_is_refused_with_indexes_or_archiving() {
+    let dir = TempDir::new("bulk-refused");
+    let mut db = Db::open(&dir).unwrap();
+    db.register_index("by-value", 1, |_, value| vec![value.to_vec()]).unwrap();
+    assert_eq!(db.bulk_loader().err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+
+    let dir = TempDir::new("bulk-archived");
+    let archive = TempDir::new("bulk-archive");
+    let mut db = Db::open(&dir).unwrap();
+    db.enable_archiving(&archive).unwrap();
+    assert_eq!(db.bulk_loader().err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    db.disable_archiving();
+    assert!(db.bulk_loader().is_ok());
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::io::ErrorKind;
+
+use rust_embedded_kv_store::{Change, ChangeBatch, ChangeCursor, Db};
+
+use common::{set, TempDir};
+
+fn put(key: &str, value: &str) -> Change {
+    Change::Put { key: key.as_bytes().to_vec(), value: value.as_bytes().to_vec() }
+}
+
+fn seqs(batches: &[ChangeBatch]) -> Vec<u64> {
+    batches.iter().map(|b| b.seq).collect()
+}
+
+#[test]
+fn batches_come_back_in_commit_order() {
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "a", "1");
+    let mut tx = db.begin_transaction();
+    tx.set("b", "2");
+    tx.delete("a");
+    tx.commit().unwrap();
+
+    let mut cursor = ChangeCursor::from_seq(1);
+    let batches = db.read_changes(&mut cursor, 10).unwrap();
+    assert_eq!(batches, [
+        ChangeBatch { seq: 1, changes: vec![put("a", "1")] },
+        ChangeBatch { seq: 2, changes: vec![put("b", "2"), Change::Delete { key: b"a".to_vec() }] },
+    ]);
+    assert_eq!(cursor.next_seq(), 3);
+    assert!(db.read_changes(&mut cursor, 10).unwrap().is_empty());
+    assert_eq!(cursor.next_seq(), 3);
+}
+
+#[test]
+fn a_saved_cursor_resumes_after_reopening() {
+    let dir = TempDir::new("changes-resume");
+    let mut db = Db::open(&dir).unwrap();
+    for i in 0..5 {
+        set(&mut db, &format!("key{i}"), "value");
+    }
+    let mut cursor = ChangeCursor::from_seq(1);
+    assert_eq!(seqs(&db.read_changes(&mut cursor, 2).unwrap()), [1, 2]);
+    let saved = cursor.next_seq();
+    drop(db);
+
+    let mut db = Db::open(&dir).unwrap();
+    set(&mut db, "key5", "value");
+    let mut cursor = ChangeCursor::from_seq(saved);
+    assert_eq!(seqs(&db.read_changes(&mut cursor, 100).unwrap()), [3, 4, 5, 6]);
+}
+
+#[test]
+fn retention_trims_old_batches_and_cursors_behind_it_fail() {
+    let dir = TempDir::new("changes-retention");
+    let mut db = Db::open(&dir).unwrap();
+    db.set_change_retention(3);
+    for i in 0..5 {
+        set(&mut db, &format!("key{i}"), "value");
+    }
+    // Trimming waits until twice the retention has built up.
+    assert_eq!(db.oldest_change_seq(), Some(1));
+    set(&mut db, "key5", "value");
+    assert_eq!(db.oldest_change_seq(), Some(4));
+
+    let e = db.read_changes(&mut ChangeCursor::from_seq(3), 10).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    let mut cursor = ChangeCursor::from_seq(4);
+    assert_eq!(seqs(&db.read_changes(&mut cursor, 10).unwrap()), [4, 5, 6]);
+
+    // The trimmed log is what a reopened Db sees.
+    drop(db);
+    let mut db = Db::open(&dir).unwrap();
+    assert_eq!(db.oldest_change_seq(), Some(4));
+    assert_eq!(db.last_seq(), 6);
+    assert_eq!(db.read_changes(&mut ChangeCursor::from_seq(1), 10).err().unwrap().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn index_entries_are_left_out() {
+    let mut db = Db::open_in_memory().unwrap();
+    db.register_index("value", 1, |_, value| vec![value.to_vec()]).unwrap();
+    set(&mut db, "a", "1");
+    let batches = db.read_changes(&mut ChangeCursor::from_seq(1), 10).unwrap();
+    assert_eq!(batches, [ChangeBatch { seq: 2, changes: vec![put("a", "1")] }]);
+}
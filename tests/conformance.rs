@@ -0,0 +1,173 @@
+//! The same behavioural checks, run against every `KvEngine` backend.
+
+mod common;
+
+use std::ops::Bound;
+use std::path::Path;
+
+use rust_embedded_kv_store::{Db, KvEngine, KvStore, WriteBatch};
+
+use common::TempDir;
+
+type Opener = fn(&Path) -> Box<dyn KvEngine>;
+
+fn open_db(dir: &Path) -> Box<dyn KvEngine> {
+    Box::new(Db::open(dir).unwrap())
+}
+
+/// Ignores `dir`: nothing is written to disk.
+fn open_db_in_memory(_dir: &Path) -> Box<dyn KvEngine> {
+    Box::new(Db::open_in_memory().unwrap())
+}
+
+fn open_kvstore(dir: &Path) -> Box<dyn KvEngine> {
+    Box::new(KvStore::open(dir.join("data.log")).unwrap())
+}
+
+fn put(engine: &mut dyn KvEngine, key: &str, value: &str) {
+    let mut batch = WriteBatch::new();
+    batch.put(key, value);
+    engine.write(batch).unwrap();
+}
+
+fn pairs(items: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    items.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+}
+
+fn missing_key_is_none(open: Opener) {
+    let dir = TempDir::new("missing");
+    let mut engine = open(&dir);
+    assert_eq!(engine.get(b"nope").unwrap(), None);
+}
+
+fn put_then_get(open: Opener) {
+    let dir = TempDir::new("put");
+    let mut engine = open(&dir);
+    put(engine.as_mut(), "a", "1");
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+    put(engine.as_mut(), "a", "2");
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"2".to_vec()));
+}
+
+fn empty_value_is_stored(open: Opener) {
+    let dir = TempDir::new("empty");
+    let mut engine = open(&dir);
+    put(engine.as_mut(), "a", "");
+    assert_eq!(engine.get(b"a").unwrap(), Some(Vec::new()));
+}
+
+fn batch_applies_in_order(open: Opener) {
+    let dir = TempDir::new("batch");
+    let mut engine = open(&dir);
+    put(engine.as_mut(), "gone", "x");
+    let mut batch = WriteBatch::new();
+    batch.put("a", "1").put("b", "1").delete("a").put("b", "2").delete("gone").delete("never");
+    engine.write(batch).unwrap();
+
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"gone").unwrap(), None);
+    engine.write(WriteBatch::new()).unwrap();
+}
+
+fn scan_respects_bounds(open: Opener) {
+    let dir = TempDir::new("scan");
+    let mut engine = open(&dir);
+    for key in ["a", "b", "ba", "bb", "c"] {
+        put(engine.as_mut(), key, key);
+    }
+    let all = pairs(&[("a", "a"), ("b", "b"), ("ba", "ba"), ("bb", "bb"), ("c", "c")]);
+
+    assert_eq!(engine.scan((Bound::Unbounded, Bound::Unbounded)).unwrap(), all);
+    assert_eq!(
+        engine.scan((Bound::Included(b"b".to_vec()), Bound::Excluded(b"c".to_vec()))).unwrap(),
+        all[1..4]
+    );
+    assert_eq!(
+        engine.scan((Bound::Excluded(b"b".to_vec()), Bound::Included(b"c".to_vec()))).unwrap(),
+        all[2..]
+    );
+    assert_eq!(engine.scan_prefix(b"b").unwrap(), all[1..4]);
+    // Inverted and empty ranges hold nothing rather than panicking.
+    assert!(engine.scan((Bound::Included(b"c".to_vec()), Bound::Included(b"a".to_vec()))).unwrap().is_empty());
+    assert!(engine.scan((Bound::Excluded(b"b".to_vec()), Bound::Excluded(b"b".to_vec()))).unwrap().is_empty());
+    assert_eq!(engine.scan_prefix(b"z").unwrap(), Vec::new());
+    assert_eq!(engine.scan((Bound::Included(vec![0x80]), Bound::Unbounded)).unwrap(), Vec::new());
+}
+
+fn scan_skips_deleted(open: Opener) {
+    let dir = TempDir::new("deleted");
+    let mut engine = open(&dir);
+    put(engine.as_mut(), "a", "1");
+    put(engine.as_mut(), "b", "2");
+    let mut batch = WriteBatch::new();
+    batch.delete("a");
+    engine.write(batch).unwrap();
+    assert_eq!(engine.scan((Bound::Unbounded, Bound::Unbounded)).unwrap(), pairs(&[("b", "2")]));
+}
+
+fn survives_reopen(open: Opener) {
+    let dir = TempDir::new("reopen");
+    {
+        let mut engine = open(&dir);
+        put(engine.as_mut(), "a", "1");
+        put(engine.as_mut(), "b", "2");
+        let mut batch = WriteBatch::new();
+        batch.delete("a").put("c", "3");
+        engine.write(batch).unwrap();
+        engine.flush().unwrap();
+    }
+    let mut engine = open(&dir);
+    assert_eq!(engine.scan((Bound::Unbounded, Bound::Unbounded)).unwrap(), pairs(&[("b", "2"), ("c", "3")]));
+}
+
+macro_rules! conformance {
+    ($backend:ident, $open:ident, [$($check:ident),* $(,)?]) => {
+        mod $backend {
+            $(
+                #[test]
+                fn $check() {
+                    super::$check(super::$open);
+                }
+            )*
+        }
+    };
+}
+
+conformance!(db, open_db, [
+    missing_key_is_none,
+    put_then_get,
+    empty_value_is_stored,
+    batch_applies_in_order,
+    scan_respects_bounds,
+    scan_skips_deleted,
+    survives_reopen,
+]);
+conformance!(db_in_memory, open_db_in_memory, [
+    missing_key_is_none,
+    put_then_get,
+    empty_value_is_stored,
+    batch_applies_in_order,
+    scan_respects_bounds,
+    scan_skips_deleted,
+]);
+conformance!(kvstore, open_kvstore, [
+    missing_key_is_none,
+    put_then_get,
+    empty_value_is_stored,
+    batch_applies_in_order,
+    scan_respects_bounds,
+    scan_skips_deleted,
+    survives_reopen,
+]);
+
+#[test]
+fn kvstore_rejects_non_utf8_batches_whole() {
+    let dir = TempDir::new("utf8");
+    let mut engine = open_kvstore(&dir);
+    let mut batch = WriteBatch::new();
+    batch.put("a", "1").put([0xFF], "2");
+    let err = engine.write(batch).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(engine.get(b"a").unwrap(), None);
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use rust_embedded_kv_store::{
+    restore_from_store, BackupOptions, BackupReplicator, BackupStatus, Db, LocalFsStore, ObjectStore, SimClock,
+};
+
+use common::{set, TempDir};
+
+#[test]
+fn local_store_puts_gets_lists_and_deletes() {
+    let root = TempDir::new("store-basics");
+    let store = LocalFsStore::open(root.join("objects")).unwrap();
+    assert_eq!(store.get("a").unwrap(), None);
+    assert!(store.list("").unwrap().is_empty());
+
+    store.put("b/2", b"two").unwrap();
+    store.put("b/1", b"one").unwrap();
+    store.put("a", b"first").unwrap();
+    store.put("a", b"replaced").unwrap();
+    store.put("bc", b"sibling").unwrap();
+    assert_eq!(store.get("a").unwrap(), Some(b"replaced".to_vec()));
+    assert_eq!(store.get("b/1").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(store.list("").unwrap(), ["a", "b/1", "b/2", "bc"]);
+    assert_eq!(store.list("b/").unwrap(), ["b/1", "b/2"]);
+    assert_eq!(store.list("b").unwrap(), ["b/1", "b/2", "bc"]);
+    assert!(store.list("c").unwrap().is_empty());
+
+    // A put interrupted before its rename leaves only a hidden temporary file.
+    std::fs::write(root.join("objects/b/3.tmp"), b"partial").unwrap();
+    assert_eq!(store.list("b/").unwrap(), ["b/1", "b/2"]);
+
+    store.delete("b/1").unwrap();
+    store.delete("b/1").unwrap();
+    store.delete("missing/key").unwrap();
+    assert_eq!(store.get("b/1").unwrap(), None);
+    assert_eq!(store.list("b/").unwrap(), ["b/2"]);
+
+    // Reopening sees the same objects.
+    let store = LocalFsStore::open(root.join("objects")).unwrap();
+    assert_eq!(store.list("").unwrap(), ["a", "b/2", "bc"]);
+}
+
+#[test]
+fn local_store_keys_cannot_leave_its_directory() {
+    let root = TempDir::new("store-keys");
+    let store = LocalFsStore::open(root.join("objects")).unwrap();
+    for key in ["", "/a", "a/", "a//b", "..", "a/../../b", "./a", "a\\b"] {
+        assert_eq!(store.put(key, b"x").err().unwrap().kind(), ErrorKind::InvalidInput, "{key:?}");
+        assert_eq!(store.get(key).err().unwrap().kind(), ErrorKind::InvalidInput, "{key:?}");
+        assert_eq!(store.delete(key).err().unwrap().kind(), ErrorKind::InvalidInput, "{key:?}");
+    }
+    assert_eq!(std::fs::read_dir(&*root).unwrap().count(), 1);
+}
+
+/// Waits for the replicator's background rounds to reach `done`.
+fn wait_for(replicator: &BackupReplicator, done: impl Fn(&BackupStatus) -> bool) -> BackupStatus {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let status = replicator.status();
+        assert_eq!(status.last_error, None);
+        if done(&status) {
+            return status;
+        }
+        assert!(Instant::now() < deadline, "timed out at {status:?}");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+struct Replicated {
+    _dir: TempDir,
+    root: TempDir,
+    db: Arc<Mutex<Db>>,
+    store: Arc<LocalFsStore>,
+    clock: SimClock,
+}
+
+fn replicated(name: &str) -> Replicated {
+    let dir = TempDir::new(&format!("{name}-db"));
+    let root = TempDir::new(&format!("{name}-store"));
+    let clock = SimClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let mut db = Db::open(&dir).unwrap();
+    db.set_clock(Arc::new(clock.clone()));
+    let store = Arc::new(LocalFsStore::open(&root).unwrap());
+    Replicated { _dir: dir, root, db: Arc::new(Mutex::new(db)), store, clock }
+}
+
+fn options(retain_snapshots: usize) -> BackupOptions {
+    BackupOptions {
+        sync_interval: Duration::from_millis(5),
+        snapshot_interval: Duration::from_secs(60),
+        retain_snapshots,
+    }
+}
+
+fn write(r: &Replicated, range: std::ops::Range<u32>) {
+    let mut db = r.db.lock().unwrap();
+    for i in range {
+        set(&mut db, &format!("key{:02}", i % 7), &format!("value{i}"));
+    }
+}
+
+#[test]
+fn restore_replays_the_log_after_the_newest_snapshot() {
+    let r = replicated("store-restore");
+    write(&r, 0..5);
+    let replicator = BackupReplicator::start(r.db.clone(), r.store.clone(), options(2)).unwrap();
+    // The first round always takes a snapshot.
+    wait_for(&replicator, |s| s.latest_snapshot_seq == Some(5));
+    write(&r, 5..12);
+    let status = wait_for(&replicator, |s| s.uploaded_seq == 12);
+    assert_eq!(status.latest_snapshot_seq, Some(5));
+    write(&r, 12..15);
+    assert_eq!(replicator.stop().uploaded_seq, 15);
+    assert_eq!(r.store.list("snapshots/").unwrap().len(), 1);
+    assert!(!r.store.list("log/").unwrap().is_empty());
+
+    let want = r.db.lock().unwrap().scan(..).unwrap();
+    let target = TempDir::new("store-restore-target");
+    let mut restored = restore_from_store(r.store.as_ref(), &target).unwrap();
+    assert_eq!(restored.last_seq(), 15);
+    assert_eq!(restored.scan(..).unwrap(), want);
+    set(&mut restored, "next", "x");
+    drop(restored);
+    assert_eq!(Db::open(&target).unwrap().last_seq(), 16);
+
+    // The target must not already hold a Db.
+    let e = restore_from_store(r.store.as_ref(), &target).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+
+    // Nor may the log have a gap after the snapshot.
+    let after_snapshot = format!("log/{:020}-", 6);
+    let logs = r.store.list(&after_snapshot).unwrap();
+    r.store.delete(&logs[0]).unwrap();
+    let e = restore_from_store(r.store.as_ref(), TempDir::new("store-restore-gap")).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    let empty = LocalFsStore::open(r.root.join("empty")).unwrap();
+    let e = restore_from_store(&empty, TempDir::new("store-restore-empty")).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn retention_keeps_the_newest_snapshots_and_the_log_they_need() {
+    let r = replicated("store-retention");
+    let replicator = BackupReplicator::start(r.db.clone(), r.store.clone(), options(2)).unwrap();
+    wait_for(&replicator, |s| s.latest_snapshot_seq == Some(0));
+    for round in 1..=4u64 {
+        write(&r, 0..3);
+        wait_for(&replicator, |s| s.uploaded_seq == 3 * round);
+        r.clock.advance(Duration::from_secs(60));
+        wait_for(&replicator, |s| s.latest_snapshot_seq == Some(3 * round));
+    }
+    write(&r, 0..2);
+    assert_eq!(replicator.stop().uploaded_seq, 14);
+
+    let seqs = |prefix: &str| -> Vec<u64> {
+        let keys = r.store.list(prefix).unwrap();
+        keys.iter().map(|key| key[prefix.len()..].split('-').next_back().unwrap().parse().unwrap()).collect()
+    };
+    assert_eq!(seqs("snapshots/"), [9, 12]);
+    // Only log objects ending after the oldest kept snapshot remain.
+    let logs = seqs("log/");
+    assert!(logs.iter().all(|&last| last > 9), "{logs:?}");
+    assert_eq!(logs.last(), Some(&14));
+
+    let want = r.db.lock().unwrap().scan(..).unwrap();
+    let target = TempDir::new("store-retention-target");
+    let mut restored = restore_from_store(r.store.as_ref(), &target).unwrap();
+    assert_eq!(restored.last_seq(), 14);
+    assert_eq!(restored.scan(..).unwrap(), want);
+
+    // Restarting resumes after what the store holds, with a fresh snapshot.
+    write(&r, 0..1);
+    let replicator = BackupReplicator::start(r.db.clone(), r.store.clone(), options(1)).unwrap();
+    let status = wait_for(&replicator, |s| s.latest_snapshot_seq == Some(15));
+    assert_eq!(status.uploaded_seq, 15);
+    drop(replicator);
+    assert_eq!(seqs("snapshots/"), [15]);
+    assert!(seqs("log/").is_empty());
+}
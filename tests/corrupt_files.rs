@@ -0,0 +1,271 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rust_embedded_kv_store::{ChangeCursor, Db, MemFs, Vfs, FORMAT_VERSION};
+
+/// Opens a Db made of `files`. Logs get a current header in front of the
+/// given contents.
+fn open_with(files: &[(&str, &[u8])]) -> std::io::Result<Db> {
+    let fs = MemFs::new();
+    for (name, contents) in files {
+        let magic: &[u8] = match *name {
+            "wal.log" => b"KVWL",
+            "changes.log" => b"KVCL",
+            name if name.ends_with(".log") => b"KVDL",
+            _ => b"",
+        };
+        let mut file = fs.create(&Path::new("db").join(name))?;
+        if !magic.is_empty() {
+            file.write(&[magic, &[FORMAT_VERSION as u8, 0, 0, 0]].concat())?;
+        }
+        file.write(contents)?;
+    }
+    Db::open_with_vfs(Arc::new(fs), "db")
+}
+
+/// A record header claiming a 4 GiB key and value, with nothing after it.
+fn huge_record(op: u8) -> Vec<u8> {
+    let mut record = vec![op];
+    record.extend_from_slice(&u32::MAX.to_le_bytes());
+    record.extend_from_slice(&u32::MAX.to_le_bytes());
+    record.extend_from_slice(b"short");
+    record
+}
+
+#[test]
+fn huge_lengths_in_the_data_log_are_rejected() {
+    let e = open_with(&[("data.log", &huge_record(1))]).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// Data log records, as `Db` writes them.
+fn records(pairs: &[(&str, &str)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in pairs {
+        data.push(1);
+        data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(key.as_bytes());
+        data.extend_from_slice(value.as_bytes());
+    }
+    data
+}
+
+#[test]
+fn corrupt_lengths_in_the_data_log_are_not_truncated_away() {
+    let fs = MemFs::new();
+    let mut data = records(&[("a", "1"), ("b", "2"), ("c", "3")]);
+    data[1..5].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+    let mut file = fs.create(Path::new("db/data.log")).unwrap();
+    file.write(&[b"KVDL".as_slice(), &[FORMAT_VERSION as u8, 0, 0, 0], &data].concat()).unwrap();
+    let len = file.len().unwrap();
+
+    let e = Db::open_with_vfs(Arc::new(fs.clone()), "db").err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("offset 8"), "{e}");
+    assert_eq!(fs.open(Path::new("db/data.log")).unwrap().len().unwrap(), len);
+}
+
+#[test]
+fn a_torn_tail_left_by_a_logged_commit_is_replayed() {
+    let mut wal = vec![0];
+    wal.extend_from_slice(&1u64.to_le_bytes());
+    wal.extend(records(&[("a", "1"), ("b", "2")]));
+    wal.push(3);
+    let data = records(&[("a", "1"), ("b", "2")]);
+    let mut db = open_with(&[("data.log", &data[..data.len() - 2]), ("wal.log", &wal)]).unwrap();
+    assert_eq!(db.scan(..).unwrap(), [(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"2".to_vec())]);
+    assert_eq!(db.last_seq(), 1);
+}
+
+#[test]
+fn huge_lengths_in_the_wal_are_rejected() {
+    let mut wal = vec![0];
+    wal.extend_from_slice(&1u64.to_le_bytes());
+    wal.extend(huge_record(1));
+    // The change log has transaction 1, so the WAL was synced in full.
+    let mut changes = vec![0];
+    changes.extend_from_slice(&1u64.to_le_bytes());
+    changes.push(3);
+    let e = open_with(&[("wal.log", &wal), ("changes.log", &changes)]).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+    // On its own it is a transaction cut short by a crash, never acknowledged.
+    let mut db = open_with(&[("wal.log", &wal)]).unwrap();
+    assert!(db.scan(..).unwrap().is_empty());
+    assert_eq!(db.last_seq(), 0);
+}
+
+#[test]
+fn huge_lengths_in_the_change_log_are_rejected() {
+    let mut changes = vec![0];
+    changes.extend_from_slice(&1u64.to_le_bytes());
+    changes.extend(huge_record(1));
+    let e = open_with(&[("changes.log", &changes)]).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn corrupt_lengths_in_the_change_log_do_not_drop_later_batches() {
+    let mut changes = Vec::new();
+    for seq in 1..=3u64 {
+        changes.push(0);
+        changes.extend_from_slice(&seq.to_le_bytes());
+        changes.extend(records(&[("key", "value")]));
+        changes.push(3);
+    }
+    // The key length of batch 2's record.
+    let batch = changes.len() / 3;
+    let mut corrupt = changes.clone();
+    corrupt[batch + 10..batch + 14].copy_from_slice(&1000u32.to_le_bytes());
+    let e = open_with(&[("changes.log", &corrupt)]).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains(&format!("offset {}", 8 + batch)), "{e}");
+
+    // A batch torn while the WAL still holds it is dropped and replayed.
+    let mut wal = vec![0];
+    wal.extend_from_slice(&3u64.to_le_bytes());
+    wal.extend(records(&[("key", "value")]));
+    wal.push(3);
+    let mut db = open_with(&[("changes.log", &changes[..changes.len() - 4]), ("wal.log", &wal)]).unwrap();
+    assert_eq!(db.last_seq(), 3);
+    assert_eq!(db.get("key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn exhausted_sequence_numbers_fail_cleanly() {
+    let mut changes = vec![0];
+    changes.extend_from_slice(&u64::MAX.to_le_bytes());
+    changes.push(3);
+    let mut db = open_with(&[("changes.log", &changes)]).unwrap();
+
+    let mut cursor = ChangeCursor::from_seq(u64::MAX);
+    db.read_changes(&mut cursor, 10).unwrap();
+    let mut tx = db.begin_transaction();
+    tx.set("k", "v");
+    assert!(tx.commit().is_err());
+}
+
+#[test]
+fn out_of_order_change_log_is_rejected() {
+    let mut changes = Vec::new();
+    for seq in [2u64, 1] {
+        changes.push(0);
+        changes.extend_from_slice(&seq.to_le_bytes());
+        changes.push(3);
+    }
+    let e = open_with(&[("changes.log", &changes)]).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+fn manifest(format: u32, segments: &str, last_seq: u64) -> Vec<u8> {
+    format!("format {format}\nsegment_size 1024\nsegments {segments}\nlast_seq {last_seq}\n").into_bytes()
+}
+
+#[test]
+fn missing_or_torn_sealed_segments_are_rejected() {
+    let current: &[u8] = b"MANIFEST-000001\n";
+    let e = open_with(&[("CURRENT", current), ("MANIFEST-000001", &manifest(FORMAT_VERSION, "0 1", 0)), ("data-000001.log", b"")])
+        .err()
+        .unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+    let e = open_with(&[
+        ("CURRENT", current),
+        ("MANIFEST-000001", &manifest(FORMAT_VERSION, "0 1", 0)),
+        ("data.log", &huge_record(1)),
+        ("data-000001.log", b""),
+    ])
+    .err()
+    .unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn inconsistent_manifests_are_rejected() {
+    let current: &[u8] = b"MANIFEST-000001\n";
+    let e = open_with(&[("CURRENT", current)]).err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+    for bad in [b"segments 0\n".to_vec(), manifest(FORMAT_VERSION, "", 0), b"format 2\nformat 2\n".to_vec()] {
+        let e = open_with(&[("CURRENT", current), ("MANIFEST-000001", &bad), ("data.log", b"")]).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "{}", String::from_utf8_lossy(&bad));
+    }
+
+    let e = open_with(&[("CURRENT", current), ("MANIFEST-000001", &manifest(99, "0", 0)), ("data.log", b"")])
+        .err()
+        .unwrap();
+    assert!(e.to_string().contains("format version 99"), "{e}");
+
+    // The change log ends at seq 1, but the manifest was written at seq 5.
+    let mut changes = vec![0];
+    changes.extend_from_slice(&1u64.to_le_bytes());
+    changes.push(3);
+    let e = open_with(&[
+        ("CURRENT", current),
+        ("MANIFEST-000001", &manifest(FORMAT_VERSION, "0", 5)),
+        ("data.log", b""),
+        ("changes.log", &changes),
+    ])
+    .err()
+    .unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn manifest_keeps_options_and_seq_across_reopens() {
+    let fs = MemFs::new();
+    let mut db = Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap();
+    db.set_segment_size(16).unwrap();
+    for i in 0..4 {
+        let mut tx = db.begin_transaction();
+        tx.set(format!("key{i}"), "value");
+        tx.commit().unwrap();
+    }
+    db.compact().unwrap();
+    let segments = db.segment_count();
+    drop(db);
+
+    let mut db = Db::open_with_vfs(Arc::new(fs), "db").unwrap();
+    assert_eq!(db.segment_count(), segments);
+    assert_eq!(db.last_seq(), 4);
+    for i in 4..6 {
+        let mut tx = db.begin_transaction();
+        tx.set(format!("key{i}"), "value");
+        tx.commit().unwrap();
+    }
+    assert!(db.segment_count() > segments, "the segment size should still be 16");
+}
+
+#[test]
+fn lost_current_is_rejected() {
+    let fs = MemFs::new();
+    let mut db = Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap();
+    db.set_segment_size(16).unwrap();
+    for i in 0..4 {
+        let mut tx = db.begin_transaction();
+        tx.set(format!("key{i}"), "value");
+        tx.commit().unwrap();
+    }
+    drop(db);
+    fs.remove_file(Path::new("db/CURRENT")).unwrap();
+    let e = Db::open_with_vfs(Arc::new(fs), "db").err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(e.to_string().contains("no CURRENT"), "{e}");
+
+    for (name, contents) in [
+        ("MANIFEST-000002", manifest(FORMAT_VERSION, "0", 0)),
+        ("MANIFEST-000001", manifest(FORMAT_VERSION, "0 1", 0)),
+        ("data-000001.log", Vec::new()),
+        ("data.hint", Vec::new()),
+    ] {
+        let e = open_with(&[("data.log", b""), (name, &contents)]).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "{name}");
+    }
+
+    // What a crash during the first open leaves behind.
+    for first in [manifest(FORMAT_VERSION, "0", 0), b"format 2\nsegm".to_vec()] {
+        let mut db = open_with(&[("data.log", b""), ("MANIFEST-000001", &first)]).unwrap();
+        assert!(db.scan(..).unwrap().is_empty());
+    }
+}
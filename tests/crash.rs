@@ -0,0 +1,229 @@
+//! Crash-consistency harness.
+//!
+//! Each seed generates a random workload of transactions and compactions.
+//! The workload runs once to count the filesystem operations it performs,
+//! then once per operation with the power cut right there (and, in torn
+//! mode, again part-way through the recovery that follows). After reopening,
+//! the Db must hold exactly the acknowledged transactions, plus the one in
+//! flight either entirely or not at all.
+//!
+//! A failure names its case; rerun a single seed with
+//! `CRASH_SEED=<seed> cargo test --test crash`, or more seeds with
+//! `CRASH_SEEDS=<count>`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use rust_embedded_kv_store::{ChangeCursor, Db, FaultFs};
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+const DIR: &str = "db";
+const KEYS: u64 = 6;
+const STEPS: usize = 12;
+/// Small enough that the workload rolls over to new segments.
+const SEGMENT_SIZE: u64 = 64;
+
+#[derive(Debug, Clone)]
+enum Step {
+    Commit(Vec<(Vec<u8>, Option<Vec<u8>>)>),
+    Compact,
+    CompactOldestSegment,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Case {
+    seed: u64,
+    /// Operations allowed before the power is cut during the workload.
+    crash_at: u64,
+    /// Whether unsynced appends partly survive the crash.
+    torn: bool,
+    /// Operations allowed before the power is cut again during recovery.
+    recovery_crash_at: Option<u64>,
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        // xorshift64
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x % n
+    }
+}
+
+fn workload(seed: u64) -> Vec<Step> {
+    let mut rng = Rng::new(seed);
+    (0..STEPS)
+        .map(|step| {
+            match rng.below(16) {
+                0 => return Step::Compact,
+                1 => return Step::CompactOldestSegment,
+                _ => {}
+            }
+            let ops = (0..1 + rng.below(4))
+                .map(|i| {
+                    let key = format!("k{}", rng.below(KEYS)).into_bytes();
+                    // Values are unique so a half-applied commit can't hide.
+                    let value = (rng.below(4) != 0).then(|| format!("v{step}.{i}").into_bytes());
+                    (key, value)
+                })
+                .collect();
+            Step::Commit(ops)
+        })
+        .collect()
+}
+
+fn apply(model: &Model, ops: &[(Vec<u8>, Option<Vec<u8>>)]) -> Model {
+    let mut model = model.clone();
+    for (key, value) in ops {
+        match value {
+            Some(value) => model.insert(key.clone(), value.clone()),
+            None => model.remove(key),
+        };
+    }
+    model
+}
+
+fn open(fs: &FaultFs) -> std::io::Result<Db> {
+    Db::open_with_vfs(Arc::new(fs.clone()), DIR)
+}
+
+fn commit(db: &mut Db, ops: &[(Vec<u8>, Option<Vec<u8>>)]) -> std::io::Result<()> {
+    let mut tx = db.begin_transaction();
+    for (key, value) in ops {
+        match value {
+            Some(value) => tx.set(key, value),
+            None => tx.delete(key),
+        }
+    }
+    tx.commit()
+}
+
+/// What the workload got acknowledged before the power went out.
+struct Outcome {
+    acked: Model,
+    acked_commits: u64,
+    /// State if the commit in flight at the crash took effect.
+    in_flight: Option<Model>,
+}
+
+fn run_workload(fs: &FaultFs, steps: &[Step]) -> Outcome {
+    let mut outcome = Outcome { acked: Model::new(), acked_commits: 0, in_flight: None };
+    let Ok(mut db) = open(fs) else {
+        return outcome;
+    };
+    if db.set_segment_size(SEGMENT_SIZE).is_err() {
+        return outcome;
+    }
+    for step in steps {
+        match step {
+            Step::Commit(ops) => {
+                if commit(&mut db, ops).is_err() {
+                    outcome.in_flight = Some(apply(&outcome.acked, ops));
+                    return outcome;
+                }
+                outcome.acked = apply(&outcome.acked, ops);
+                outcome.acked_commits += 1;
+            }
+            Step::Compact => {
+                if db.compact().is_err() {
+                    return outcome;
+                }
+            }
+            Step::CompactOldestSegment => {
+                if db.compact_segments(1).is_err() {
+                    return outcome;
+                }
+            }
+        }
+    }
+    outcome
+}
+
+fn scan(db: &mut Db) -> Model {
+    db.scan(..).unwrap().into_iter().collect()
+}
+
+fn check(case: Case, steps: &[Step]) {
+    let fs = FaultFs::new();
+    fs.lose_power_after(Some(case.crash_at));
+    let outcome = run_workload(&fs, steps);
+
+    let crash = |salt: u64| {
+        if case.torn {
+            fs.crash_torn(case.seed ^ case.crash_at.rotate_left(32) ^ salt);
+        } else {
+            fs.crash();
+        }
+    };
+    crash(0);
+    if let Some(at) = case.recovery_crash_at {
+        fs.lose_power_after(Some(at));
+        if open(&fs).is_ok() {
+            fs.lose_power_after(None);
+        }
+        crash(1);
+    }
+
+    let mut db = open(&fs).unwrap_or_else(|e| panic!("{case:?}: reopen failed: {e}"));
+    let state = scan(&mut db);
+    let seq = db.last_seq();
+    let recovered_acked = state == outcome.acked && seq == outcome.acked_commits;
+    let recovered_in_flight = outcome.in_flight.as_ref() == Some(&state) && seq == outcome.acked_commits + 1;
+    assert!(
+        recovered_acked || recovered_in_flight,
+        "{case:?}: recovered state matches neither the acknowledged commits nor the one in flight\n\
+         recovered: {state:?} at seq {seq}\nacknowledged: {:?} at seq {}\nin flight: {:?}",
+        outcome.acked, outcome.acked_commits, outcome.in_flight
+    );
+
+    let seqs: Vec<u64> = db
+        .read_changes(&mut ChangeCursor::from_seq(1), usize::MAX)
+        .unwrap_or_else(|e| panic!("{case:?}: change log unreadable: {e}"))
+        .into_iter()
+        .map(|batch| batch.seq)
+        .collect();
+    assert_eq!(seqs, (1..=seq).collect::<Vec<_>>(), "{case:?}: change log is not contiguous");
+
+    // The recovered Db must keep working and survive another crash.
+    let ops = vec![(b"after".to_vec(), Some(b"crash".to_vec()))];
+    commit(&mut db, &ops).unwrap_or_else(|e| panic!("{case:?}: commit after recovery failed: {e}"));
+    drop(db);
+    fs.crash();
+    let mut db = open(&fs).unwrap();
+    assert_eq!(scan(&mut db), apply(&state, &ops), "{case:?}: commit after recovery was lost");
+}
+
+fn seeds() -> Vec<u64> {
+    if let Some(seed) = std::env::var("CRASH_SEED").ok().and_then(|s| s.parse().ok()) {
+        return vec![seed];
+    }
+    let count = std::env::var("CRASH_SEEDS").ok().and_then(|s| s.parse().ok()).unwrap_or(8);
+    (0..count).collect()
+}
+
+#[test]
+fn survives_power_loss_at_every_operation() {
+    for seed in seeds() {
+        let steps = workload(seed);
+        let fs = FaultFs::new();
+        let outcome = run_workload(&fs, &steps);
+        assert!(outcome.in_flight.is_none(), "seed {seed}: workload failed without faults");
+        let operations = fs.operations();
+
+        for crash_at in 0..=operations {
+            check(Case { seed, crash_at, torn: false, recovery_crash_at: None }, &steps);
+            let recovery_crash_at = Some((seed + crash_at) % 8);
+            check(Case { seed, crash_at, torn: true, recovery_crash_at }, &steps);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use std::io::ErrorKind;
+
+use rust_embedded_kv_store::{BinaryEncoding, Db, Format, ImportOptions};
+
+use common::set;
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn export(db: &mut Db, prefix: &[u8], format: Format, encoding: BinaryEncoding) -> String {
+    let mut out = Vec::new();
+    db.export(&mut out, prefix, format, encoding).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn import(db: &mut Db, text: &str, format: Format, encoding: BinaryEncoding) -> std::io::Result<u64> {
+    let options = ImportOptions { format, encoding, ..ImportOptions::default() };
+    db.import(text.as_bytes(), &options, |_| {})
+}
+
+fn tricky_db() -> Db {
+    let mut db = Db::open_in_memory().unwrap();
+    let mut tx = db.begin_transaction();
+    tx.set("plain", "value");
+    tx.set("comma,key", "a \"quoted\" value");
+    tx.set("lines", "first\nsecond\r\nthird");
+    tx.set(" padded ", "\ttab and \\ backslash");
+    tx.set("unicode", "é 中 🦀 \u{1}");
+    tx.set("empty", "");
+    tx.commit().unwrap();
+    db
+}
+
+/// Exports `source` and imports the result into an empty Db.
+fn round_trip(source: &mut Db, format: Format, encoding: BinaryEncoding) -> Pairs {
+    let text = export(source, b"", format, encoding);
+    let mut target = Db::open_in_memory().unwrap();
+    let count = import(&mut target, &text, format, encoding).unwrap();
+    assert_eq!(count as usize, source.scan(..).unwrap().len());
+    target.scan(..).unwrap()
+}
+
+#[test]
+fn text_round_trips_through_both_formats() {
+    let mut db = tricky_db();
+    let want = db.scan(..).unwrap();
+    for format in [Format::JsonLines, Format::Csv] {
+        assert_eq!(round_trip(&mut db, format, BinaryEncoding::Utf8), want, "{format:?}");
+    }
+
+    assert_eq!(
+        export(&mut db, b"p", Format::JsonLines, BinaryEncoding::Utf8),
+        "{\"key\":\"plain\",\"value\":\"value\"}\n"
+    );
+    assert_eq!(
+        export(&mut db, b"comma", Format::Csv, BinaryEncoding::Utf8),
+        "key,value\n\"comma,key\",\"a \"\"quoted\"\" value\"\n"
+    );
+}
+
+#[test]
+fn binary_round_trips_as_base64_and_hex() {
+    let mut db = Db::open_in_memory().unwrap();
+    let mut tx = db.begin_transaction();
+    // Lengths 0..=4 cover every base64 padding case.
+    for len in 0..=4u8 {
+        tx.set([0x80, len], (0..len).map(|b| b.wrapping_mul(97) ^ 0xF0).collect::<Vec<_>>());
+    }
+    tx.set([0x00, 0xFE], [0xFF, 0x00, b'"', b',', b'\n']);
+    tx.commit().unwrap();
+    let want = db.scan(..).unwrap();
+
+    let mut utf8 = Vec::new();
+    let e = db.export(&mut utf8, b"", Format::JsonLines, BinaryEncoding::Utf8).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    for format in [Format::JsonLines, Format::Csv] {
+        for encoding in [BinaryEncoding::Base64, BinaryEncoding::Hex] {
+            assert_eq!(round_trip(&mut db, format, encoding), want, "{format:?} {encoding:?}");
+        }
+    }
+
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "Man", "any carnal pleas");
+    assert_eq!(export(&mut db, b"", Format::Csv, BinaryEncoding::Base64), "key,value\nTWFu,YW55IGNhcm5hbCBwbGVhcw==\n");
+    assert_eq!(
+        export(&mut db, b"", Format::Csv, BinaryEncoding::Hex),
+        "key,value\n4d616e,616e79206361726e616c20706c656173\n"
+    );
+}
+
+#[test]
+fn csv_fields_may_span_lines() {
+    let mut db = Db::open_in_memory().unwrap();
+    let text = "key,value\n\"multi\",\"one\ntwo, \"\"three\"\"\n\"\nsimple,x\r\n\n";
+    assert_eq!(import(&mut db, text, Format::Csv, BinaryEncoding::Utf8).unwrap(), 2);
+    assert_eq!(db.get("multi").unwrap(), Some(b"one\ntwo, \"three\"\n".to_vec()));
+    assert_eq!(db.get("simple").unwrap(), Some(b"x".to_vec()));
+
+    // The header row is optional.
+    assert_eq!(import(&mut db, "k,v\n", Format::Csv, BinaryEncoding::Utf8).unwrap(), 1);
+    assert_eq!(db.get("k").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn a_null_value_deletes_the_key() {
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "gone", "x");
+    set(&mut db, "kept", "x");
+    let text =
+        "{\"key\": \"gone\", \"value\": null}\n{ \"value\" : \"y\", \"extra\": \"ignored\", \"key\": \"new\" }\n";
+    assert_eq!(import(&mut db, text, Format::JsonLines, BinaryEncoding::Utf8).unwrap(), 2);
+    assert_eq!(db.get("gone").unwrap(), None);
+    assert_eq!(db.get("new").unwrap(), Some(b"y".to_vec()));
+    assert_eq!(db.get("kept").unwrap(), Some(b"x".to_vec()));
+
+    let escaped = r#"{"key":"\u00e9\ud83e\udd80\/","value":"a\"b"}"#;
+    import(&mut db, escaped, Format::JsonLines, BinaryEncoding::Utf8).unwrap();
+    assert_eq!(db.get("é🦀/").unwrap(), Some(b"a\"b".to_vec()));
+}
+
+/// Imports `text` in batches of two and returns the error's kind and message.
+fn import_error(db: &mut Db, text: &str, format: Format, encoding: BinaryEncoding) -> (ErrorKind, String) {
+    let options = ImportOptions { format, encoding, batch_size: 2 };
+    let e = db.import(text.as_bytes(), &options, |_| {}).err().unwrap();
+    (e.kind(), e.to_string())
+}
+
+#[test]
+fn errors_name_their_line_and_keep_earlier_batches() {
+    let mut db = Db::open_in_memory().unwrap();
+    let json = "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\",\"value\":\"2\"}\n{\"key\":\"c\",\"value\":\"3\"}\n{\"key\":\"d\"}\n";
+    let (kind, msg) = import_error(&mut db, json, Format::JsonLines, BinaryEncoding::Utf8);
+    assert_eq!(kind, ErrorKind::InvalidData);
+    assert!(msg.starts_with("line 5: "), "{msg}");
+    // The first batch was committed; the second was cut short.
+    assert_eq!(db.scan(..).unwrap().len(), 2);
+    assert_eq!(db.get("c").unwrap(), None);
+
+    let cases = [
+        ("{\"key\":\"x\",\"value\":1}\n", Format::JsonLines, BinaryEncoding::Utf8, 1),
+        ("{\"key\":\"x\",\"value\":\"1\"} trailing\n", Format::JsonLines, BinaryEncoding::Utf8, 1),
+        ("{\"key\":\"x\",\"value\":\"\\q\"}\n", Format::JsonLines, BinaryEncoding::Utf8, 1),
+        ("{\"key\":\"zz\",\"value\":\"00\"}\n", Format::JsonLines, BinaryEncoding::Hex, 1),
+        ("{\"key\":\"QQ\",\"value\":\"QQ==\"}\n", Format::JsonLines, BinaryEncoding::Base64, 1),
+        ("key,value\nx,1\ny,2,3\n", Format::Csv, BinaryEncoding::Utf8, 3),
+        ("key,value\nx,\"1\" \n", Format::Csv, BinaryEncoding::Utf8, 2),
+        ("key,value\n\"x\n\ny,1\n", Format::Csv, BinaryEncoding::Utf8, 2),
+        // A multi-line record is reported at its first line.
+        ("key,value\n\"x\ny\",\"1\n2\"\nz\"z,1\n", Format::Csv, BinaryEncoding::Utf8, 5),
+    ];
+    for (text, format, encoding, line) in cases {
+        let (kind, msg) = import_error(&mut Db::open_in_memory().unwrap(), text, format, encoding);
+        assert_eq!(kind, ErrorKind::InvalidData, "{text:?}");
+        assert!(msg.starts_with(&format!("line {line}: ")), "{text:?}: {msg}");
+    }
+}
+
+#[test]
+fn system_keys_are_neither_exported_nor_imported() {
+    let mut db = Db::open_in_memory().unwrap();
+    db.register_index("value", 1, |_, value| vec![value.to_vec()]).unwrap();
+    set(&mut db, "a", "1");
+    assert_eq!(export(&mut db, b"", Format::Csv, BinaryEncoding::Hex), "key,value\n61,31\n");
+    assert_eq!(export(&mut db, &[0xFF], Format::Csv, BinaryEncoding::Hex), "key,value\n");
+
+    let text = "key,value\n62,32\n63,33\nff00,34\n";
+    let (kind, msg) = import_error(&mut db, text, Format::Csv, BinaryEncoding::Hex);
+    assert_eq!(kind, ErrorKind::InvalidInput);
+    assert!(msg.starts_with("line 4: "), "{msg}");
+    assert_eq!(db.scan(..).unwrap().len(), 3);
+}
@@ -0,0 +1,245 @@
+//! File headers and upgrading files from older format versions, checked
+//! against golden files written by earlier builds:
+//!
+//! - `v1-db`: a Db from before manifests, with a commit left in its WAL
+//! - `v1-segmented-db`: a segmented Db with a format 1 manifest
+//! - `v1-backup` and `v1-archive`: a backup taken after 20 commits and an
+//!   archive of all 30
+//! - `v1-kvstore.log`: a `KvStore` log
+//! - `v2-db`: a segmented Db in the current format
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rust_embedded_kv_store::{
+    detect_format, upgrade_archive, upgrade_db, upgrade_kvstore, ChangeCursor, DataFormat, Db, KvStore, MemFs,
+    RecoveryTarget, Vfs, FORMAT_VERSION,
+};
+
+use common::TempDir;
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A copy of the golden file or directory `name` in `scratch`.
+fn golden(name: &str, scratch: &TempDir) -> PathBuf {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+    let target = scratch.join(name);
+    copy(&source, &target);
+    target
+}
+
+fn copy(source: &Path, target: &Path) {
+    if source.is_dir() {
+        std::fs::create_dir_all(target).unwrap();
+        for entry in std::fs::read_dir(source).unwrap() {
+            let entry = entry.unwrap();
+            copy(&entry.path(), &target.join(entry.file_name()));
+        }
+    } else {
+        std::fs::copy(source, target).unwrap();
+    }
+}
+
+/// What the golden Dbs hold after `commits` of the workload that wrote them.
+fn expected(commits: std::ops::Range<u32>) -> Pairs {
+    let mut model = BTreeMap::new();
+    for i in commits {
+        model.insert(format!("key{:02}", i % 12), format!("value{i}"));
+        if i % 5 == 4 {
+            model.remove(&format!("key{:02}", (i * 7) % 12));
+        }
+    }
+    model.into_iter().map(|(k, v)| (k.into_bytes(), v.into_bytes())).collect()
+}
+
+fn header(magic: &[u8; 4], version: u16, flags: u16) -> Vec<u8> {
+    [magic.as_slice(), &version.to_le_bytes(), &flags.to_le_bytes()].concat()
+}
+
+#[test]
+fn legacy_db_is_refused_until_upgraded() {
+    let scratch = TempDir::new("golden");
+    let dir = golden("v1-db", &scratch);
+    let e = Db::open(&dir).err().unwrap();
+    assert!(e.to_string().contains("upgrade_db"), "{e}");
+
+    let report = upgrade_db(&dir).unwrap();
+    assert_eq!(report.from_version, 1);
+    assert_eq!(report.upgraded.len(), 3, "{:?}", report.upgraded);
+
+    let mut db = Db::open(&dir).unwrap();
+    let mut want = expected(0..30);
+    want.push((b"wal-only".to_vec(), b"replayed".to_vec()));
+    want.sort();
+    assert_eq!(db.scan(..).unwrap(), want);
+    assert_eq!(db.last_seq(), 31);
+}
+
+#[test]
+fn segmented_db_is_refused_until_upgraded() {
+    let scratch = TempDir::new("golden");
+    let dir = golden("v1-segmented-db", &scratch);
+    let e = Db::open(&dir).err().unwrap();
+    assert!(e.to_string().contains("format version 1"), "{e}");
+
+    let report = upgrade_db(&dir).unwrap();
+    assert_eq!(report.from_version, 1);
+    assert!(!report.upgraded.is_empty());
+
+    let mut db = Db::open(&dir).unwrap();
+    assert_eq!(db.scan(..).unwrap(), expected(0..30));
+    assert_eq!(db.last_seq(), 30);
+    assert!(db.segment_count() > 1);
+}
+
+/// A record as the data log and WAL frame it: `[op][klen][vlen][key][value]`.
+fn record(op: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+    [&[op], &(key.len() as u32).to_le_bytes()[..], &(value.len() as u32).to_le_bytes(), key, value].concat()
+}
+
+#[test]
+fn wal_from_before_the_change_log_is_renumbered() {
+    // Before the change log existed, WAL transactions had no sequence number
+    // after BEGIN, and the Db had no change log.
+    let dir = TempDir::new("unsequenced-wal");
+    std::fs::write(dir.join("data.log"), record(1, b"a", b"1")).unwrap();
+    let wal = [
+        &[0][..],
+        &record(1, b"b", b"2"),
+        &record(2, b"a", b""),
+        &[3],
+        &[0],
+        &record(1, b"c", b"3"),
+        &[3],
+        // Cut short by a crash.
+        &[0],
+        &record(1, b"d", b"4")[..5],
+    ]
+    .concat();
+    std::fs::write(dir.join("wal.log"), wal).unwrap();
+
+    let report = upgrade_db(&dir).unwrap();
+    assert_eq!(report.from_version, 1);
+    assert_eq!(report.upgraded, [dir.join("data.log"), dir.join("wal.log")]);
+
+    let mut db = Db::open(&dir).unwrap();
+    assert_eq!(db.scan(..).unwrap(), [(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]);
+    assert_eq!(db.last_seq(), 2);
+    let batches = db.read_changes(&mut ChangeCursor::from_seq(1), 10).unwrap();
+    assert_eq!(batches.iter().map(|b| b.seq).collect::<Vec<_>>(), [1, 2]);
+}
+
+#[test]
+fn upgrading_twice_changes_nothing() {
+    let scratch = TempDir::new("golden");
+    let dir = golden("v1-segmented-db", &scratch);
+    upgrade_db(&dir).unwrap();
+    let report = upgrade_db(&dir).unwrap();
+    assert_eq!(report.from_version, FORMAT_VERSION);
+    assert!(report.upgraded.is_empty());
+    assert_eq!(Db::open(&dir).unwrap().scan(..).unwrap(), expected(0..30));
+}
+
+#[test]
+fn current_format_opens_as_is() {
+    let scratch = TempDir::new("golden");
+    let dir = golden("v2-db", &scratch);
+    assert_eq!(Db::open(&dir).unwrap().scan(..).unwrap(), expected(0..30));
+    assert!(upgrade_db(&dir).unwrap().upgraded.is_empty());
+}
+
+#[test]
+fn kvstore_log_is_refused_until_upgraded() {
+    let scratch = TempDir::new("golden");
+    let path = golden("v1-kvstore.log", &scratch);
+    let e = KvStore::open(&path).err().unwrap();
+    assert!(e.to_string().contains("upgrade_kvstore"), "{e}");
+
+    let report = upgrade_kvstore(&path).unwrap();
+    assert_eq!(report.upgraded, vec![path.clone()]);
+    assert_eq!(detect_format(&path).unwrap(), DataFormat::KvStore);
+
+    let mut want = BTreeMap::new();
+    for i in 0..12 {
+        want.insert(format!("key{}", i % 5), format!("value{i}"));
+    }
+    want.remove("key1");
+    let mut store = KvStore::open(&path).unwrap();
+    assert_eq!(store.scan(..).unwrap(), want.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn upgrading_refuses_the_other_stores_log() {
+    let scratch = TempDir::new("golden");
+    let log = golden("v1-kvstore.log", &scratch);
+    let mixed = scratch.join("mixed");
+    std::fs::create_dir(&mixed).unwrap();
+    std::fs::rename(log, mixed.join("data.log")).unwrap();
+    assert!(upgrade_db(&mixed).is_err());
+
+    let dir = golden("v1-db", &scratch);
+    assert!(upgrade_kvstore(dir.join("data.log")).is_err());
+}
+
+#[test]
+fn backup_and_archive_recover_once_upgraded() {
+    let scratch = TempDir::new("golden");
+    let backup = golden("v1-backup", &scratch);
+    let archive = golden("v1-archive", &scratch);
+    assert!(Db::recover_to(&backup, &archive, scratch.join("early"), RecoveryTarget::Latest).is_err());
+
+    assert_eq!(upgrade_db(&backup).unwrap().from_version, 1);
+    assert_eq!(upgrade_archive(&archive).unwrap().upgraded.len(), 1);
+
+    let mut db = Db::recover_to(&backup, &archive, scratch.join("recovered"), RecoveryTarget::Latest).unwrap();
+    assert_eq!(db.scan(..).unwrap(), expected(0..30));
+    assert_eq!(db.last_seq(), 30);
+
+    let mut db = Db::recover_to(&backup, &archive, scratch.join("base"), RecoveryTarget::Seq(20)).unwrap();
+    assert_eq!(db.scan(..).unwrap(), expected(0..20));
+}
+
+#[test]
+fn newer_versions_and_unknown_flags_are_refused() {
+    let newer = FORMAT_VERSION as u16 + 1;
+    for (magic, name) in [(b"KVDL", "data.log"), (b"KVWL", "wal.log"), (b"KVCL", "changes.log")] {
+        let cases = [(newer, 0, format!("format version {newer}")), (FORMAT_VERSION as u16, 1, "flags".to_string())];
+        for (version, flags, message) in cases {
+            let fs = MemFs::new();
+            fs.create(&Path::new("db").join(name)).unwrap().write(&header(magic, version, flags)).unwrap();
+            let e = Db::open_with_vfs(Arc::new(fs), "db").err().unwrap();
+            assert!(e.to_string().contains(&message), "{name}: {e}");
+        }
+    }
+
+    let dir = TempDir::new("kvstore-newer");
+    let path = dir.join("data.log");
+    std::fs::write(&path, header(b"KVSL", newer, 0)).unwrap();
+    let e = KvStore::open(&path).err().unwrap();
+    assert!(e.to_string().contains(&format!("format version {newer}")), "{e}");
+
+    let archive = TempDir::new("archive-newer");
+    std::fs::write(archive.join("00000000000000000001.archive"), header(b"KVAR", newer, 0)).unwrap();
+    let mut db = Db::open(TempDir::new("archive-newer-db")).unwrap();
+    let e = db.enable_archiving(&archive).err().unwrap();
+    assert!(e.to_string().contains(&format!("format version {newer}")), "{e}");
+    assert!(upgrade_archive(&archive).is_err());
+}
+
+#[test]
+fn files_of_the_wrong_kind_are_refused() {
+    let dir = TempDir::new("wrong-kind");
+    let mut store = KvStore::open(dir.join("data.log")).unwrap();
+    store.put("key".to_string(), "value".to_string()).unwrap();
+    drop(store);
+    let e = Db::open(&dir).err().unwrap();
+    assert!(e.to_string().contains("migrate_kvstore"), "{e}");
+
+    let fs = MemFs::new();
+    fs.create(Path::new("db/wal.log")).unwrap().write(&header(b"KVDL", FORMAT_VERSION as u16, 0)).unwrap();
+    let e = Db::open_with_vfs(Arc::new(fs), "db").err().unwrap();
+    assert!(e.to_string().contains("not a Db WAL"), "{e}");
+}
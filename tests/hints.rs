@@ -0,0 +1,99 @@
+//! Hint files: written when segments are sealed or compacted, used on open
+//! when they check out, and ignored otherwise.
+
+mod common;
+
+use std::path::Path;
+
+use rust_embedded_kv_store::Db;
+
+use common::{set, TempDir};
+
+fn keys(dir: &Path) -> Vec<Vec<u8>> {
+    Db::open(dir).unwrap().scan(..).unwrap().into_iter().map(|(k, _)| k).collect()
+}
+
+/// A Db whose sealed segment 1 holds the single record `alpha`, with the
+/// key's last byte then changed to `X` in place. Opening from the hint still
+/// finds `alpha`; scanning the segment finds `alphX`.
+fn db_with_doctored_segment(name: &str) -> TempDir {
+    let dir = TempDir::new(name);
+    let mut db = Db::open(&dir).unwrap();
+    // Every commit goes into a segment of its own, after an empty one.
+    db.set_segment_size(1).unwrap();
+    set(&mut db, "alpha", "1");
+    set(&mut db, "beta", "2");
+    drop(db);
+    assert!(dir.join("data-000001.hint").exists());
+
+    let path = dir.join("data-000001.log");
+    let mut data = std::fs::read(&path).unwrap();
+    // Header, then [op][klen][vlen], then the key.
+    let last = 8 + 9 + "alpha".len() - 1;
+    assert_eq!(data[last], b'a');
+    data[last] = b'X';
+    std::fs::write(&path, data).unwrap();
+    dir
+}
+
+#[test]
+fn open_indexes_sealed_segments_from_their_hints() {
+    let dir = db_with_doctored_segment("used");
+    assert_eq!(keys(&dir), [b"alpha".to_vec(), b"beta".to_vec()]);
+}
+
+#[test]
+fn missing_or_corrupt_hints_fall_back_to_scanning() {
+    let dir = db_with_doctored_segment("missing");
+    std::fs::remove_file(dir.join("data-000001.hint")).unwrap();
+    assert_eq!(keys(&dir), [b"alphX".to_vec(), b"beta".to_vec()]);
+
+    let dir = db_with_doctored_segment("corrupt");
+    let path = dir.join("data-000001.hint");
+    let mut hint = std::fs::read(&path).unwrap();
+    *hint.last_mut().unwrap() ^= 1;
+    std::fs::write(&path, hint).unwrap();
+    assert_eq!(keys(&dir), [b"alphX".to_vec(), b"beta".to_vec()]);
+
+    let dir = db_with_doctored_segment("torn");
+    let path = dir.join("data-000001.hint");
+    let hint = std::fs::read(&path).unwrap();
+    std::fs::write(&path, &hint[..hint.len() - 1]).unwrap();
+    assert_eq!(keys(&dir), [b"alphX".to_vec(), b"beta".to_vec()]);
+}
+
+#[test]
+fn hints_for_a_different_segment_are_ignored() {
+    let dir = db_with_doctored_segment("stale");
+    let mut db = Db::open(&dir).unwrap();
+    set(&mut db, "gamma-with-a-longer-key", "3");
+    drop(db);
+
+    std::fs::copy(dir.join("data-000002.hint"), dir.join("data-000001.hint")).unwrap();
+    assert_eq!(keys(&dir), [b"alphX".to_vec(), b"beta".to_vec(), b"gamma-with-a-longer-key".to_vec()]);
+}
+
+#[test]
+fn compaction_replaces_hints_along_with_segments() {
+    let dir = TempDir::new("compaction");
+    let mut db = Db::open(&dir).unwrap();
+    db.set_segment_size(32).unwrap();
+    for i in 0..20 {
+        set(&mut db, &format!("key{}", i % 7), &format!("value{i}"));
+    }
+    db.compact().unwrap();
+    let live = db.scan(..).unwrap();
+    drop(db);
+
+    let mut hints = 0;
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        if let Some(stem) = name.strip_suffix(".hint") {
+            assert!(dir.join(format!("{stem}.log")).exists(), "{name} outlived its segment");
+            hints += 1;
+        }
+    }
+    let mut db = Db::open(&dir).unwrap();
+    assert_eq!(hints, db.segment_count() - 1);
+    assert_eq!(db.scan(..).unwrap(), live);
+}
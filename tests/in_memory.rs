@@ -0,0 +1,60 @@
+mod common;
+
+use rust_embedded_kv_store::Db;
+
+use common::{set, TempDir};
+
+#[test]
+fn writes_no_files() {
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "a", "1");
+    db.compact().unwrap();
+    assert!(!db.dir().exists());
+    assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn instances_are_independent() {
+    let mut first = Db::open_in_memory().unwrap();
+    let mut second = Db::open_in_memory().unwrap();
+    set(&mut first, "a", "1");
+    assert_eq!(second.get("a").unwrap(), None);
+    assert_eq!(second.last_seq(), 0);
+}
+
+#[test]
+fn transactions_see_their_own_writes() {
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "a", "1");
+    set(&mut db, "b", "2");
+
+    let mut tx = db.begin_transaction();
+    tx.delete("a");
+    tx.set("c", "3");
+    assert_eq!(tx.get("a").unwrap(), None);
+    assert_eq!(
+        tx.scan(..).unwrap(),
+        vec![(b"b".to_vec(), b"2".to_vec()), (b"c".to_vec(), b"3".to_vec())]
+    );
+    drop(tx);
+    assert_eq!(db.get("a").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn persists_to_a_directory() {
+    let dir = TempDir::new("persist");
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "a", "1");
+    set(&mut db, "b", "2");
+    set(&mut db, "a", "3");
+    db.persist_to(&dir).unwrap();
+    set(&mut db, "later", "x");
+
+    let mut reopened = Db::open(&dir).unwrap();
+    assert_eq!(reopened.last_seq(), 3);
+    assert_eq!(
+        reopened.scan(..).unwrap(),
+        vec![(b"a".to_vec(), b"3".to_vec()), (b"b".to_vec(), b"2".to_vec())]
+    );
+    assert!(db.persist_to(&dir).is_err());
+}
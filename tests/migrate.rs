@@ -0,0 +1,164 @@
+mod common;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use rust_embedded_kv_store::{detect_format, migrate_kvstore, DataFormat, Db, KvStore, MigrationReport};
+
+use common::{set, TempDir};
+
+/// A headerless data log record: `[op][klen][vlen][key][value]`.
+fn record(op: u8, key: &str, value: &str) -> Vec<u8> {
+    let mut out = vec![op];
+    out.extend((key.len() as u32).to_le_bytes());
+    out.extend((value.len() as u32).to_le_bytes());
+    out.extend(key.as_bytes());
+    out.extend(value.as_bytes());
+    out
+}
+
+/// Writes a `KvStore` log at `path` whose live keys are `a`, `c` and `d`.
+fn write_kvstore(path: &Path) {
+    let mut store = KvStore::open(path).unwrap();
+    for (key, value) in [("a", "1"), ("b", "2"), ("c", "3"), ("a", "4"), ("d", "")] {
+        store.put(key.to_string(), value.to_string()).unwrap();
+    }
+    store.delete("b".to_string()).unwrap();
+}
+
+fn migrated() -> Vec<(Vec<u8>, Vec<u8>)> {
+    [("a", "4"), ("c", "3"), ("d", "")].map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).to_vec()
+}
+
+fn file_names(dir: &Path) -> BTreeSet<String> {
+    fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect()
+}
+
+#[test]
+fn migrating_in_place_keeps_the_original_log() {
+    let dir = TempDir::new("migrate-in-place");
+    let source = dir.join("data.log");
+    write_kvstore(&source);
+    let original = fs::read(&source).unwrap();
+
+    let report = migrate_kvstore(&source, &dir).unwrap();
+    assert_eq!(report, MigrationReport { records: 6, keys: 3 });
+    let kept = dir.join("data.log.kvstore");
+    assert_eq!(fs::read(&kept).unwrap(), original);
+    assert_eq!(detect_format(&kept).unwrap(), DataFormat::KvStore);
+
+    let mut db = Db::open(&dir).unwrap();
+    assert_eq!(db.scan(..).unwrap(), migrated());
+    set(&mut db, "e", "5");
+    drop(db);
+    assert_eq!(Db::open(&dir).unwrap().get("e").unwrap(), Some(b"5".to_vec()));
+
+    // The directory now holds a Db, so running it again is refused.
+    assert_eq!(migrate_kvstore(&kept, &dir).err().unwrap().kind(), ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn migrating_into_another_directory_leaves_the_source_alone() {
+    let scratch = TempDir::new("migrate-separate");
+    let source = scratch.join("store.log");
+    write_kvstore(&source);
+    let original = fs::read(&source).unwrap();
+
+    let target = scratch.join("nested").join("db");
+    assert_eq!(migrate_kvstore(&source, &target).unwrap().keys, 3);
+    assert_eq!(fs::read(&source).unwrap(), original);
+    assert!(!target.join("data.log.kvstore").exists());
+    assert_eq!(Db::open(&target).unwrap().scan(..).unwrap(), migrated());
+
+    // An empty source gives an empty Db.
+    fs::write(scratch.join("empty.log"), b"").unwrap();
+    let report = migrate_kvstore(scratch.join("empty.log"), scratch.join("empty")).unwrap();
+    assert_eq!(report, MigrationReport { records: 0, keys: 0 });
+    assert!(Db::open(scratch.join("empty")).unwrap().scan(..).unwrap().is_empty());
+}
+
+#[test]
+fn a_target_that_holds_data_is_refused() {
+    let scratch = TempDir::new("migrate-refused");
+    let source = scratch.join("store.log");
+    write_kvstore(&source);
+
+    let target = scratch.join("db");
+    let mut db = Db::open(&target).unwrap();
+    set(&mut db, "existing", "x");
+    drop(db);
+    let before = file_names(&target);
+    let e = migrate_kvstore(&source, &target).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(file_names(&target), before);
+    assert_eq!(Db::open(&target).unwrap().scan(..).unwrap().len(), 1);
+
+    // A data log from some other store, without a manifest beside it.
+    let other = scratch.join("other");
+    fs::create_dir(&other).unwrap();
+    fs::write(other.join("data.log"), record(0, "k", "v")).unwrap();
+    let e = migrate_kvstore(&source, &other).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+    assert_eq!(file_names(&other), BTreeSet::from(["data.log".to_string()]));
+}
+
+#[test]
+fn a_failed_migration_puts_the_original_back() {
+    let dir = TempDir::new("migrate-failed");
+    let source = dir.join("data.log");
+    write_kvstore(&source);
+    let original = fs::read(&source).unwrap();
+    // A leftover WAL that isn't a Db's makes opening the new Db fail.
+    fs::write(dir.join("wal.log"), b"KVCL\x02\x00\x00\x00").unwrap();
+    let before = file_names(&dir);
+
+    assert!(migrate_kvstore(&source, &dir).is_err());
+    assert_eq!(file_names(&dir), before);
+    assert_eq!(fs::read(&source).unwrap(), original);
+
+    fs::remove_file(dir.join("wal.log")).unwrap();
+    assert_eq!(migrate_kvstore(&source, &dir).unwrap().keys, 3);
+    assert_eq!(Db::open(&dir).unwrap().scan(..).unwrap(), migrated());
+}
+
+#[test]
+fn formats_are_detected_from_headers_or_records() {
+    let dir = TempDir::new("migrate-detect");
+    let detect = |name: &str, bytes: &[u8]| {
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        detect_format(&path).unwrap()
+    };
+
+    assert_eq!(detect_format(dir.join("missing")).unwrap(), DataFormat::Empty);
+    assert_eq!(detect("empty", b""), DataFormat::Empty);
+    assert_eq!(detect("torn-only", &record(0, "key", "value")[..5]), DataFormat::Empty);
+
+    // Headerless logs from format version 1.
+    let kvstore = [record(0, "a", "1"), record(1, "a", "")].concat();
+    assert_eq!(detect("v1-kvstore", &kvstore), DataFormat::KvStore);
+    let db = [record(1, "a", "1"), record(2, "a", "")].concat();
+    assert_eq!(detect("v1-db", &db), DataFormat::Db);
+    let torn = [record(1, "a", "1"), record(0, "b", "2")[..8].to_vec()].concat();
+    assert_eq!(detect("v1-db-torn", &torn), DataFormat::Db);
+
+    // Opcode 1 without a value is a KvStore delete or a Db put of "".
+    let ambiguous = [record(1, "a", ""), record(1, "b", "")].concat();
+    assert_eq!(detect("ambiguous", &ambiguous), DataFormat::Ambiguous);
+    assert_eq!(migrate_kvstore(dir.join("ambiguous"), dir.join("out")).err().unwrap().kind(), ErrorKind::InvalidData);
+
+    let mixed = [record(0, "a", "1"), record(1, "b", "2")].concat();
+    assert_eq!(detect("mixed", &mixed), DataFormat::Unknown);
+    assert_eq!(detect("unknown-op", &record(7, "a", "1")), DataFormat::Unknown);
+    assert_eq!(detect("delete-with-value", &record(2, "a", "1")), DataFormat::Unknown);
+
+    // Current logs say which store wrote them.
+    let store_dir = TempDir::new("migrate-detect-kvstore");
+    KvStore::open(store_dir.join("data.log")).unwrap();
+    assert_eq!(detect_format(store_dir.join("data.log")).unwrap(), DataFormat::KvStore);
+    let db_dir = TempDir::new("migrate-detect-db");
+    Db::open(&db_dir).unwrap();
+    assert_eq!(detect_format(db_dir.join("data.log")).unwrap(), DataFormat::Db);
+}
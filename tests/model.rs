@@ -0,0 +1,156 @@
+//! Model-based property tests: random sequences of operations run against a
+//! Db and against a `BTreeMap`, which must agree after every step. Failures
+//! shrink to a minimal sequence, and proptest records the seed under
+//! `proptest-regressions/` so it is replayed on the next run.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use proptest::prelude::*;
+use rust_embedded_kv_store::{Db, MemFs, Transaction};
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Few keys, including the empty key and keys that prefix one another, so
+/// that operations collide and range bounds fall between and on keys.
+const KEYS: &[&[u8]] = &[b"", b"a", b"ab", b"abc", b"b", b"ba", b"c", b"\x00", b"\xfe"];
+
+#[derive(Debug, Clone)]
+enum Action {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Commit,
+    Abort,
+    Reopen,
+    Compact,
+    CompactSegments(usize),
+    Scan(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    prop::sample::select(KEYS).prop_map(<[u8]>::to_vec)
+}
+
+fn bound() -> impl Strategy<Value = Bound<Vec<u8>>> {
+    prop_oneof![
+        Just(Bound::Unbounded),
+        key().prop_map(Bound::Included),
+        key().prop_map(Bound::Excluded),
+    ]
+}
+
+fn action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        6 => (key(), prop::collection::vec(any::<u8>(), 0..8)).prop_map(|(k, v)| Action::Set(k, v)),
+        3 => key().prop_map(Action::Delete),
+        3 => Just(Action::Commit),
+        1 => Just(Action::Abort),
+        1 => Just(Action::Reopen),
+        1 => Just(Action::Compact),
+        1 => (1..4usize).prop_map(Action::CompactSegments),
+        2 => (bound(), bound()).prop_map(|(lo, hi)| Action::Scan(lo, hi)),
+    ]
+}
+
+fn open(fs: &MemFs) -> Db {
+    Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap()
+}
+
+fn apply(model: &Model, pending: &[(Vec<u8>, Option<Vec<u8>>)]) -> Model {
+    let mut model = model.clone();
+    for (key, value) in pending {
+        match value {
+            Some(value) => model.insert(key.clone(), value.clone()),
+            None => model.remove(key),
+        };
+    }
+    model
+}
+
+fn valid_range(lo: &Bound<Vec<u8>>, hi: &Bound<Vec<u8>>) -> bool {
+    // BTreeMap::range panics on inverted or empty-excluded ranges.
+    match (lo, hi) {
+        (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b)) => {
+            a < b || (a == b && matches!((lo, hi), (Bound::Included(_), Bound::Included(_))))
+        }
+        _ => true,
+    }
+}
+
+fn stage<'db>(db: &'db mut Db, pending: &[(Vec<u8>, Option<Vec<u8>>)]) -> Transaction<'db> {
+    let mut tx = db.begin_transaction();
+    for (key, value) in pending {
+        match value {
+            Some(value) => tx.set(key, value),
+            None => tx.delete(key),
+        }
+    }
+    tx
+}
+
+fn range(model: &Model, lo: &Bound<Vec<u8>>, hi: &Bound<Vec<u8>>) -> Vec<(Vec<u8>, Vec<u8>)> {
+    if !valid_range(lo, hi) {
+        return Vec::new();
+    }
+    model.range((lo.clone(), hi.clone())).map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+/// The committed state and the open transaction's view must both match.
+fn check(db: &mut Db, model: &Model, pending: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<(), TestCaseError> {
+    for key in KEYS {
+        prop_assert_eq!(db.get(key).unwrap(), model.get(*key).cloned(), "get {:?}", key);
+    }
+    prop_assert_eq!(&db.scan(..).unwrap().into_iter().collect::<Model>(), model);
+
+    let expected = apply(model, pending);
+    let mut tx = stage(db, pending);
+    for key in KEYS {
+        prop_assert_eq!(tx.get(key).unwrap(), expected.get(*key).cloned(), "transaction get {:?}", key);
+    }
+    prop_assert_eq!(tx.scan(..).unwrap().into_iter().collect::<Model>(), expected);
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn db_matches_a_btreemap(actions in prop::collection::vec(action(), 1..64)) {
+        let fs = MemFs::new();
+        let mut db = open(&fs);
+        // Roll over every commit or two, so reads span many segments. The
+        // size is kept in the manifest across reopens.
+        db.set_segment_size(32).unwrap();
+        let mut model = Model::new();
+        let mut pending: Vec<(Vec<u8>, Option<Vec<u8>>)> = Vec::new();
+
+        for action in actions {
+            match action {
+                Action::Set(key, value) => pending.push((key, Some(value))),
+                Action::Delete(key) => pending.push((key, None)),
+                Action::Commit => {
+                    stage(&mut db, &pending).commit().unwrap();
+                    model = apply(&model, &pending);
+                    pending.clear();
+                }
+                Action::Abort => pending.clear(),
+                Action::Reopen => {
+                    // Uncommitted writes live only in memory.
+                    pending.clear();
+                    drop(db);
+                    db = open(&fs);
+                }
+                Action::Compact => db.compact().unwrap(),
+                Action::CompactSegments(count) => db.compact_segments(count).unwrap(),
+                Action::Scan(lo, hi) => {
+                    let found = db.scan((lo.clone(), hi.clone())).unwrap();
+                    prop_assert_eq!(found, range(&model, &lo, &hi));
+                    let found = stage(&mut db, &pending).scan((lo.clone(), hi.clone())).unwrap();
+                    prop_assert_eq!(found, range(&apply(&model, &pending), &lo, &hi));
+                }
+            }
+            check(&mut db, &model, &pending)?;
+        }
+    }
+}
@@ -0,0 +1,222 @@
+mod common;
+
+use rust_embedded_kv_store::raft::sim::SimNetwork;
+use rust_embedded_kv_store::raft::{MembershipChange, NodeId, PendingCommit, RaftConfig};
+
+use common::TempDir;
+
+fn set(net: &mut SimNetwork, leader: NodeId, key: &str, value: &str) -> PendingCommit {
+    let node = net.node_mut(leader).expect("leader is running");
+    let mut tx = node.begin_transaction();
+    tx.set(key, value);
+    tx.commit().expect("leader accepts proposals")
+}
+
+fn value(net: &mut SimNetwork, id: NodeId, key: &str) -> Option<Vec<u8>> {
+    net.node_mut(id).expect("node is running").get(key).unwrap()
+}
+
+#[test]
+fn elects_one_leader() {
+    let dir = TempDir::new("elect");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], RaftConfig::default(), 7).unwrap();
+    let leader = net.wait_for_leader(200).unwrap();
+    net.run(50).unwrap();
+
+    assert_eq!(net.leader(), Some(leader));
+    let term = net.node(leader).unwrap().term();
+    for id in [1, 2, 3] {
+        let node = net.node(id).unwrap();
+        assert_eq!(node.term(), term);
+        assert_eq!(node.leader(), Some(leader));
+    }
+}
+
+#[test]
+fn committed_writes_reach_every_node() {
+    let dir = TempDir::new("commit");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], RaftConfig::default(), 11).unwrap();
+    let leader = net.wait_for_leader(200).unwrap();
+
+    let pending = set(&mut net, leader, "a", "1");
+    // Appended on the leader alone: not committed yet.
+    assert!(!net.node(leader).unwrap().poll_commit(pending).unwrap());
+    net.wait_committed(leader, pending, 50).unwrap();
+    net.run(10).unwrap();
+
+    for id in [1, 2, 3] {
+        assert_eq!(value(&mut net, id, "a"), Some(b"1".to_vec()));
+    }
+}
+
+#[test]
+fn rejects_writes_on_followers_and_to_system_keys() {
+    let dir = TempDir::new("reject");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], RaftConfig::default(), 3).unwrap();
+    let leader = net.wait_for_leader(200).unwrap();
+    let follower = [1, 2, 3].into_iter().find(|&id| id != leader).unwrap();
+
+    let mut tx = net.node_mut(follower).unwrap().begin_transaction();
+    tx.set("k", "v");
+    assert!(tx.commit().is_err());
+
+    let mut tx = net.node_mut(leader).unwrap().begin_transaction();
+    tx.set([0xFF, 1], "v");
+    assert_eq!(tx.commit().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn isolated_leader_cannot_commit_and_is_replaced() {
+    let dir = TempDir::new("partition");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3, 4, 5], RaftConfig::default(), 42).unwrap();
+    let old_leader = net.wait_for_leader(200).unwrap();
+
+    net.isolate(old_leader);
+    let lost = set(&mut net, old_leader, "k", "lost");
+    net.run(100).unwrap();
+    assert!(!net.node(old_leader).unwrap().poll_commit(lost).unwrap());
+
+    let new_leader = net.leader().unwrap();
+    assert_ne!(new_leader, old_leader);
+    let kept = set(&mut net, new_leader, "k", "kept");
+    net.wait_committed(new_leader, kept, 50).unwrap();
+
+    net.heal();
+    net.run(50).unwrap();
+    assert_eq!(net.leader(), Some(new_leader));
+    assert!(net.node(old_leader).unwrap().poll_commit(lost).is_err());
+    for id in [1, 2, 3, 4, 5] {
+        assert_eq!(value(&mut net, id, "k"), Some(b"kept".to_vec()));
+    }
+}
+
+#[test]
+fn writes_compacted_away_still_report_committed() {
+    let dir = TempDir::new("poll-compacted");
+    let config = RaftConfig { snapshot_threshold: 2, ..RaftConfig::default() };
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], config, 13).unwrap();
+    let old_leader = net.wait_for_leader(200).unwrap();
+
+    let early: Vec<PendingCommit> = (0..6).map(|i| set(&mut net, old_leader, &format!("early{i}"), "v")).collect();
+    net.wait_committed(old_leader, early[5], 50).unwrap();
+    net.run(10).unwrap();
+    for &p in &early {
+        assert!(net.node(old_leader).unwrap().poll_commit(p).unwrap(), "entry {}", p.index());
+    }
+
+    // Entries from a later term are compacted on top of them.
+    net.isolate(old_leader);
+    net.run(100).unwrap();
+    net.heal();
+    net.run(50).unwrap();
+    let new_leader = net.leader().unwrap();
+    assert_ne!(new_leader, old_leader);
+    let late: Vec<PendingCommit> = (0..6).map(|i| set(&mut net, new_leader, &format!("late{i}"), "v")).collect();
+    net.wait_committed(new_leader, late[5], 50).unwrap();
+    net.run(20).unwrap();
+
+    for id in [1, 2, 3] {
+        let node = net.node(id).unwrap();
+        for &p in early.iter().chain(&late) {
+            assert!(node.poll_commit(p).unwrap(), "node {id}, entry {}", p.index());
+        }
+    }
+}
+
+#[test]
+fn survives_message_loss() {
+    let dir = TempDir::new("lossy");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], RaftConfig::default(), 5).unwrap();
+    net.set_drop_rate(0.2);
+
+    let mut written = 0;
+    for i in 0..20 {
+        let leader = net.wait_for_leader(500).unwrap();
+        let pending = set(&mut net, leader, &format!("key{i:02}"), &i.to_string());
+        if net.wait_committed(leader, pending, 200).is_ok() {
+            written += 1;
+        }
+    }
+    assert!(written > 0);
+
+    net.set_drop_rate(0.0);
+    net.run(100).unwrap();
+    let reference = net.node_mut(1).unwrap().scan(..).unwrap();
+    assert_eq!(reference.len(), written);
+    for id in [2, 3] {
+        assert_eq!(net.node_mut(id).unwrap().scan(..).unwrap(), reference);
+    }
+}
+
+#[test]
+fn restarted_node_catches_up_without_reapplying() {
+    let dir = TempDir::new("restart");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], RaftConfig::default(), 9).unwrap();
+    let leader = net.wait_for_leader(200).unwrap();
+    let follower = [1, 2, 3].into_iter().find(|&id| id != leader).unwrap();
+
+    let p = set(&mut net, leader, "before", "1");
+    net.wait_committed(leader, p, 50).unwrap();
+    net.run(10).unwrap();
+    net.crash(follower);
+
+    let p = set(&mut net, leader, "during", "2");
+    net.wait_committed(leader, p, 50).unwrap();
+
+    net.restart(follower).unwrap();
+    net.run(50).unwrap();
+    assert_eq!(value(&mut net, follower, "before"), Some(b"1".to_vec()));
+    assert_eq!(value(&mut net, follower, "during"), Some(b"2".to_vec()));
+    assert_eq!(
+        net.node(follower).unwrap().applied_index(),
+        net.node(leader).unwrap().applied_index()
+    );
+}
+
+#[test]
+fn new_member_is_caught_up_from_a_snapshot() {
+    let config = RaftConfig { snapshot_threshold: 5, ..RaftConfig::default() };
+    let dir = TempDir::new("snapshot");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], config, 13).unwrap();
+    let leader = net.wait_for_leader(200).unwrap();
+
+    for i in 0..30 {
+        let p = set(&mut net, leader, &format!("key{i:02}"), &i.to_string());
+        net.wait_committed(leader, p, 50).unwrap();
+    }
+
+    net.add_node(4).unwrap();
+    let p = net.node_mut(leader).unwrap().propose_membership(MembershipChange::AddNode(4)).unwrap();
+    net.wait_committed(leader, p, 50).unwrap();
+    net.run(20).unwrap();
+
+    let expected = net.node_mut(leader).unwrap().scan(..).unwrap();
+    assert_eq!(expected.len(), 30);
+    assert_eq!(net.node_mut(4).unwrap().scan(..).unwrap(), expected);
+    assert_eq!(net.node(4).unwrap().members(), [1, 2, 3, 4].into_iter().collect());
+
+    // With four members, losing two must stop progress.
+    let others: Vec<NodeId> = [1, 2, 3, 4].into_iter().filter(|&id| id != leader).collect();
+    net.isolate(others[0]);
+    net.isolate(others[1]);
+    let p = set(&mut net, leader, "blocked", "x");
+    net.run(30).unwrap();
+    assert!(!net.node(leader).unwrap().poll_commit(p).unwrap());
+}
+
+#[test]
+fn removed_leader_steps_down() {
+    let dir = TempDir::new("remove");
+    let mut net = SimNetwork::new(&dir, &[1, 2, 3], RaftConfig::default(), 21).unwrap();
+    let leader = net.wait_for_leader(200).unwrap();
+
+    let p = net.node_mut(leader).unwrap().propose_membership(MembershipChange::RemoveNode(leader)).unwrap();
+    net.wait_committed(leader, p, 50).unwrap();
+    net.crash(leader);
+
+    let new_leader = net.wait_for_leader(200).unwrap();
+    assert_ne!(new_leader, leader);
+    assert_eq!(net.node(new_leader).unwrap().members().len(), 2);
+    let p = set(&mut net, new_leader, "k", "v");
+    net.wait_committed(new_leader, p, 50).unwrap();
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rust_embedded_kv_store::{ChangeCursor, Db, Follower, Primary};
+
+use common::{set, TempDir};
+
+/// Polls `check` until it holds, failing the test after ten seconds.
+fn eventually(what: &str, mut check: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !check() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn start_primary(name: &str) -> (TempDir, Arc<Mutex<Db>>, Primary) {
+    let dir = TempDir::new(name);
+    let db = Arc::new(Mutex::new(Db::open(&dir).unwrap()));
+    let primary = Primary::start(Arc::clone(&db), "127.0.0.1:0").unwrap();
+    (dir, db, primary)
+}
+
+#[test]
+fn follower_tails_commits() {
+    let (_dir, db, primary) = start_primary("tail-primary");
+    set(&mut db.lock().unwrap(), "a", "1");
+
+    let replica = TempDir::new("tail-follower");
+    let follower = Follower::start(&replica, primary.local_addr()).unwrap();
+    eventually("the first commit", || follower.get("a").unwrap().is_some());
+
+    set(&mut db.lock().unwrap(), "b", "2");
+    {
+        let mut db = db.lock().unwrap();
+        let mut tx = db.begin_transaction();
+        tx.delete("a");
+        tx.set("c", "3");
+        tx.commit().unwrap();
+    }
+    eventually("later commits", || follower.applied_seq().unwrap() == 3);
+    assert_eq!(follower.scan(..).unwrap(), db.lock().unwrap().scan(..).unwrap());
+
+    // A restarted follower resumes from where it stopped.
+    drop(follower.stop().unwrap());
+    set(&mut db.lock().unwrap(), "d", "4");
+    let follower = Follower::start(&replica, primary.local_addr()).unwrap();
+    eventually("the commit made while stopped", || follower.get("d").unwrap().is_some());
+    assert_eq!(follower.applied_seq().unwrap(), 4);
+}
+
+#[test]
+fn follower_behind_retention_catches_up_from_a_snapshot() {
+    let (_dir, db, primary) = start_primary("snapshot-primary");
+    {
+        let mut db = db.lock().unwrap();
+        db.set_change_retention(2);
+        for i in 0..20 {
+            set(&mut db, &format!("key{:02}", i % 7), &i.to_string());
+        }
+        assert!(db.oldest_change_seq().unwrap() > 1);
+    }
+
+    let replica = TempDir::new("snapshot-follower");
+    let follower = Follower::start(&replica, primary.local_addr()).unwrap();
+    eventually("the snapshot", || follower.applied_seq().unwrap() == 20);
+    assert_eq!(follower.scan(..).unwrap(), db.lock().unwrap().scan(..).unwrap());
+
+    // Streaming carries on after the snapshot.
+    set(&mut db.lock().unwrap(), "after", "x");
+    eventually("the commit after the snapshot", || follower.get("after").unwrap().is_some());
+    assert_eq!(follower.applied_seq().unwrap(), 21);
+
+    // The replica's own history starts at the snapshot.
+    let mut replica_db = follower.stop().unwrap();
+    assert_eq!(replica_db.oldest_change_seq(), Some(20));
+    let e = replica_db.read_changes(&mut ChangeCursor::from_seq(1), 10).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    drop(replica_db);
+    assert_eq!(Db::open(&replica).unwrap().last_seq(), 21);
+}
+
+#[test]
+fn follower_ahead_of_the_primary_keeps_its_commits() {
+    let (_dir, db, primary) = start_primary("ahead-primary");
+    set(&mut db.lock().unwrap(), "a", "primary");
+
+    let replica = TempDir::new("ahead-follower");
+    let mut local = Db::open(&replica).unwrap();
+    for i in 0..3 {
+        set(&mut local, &format!("local{i}"), "x");
+    }
+    let want = local.scan(..).unwrap();
+    drop(local);
+
+    // The primary answers with a snapshot at seq 1, which the follower refuses.
+    let follower = Follower::start(&replica, primary.local_addr()).unwrap();
+    eventually("contact with the primary", || follower.lag().unwrap().since_contact.is_some());
+    thread::sleep(Duration::from_millis(200));
+    let mut local = follower.stop().unwrap();
+    assert_eq!(local.last_seq(), 3);
+    assert_eq!(local.scan(..).unwrap(), want);
+}
+
+#[test]
+fn lag_reports_progress_and_lost_contact() {
+    let (_dir, db, primary) = start_primary("lag-primary");
+    for i in 0..5 {
+        set(&mut db.lock().unwrap(), &format!("key{i}"), "value");
+    }
+
+    let replica = TempDir::new("lag-follower");
+    let follower = Follower::start(&replica, primary.local_addr()).unwrap();
+    eventually("the follower to catch up", || {
+        let lag = follower.lag().unwrap();
+        lag.connected && lag.applied_seq == 5 && lag.primary_seq == 5
+    });
+    let lag = follower.lag().unwrap();
+    assert_eq!(lag.behind, 0);
+    assert!(lag.since_contact.unwrap() < Duration::from_secs(5));
+
+    primary.stop();
+    eventually("the follower to notice", || !follower.lag().unwrap().connected);
+    let lag = follower.lag().unwrap();
+    assert_eq!((lag.applied_seq, lag.primary_seq), (5, 5));
+    assert!(lag.since_contact.is_some());
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use std::ops::Bound;
+
+use rust_embedded_kv_store::{Db, IndexEntry};
+
+use common::{set, TempDir};
+
+/// Indexes each record by its value's first byte.
+fn by_initial(_key: &[u8], value: &[u8]) -> Vec<Vec<u8>> {
+    value.first().map(|&b| vec![b]).into_iter().collect()
+}
+
+fn keys(entries: Vec<IndexEntry>) -> Vec<String> {
+    entries.into_iter().map(|e| String::from_utf8(e.key).unwrap()).collect()
+}
+
+fn delete(db: &mut Db, key: &str) {
+    let mut tx = db.begin_transaction();
+    tx.delete(key);
+    tx.commit().unwrap();
+}
+
+#[test]
+fn registering_backfills_existing_records() {
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "k1", "apple");
+    set(&mut db, "k2", "banana");
+    set(&mut db, "k3", "avocado");
+    set(&mut db, "k4", "");
+
+    db.register_index("initial", 1, by_initial).unwrap();
+    assert_eq!(keys(db.index_get("initial", "a").unwrap()), ["k1", "k3"]);
+    let entry = &db.index_get("initial", "b").unwrap()[0];
+    assert_eq!((entry.index_key.as_slice(), entry.value.as_slice()), (&b"b"[..], &b"banana"[..]));
+
+    // Index entries stay out of user scans.
+    assert_eq!(db.scan(..).unwrap().len(), 4);
+    assert!(db.register_index("initial", 1, by_initial).is_err());
+    assert!(db.index_get("missing", "a").is_err());
+}
+
+#[test]
+fn commits_maintain_the_index() {
+    let mut db = Db::open_in_memory().unwrap();
+    db.register_index("initial", 1, by_initial).unwrap();
+    set(&mut db, "k1", "apple");
+    set(&mut db, "k2", "apricot");
+    assert_eq!(keys(db.index_get("initial", "a").unwrap()), ["k1", "k2"]);
+
+    set(&mut db, "k1", "blueberry");
+    assert_eq!(keys(db.index_get("initial", "a").unwrap()), ["k2"]);
+    assert_eq!(keys(db.index_get("initial", "b").unwrap()), ["k1"]);
+
+    delete(&mut db, "k2");
+    assert!(db.index_get("initial", "a").unwrap().is_empty());
+
+    // Several writes to one key in a transaction leave only the last entry.
+    let mut tx = db.begin_transaction();
+    tx.set("k3", "cherry");
+    tx.set("k3", "date");
+    tx.commit().unwrap();
+    assert!(db.index_get("initial", "c").unwrap().is_empty());
+    assert_eq!(keys(db.index_get("initial", "d").unwrap()), ["k3"]);
+}
+
+#[test]
+fn index_scan_honours_its_bounds() {
+    let mut db = Db::open_in_memory().unwrap();
+    db.register_index("initial", 1, by_initial).unwrap();
+    for (key, value) in [("k1", "a"), ("k2", "b"), ("k3", "b"), ("k4", "c"), ("k5", "d")] {
+        set(&mut db, key, value);
+    }
+    let scan = |db: &mut Db, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)| keys(db.index_scan("initial", range).unwrap());
+    let b = || b"b".to_vec();
+    let c = || b"c".to_vec();
+    assert_eq!(scan(&mut db, (Bound::Included(b()), Bound::Included(c()))), ["k2", "k3", "k4"]);
+    assert_eq!(scan(&mut db, (Bound::Excluded(b()), Bound::Included(c()))), ["k4"]);
+    assert_eq!(scan(&mut db, (Bound::Included(b()), Bound::Excluded(c()))), ["k2", "k3"]);
+    assert_eq!(scan(&mut db, (Bound::Unbounded, Bound::Excluded(b()))), ["k1"]);
+    assert_eq!(scan(&mut db, (Bound::Excluded(c()), Bound::Unbounded)), ["k5"]);
+    assert_eq!(scan(&mut db, (Bound::Unbounded, Bound::Unbounded)).len(), 5);
+
+    // Index keys that extend another one are not part of its bounds.
+    db.register_index("value", 1, |_, value| vec![value.to_vec()]).unwrap();
+    set(&mut db, "k6", "b\0");
+    set(&mut db, "k7", "bb");
+    assert_eq!(keys(db.index_get("value", "b").unwrap()), ["k2", "k3"]);
+    assert_eq!(keys(db.index_scan("value", b"b\0".to_vec()..).unwrap()), ["k6", "k7", "k4", "k5"]);
+}
+
+#[test]
+fn reopening_reuses_the_index_until_it_changes() {
+    let dir = TempDir::new("secondary-reopen");
+    let mut db = Db::open(&dir).unwrap();
+    set(&mut db, "k1", "apple");
+    db.register_index("initial", 1, by_initial).unwrap();
+    set(&mut db, "k2", "avocado");
+    let seq = db.last_seq();
+    drop(db);
+
+    // The same version is used as it is, without a backfill.
+    let mut db = Db::open(&dir).unwrap();
+    db.register_index("initial", 1, by_initial).unwrap();
+    assert_eq!(db.last_seq(), seq);
+    assert_eq!(keys(db.index_get("initial", "a").unwrap()), ["k1", "k2"]);
+    drop(db);
+
+    // A new version rebuilds the entries with the new function.
+    let mut db = Db::open(&dir).unwrap();
+    db.register_index("initial", 2, |key, _| vec![key.to_vec()]).unwrap();
+    assert_eq!(db.last_seq(), seq + 1);
+    assert!(db.index_get("initial", "a").unwrap().is_empty());
+    assert_eq!(keys(db.index_get("initial", "k2").unwrap()), ["k2"]);
+}
+
+#[test]
+fn commits_made_without_the_index_force_a_rebuild() {
+    let dir = TempDir::new("secondary-unregistered");
+    let mut db = Db::open(&dir).unwrap();
+    db.register_index("initial", 1, by_initial).unwrap();
+    set(&mut db, "k1", "apple");
+    set(&mut db, "k2", "avocado");
+    drop(db);
+
+    let mut db = Db::open(&dir).unwrap();
+    delete(&mut db, "k1");
+    set(&mut db, "k3", "apricot");
+    drop(db);
+
+    let mut db = Db::open(&dir).unwrap();
+    db.register_index("initial", 1, by_initial).unwrap();
+    assert_eq!(keys(db.index_get("initial", "a").unwrap()), ["k2", "k3"]);
+    drop(db);
+
+    // A bulk load doesn't maintain stored indexes either.
+    let mut db = Db::open(&dir).unwrap();
+    let mut loader = db.bulk_loader().unwrap();
+    loader.insert("k4", "almond").unwrap();
+    loader.finish().unwrap();
+    db.register_index("initial", 1, by_initial).unwrap();
+    assert_eq!(keys(db.index_get("initial", "a").unwrap()), ["k2", "k3", "k4"]);
+}
@@ -0,0 +1,113 @@
+use std::ops::Bound;
+
+use rust_embedded_kv_store::tuple::{self, Element};
+use rust_embedded_kv_store::Db;
+
+fn round_trip(elements: Vec<Element>) {
+    let packed = tuple::pack(elements.clone());
+    assert_eq!(tuple::unpack(&packed).unwrap(), elements, "{packed:?}");
+}
+
+#[test]
+fn packed_tuples_unpack_to_the_same_elements() {
+    round_trip(vec![]);
+    round_trip(vec![Element::Null]);
+    for i in [i64::MIN, i64::MIN + 1, -65_536, -256, -255, -1, 0, 1, 255, 256, 65_536, i64::MAX] {
+        round_trip(vec![Element::Int(i)]);
+    }
+    round_trip(vec![Element::String(String::new()), Element::String("a\0b\0".into())]);
+    round_trip(vec![Element::Bytes(vec![0, 0xFF, 0]), Element::Bytes(vec![])]);
+    round_trip(vec![Element::Bool(false), Element::Bool(true)]);
+    round_trip(vec![
+        Element::Tuple(vec![Element::Null, Element::Tuple(vec![]), Element::Int(-7)]),
+        Element::Tuple(vec![Element::Tuple(vec![Element::Null])]),
+        Element::Null,
+    ]);
+
+    assert_eq!(
+        tuple::unpack(&tuple::pack(("acme", 42, true))).unwrap(),
+        [Element::String("acme".into()), Element::Int(42), Element::Bool(true)]
+    );
+    assert_eq!(tuple::pack((Some(3u8), None::<u8>)), tuple::pack((3, ())));
+}
+
+#[test]
+fn byte_order_matches_tuple_order() {
+    let mut elements = vec![
+        Element::Null,
+        Element::Bytes(vec![]),
+        Element::Bytes(vec![0]),
+        Element::Bytes(vec![0, 0]),
+        Element::Bytes(vec![0, 1]),
+        Element::Bytes(vec![1]),
+        Element::Bytes(vec![0xFF]),
+        Element::String(String::new()),
+        Element::String("\0".into()),
+        Element::String("a".into()),
+        Element::String("a\0".into()),
+        Element::String("ab".into()),
+        Element::Tuple(vec![]),
+        Element::Tuple(vec![Element::Null]),
+        Element::Tuple(vec![Element::Null, Element::Null]),
+        Element::Tuple(vec![Element::Int(0)]),
+        Element::Tuple(vec![Element::Tuple(vec![Element::Int(1)]), Element::Int(0)]),
+        Element::Tuple(vec![Element::Int(1)]),
+        Element::Bool(false),
+        Element::Bool(true),
+    ];
+    for i in [i64::MIN, -65_536, -65_535, -256, -255, -1, 0, 1, 255, 256, 65_535, 65_536, i64::MAX] {
+        elements.push(Element::Int(i));
+    }
+    elements.sort();
+
+    // Every pair, alone and followed by further elements.
+    for a in &elements {
+        for b in &elements {
+            let (pa, pb) = (tuple::pack(vec![a.clone()]), tuple::pack(vec![b.clone()]));
+            assert_eq!(pa.cmp(&pb), a.cmp(b), "{a:?} vs {b:?}");
+            let longer = tuple::pack(vec![a.clone(), Element::Int(i64::MIN)]);
+            let shorter = tuple::pack(vec![b.clone(), Element::Int(i64::MAX)]);
+            assert_eq!(longer.cmp(&shorter), a.cmp(b).then(std::cmp::Ordering::Less), "{a:?} vs {b:?}");
+        }
+    }
+
+    // A tuple sorts before every tuple it prefixes.
+    assert!(tuple::pack(("a",)) < tuple::pack(("a", i64::MIN)));
+    assert!(tuple::pack(("a", ())) < tuple::pack(("a", 0)));
+    assert!(tuple::pack(("a", 9)) < tuple::pack(("a\0", 0)));
+}
+
+#[test]
+fn malformed_keys_are_rejected() {
+    for bytes in [&[0x02, b'a'][..], &[0x15], &[0x05, 0x14], &[0x03], &[0x02, 0xFF, 0x00]] {
+        let e = tuple::unpack(bytes).err().unwrap_or_else(|| panic!("{bytes:?} unpacked"));
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+fn values(db: &mut Db, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Vec<String> {
+    db.scan(range).unwrap().into_iter().map(|(_, v)| String::from_utf8(v).unwrap()).collect()
+}
+
+#[test]
+fn range_scans_every_extension_of_a_prefix() {
+    let mut db = Db::open_in_memory().unwrap();
+    let mut tx = db.begin_transaction();
+    tx.set(tuple::pack(("acme",)), "tenant");
+    for (tenant, user, ts) in
+        [("acme", 1, 10), ("acme", 2, 5), ("acme", 2, -5), ("acme\0", 1, 0), ("acmf", 0, 0), ("acm", 9, 9)]
+    {
+        tx.set(tuple::pack((tenant, user, ts)), format!("{tenant}/{user}/{ts}"));
+    }
+    // Null sorts before any integer.
+    tx.set(tuple::pack(("acme", 2, (), "nested")), "null");
+    tx.commit().unwrap();
+
+    assert_eq!(values(&mut db, tuple::range(("acme",))), ["acme/1/10", "null", "acme/2/-5", "acme/2/5"]);
+    assert_eq!(values(&mut db, tuple::range(("acme", 2))), ["null", "acme/2/-5", "acme/2/5"]);
+    assert_eq!(values(&mut db, tuple::range(("acme", 3))), Vec::<String>::new());
+    assert_eq!(values(&mut db, tuple::range(())).len(), 8);
+
+    let keys = db.scan(tuple::range(("acme", 1))).unwrap();
+    assert_eq!(tuple::unpack(&keys[0].0).unwrap(), [Element::String("acme".into()), Element::Int(1), Element::Int(10)]);
+}
@@ -0,0 +1,211 @@
+#![cfg(feature = "serde")]
+
+use std::fmt::Debug;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use rust_embedded_kv_store::typed::{from_key, to_key};
+use rust_embedded_kv_store::{tuple, Db, TypedTree};
+
+/// Checks that `values`, given in ascending order, round-trip and encode to
+/// ascending keys.
+fn assert_ordered<T>(values: &[T])
+where
+    T: Serialize + DeserializeOwned + Debug + PartialEq,
+{
+    let keys: Vec<Vec<u8>> = values.iter().map(|v| to_key(v).unwrap()).collect();
+    for (value, key) in values.iter().zip(&keys) {
+        assert_eq!(&from_key::<T>(key).unwrap(), value);
+    }
+    for (i, pair) in keys.windows(2).enumerate() {
+        assert!(pair[0] < pair[1], "{:?} does not sort before {:?}", values[i], values[i + 1]);
+    }
+}
+
+#[test]
+fn integers_keep_their_order() {
+    assert_ordered(&[i8::MIN, -1, 0, 1, i8::MAX]);
+    assert_ordered(&[i32::MIN, -65_536, -256, -1, 0, 1, 255, 256, i32::MAX]);
+    assert_ordered(&[i64::MIN, i64::MIN + 1, -1, 0, 1, i64::MAX]);
+    assert_ordered(&[i128::MIN, -(1 << 64), -1, 0, 1, 1 << 64, i128::MAX]);
+    assert_ordered(&[0u8, 1, 255]);
+    assert_ordered(&[0u32, 1, 256, u32::MAX]);
+    assert_ordered(&[0u64, 1, i64::MAX as u64, i64::MAX as u64 + 1, u64::MAX]);
+    assert_ordered(&[0u128, 1, u64::MAX as u128, u64::MAX as u128 + 1, u128::MAX]);
+    assert_ordered(&['\0', 'a', 'é', char::MAX]);
+
+    // Narrow integers decode only into their own range.
+    assert!(from_key::<u8>(&to_key(&256u32).unwrap()).is_err());
+    assert!(from_key::<u32>(&to_key(&-1i64).unwrap()).is_err());
+}
+
+#[test]
+fn floats_keep_their_order() {
+    assert_ordered(&[
+        f64::NEG_INFINITY,
+        f64::MIN,
+        -1.0,
+        -f64::MIN_POSITIVE,
+        -0.0,
+        0.0,
+        f64::MIN_POSITIVE,
+        1.0,
+        f64::MAX,
+        f64::INFINITY,
+    ]);
+    assert_ordered(&[f32::NEG_INFINITY, -1.5, -0.0, 0.0, 1.5, f32::INFINITY]);
+
+    // -0.0 and 0.0 compare equal but keep distinct keys; NaN sorts last.
+    assert_eq!(from_key::<f64>(&to_key(&-0.0f64).unwrap()).unwrap().to_bits(), (-0.0f64).to_bits());
+    let nan = to_key(&f64::NAN).unwrap();
+    assert!(from_key::<f64>(&nan).unwrap().is_nan());
+    assert!(to_key(&f64::INFINITY).unwrap() < nan);
+    assert!(from_key::<f32>(&to_key(&f32::NAN).unwrap()).unwrap().is_nan());
+}
+
+/// A byte string, serialized with `serialize_bytes`.
+#[derive(Debug, PartialEq)]
+struct Blob(Vec<u8>);
+
+impl Serialize for Blob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Blob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BlobVisitor;
+        impl serde::de::Visitor<'_> for BlobVisitor {
+            type Value = Blob;
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("bytes")
+            }
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Blob, E> {
+                Ok(Blob(v))
+            }
+        }
+        deserializer.deserialize_byte_buf(BlobVisitor)
+    }
+}
+
+#[test]
+fn strings_and_bytes_with_nul_keep_their_order() {
+    let strings = ["", "\0", "\0\0", "\0a", "a", "a\0", "a\0b", "ab", "b"];
+    assert_ordered(&strings.map(String::from));
+    assert_ordered(&strings.map(|s| Blob(s.as_bytes().to_vec())));
+    assert_ordered(&[Blob(vec![0xFE]), Blob(vec![0xFF]), Blob(vec![0xFF, 0])]);
+
+    // A string that another extends is still followed by its terminator.
+    assert_ordered(&[("a".to_string(), 9u8), ("a\0".to_string(), 0u8)]);
+}
+
+#[test]
+fn options_sequences_and_tuples_keep_their_order() {
+    assert_ordered(&[None, Some(i32::MIN), Some(0), Some(i32::MAX)]);
+    assert_ordered(&[None, Some(None), Some(Some(0u8)), Some(Some(1u8))]);
+    assert_ordered(&[None, Some(())]);
+
+    assert_ordered(&[vec![], vec![0u16], vec![0, 0], vec![0, 1], vec![1]]);
+    assert_ordered(&[
+        vec![],
+        vec![String::new()],
+        vec!["a".to_string()],
+        vec!["a".into(), String::new()],
+        vec!["a\0".into()],
+    ]);
+    assert_ordered(&[(vec![1u8], 9u8), (vec![1u8, 0], 0u8)]);
+
+    assert_ordered(&[(0u32, "z".to_string()), (1, String::new()), (1, "a".into()), (2, String::new())]);
+    assert_ordered(&[(false, 1u8), (true, 0u8)]);
+    assert_ordered(&[(-1i64, None, 0.5f64), (-1, Some(0u8), -0.5), (0, None, -1.0)]);
+
+    let map: std::collections::BTreeMap<String, u32> = [("a".to_string(), 1), ("b".to_string(), 2)].into();
+    assert_eq!(from_key::<std::collections::BTreeMap<String, u32>>(&to_key(&map).unwrap()).unwrap(), map);
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Event {
+    Started,
+    Moved(i32),
+    Resized(u16, u16),
+    Renamed { from: String, to: String },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Versioned {
+    id: u64,
+    event: Event,
+}
+
+#[test]
+fn enums_and_structs_keep_their_order() {
+    assert_ordered(&[
+        Event::Started,
+        Event::Moved(-5),
+        Event::Moved(5),
+        Event::Resized(1, 9),
+        Event::Resized(2, 0),
+        Event::Renamed { from: "a".into(), to: "z".into() },
+        Event::Renamed { from: "b".into(), to: String::new() },
+    ]);
+    assert_ordered(&[
+        Versioned { id: 1, event: Event::Renamed { from: String::new(), to: String::new() } },
+        Versioned { id: 2, event: Event::Started },
+    ]);
+}
+
+#[test]
+fn keys_are_packed_tuples() {
+    assert_eq!(to_key(&(7u32, "x", -3i8)).unwrap(), tuple::pack((7, "x", -3)));
+    assert_eq!(to_key(&Blob(b"a\0b".to_vec())).unwrap(), tuple::pack((b"a\0b".as_slice(),)));
+    assert_eq!(from_key::<(i64, String)>(&tuple::pack((42, "answer"))).unwrap(), (42, "answer".to_string()));
+
+    assert!(from_key::<u8>(&to_key(&(1u8, 2u8)).unwrap()).is_err(), "trailing elements");
+    assert!(from_key::<String>(&to_key(&1u8).unwrap()).is_err(), "wrong element type");
+    assert!(from_key::<(u8, u8)>(&to_key(&1u8).unwrap()).is_err(), "too few elements");
+}
+
+#[test]
+fn trees_read_and_write_inside_transactions() {
+    let mut db = Db::open_in_memory().unwrap();
+    let users: TypedTree<(u32, String), Vec<String>> = TypedTree::new("users");
+
+    let mut tx = db.begin_transaction();
+    for (id, name) in [(3, "cy"), (1, "ada"), (2, "bo"), (1, "al")] {
+        users.insert(&mut tx, &(id, name.to_string()), &vec![name.to_uppercase()]).unwrap();
+    }
+    // Reads through the transaction see its own writes.
+    assert_eq!(users.get(&mut tx, &(2, "bo".into())).unwrap(), Some(vec!["BO".to_string()]));
+    users.remove(&mut tx, &(2, "bo".into())).unwrap();
+    assert_eq!(users.get(&mut tx, &(2, "bo".into())).unwrap(), None);
+    let ones: Vec<_> = users.range(&mut tx, (1, String::new())..(2, String::new())).unwrap();
+    assert_eq!(ones.iter().map(|(k, _)| k.1.as_str()).collect::<Vec<_>>(), ["ada", "al"]);
+    tx.commit().unwrap();
+
+    assert_eq!(users.get(&mut db, &(2, "bo".into())).unwrap(), None);
+    let all = users.iter(&mut db).unwrap();
+    assert_eq!(all.iter().map(|(k, _)| k.0).collect::<Vec<_>>(), [1, 1, 3]);
+    assert_eq!(users.range(&mut db, (1, "al".to_string())..=(3, "cy".to_string())).unwrap().len(), 2);
+
+    // A transaction that is dropped leaves nothing behind.
+    let mut tx = db.begin_transaction();
+    users.insert(&mut tx, &(9, "zed".into()), &Vec::new()).unwrap();
+    drop(tx);
+    assert_eq!(users.get(&mut db, &(9, "zed".into())).unwrap(), None);
+}
+
+#[test]
+fn trees_do_not_overlap() {
+    let mut db = Db::open_in_memory().unwrap();
+    let short: TypedTree<u8, u8> = TypedTree::new("a");
+    let long: TypedTree<u8, u8> = TypedTree::new("a\0");
+    let mut tx = db.begin_transaction();
+    short.insert(&mut tx, &1, &10).unwrap();
+    long.insert(&mut tx, &2, &20).unwrap();
+    tx.commit().unwrap();
+
+    assert_eq!(short.iter(&mut db).unwrap(), [(1, 10)]);
+    assert_eq!(long.iter(&mut db).unwrap(), [(2, 20)]);
+}
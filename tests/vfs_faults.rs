@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use rust_embedded_kv_store::{ChangeCursor, Db, FaultFs};
+
+// Syncs made by one commit, in order: WAL, change log, data log, cleared WAL.
+const WAL_SYNC: u64 = 0;
+const CHANGES_SYNC: u64 = 1;
+const DATA_SYNC: u64 = 2;
+
+fn open(fs: &FaultFs) -> Db {
+    Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap()
+}
+
+fn set(db: &mut Db, key: &str, value: &str) -> std::io::Result<()> {
+    let mut tx = db.begin_transaction();
+    tx.set(key, value);
+    tx.commit()
+}
+
+fn keys(db: &mut Db) -> Vec<String> {
+    db.scan(..).unwrap().into_iter().map(|(k, _)| String::from_utf8(k).unwrap()).collect()
+}
+
+#[test]
+fn crash_keeps_only_committed_writes() {
+    let fs = FaultFs::new();
+    let mut db = open(&fs);
+    set(&mut db, "a", "1").unwrap();
+    set(&mut db, "b", "2").unwrap();
+    fs.crash();
+    assert!(db.get("a").is_err(), "handles from before the crash must not work");
+    drop(db);
+
+    let mut db = open(&fs);
+    assert_eq!(keys(&mut db), ["a", "b"]);
+    assert_eq!(db.last_seq(), 2);
+}
+
+#[test]
+fn failed_wal_sync_discards_the_commit() {
+    let fs = FaultFs::new();
+    let mut db = open(&fs);
+    set(&mut db, "a", "1").unwrap();
+
+    fs.fail_sync(Some(WAL_SYNC));
+    assert!(set(&mut db, "b", "2").is_err());
+    assert_eq!(db.get("b").unwrap(), None);
+
+    // The Db stays usable, and the failed commit is not resurrected by the
+    // next commit or by recovery.
+    set(&mut db, "c", "3").unwrap();
+    assert_eq!(db.last_seq(), 2);
+    drop(db);
+    let mut db = open(&fs);
+    assert_eq!(keys(&mut db), ["a", "c"]);
+    fs.crash();
+    let mut db = open(&fs);
+    assert_eq!(keys(&mut db), ["a", "c"]);
+}
+
+#[test]
+fn failure_after_the_wal_sync_is_recovered_on_reopen() {
+    for failing_sync in [CHANGES_SYNC, DATA_SYNC] {
+        let fs = FaultFs::new();
+        let mut db = open(&fs);
+        set(&mut db, "a", "1").unwrap();
+
+        fs.fail_sync(Some(failing_sync));
+        assert!(set(&mut db, "b", "2").is_err());
+        assert!(set(&mut db, "c", "3").is_err(), "a Db with a half-applied commit must refuse writes");
+
+        fs.crash();
+        let mut db = open(&fs);
+        assert_eq!(keys(&mut db), ["a", "b"], "failing sync {failing_sync}");
+        assert_eq!(db.last_seq(), 2);
+        let seqs: Vec<u64> = db
+            .read_changes(&mut ChangeCursor::from_seq(1), 10)
+            .unwrap()
+            .into_iter()
+            .map(|batch| batch.seq)
+            .collect();
+        assert_eq!(seqs, [1, 2]);
+    }
+}
+
+#[test]
+fn full_disk_fails_the_commit_cleanly() {
+    let fs = FaultFs::new();
+    let mut db = open(&fs);
+    set(&mut db, "a", "1").unwrap();
+
+    fs.fail_writes(Some(5));
+    assert!(set(&mut db, "b", "2").is_err());
+    fs.fail_writes(None);
+    set(&mut db, "c", "3").unwrap();
+
+    fs.crash();
+    let mut db = open(&fs);
+    assert_eq!(keys(&mut db), ["a", "c"]);
+}
+
+#[test]
+fn short_writes_are_completed() {
+    let fs = FaultFs::new();
+    let mut db = open(&fs);
+    fs.short_writes(Some(3));
+    for i in 0..10 {
+        set(&mut db, &format!("key{i}"), "some longer value").unwrap();
+    }
+    db.compact().unwrap();
+
+    fs.crash();
+    let mut db = open(&fs);
+    assert_eq!(keys(&mut db).len(), 10);
+    assert_eq!(db.get("key7").unwrap(), Some(b"some longer value".to_vec()));
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+
+use rust_embedded_kv_store::{ChangeEvent, Db, FaultFs};
+
+use common::set;
+
+fn event(seq: u64, key: &str, old: Option<&str>, new: Option<&str>) -> ChangeEvent {
+    let bytes = |s: Option<&str>| s.map(|s| s.as_bytes().to_vec());
+    ChangeEvent { seq, key: key.as_bytes().to_vec(), old: bytes(old), new: bytes(new) }
+}
+
+fn drain(rx: &Receiver<ChangeEvent>) -> Vec<ChangeEvent> {
+    rx.try_iter().collect()
+}
+
+#[test]
+fn events_follow_the_commit_with_old_and_new_values() {
+    let mut db = Db::open_in_memory().unwrap();
+    set(&mut db, "a", "1");
+    let rx = db.watch("");
+
+    let mut tx = db.begin_transaction();
+    tx.set("a", "2");
+    tx.set("b", "new");
+    tx.delete("a");
+    tx.set("c", "x");
+    assert!(drain(&rx).is_empty());
+    tx.commit().unwrap();
+    // One event per key with its net change, in key order.
+    assert_eq!(
+        drain(&rx),
+        [event(2, "a", Some("1"), None), event(2, "b", None, Some("new")), event(2, "c", None, Some("x"))]
+    );
+
+    set(&mut db, "b", "newer");
+    assert_eq!(drain(&rx), [event(3, "b", Some("new"), Some("newer"))]);
+
+    // A transaction that is dropped sends nothing.
+    let mut tx = db.begin_transaction();
+    tx.set("d", "d");
+    drop(tx);
+    assert!(drain(&rx).is_empty());
+}
+
+#[test]
+fn a_failed_commit_sends_nothing() {
+    let fs = FaultFs::new();
+    let mut db = Db::open_with_vfs(Arc::new(fs.clone()), "db").unwrap();
+    let rx = db.watch("");
+    fs.fail_sync(Some(0));
+    let mut tx = db.begin_transaction();
+    tx.set("a", "1");
+    assert!(tx.commit().is_err());
+    assert!(drain(&rx).is_empty());
+}
+
+#[test]
+fn only_keys_under_the_prefix_are_reported() {
+    let mut db = Db::open_in_memory().unwrap();
+    let users = db.watch("user/");
+    let everything = db.watch("");
+
+    let mut tx = db.begin_transaction();
+    tx.set("user/1", "ada");
+    tx.set("user", "not under the prefix");
+    tx.set("order/1", "book");
+    tx.commit().unwrap();
+    assert_eq!(drain(&users), [event(1, "user/1", None, Some("ada"))]);
+    assert_eq!(drain(&everything).len(), 3);
+
+    // A commit that touches nothing under the prefix sends nothing.
+    set(&mut db, "order/2", "pen");
+    assert!(drain(&users).is_empty());
+    assert_eq!(drain(&everything), [event(2, "order/2", None, Some("pen"))]);
+}
+
+#[test]
+fn unchanged_keys_and_index_entries_are_skipped() {
+    let mut db = Db::open_in_memory().unwrap();
+    db.register_index("value", 1, |_, value| vec![value.to_vec()]).unwrap();
+    let everything = db.watch("");
+    let system = db.watch([0xFF]);
+
+    set(&mut db, "a", "1");
+    assert_eq!(drain(&everything), [event(2, "a", None, Some("1"))]);
+
+    // Rewriting a value or deleting a missing key changes nothing.
+    let mut tx = db.begin_transaction();
+    tx.set("a", "1");
+    tx.delete("missing");
+    tx.commit().unwrap();
+    assert!(drain(&everything).is_empty());
+
+    set(&mut db, "a", "2");
+    assert_eq!(drain(&everything), [event(4, "a", Some("1"), Some("2"))]);
+    assert!(drain(&system).is_empty());
+}
+
+#[test]
+fn dropping_the_receiver_unsubscribes() {
+    let mut db = Db::open_in_memory().unwrap();
+    let kept = db.watch("");
+    let dropped = db.watch("");
+    drop(dropped);
+
+    set(&mut db, "a", "1");
+    assert_eq!(drain(&kept).len(), 1);
+    drop(db);
+    // The Db held the only sender left.
+    assert_eq!(kept.try_recv(), Err(TryRecvError::Disconnected));
+}